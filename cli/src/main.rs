@@ -20,7 +20,8 @@ use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread;
 use std::fs;
 use std::path::{Path, PathBuf};
-use hex_database::{Instance, View, search::SearchQuery, Track, GossipConf, TrackKey, Playlist};
+use std::time::{SystemTime, UNIX_EPOCH};
+use hex_database::{Instance, View, search::SearchQuery, Track, GossipConf, Keypair, Permissions, TrackKey, Playlist};
 
 use futures::Future;
 
@@ -36,22 +37,42 @@ fn main() {
     let db_path = path.join("music.db");
 
     let mut gossip = GossipConf::new();
+    let mut permissions = Permissions::new();
 
     if let Some(ref peer) = conf.peer {
         gossip = gossip.addr((conf.host, peer.port));
-        gossip = gossip.id(peer.id());
+        gossip = gossip.keypair(Keypair::from_seed(&peer.secret()));
         gossip = gossip.network_key(peer.network_key());
+        for key in peer.accepted_network_keys() {
+            gossip = gossip.accept_network_key(key);
+        }
+        permissions = Permissions::from_names(peer.trusted());
     }
 
-    let mut instance = Instance::from_file(&db_path, gossip);
-    let view = instance.view();
+    let mut instance = match Instance::with_permissions(&db_path, gossip, permissions) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error: Could not open database {:?}", err);
+            return;
+        }
+    };
+    let view = match instance.view() {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error: Could not open database view {:?}", err);
+            return;
+        }
+    };
 
     let (sender, receiver): (Sender<TrackKey>, Receiver<TrackKey>) = channel();
     let path_copy = data_path.clone();
     thread::spawn(move || {
         while let Ok(key) = receiver.recv() {
             if !path_copy.join(key.to_string()).exists() {
-                instance.ask_for_file(key.to_vec()).wait().unwrap();
+                match instance.ask_for_file(key.to_vec()) {
+                    Ok(file) => file.wait().unwrap(),
+                    Err(err) => eprintln!("Could not download file {}: {:?}", key.to_string(), err)
+                }
             }
         }
     });
@@ -84,9 +105,26 @@ fn main() {
             args.push("");
         }
 
-        let query = SearchQuery::new(&args[1]);
-        let mut query = view.search_prep(query).unwrap();
-        let tracks: Vec<Track> = view.search(&mut query).collect();
+        let query = match SearchQuery::new(&args[1]) {
+            Ok(query) => query,
+            Err(err) => {
+                eprintln!("Error: Invalid search query {:?}", err);
+                continue;
+            }
+        };
+        let tracks = view.search_prep(query).and_then(|mut query| {
+            let tracks = view.search(&mut query)?.collect::<Result<Vec<Track>, _>>();
+
+            tracks
+        });
+
+        let tracks = match tracks {
+            Ok(tracks) => tracks,
+            Err(err) => {
+                eprintln!("Error: Could not search in database {:?}", err);
+                continue;
+            }
+        };
 
         let data_path = data_path.clone();
         let sender = sender.clone();
@@ -116,13 +154,16 @@ fn main() {
             "modify" => {
                 modify::modify_tracks(&view, tracks);
             },
+            "import" => {
+                import_data(&view, &data_path);
+            },
             "quit" => {
                 println!("Exit ..");
                 return;
             },
             _ => {
                 println!("Supported actions:");
-                println!("  show, delete, add-playlist, sync, play, modify, quit");
+                println!("  show, delete, add-playlist, sync, play, modify, import, quit");
             }
         }
     }
@@ -140,6 +181,13 @@ fn show_tracks(query: &str, tracks: Vec<Track>) {
 
 }
 
+fn import_data(db: &View, data_path: &Path) {
+    match db.import_data(data_path) {
+        Ok(keys) => println!("Imported {} tracks from {:?}", keys.len(), data_path),
+        Err(err) => eprintln!("Error: Could not import tracks {:?}", err)
+    }
+}
+
 fn add_playlist(db: &View, tracks: Vec<Track>) {
     println!("Create new playlist with {} tracks", tracks.len());
 
@@ -149,7 +197,8 @@ fn add_playlist(db: &View, tracks: Vec<Track>) {
         title: "New Playlist".into(),
         desc: None,
         tracks: tracks.into_iter().map(|x| x.key).collect(),
-        origin: vec![0; 16]
+        origin: vec![0; 16],
+        query: None
     };
 
     db.add_playlist(pl).unwrap();
@@ -182,22 +231,64 @@ fn delete_tracks(db: &View, data_path: &Path, tracks: Vec<Track>) {
 }
 
 fn print_overview(db: &View) {
-    let mut tracks = db.get_tracks();
-    tracks.sort_by(|a, b| a.favs_count.cmp(&b.favs_count).reverse());
+    let tracks = match db.get_tracks() {
+        Ok(tracks) => tracks,
+        Err(err) => {
+            eprintln!("Error: Could not get tracks {:?}", err);
+            return;
+        }
+    };
 
     let duration = tracks.iter().fold(0.0, |y,x| y + x.duration);
 
     println!(" => Found {} tracks in total length {} min", tracks.len(), (duration / 60.0).floor());
 
-    for track in tracks.iter().take(10) {
-        if let (Some(ref title), Some(ref interpret)) = (&track.title, &track.interpret) {
-            println!("\t{} ## {}", title, interpret);
-        }
+    // statistics of the last 30 days
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let range = now - 30 * 24 * 3600 .. now + 1;
+
+    match db.get_top_tracks(range.clone(), None, 10) {
+        Ok(top) => {
+            println!(" => Most played tracks in the last 30 days:");
+
+            for (track, plays) in top {
+                if let (Some(ref title), Some(ref interpret)) = (&track.title, &track.interpret) {
+                    println!("\t{} ## {} ({} plays)", title, interpret, plays);
+                }
+            }
+        },
+        Err(err) => eprintln!("Error: Could not get most played tracks {:?}", err)
+    }
+
+    match db.get_top_interprets(range.clone(), None, 5) {
+        Ok(top) => {
+            println!(" => Most played interprets in the last 30 days:");
+
+            for (interpret, plays) in top {
+                println!("\t{} ({} plays)", interpret, plays);
+            }
+        },
+        Err(err) => eprintln!("Error: Could not get most played interprets {:?}", err)
+    }
+
+    match db.get_listening_time(range, None) {
+        Ok(days) => {
+            let total = days.iter().fold(0.0, |y, x| y + x.1);
+
+            println!(" => Listened {} min on {} days in the last 30 days", (total / 60.0).floor(), days.len());
+        },
+        Err(err) => eprintln!("Error: Could not get listening time {:?}", err)
     }
 
     println!("");
 
-    let playlists = db.get_playlists();
+    let playlists = match db.get_playlists() {
+        Ok(playlists) => playlists,
+        Err(err) => {
+            eprintln!("Error: Could not get playlists {:?}", err);
+            return;
+        }
+    };
 
     println!(" => Found {} playlists:", playlists.len());
 
@@ -56,8 +56,11 @@ pub fn player(data_path: PathBuf, tracks: Vec<Track>, events: Receiver<Event>) {
 
         println!("{}", tracks[idx].title.clone().unwrap_or("Unknown".into()));
 
+        // normalize the playback to the reference loudness
+        let gain = tracks[idx].measured_loudness().map(|x| x.gain()).unwrap_or(1.0);
+
         let mut pos = 0.0;
-        'inner: while let Ok(buf) = container.next_packet(Configuration::Stereo) {
+        'inner: while let Ok(buf) = container.next_packet(Configuration::Stereo, gain) {
             pos += buf.len() as f64 / 48000.0 / 2.0;
 
             print!("\rPlaying [");
@@ -7,5 +7,10 @@ pub type Result<T> = result::Result<T, Error>;
 pub enum Error {
     Deserialize(toml::de::Error),
     ConfigurationNotFound,
-    MissingEnv
+    MissingEnv,
+    /// The peer has no `secret`
+    MissingSecret,
+    /// The peer only has the public `id` of older configurations, generate a new `secret` with
+    /// 64 random hex digits
+    PublicIdAsSecret
 }
@@ -62,45 +62,112 @@ pub struct DatabasePeer {
     /// dataspace
     #[serde(default)]
     pub sync_all: bool,
-    /// Unique identification to all other peers (256bits)
-    pub id: String,
+    /// Keep only the audio data of these playlists, unless `sync_all` is set
+    #[serde(default)]
+    pub sync_playlists: Vec<i64>,
+    /// Keep only the audio data of the playlists of these tokens, unless `sync_all` is set
+    #[serde(default)]
+    pub sync_tokens: Vec<i64>,
+    /// Keep the recently played audio data in a cache of this size (MiB) instead
+    pub cache_quota: Option<u64>,
+    /// Secret seed of the Ed25519 keypair signing our transitions (256bits), the public key
+    /// derived from it identifies us to all other peers
+    #[serde(default)]
+    pub secret: String,
+    /// Public id of configurations written before transitions were signed, only used to refuse
+    /// them. The id is known to all peers and must never be used as secret.
+    #[serde(default)]
+    pub id: Option<String>,
     /// Network key (256bits)
     pub network: String,
+    /// Previous or upcoming network keys, still accepted while all devices switch to a new key
+    #[serde(default)]
+    pub accept_networks: Vec<String>,
     /// The sync server port is optional and defaults to 8004
     #[serde(default = "default_port_dbpeer")]
-    pub port: u16
+    pub port: u16,
+    /// Peers allowed to change the database, everyone knowing the network key if empty
+    #[serde(default)]
+    pub trust: Vec<TrustedPeer>
+}
+
+/// A peer allowed to change the database
+#[derive(Deserialize, Debug, Clone)]
+pub struct TrustedPeer {
+    /// Public key of the peer (256bits)
+    pub id: String,
+    /// Either `full`, `read-only` or `token-only`
+    pub role: String
 }
 
 impl DatabasePeer {
-    pub fn id(&self) -> Vec<u8> {
-        if self.id.len() != 64 {
-            panic!("Error: Invalid peer id length - {} != 64", self.id.len());
+    /// Check that the peer has a secret of its own
+    pub fn validate(&self) -> Result<()> {
+        match (self.secret.is_empty(), &self.id) {
+            (true, Some(_)) => Err(Error::PublicIdAsSecret),
+            (true, None) => Err(Error::MissingSecret),
+            (false, _) => Ok(())
         }
+    }
 
-        let mut key = vec![0u8; 32];
+    pub fn secret(&self) -> [u8; 32] {
+        if self.secret.len() != 64 {
+            panic!("Error: Invalid peer secret length - {} != 64", self.secret.len());
+        }
+
+        let mut key = [0u8; 32];
 
         for i in 0..32 {
-            key[i] = u8::from_str_radix(&self.id[i*2..i*2+2], 16).unwrap();
+            key[i] = u8::from_str_radix(&self.secret[i*2..i*2+2], 16).unwrap();
         }   
 
         key
     }
 
-    pub fn network_key(&self) -> [u8; 32] {
-        if self.id.len() != 64 {
-            panic!("Error: Invalid peer id length - {} != 64", self.id.len());
-        }
+    /// Return the ids of all trusted peers together with the name of their role
+    pub fn trusted(&self) -> Vec<(Vec<u8>, String)> {
+        self.trust.iter().map(|peer| {
+            if peer.id.len() != 64 {
+                panic!("Error: Invalid trusted peer id length - {} != 64", peer.id.len());
+            }
 
-        let mut key = [0u8; 32];
+            let id = (0..32)
+                .map(|i| u8::from_str_radix(&peer.id[i*2..i*2+2], 16).unwrap())
+                .collect();
 
-        for i in 0..32 {
-            key[i] = u8::from_str_radix(&self.network[i*2..i*2+2], 16).unwrap();
-        }   
+            (id, peer.role.clone())
+        }).collect()
+    }
 
-        key
+    /// Size of the cache in bytes, if set
+    pub fn cache_quota(&self) -> Option<u64> {
+        self.cache_quota.map(|x| x * 1024 * 1024)
+    }
+
+    pub fn network_key(&self) -> [u8; 32] {
+        parse_network_key(&self.network)
+    }
+
+    /// Return the further network keys we accept
+    pub fn accepted_network_keys(&self) -> Vec<[u8; 32]> {
+        self.accept_networks.iter().map(|x| parse_network_key(x)).collect()
     }
 }
 
+fn parse_network_key(network: &str) -> [u8; 32] {
+    if network.len() != 64 {
+        panic!("Error: Invalid network key length - {} != 64", network.len());
+    }
+
+    let mut key = [0u8; 32];
+
+    for i in 0..32 {
+        key[i] = u8::from_str_radix(&network[i*2..i*2+2], 16).unwrap();
+    }   
+
+    key
+}
+
 /// Global configuration
 #[derive(Deserialize,Debug)]
 pub struct Conf {
@@ -133,8 +200,14 @@ impl Conf {
         let mut contents = String::new();
         file.read_to_string(&mut contents).unwrap();
 
-        toml::from_str(&contents)
-            .map_err(|e| Error::Deserialize(e))
+        let conf: Conf = toml::from_str(&contents)
+            .map_err(|e| Error::Deserialize(e))?;
+
+        if let Some(ref peer) = conf.peer {
+            peer.validate()?;
+        }
+
+        Ok(conf)
     }
 
     /// Load the configuration from the environment
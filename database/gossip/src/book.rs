@@ -0,0 +1,370 @@
+//! Known peers and the liveness of their connections
+//!
+//! Every peer we were connected to is kept in the book together with the address it listens at. A
+//! connection is considered dead if nothing arrived from the peer for `TIMEOUT` milliseconds, all
+//! peers send a heartbeat every `HEARTBEAT` milliseconds to keep idle connections alive. We try to
+//! reach peers without connection again with an exponential backoff. The book can be persisted to
+//! a file, so a restarted peer finds the network again without contact or discovery.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bincode::{serialize_into, deserialize_from};
+
+use {PeerId, PeerPresence};
+use transition::TransitionKey;
+
+/// Interval between two heartbeats in milliseconds
+pub const HEARTBEAT: u64 = 5_000;
+/// Time without any packet, after which a connection is considered dead
+pub const TIMEOUT: u64 = 3 * HEARTBEAT;
+/// Delay of the first reconnection attempt, doubled after every failure
+pub const BACKOFF_MIN: u64 = 500;
+/// Longest delay between two reconnection attempts
+pub const BACKOFF_MAX: u64 = 60_000;
+/// Peers not seen for a week are forgotten
+pub const EXPIRY: u64 = 7 * 24 * 3600 * 1000;
+
+/// Status of a known peer, all times are in milliseconds of the transport clock
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerStatus {
+    pub id: PeerId,
+    pub addr: SocketAddr,
+    /// Start of the current connection, `None` if not connected
+    pub connected_since: Option<u64>,
+    /// Last time a packet arrived from the peer
+    pub last_seen: Option<u64>,
+    /// Bytes received over the current connection
+    pub bytes_in: u64,
+    /// Bytes sent over the current connection
+    pub bytes_out: u64,
+    /// Number of transitions requested from the peer, which did not arrive yet
+    pub pending: usize,
+    /// Negotiated protocol version of the current connection
+    pub version: Option<u8>,
+    /// Optional features both peers support
+    pub features: u32
+}
+
+/// An established connection to a peer
+struct Connection {
+    /// Distinguishes the connection from earlier ones to the same peer
+    conn: usize,
+    /// Negotiated version and features
+    protocol: (u8, u32),
+    since: u64,
+    received: Arc<AtomicUsize>,
+    requested: HashSet<TransitionKey>
+}
+
+struct Entry {
+    addr: SocketAddr,
+    /// Time we learned about the peer
+    learned: u64,
+    last_seen: Option<u64>,
+    connection: Option<Connection>,
+    /// Failed reconnection attempts and the time of the next one
+    attempts: u32,
+    retry_at: u64
+}
+
+impl Entry {
+    fn disconnected(addr: SocketAddr, now: u64, retry_at: u64) -> Entry {
+        Entry { addr, learned: now, last_seen: None, connection: None, attempts: 0, retry_at }
+    }
+}
+
+/// Delay before the next attempt after `attempts` failed ones
+fn backoff(attempts: u32) -> u64 {
+    cmp::min(BACKOFF_MIN << cmp::min(attempts, 16), BACKOFF_MAX)
+}
+
+/// All peers we know about, either connected or waiting for a reconnection
+pub struct PeerBook {
+    entries: HashMap<PeerId, Entry>,
+    path: Option<PathBuf>,
+    changed: bool
+}
+
+impl Default for PeerBook {
+    fn default() -> PeerBook {
+        PeerBook::new()
+    }
+}
+
+impl PeerBook {
+    /// Create an empty book, which is not persisted
+    pub fn new() -> PeerBook {
+        PeerBook { entries: HashMap::new(), path: None, changed: false }
+    }
+
+    /// Load the book from a file and schedule a connection to every peer in it
+    ///
+    /// A missing or unreadable file results in an empty book, which is saved to `path` later on.
+    pub fn load(path: PathBuf, now: u64) -> PeerBook {
+        let stored: Vec<(PeerId, SocketAddr, Option<u64>)> = match File::open(&path) {
+            Ok(file) => deserialize_from(file).unwrap_or_else(|err| {
+                warn!("Could not read the peer book {:?}: {}", path, err);
+
+                Vec::new()
+            }),
+            Err(_) => Vec::new()
+        };
+
+        let entries = stored.into_iter()
+            .filter(|x| x.2.map(|seen| seen + EXPIRY >= now).unwrap_or(false))
+            .map(|(id, addr, last_seen)| {
+                let mut entry = Entry::disconnected(addr, now, now);
+                entry.last_seen = last_seen;
+
+                (id, entry)
+            })
+            .collect();
+
+        PeerBook { entries, path: Some(path), changed: false }
+    }
+
+    /// Write the book to its file, if it is persisted and has changed since
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = match (self.changed, &self.path) {
+            (true, Some(path)) => path.clone(),
+            _ => return Ok(())
+        };
+
+        let stored: Vec<(&PeerId, &SocketAddr, Option<u64>)> = self.entries.iter()
+            .map(|(id, entry)| (id, &entry.addr, entry.last_seen))
+            .collect();
+
+        let file = File::create(path)?;
+        serialize_into(file, &stored)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+        self.changed = false;
+
+        Ok(())
+    }
+
+    /// Remember a peer we are not connected to yet, the first attempt is made by the caller
+    pub fn insert(&mut self, presence: &PeerPresence, now: u64) {
+        if !self.entries.contains_key(&presence.id) {
+            self.entries.insert(presence.id.clone(), Entry::disconnected(presence.addr, now, now + BACKOFF_MIN));
+            self.changed = true;
+        }
+    }
+
+    pub fn contains(&self, id: &PeerId) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    /// We are connected to at least a single peer
+    pub fn any_connected(&self) -> bool {
+        self.entries.values().any(|x| x.connection.is_some())
+    }
+
+    /// A new connection to a peer is established, it replaces any existing one
+    pub fn connect(&mut self, presence: &PeerPresence, conn: usize, protocol: (u8, u32), now: u64, received: Arc<AtomicUsize>) {
+        let entry = self.entries.entry(presence.id.clone())
+            .or_insert_with(|| Entry::disconnected(presence.addr, now, now));
+
+        entry.addr = presence.addr;
+        entry.last_seen = Some(now);
+        entry.attempts = 0;
+        entry.connection = Some(Connection { conn, protocol, since: now, received, requested: HashSet::new() });
+
+        self.changed = true;
+    }
+
+    /// Close the connection to a peer, if `conn` is the current one or `None`
+    ///
+    /// Returns whether a connection was closed. The peer is contacted again after a backoff.
+    pub fn disconnect(&mut self, id: &PeerId, conn: Option<usize>, now: u64) -> bool {
+        let entry = match self.entries.get_mut(id) {
+            Some(entry) => entry,
+            None => return false
+        };
+
+        let current = match entry.connection {
+            Some(ref connection) => conn.map(|x| x == connection.conn).unwrap_or(true),
+            None => false
+        };
+
+        if current {
+            entry.connection = None;
+            entry.attempts = 0;
+            entry.retry_at = now + BACKOFF_MIN;
+            self.changed = true;
+        }
+
+        current
+    }
+
+    /// A packet arrived from a peer
+    pub fn seen(&mut self, id: &PeerId, now: u64) {
+        if let Some(entry) = self.entries.get_mut(id) {
+            if entry.connection.is_some() {
+                entry.last_seen = Some(now);
+            }
+        }
+    }
+
+    /// Connected peers, from which nothing arrived for `TIMEOUT` milliseconds
+    pub fn timed_out(&self, now: u64) -> Vec<PeerId> {
+        self.entries.iter()
+            .filter(|(_, entry)| entry.connection.is_some())
+            .filter(|(_, entry)| entry.last_seen.map(|x| x + TIMEOUT < now).unwrap_or(true))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Peers to contact again now, the next attempt is scheduled with a doubled delay
+    ///
+    /// Peers not seen for `EXPIRY` milliseconds are forgotten instead.
+    pub fn due(&mut self, now: u64) -> Vec<(PeerId, SocketAddr)> {
+        let expired: Vec<PeerId> = self.entries.iter()
+            .filter(|(_, entry)| entry.connection.is_none())
+            .filter(|(_, entry)| entry.last_seen.unwrap_or(entry.learned) + EXPIRY < now)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in expired {
+            info!("Forget peer {:?}", id);
+
+            self.entries.remove(&id);
+            self.changed = true;
+        }
+
+        let mut due = Vec::new();
+        for (id, entry) in self.entries.iter_mut() {
+            if entry.connection.is_none() && entry.retry_at <= now {
+                entry.attempts += 1;
+                entry.retry_at = now + backoff(entry.attempts);
+
+                due.push((id.clone(), entry.addr));
+            }
+        }
+
+        due
+    }
+
+    /// Presences of all connected peers except `except`
+    pub fn presences(&self, except: &PeerId) -> Vec<PeerPresence> {
+        self.entries.iter()
+            .filter(|(id, entry)| *id != except && entry.connection.is_some())
+            .map(|(id, entry)| PeerPresence { id: id.clone(), addr: entry.addr, writer: None })
+            .collect()
+    }
+
+    /// We asked the peer for transitions
+    pub fn requested(&mut self, id: &PeerId, keys: &[TransitionKey]) {
+        if let Some(connection) = self.entries.get_mut(id).and_then(|x| x.connection.as_mut()) {
+            connection.requested.extend(keys.iter().cloned());
+        }
+    }
+
+    /// Transitions arrived from the peer
+    pub fn arrived(&mut self, id: &PeerId, keys: &[TransitionKey]) {
+        if let Some(connection) = self.entries.get_mut(id).and_then(|x| x.connection.as_mut()) {
+            for key in keys {
+                connection.requested.remove(key);
+            }
+        }
+    }
+
+    /// Status of all known peers, sorted by their id
+    ///
+    /// The bytes written to a peer are counted by its writer and passed in `written`.
+    pub fn status(&self, written: &HashMap<PeerId, u64>) -> Vec<PeerStatus> {
+        let mut status: Vec<PeerStatus> = self.entries.iter()
+            .map(|(id, entry)| {
+                let connection = entry.connection.as_ref();
+
+                PeerStatus {
+                    id: id.clone(),
+                    addr: entry.addr,
+                    connected_since: connection.map(|x| x.since),
+                    last_seen: entry.last_seen,
+                    bytes_in: connection.map(|x| x.received.load(Ordering::Relaxed) as u64).unwrap_or(0),
+                    bytes_out: connection.and_then(|_| written.get(id).cloned()).unwrap_or(0),
+                    pending: connection.map(|x| x.requested.len()).unwrap_or(0),
+                    version: connection.map(|x| x.protocol.0),
+                    features: connection.map(|x| x.protocol.1).unwrap_or(0)
+                }
+            })
+            .collect();
+
+        status.sort_by(|a, b| a.id.cmp(&b.id));
+
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+
+    use PeerPresence;
+    use protocol::VERSION;
+    use super::{PeerBook, TIMEOUT};
+
+    fn presence(id: u8) -> PeerPresence {
+        PeerPresence { id: vec![id], addr: SocketAddr::from(([10, 0, 0, id], 8000)), writer: None }
+    }
+
+    #[test]
+    fn timeout_and_backoff() {
+        let mut book = PeerBook::new();
+        book.connect(&presence(1), 0, (VERSION, 0), 0, Arc::new(AtomicUsize::new(0)));
+
+        book.seen(&vec![1], 1000);
+        assert!(book.timed_out(1000 + TIMEOUT).is_empty());
+        assert_eq!(book.timed_out(1001 + TIMEOUT), vec![vec![1]]);
+
+        // an earlier connection closing doesn't affect the current one
+        assert!(!book.disconnect(&vec![1], Some(1), 2000));
+        assert!(book.disconnect(&vec![1], None, 2000));
+        assert!(!book.any_connected());
+
+        // the delay doubles with every attempt
+        let mut attempts = Vec::new();
+        for now in 2000..20000 {
+            if !book.due(now).is_empty() {
+                attempts.push(now);
+            }
+        }
+        assert_eq!(attempts, vec![2500, 3500, 5500, 9500, 17500]);
+
+        let status = book.status(&HashMap::new());
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].connected_since, None);
+        assert_eq!(status[0].last_seen, Some(1000));
+    }
+
+    #[test]
+    fn persist() {
+        let path = ::std::env::temp_dir().join("hex-gossip-book.test");
+        let _ = fs::remove_file(&path);
+
+        let mut book = PeerBook::load(path.clone(), 0);
+        book.connect(&presence(1), 0, (VERSION, 0), 100, Arc::new(AtomicUsize::new(0)));
+        book.connect(&presence(2), 1, (VERSION, 0), 100, Arc::new(AtomicUsize::new(0)));
+        book.save().unwrap();
+
+        // all peers are contacted immediately after loading
+        let mut book = PeerBook::load(path.clone(), 200);
+        let mut due = book.due(200);
+        due.sort();
+        assert_eq!(due, vec![(vec![1], presence(1).addr), (vec![2], presence(2).addr)]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
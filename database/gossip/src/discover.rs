@@ -4,6 +4,9 @@
 //! peer-to-peer network. This address can then be used to bootstrap the join process.
 //! The probing packet consists of [version, 0x00, 0x00, 0x01], allowing the
 //! replying server to ignore in incompatible peers
+//!
+//! Both sides are generic over the `Datagram` socket, the tests use the simulated sockets of
+//! `memory::Network` and drive the `Beacon` with its simulated clock.
 
 use std::io::{self, Write, ErrorKind};
 use std::time::{Instant, Duration};
@@ -13,7 +16,7 @@ use std::net::UdpSocket as UdpSocket2;
 use nix::ifaddrs::getifaddrs;
 use nix::sys::socket::SockAddr;
 use tokio::{net::UdpSocket, reactor::Handle};
-use futures::{Async, Stream};
+use futures::{Async, Poll, Stream};
 use std::net::{SocketAddrV4, Ipv4Addr, SocketAddr, IpAddr};
 
 use net2::UdpBuilder;
@@ -23,6 +26,42 @@ use ring::digest;
 use local_ip;
 use protocol::NetworkKey;
 
+/// A socket sending and receiving datagrams, without blocking
+pub trait Datagram {
+    /// Send a datagram to `addr`, which may be the broadcast address
+    fn poll_send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> Poll<usize, io::Error>;
+
+    /// Receive the next datagram together with its sender
+    fn poll_recv_from(&mut self, buf: &mut [u8]) -> Poll<(usize, SocketAddr), io::Error>;
+}
+
+impl Datagram for UdpSocket {
+    fn poll_send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> Poll<usize, io::Error> {
+        UdpSocket::poll_send_to(self, buf, addr)
+    }
+
+    fn poll_recv_from(&mut self, buf: &mut [u8]) -> Poll<(usize, SocketAddr), io::Error> {
+        UdpSocket::poll_recv_from(self, buf)
+    }
+}
+
+/// A non-blocking socket of the standard library, which is not ready instead of blocking
+impl Datagram for UdpSocket2 {
+    fn poll_send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> Poll<usize, io::Error> {
+        match self.send_to(buf, addr) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Async::NotReady),
+            res => res.map(Async::Ready)
+        }
+    }
+
+    fn poll_recv_from(&mut self, buf: &mut [u8]) -> Poll<(usize, SocketAddr), io::Error> {
+        match self.recv_from(buf) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Async::NotReady),
+            res => res.map(Async::Ready)
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct Packet {
     version: u8,
@@ -53,15 +92,15 @@ impl Packet {
 }
 
 /// Reply to probing packets with the correct version field
-pub struct Discover {
-    socket: UdpSocket,
+pub struct Discover<D = UdpSocket> {
+    socket: D,
     buf: Vec<u8>,
     answer_to: Option<(usize, SocketAddr)>,
     packet: Packet,
     ips: Vec<IpAddr>
 }
 
-impl Stream for Discover {
+impl<D: Datagram> Stream for Discover<D> {
     type Item = ();
     type Error = io::Error;
 
@@ -83,9 +122,6 @@ impl Stream for Discover {
                 }
             }
 
-
-            //println!("{:?}", self.answer_to);
-            //println!("{:?}", try_ready!(self.socket.poll_recv_from(&mut self.buf)));
             self.answer_to = Some(try_ready!(self.socket.poll_recv_from(&mut self.buf)));
         }
     }
@@ -103,45 +139,54 @@ impl Discover {
         socket.set_broadcast(true).unwrap();
         //socket.set_nonblocking(true).unwrap();
 
-        let packet = Packet::new(version, network, contact_port);
+        let socket = UdpSocket::from_std(socket, &Handle::default()).unwrap();
+
+        Discover::with_socket(socket, version, network, contact_port, local_ip::get().unwrap())
+    }
+}
 
+impl<D: Datagram> Discover<D> {
+    /// Reply with `socket` to probes of other peers, probes sent from one of our `ips` to our own
+    /// `contact_port` are ignored
+    pub fn with_socket(socket: D, version: u8, network: NetworkKey, contact_port: u16, ips: Vec<IpAddr>) -> Discover<D> {
         Discover {
             buf: vec![0; 1024],
             answer_to: None,
-            packet,
-            socket: UdpSocket::from_std(socket, &Handle::default()).unwrap(),
-            ips: local_ip::get().unwrap()
+            packet: Packet::new(version, network, contact_port),
+            socket,
+            ips
         }
     }
 }
 
 /// Probe into an unknown network structure and discover other peers. 
 ///
-/// If no peer replies after two seconds, the `Future` will be resolved with `Option::None`
+/// If no peer replies after `nsecs` seconds, `wait` returns `None`
 ///
 /// ## Example
-/// ```rust
-/// extern crate futures;
+/// ```rust,no_run
 /// extern crate hex_gossip;
 ///
-/// use futures::future::Future;
 /// use hex_gossip::discover::Beacon;
 ///
-/// let beacon = Beacon::new(0, 200)
-///     .map(|addr| println!("Discovered contact at {:?}", addr))
-///     .map_err(|err| eprintln!("Err = {:?}", err));
+/// let mut beacon = Beacon::new(1, [0u8; 32], 8000);
 ///
-/// tokio::run(beacon);
+/// match beacon.wait(2) {
+///     Some(addr) => println!("Discovered contact at {:?}", addr),
+///     None => eprintln!("Nobody replied")
+/// }
 /// ```
-pub struct Beacon {
-    socket: UdpSocket2,
+pub struct Beacon<D = UdpSocket2> {
+    socket: D,
     buf: Vec<u8>,
-    packet: Packet
+    packet: Packet,
+    ips: Vec<IpAddr>,
+    last_sent: Option<u64>
 }
 
 impl Beacon {
-    /// Create a new `Beacon` struct which tries to discover peers at an interval `interval` and
-    /// with version `version`
+    /// Create a new `Beacon` struct which tries to discover peers of network `network` with
+    /// version `version`, announcing our own `contact_port`
     pub fn new(version: u8, network: NetworkKey, contact_port: u16) -> Beacon {
         let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 8004);
         
@@ -153,95 +198,159 @@ impl Beacon {
         socket.set_broadcast(true).unwrap();
         socket.set_nonblocking(true).unwrap();
 
-        Beacon {
-            buf: vec![0; 1024],
-            socket,
-            packet: Packet::new(version, network, contact_port)
-        }
+        let ips: Vec<IpAddr> = getifaddrs().unwrap().filter_map(|x| {
+            match x.address{
+                Some(SockAddr::Inet(inet)) => Some(inet.to_std().ip()),
+                _ => None
+            }
+        }).collect();
+
+        Beacon::with_socket(socket, version, network, contact_port, ips)
     }
 
+    /// Probe for peers during `nsecs` seconds, returns the contact address of the first one
+    /// replying
     pub fn wait(&mut self, nsecs: u64) -> Option<SocketAddr> {
         let start = Instant::now();
-        let mut last_sent = Instant::now();
+        let mut dots = 0;
         print!("Search for peers ");
         std::io::stdout().flush().unwrap();
 
-        let my_ips: Vec<IpAddr> = getifaddrs().unwrap().filter_map(|x| {
-            match x.address{
-                Some(SockAddr::Inet(inet)) => Some(inet.to_std().ip()),
-                _ => None
-            }
-        }).collect();
-
         loop {
-            if Instant::now().duration_since(start).as_secs() >= nsecs {
+            let elapsed = Instant::now().duration_since(start);
+            if elapsed.as_secs() >= nsecs {
                 println!(" nobody found!");
                 return None;
             }
 
-            if Instant::now().duration_since(last_sent).as_millis() > 500 {
+            // one dot for each probe
+            let now = elapsed.as_millis() as u64;
+            if now / PROBE_INTERVAL >= dots {
                 print!(".");
                 std::io::stdout().flush().unwrap();
+                dots += 1;
+            }
 
-                if let Err(err) = self.socket.send_to(
-                        &self.packet.to_vec(), 
-                        &(SocketAddrV4::new(Ipv4Addr::BROADCAST, 8004))) {
-                    eprintln!(" could not send ping {}", err);
-
+            match self.probe(now) {
+                Ok(Some(addr)) => {
+                    println!(" found peer at {}", addr);
+                    return Some(addr);
+                },
+                Ok(None) => {},
+                Err(err) => {
+                    eprintln!(" could not probe for peers {}", err);
                     return None;
                 }
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/// Time in milliseconds between two probes of a `Beacon`
+const PROBE_INTERVAL: u64 = 500;
 
-                last_sent = Instant::now();
+impl<D: Datagram> Beacon<D> {
+    /// Probe with `socket` for peers, replies from one of our `ips` to our own `contact_port`
+    /// are ignored
+    pub fn with_socket(socket: D, version: u8, network: NetworkKey, contact_port: u16, ips: Vec<IpAddr>) -> Beacon<D> {
+        Beacon {
+            buf: vec![0; 1024],
+            socket,
+            packet: Packet::new(version, network, contact_port),
+            ips,
+            last_sent: None
+        }
+    }
+
+    /// Broadcast a probe if the last one is older than half a second and check the replies
+    ///
+    /// The time `now` is given in milliseconds. Returns the contact address of a peer replying.
+    pub fn probe(&mut self, now: u64) -> io::Result<Option<SocketAddr>> {
+        if self.last_sent.map(|x| now >= x + PROBE_INTERVAL).unwrap_or(true) {
+            let broadcast = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, 8004));
+            if let Async::Ready(_) = self.socket.poll_send_to(&self.packet.to_vec(), &broadcast)? {
+                self.last_sent = Some(now);
             }
+        }
 
-            'inner: loop {
-                let (nread, mut addr) = match self.socket.recv_from(&mut self.buf) {
-                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                        break 'inner
-                    },
-                    Ok(a) => a,
-                    _ => return None
-                };
-
-                // check if request originates from our address and the corresponding port
-                if let Some(packet) = Packet::from_vec(&self.buf[0..nread]) {
-                    if (!my_ips.contains(&addr.ip()) || packet.contact_port != self.packet.contact_port) &&
-                        packet.key == self.packet.key && 
-                        packet.version == self.packet.version {
-                            addr.set_port(packet.contact_port);
-
-                            println!(" found peer at {}", addr);
-                            return Some(addr);
-                    }
+        loop {
+            let (nread, mut addr) = match self.socket.poll_recv_from(&mut self.buf)? {
+                Async::Ready(x) => x,
+                Async::NotReady => return Ok(None)
+            };
+
+            // check if request originates from our address and the corresponding port
+            if let Some(packet) = Packet::from_vec(&self.buf[0..nread]) {
+                if (!self.ips.contains(&addr.ip()) || packet.contact_port != self.packet.contact_port) &&
+                    packet.key == self.packet.key && 
+                    packet.version == self.packet.version {
+                        addr.set_port(packet.contact_port);
+
+                        return Ok(Some(addr));
                 }
             }
-
-            thread::sleep(Duration::from_millis(50));
-
         }
     }
 }
 
-/*#[cfg(test)]
+#[cfg(test)]
 mod tests {
+    use std::net::{SocketAddr, IpAddr};
+
+    use futures::{future, Async, Future, Stream};
+    use memory::{Link, Network};
     use super::{Beacon, Discover};
-    use futures::{Future, Stream};
-    use tokio;
+
+    fn addr(host: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, host], 8000))
+    }
+
+    /// Probe with a beacon at the second peer for `ms` simulated milliseconds
+    fn search(network: &Network, discover_key: [u8; 32], beacon_key: [u8; 32], ms: u64) -> Option<SocketAddr> {
+        let ip = |host: u8| -> IpAddr { addr(host).ip() };
+
+        // poll inside a task, because receiving registers the current task
+        future::lazy(|| {
+            let socket = network.transport(addr(1)).datagram(8004).unwrap();
+            let mut discover = Discover::with_socket(socket, 1, discover_key, 8000, vec![ip(1)]);
+            let socket = network.transport(addr(2)).datagram(8004).unwrap();
+            let mut beacon = Beacon::with_socket(socket, 1, beacon_key, 8000, vec![ip(2)]);
+
+            while network.now() < ms {
+                if let Some(addr) = beacon.probe(network.now()).unwrap() {
+                    return Ok(Some(addr));
+                }
+
+                assert_eq!(discover.poll().unwrap(), Async::NotReady);
+                network.advance(50);
+            }
+
+            Ok::<_, ()>(None)
+        }).wait().unwrap()
+    }
 
     #[test]
     fn send_beacon() {
-        let beacon = Beacon::new(1, 500);
+        let network = Network::new(1);
+        network.set_default(Link::new(20, 0.0));
 
-        tokio::run(beacon.map_err(|e| println!("Beacon error = {:?}", e)).map(|x| println!("Beacon got = {:?}", x)));
+        // the beacon ignores its own probes, even though it listens at the same port
+        assert_eq!(search(&network, [1; 32], [1; 32], 2000), Some(addr(1)));
+        assert!(network.now() >= 40);
     }
 
     #[test]
     fn discover() {
-        let discover = Discover::new(1);
+        let network = Network::new(1);
 
-        tokio::run(discover
-           .for_each(|x| { println!("Detected peer = {:?}", x); Ok(())})
-           .map_err(|_| ())
-        );
+        // peers of other networks are not answered
+        assert_eq!(search(&network, [1; 32], [2; 32], 2000), None);
+
+        // probes are sent again, so that a lost one doesn't hide the peer
+        let network = Network::new(7);
+        network.set_default(Link::new(20, 0.5));
+        assert_eq!(search(&network, [1; 32], [1; 32], 10_000), Some(addr(1)));
     }
-}*/
+}
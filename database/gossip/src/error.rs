@@ -9,5 +9,9 @@ pub enum Error {
     Cryptography,
     Deserialize,
     WrongVersion,
+    /// The key exchange of a connection failed, for example a greeting with an invalid signature
+    Handshake,
+    /// A packet arrived with the wrong number, for example a replayed frame
+    Replay,
     Io(io::Error)
 }
@@ -0,0 +1,264 @@
+//! Deterministic simulation of many peers
+//!
+//! The harness starts a number of peers keeping their transitions in memory, see `Graph`. They
+//! are connected over a simulated `memory::Network` and driven by a single threaded runtime,
+//! which advances the simulated clock one millisecond at a time. A simulation with the same seed
+//! delivers the same segments at the same time.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use futures::{future, task, Async, Future, Stream};
+use tokio::runtime::current_thread::Runtime;
+
+use {Gossip, GossipConf, Spread};
+use memory::{Link, Memory, Network};
+use reconcile::missing_refs;
+use transfer::FileInfo;
+use transition::{Inspector, Job, Keypair, Transition, TransitionKey};
+
+/// Keeps all transitions in memory
+#[derive(Clone)]
+pub struct Graph {
+    transitions: Arc<Mutex<BTreeMap<TransitionKey, Transition>>>
+}
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph { transitions: Arc::new(Mutex::new(BTreeMap::new())) }
+    }
+
+    pub fn commit(&self, keypair: &Keypair, data: u32) {
+        let transition = Transition::new(keypair, self.tips(), data.to_le_bytes().to_vec());
+        self.store(transition);
+    }
+
+    /// Transitions with a missing reference
+    pub fn pending(&self) -> usize {
+        let transitions: Vec<Transition> = self.transitions.lock().unwrap().values().cloned().collect();
+
+        transitions.iter().filter(|x| !missing_refs(self, x).is_empty()).count()
+    }
+}
+
+impl Inspector for Graph {
+    fn approve(&self, trans: &Transition) -> bool { trans.verify() }
+
+    fn store(&self, trans: Transition) {
+        self.transitions.lock().unwrap().insert(trans.key.clone(), trans);
+    }
+
+    fn restore(&self, keys: Vec<TransitionKey>) -> Option<Vec<Transition>> {
+        let transitions = self.transitions.lock().unwrap();
+        keys.iter().map(|x| transitions.get(x).cloned()).collect()
+    }
+
+    fn tips(&self) -> Vec<TransitionKey> {
+        let transitions = self.transitions.lock().unwrap();
+        transitions.keys()
+            .filter(|key| !transitions.values().any(|x| x.refs.contains(key)))
+            .cloned().collect()
+    }
+
+    fn has(&self, key: &TransitionKey) -> bool {
+        self.transitions.lock().unwrap().contains_key(key)
+    }
+
+    fn keys(&self, lower: Option<&TransitionKey>, upper: Option<&TransitionKey>) -> Vec<TransitionKey> {
+        self.transitions.lock().unwrap().keys()
+            .filter(|x| lower.map(|l| *x >= l).unwrap_or(true) && upper.map(|u| *x < u).unwrap_or(true))
+            .cloned().collect()
+    }
+
+    fn file_info(&self, _: &[u8]) -> Option<Job<Option<FileInfo>>> { None }
+    fn read_chunk(&self, _: &[u8], _: u64) -> Option<Vec<u8>> { None }
+    fn partial(&self, _: &[u8]) -> Option<(FileInfo, u64)> { None }
+    fn begin_file(&self, _: &[u8], _: &FileInfo) -> bool { false }
+    fn write_chunk(&self, _: &[u8], _: u64, _: &[u8]) -> bool { false }
+    fn finish_file(&self, _: &[u8]) -> Job<bool> { Box::new(|| false) }
+}
+
+/// A simulated peer
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub graph: Graph,
+    pub spread: Spread<Graph, Memory>
+}
+
+pub struct Harness {
+    pub network: Network,
+    pub peers: Vec<Peer>,
+    runtime: Runtime
+}
+
+impl Harness {
+    /// Start `n` peers connected by links with the same conditions
+    ///
+    /// The peers join one after another over the first peer, until all of them are connected.
+    pub fn new(n: usize, link: Link, seed: u64) -> Harness {
+        let network = Network::new(seed);
+        network.set_default(link.clone());
+
+        let mut harness = Harness { network, peers: Vec::new(), runtime: Runtime::new().unwrap() };
+
+        for i in 0..n {
+            let addr = SocketAddr::from(([10, 0, 0, i as u8 + 1], 8000));
+            let mut conf = GossipConf::new()
+                .addr(addr)
+                .network_key([1u8; 32])
+                .keypair(Keypair::from_seed(&[i as u8; 32]));
+
+            if let Some(first) = harness.peers.first() {
+                conf = conf.contact(first.addr);
+            }
+
+            let graph = Graph::new();
+            let gossip = Gossip::with_transport(conf, graph.clone(), harness.network.transport(addr));
+            let spread = gossip.writer();
+
+            harness.runtime.spawn(gossip.for_each(|_| Ok(())).map_err(|err| panic!("Gossip failed: {:?}", err)));
+            harness.runtime.spawn(spread.get());
+            harness.peers.push(Peer { addr, graph, spread });
+
+            // wait until the peer joined, before the next one asks for the peers
+            harness.run(100 + 10 * link.latency);
+        }
+
+        harness
+    }
+
+    /// Commit a transition on a peer and push it to the others
+    pub fn commit(&self, peer: usize, data: u32) {
+        self.peers[peer].spread.push(data.to_le_bytes().to_vec());
+    }
+
+    /// Continue the simulation for `ms` milliseconds
+    pub fn run(&mut self, ms: u64) {
+        let network = self.network.clone();
+        let mut remaining = ms;
+
+        self.runtime.block_on(future::poll_fn(move || -> Result<Async<()>, ()> {
+            if remaining == 0 {
+                return Ok(Async::Ready(()));
+            }
+
+            remaining -= 1;
+            network.advance(1);
+
+            // give all peers the chance to process the new millisecond
+            task::current().notify();
+
+            Ok(Async::NotReady)
+        })).unwrap();
+    }
+
+    /// Keys known to a peer
+    pub fn keys(&self, peer: usize) -> Vec<TransitionKey> {
+        self.peers[peer].graph.keys(None, None)
+    }
+
+    /// All peers know the same transitions without missing references
+    pub fn converged(&self) -> bool {
+        let keys = self.keys(0);
+
+        self.peers.iter().all(|x| x.graph.keys(None, None) == keys && x.graph.pending() == 0)
+    }
+}
+
+mod tests {
+    use memory::Link;
+    use book::{TIMEOUT, BACKOFF_MAX};
+    use reconcile;
+    use transition::Keypair;
+    use super::Harness;
+
+    #[test]
+    fn converge_over_lossy_links() {
+        let mut harness = Harness::new(5, Link::new(20, 0.2), 42);
+
+        for i in 0..50 {
+            harness.commit(i % 5, i as u32);
+            harness.run(5);
+        }
+        harness.run(5000);
+
+        assert!(harness.converged());
+        assert_eq!(harness.keys(0).len(), 50);
+    }
+
+    #[test]
+    fn converge_after_partition() {
+        let mut harness = Harness::new(4, Link::new(5, 0.0), 7);
+
+        // both sides of the partition continue on their own
+        let addrs: Vec<_> = harness.peers.iter().map(|x| x.addr).collect();
+        harness.network.partition(vec![addrs[..2].to_vec(), addrs[2..].to_vec()]);
+
+        for i in 0..40 {
+            harness.commit(i % 4, i as u32);
+            harness.run(10);
+        }
+        harness.run(1000);
+
+        assert!(!harness.converged());
+        assert_eq!(harness.keys(0), harness.keys(1));
+        assert_eq!(harness.keys(2), harness.keys(3));
+        assert_eq!(harness.keys(0).len() + harness.keys(2).len(), 40);
+
+        // the held back segments arrive after healing
+        harness.network.heal();
+        harness.run(2000);
+
+        assert!(harness.converged());
+        assert_eq!(harness.keys(0).len(), 40);
+    }
+
+    #[test]
+    fn reconnect_after_timeout() {
+        let mut harness = Harness::new(3, Link::new(5, 0.0), 3);
+
+        // the first peer is cut off long enough for its connections to time out
+        let addrs: Vec<_> = harness.peers.iter().map(|x| x.addr).collect();
+        harness.network.partition(vec![addrs[..1].to_vec(), addrs[1..].to_vec()]);
+
+        for i in 0..10 {
+            harness.commit(i % 2, i as u32);
+            harness.run(10);
+        }
+        harness.run(2 * TIMEOUT);
+
+        assert!(harness.peers[0].spread.peers().iter().all(|x| x.connected_since.is_none()));
+        assert_eq!(harness.peers[1].spread.peers().iter().filter(|x| x.connected_since.is_some()).count(), 1);
+
+        // the peers find each other again with the addresses in their book
+        harness.network.heal();
+        harness.run(BACKOFF_MAX);
+
+        assert!(harness.converged());
+        assert_eq!(harness.keys(0).len(), 10);
+
+        for peer in &harness.peers {
+            let status = peer.spread.peers();
+
+            assert_eq!(status.len(), 2);
+            assert!(status.iter().all(|x| x.connected_since.is_some() && x.pending == 0));
+        }
+    }
+
+    #[test]
+    fn reconcile_regularly() {
+        let mut harness = Harness::new(3, Link::new(5, 0.0), 11);
+
+        // a transition which is stored, but never pushed to the others
+        harness.peers[1].graph.commit(&Keypair::from_seed(&[1; 32]), 1);
+        harness.run(1000);
+        assert!(!harness.converged());
+
+        // is exchanged by the next reconciliation of the connected peers
+        harness.run(reconcile::INTERVAL);
+
+        assert!(harness.converged());
+        assert_eq!(harness.keys(0).len(), 1);
+    }
+}
@@ -29,6 +29,7 @@ extern crate nix;
 extern crate bytes;
 extern crate net2;
 extern crate ring;
+extern crate untrusted;
 extern crate bincode;
 extern crate tokio;
 #[macro_use]
@@ -41,27 +42,44 @@ pub mod error;
 pub mod transition;
 mod protocol;
 pub mod discover;
+pub mod transfer;
+pub mod reconcile;
+pub mod transport;
+pub mod memory;
+pub mod book;
+pub mod session;
+#[cfg(test)]
+mod harness;
 
 pub use error::*;
-pub use transition::{Transition, TransitionKey, Inspector};
+pub use transition::{Transition, TransitionKey, Inspector, Job, Keypair, Signature};
+pub use transfer::FileInfo;
+pub use transport::{Transport, Tcp};
+pub use book::PeerStatus;
 
 use std::sync::{Mutex, Arc};
 use std::net::SocketAddr;
-use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, VecDeque};
+use std::thread;
 
-use futures::{Async, Stream, task, Poll, future, Future};
-use futures::sync::mpsc::{Receiver, Sender, channel};
+use futures::{Async, Stream, task, Poll, future, Future, Oneshot};
+use futures::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender, channel, unbounded};
 use tokio::io;
 use tokio::prelude::task::Task;
-use tokio::net::{TcpListener, TcpStream, tcp::Incoming};
+use tokio::timer::Interval;
 
 use self::protocol::{Peer, ResolvePeers, PeerCodecWrite, NetworkKey};
-pub use self::protocol::Packet;
+use self::session::Keyring;
+use self::transfer::{Downloads, Outgoing};
+use self::book::PeerBook;
+pub use self::protocol::{Packet, Capabilities};
 pub use self::discover::{Beacon, Discover};
 
 /// Identification of a peer. This is the public key (256bit) of a Schnorr signature using a
-/// twisted Edwards form of Curve25519. The key is used to verify that a message is signed by its
-/// author.
+/// twisted Edwards form of Curve25519 (Ed25519, see `Keypair`). The key is used to verify that a
+/// transition is signed by its author.
 pub type PeerId = Vec<u8>;
 
 /// Contains information about the whereabouts of a peer
@@ -84,20 +102,25 @@ pub enum SpreadTo {
 ///
 /// This wraps the write map inside a mutex and is therefore safe to share across threads. Any
 /// attempts to write to a closed socket is at the moment ignored. Furthermore it is assumed that
-/// flushing is immediately successful.
-pub struct Spread<T: Inspector> {
-    my_id: PeerId,
+/// flushing is immediately successful. The peer book is shared as well, to report the status of
+/// all peers from any thread.
+pub struct Spread<T: Inspector, N: Transport = Tcp> {
+    keypair: Keypair,
     task: Arc<Mutex<Option<Task>>>,
-    peers: Arc<Mutex<HashMap<PeerId, PeerCodecWrite<TcpStream>>>>,
+    peers: Arc<Mutex<HashMap<PeerId, PeerCodecWrite<N::Stream>>>>,
+    downloads: Arc<Mutex<Downloads>>,
+    book: Arc<Mutex<PeerBook>>,
     inspector: Arc<Mutex<T>>
 }
 
-impl<T: Inspector> Clone for Spread<T> {
-    fn clone(&self) -> Spread<T> {
+impl<T: Inspector, N: Transport> Clone for Spread<T, N> {
+    fn clone(&self) -> Spread<T, N> {
         Spread {
-            my_id: self.my_id.clone(),
+            keypair: self.keypair.clone(),
             task: self.task.clone(),
             peers: self.peers.clone(),
+            downloads: self.downloads.clone(),
+            book: self.book.clone(),
             inspector: self.inspector.clone()
         }
     }
@@ -106,12 +129,14 @@ impl<T: Inspector> Clone for Spread<T> {
 //unsafe impl<T: Inspector> Send for Spread<T> {}
 //unsafe impl<T: Inspector> Sync for Spread<T> {}
 
-impl<T: Inspector> Spread<T> {
-    pub fn new(my_id: PeerId, inspector: Arc<Mutex<T>>) -> Spread<T> {
+impl<T: Inspector, N: Transport> Spread<T, N> {
+    pub fn new(keypair: Keypair, inspector: Arc<Mutex<T>>, book: PeerBook) -> Spread<T, N> {
         Spread { 
             peers: Arc::new(Mutex::new(HashMap::new())), 
             task: Arc::new(Mutex::new(None)),
-            my_id, inspector 
+            downloads: Arc::new(Mutex::new(Downloads::new())),
+            book: Arc::new(Mutex::new(book)),
+            keypair, inspector 
         }
     }
 
@@ -154,7 +179,7 @@ impl<T: Inspector> Spread<T> {
         future
     }
 
-    pub fn add_peer(&self, id: &PeerId, writer: PeerCodecWrite<TcpStream>) -> usize {
+    pub fn add_peer(&self, id: &PeerId, writer: PeerCodecWrite<N::Stream>) -> usize {
         let mut peers = self.peers.lock().unwrap();
         let len = peers.keys().len();
         peers.insert(id.clone(), writer);
@@ -162,6 +187,13 @@ impl<T: Inspector> Spread<T> {
         return len;
     }
 
+    /// Stop writing to a peer and close the connection
+    pub fn remove_peer(&self, id: &PeerId) {
+        if let Some(writer) = self.peers.lock().unwrap().remove(id) {
+            let _ = writer.shutdown();
+        }
+    }
+
     pub fn spread(&self, packet: Packet, dest: SpreadTo) {
         match dest {
             SpreadTo::Everyone => {
@@ -183,10 +215,49 @@ impl<T: Inspector> Spread<T> {
         }
     }
 
+    fn spread_all(&self, packets: Outgoing) {
+        for (packet, dest) in packets {
+            self.spread(packet, dest);
+        }
+    }
+
+    /// Ids of all connected peers
+    fn connected(&self) -> Vec<PeerId> {
+        self.peers.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Status of all known peers, connected or not
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        let written: HashMap<PeerId, u64> = self.peers.lock().unwrap().iter()
+            .map(|(id, writer)| (id.clone(), writer.written()))
+            .collect();
+
+        self.book.lock().unwrap().status(&written)
+    }
+
+    /// Download a file from a peer having it, resolves once the inspector finished the file
+    pub fn request_file(&self, id: Vec<u8>) -> Oneshot<()> {
+        let connected = self.connected();
+        let (oneshot, packets) = self.downloads.lock().unwrap().request(id, &connected);
+        self.spread_all(packets);
+
+        oneshot
+    }
+
+    /// Peers known to have a file, because they described it to us
+    pub fn holders(&self, id: &[u8]) -> Vec<PeerId> {
+        self.downloads.lock().unwrap().holders(id)
+    }
+
+    /// Ask everyone for the description of a file, peers having it become known holders
+    pub fn locate_file(&self, id: Vec<u8>) {
+        self.spread(Packet::FileInfo(id, None), SpreadTo::Everyone);
+    }
+
     pub fn push(&self, buf: Vec<u8>) {
         let tips = self.inspector.lock().unwrap().tips();
 
-        let transition = Transition::new(self.my_id.clone(), tips, buf);
+        let transition = Transition::new(&self.keypair, tips, buf);
         // store the new transition in our database (assuming it is correct)
         self.inspector.lock().unwrap().store(transition.clone());
 
@@ -200,12 +271,16 @@ pub struct GossipConf {
     pub addr: Option<SocketAddr>,
     pub key: Option<NetworkKey>,
     contact: Option<SocketAddr>,
-    pub id: Option<PeerId>
+    pub id: Option<PeerId>,
+    pub keypair: Option<Keypair>,
+    pub book: Option<PathBuf>,
+    /// Further network keys accepted from other peers, see `session`
+    accepted: Vec<NetworkKey>
 }
 
 impl GossipConf {
     pub fn new() -> GossipConf {
-        GossipConf { addr: None, key: None, contact: None, id: None }
+        GossipConf { addr: None, key: None, contact: None, id: None, keypair: None, book: None, accepted: Vec::new() }
     }
 
     pub fn addr<T: Into<SocketAddr>>(mut self, addr: T) -> GossipConf {
@@ -220,24 +295,48 @@ impl GossipConf {
         self
     }
 
+    /// Accept connections with another network key, while the network is rotated to a new key
+    pub fn accept_network_key<T: Into<NetworkKey>>(mut self, key: T) -> GossipConf {
+        self.accepted.push(key.into());
+
+        self
+    }
+
     pub fn contact<T: Into<SocketAddr>>(mut self, contact: T) -> GossipConf {
         self.contact = Some(contact.into());
 
         self
     }
 
-    pub fn id<T: Into<PeerId>>(mut self, id: T) -> GossipConf {
-        self.id = Some(id.into());
+    /// Set the keypair signing our transitions, the peer id is its public key
+    pub fn keypair(mut self, keypair: Keypair) -> GossipConf {
+        self.id = Some(keypair.id());
+        self.keypair = Some(keypair);
+
+        self
+    }
+
+    /// Persist the known peers in a file, they are contacted again after a restart
+    pub fn peer_book<T: Into<PathBuf>>(mut self, path: T) -> GossipConf {
+        self.book = Some(path.into());
 
         self
     }
 
-    pub fn retrieve(self) -> (SocketAddr, NetworkKey, Option<SocketAddr>, PeerId) {
+    pub fn retrieve(self) -> (SocketAddr, NetworkKey, Option<SocketAddr>, Keyring) {
+        let key = self.key.expect("Network key is missing!");
+
+        let mut network = vec![key];
+        network.extend(self.accepted.into_iter().filter(|x| *x != key));
+
         (
             self.addr.expect("Missing binding addr!"),
-            self.key.expect("Network key is missing!"),
+            key,
             self.contact,
-            self.id.expect("Peer identification is missing!")
+            Keyring {
+                identity: self.keypair.expect("Peer keypair is missing!"),
+                network
+            }
         )
     }
 }
@@ -247,61 +346,80 @@ impl GossipConf {
 /// It consists of four parts. First a channel to which connected peers are hooked up. They
 /// will send packets through the PeerCodec. Second an incoming field to accept new peers asking
 /// for a connection. Third a stream of emerging connections which are not fully established. And
-/// forth a log of existing connections to peer.
-pub struct Gossip<T: Inspector> {
+/// forth a log of existing connections to peer. The connections are opened by a `Transport`,
+/// which is TCP by default. Connections are kept alive with heartbeats, lost ones are
+/// reestablished with the addresses in the peer book.
+pub struct Gossip<T: Inspector, N: Transport = Tcp> {
     myself: PeerPresence,
-    recv: Receiver<(PeerId, Packet)>,
-    sender: Sender<(PeerId, Packet)>,
-    books: HashMap<PeerId, PeerPresence>,
-    writer: Spread<T>,
-    resolve: ResolvePeers,
-    incoming: Incoming,
+    recv: Receiver<(PeerId, usize, Packet)>,
+    sender: Sender<(PeerId, usize, Packet)>,
+    writer: Spread<T, N>,
+    resolve: ResolvePeers<N>,
+    transport: N,
+    incoming: N::Incoming,
     key: NetworkKey,
-    inspector: Arc<Mutex<T>>
+    /// Keys securing our connections
+    keyring: Keyring,
+    inspector: Arc<Mutex<T>>,
+    /// New transitions received by the reconciliation, which are not yet returned
+    arrived: VecDeque<Transition>,
+    /// Answers of jobs finished on a worker thread, which are not yet sent
+    answered: UnboundedReceiver<(Packet, SpreadTo)>,
+    answer: UnboundedSender<(Packet, SpreadTo)>,
+    /// Number of the next established connection
+    connections: usize,
+    /// Wakes us up regularly to check the liveness of connections
+    tick: Interval,
+    next_heartbeat: u64,
+    next_reconcile: u64
 }
 
 impl<T: Inspector> Gossip<T> {
+    /// Join the network over TCP
     pub fn new(conf: GossipConf, inspector: T) -> Gossip<T> {
-        let (mut addr, key, contact, id) = conf.retrieve();
+        Gossip::with_transport(conf, inspector, Tcp)
+    }
+}
+
+impl<T: Inspector, N: Transport> Gossip<T, N> {
+    /// Join the network over a certain transport
+    pub fn with_transport(mut conf: GossipConf, inspector: T, transport: N) -> Gossip<T, N> {
+        let book = match conf.book.take() {
+            Some(path) => PeerBook::load(path, transport.now()),
+            None => PeerBook::new()
+        };
+        let (mut addr, key, contact, keyring) = conf.retrieve();
+        let id = keyring.identity.id();
 
         let (sender, receiver) = channel(1024);
 
         // check if port is available
-        let listener;
-        loop {
-            match TcpListener::bind(&addr) {
-                Ok(a) => {listener = a; break; }
+        let (incoming, local_addr) = loop {
+            match transport.bind(&addr) {
+                Ok(a) => break a,
                 Err(_) => {
                     let old_port = addr.port();
                     addr.set_port(old_port + 1);
                 }
             }
-        }
+        };
 
         let myself = PeerPresence {
             id: id.clone(),
-            addr: listener.local_addr().unwrap(),
+            addr: local_addr,
             writer: None
         };
 
         let tips = inspector.tips();
         let tips = inspector.restore(tips).unwrap();
 
-        let peers = match contact {
-            Some(addr) => {
-                vec![Peer::connect(&addr, key, myself.clone(), tips)]
-            },
-            None => {
-                match Beacon::new(1, key, myself.addr.port()).wait(2) {
-                    Some(contact) => vec![Peer::connect(&contact, key, myself.clone(), tips)],
-                    _ => {
-                        Vec::new()
-                    }
-                }
-            }
+        let peers = match contact.or_else(|| transport.discover(key, myself.addr.port())) {
+            Some(contact) => vec![Peer::connect(&transport, &contact, keyring.clone(), myself.clone(), tips)],
+            None => Vec::new()
         };
 
         let inspector = Arc::new(Mutex::new(inspector));
+        let (answer, answered) = unbounded();
 
         println!("Gossip: Start server with addr {:?}", addr);
 
@@ -309,15 +427,21 @@ impl<T: Inspector> Gossip<T> {
             myself: myself,
             recv: receiver,
             sender: sender,
-            books: HashMap::new(),
-            incoming: listener.incoming(),
+            incoming: incoming,
             resolve: ResolvePeers::new(peers),
-            writer: Spread::new(id, inspector.clone()),
-            key, inspector
+            transport: transport,
+            writer: Spread::new(keyring.identity.clone(), inspector.clone(), book),
+            arrived: VecDeque::new(),
+            answered, answer,
+            connections: 0,
+            tick: Interval::new(Instant::now(), Duration::from_secs(1)),
+            next_heartbeat: 0,
+            next_reconcile: reconcile::INTERVAL,
+            key, keyring, inspector
         }
     }
 
-    pub fn writer(&self) -> Spread<T> {
+    pub fn writer(&self) -> Spread<T, N> {
         self.writer.clone()
     }
 
@@ -332,23 +456,95 @@ impl<T: Inspector> Gossip<T> {
     pub fn addr(&self) -> SocketAddr {
         self.myself.addr.clone()
     }
+
+    /// Status of all known peers, connected or not
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        self.writer.peers()
+    }
+
+    /// Our tips, sent to a peer when connecting
+    fn tips(&self) -> Vec<Transition> {
+        let inspector = self.inspector.lock().unwrap();
+        let tips = inspector.tips();
+
+        inspector.restore(tips).unwrap()
+    }
+
+    /// Send heartbeats, reconcile regularly, drop silent connections and reconnect to lost peers
+    fn maintain(&mut self) {
+        let now = self.transport.now();
+
+        if now >= self.next_heartbeat {
+            self.writer.spread(Packet::Heartbeat, SpreadTo::Everyone);
+            self.next_heartbeat = now + book::HEARTBEAT;
+        }
+
+        // a lost push would otherwise be missing until the next connection
+        if now >= self.next_reconcile {
+            let packet = reconcile::start(&*self.inspector.lock().unwrap());
+            self.writer.spread(packet, SpreadTo::Everyone);
+            self.next_reconcile = now + reconcile::INTERVAL;
+        }
+
+        let timed_out = self.writer.book.lock().unwrap().timed_out(now);
+        for id in timed_out {
+            info!("Connection to {:?} timed out", id);
+
+            self.disconnect(&id, None);
+        }
+
+        let due = self.writer.book.lock().unwrap().due(now);
+        for (id, addr) in due {
+            trace!("Reconnect to {:?} at {:?}", id, addr);
+
+            let tips = self.tips();
+            self.resolve.add_peer(Peer::connect(&self.transport, &addr, self.keyring.clone(), self.myself.clone(), tips));
+        }
+
+        if let Err(err) = self.writer.book.lock().unwrap().save() {
+            warn!("Could not save the peer book: {}", err);
+        }
+    }
+
+    /// Forget the connection to a peer, if `conn` is still the current one
+    fn disconnect(&mut self, id: &PeerId, conn: Option<usize>) {
+        let now = self.transport.now();
+        if !self.writer.book.lock().unwrap().disconnect(id, conn, now) {
+            return;
+        }
+
+        self.writer.remove_peer(id);
+
+        // continue downloads from this peer with someone else
+        let connected = self.writer.connected();
+        let packets = self.writer.downloads.lock().unwrap().disconnected(id, &connected);
+        self.writer.spread_all(packets);
+    }
 }
 
 /// Create a new stream, managing the gossip protocol
-impl<T: Inspector> Stream for Gossip<T> {
+impl<T: Inspector, N: Transport> Stream for Gossip<T, N> {
     type Item = Packet;
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // wake up regularly, even if no packet arrives
+        while let Ok(Async::Ready(Some(_))) = self.tick.poll() {}
+        self.maintain();
+
+        // send the answers of finished jobs
+        while let Ok(Async::Ready(Some((packet, dest)))) = self.answered.poll() {
+            self.writer.spread(packet, dest);
+        }
+
         // first look for newly arriving peers and await a Join message
         match self.incoming.poll() {
             Ok(Async::Ready(Some(socket))) => {
-                let tips = self.inspector.lock().unwrap().tips();
-                let tips = self.inspector.lock().unwrap().restore(tips).unwrap();
+                let tips = self.tips();
 
-                trace!("New connection from {}", socket.peer_addr().unwrap());
+                trace!("New connection {:?}", socket);
 
-                self.resolve.add_peer(Peer::send_join(socket, self.key, self.myself.clone(), tips));
+                self.resolve.add_peer(Peer::accept(socket, self.keyring.clone(), self.myself.clone(), tips));
             },
             Err(err) => {
                 println!("Listener err: {:?}", err);
@@ -370,29 +566,33 @@ impl<T: Inspector> Stream for Gossip<T> {
                 } else {
                     info!("New peer connected with {:?} tips from {:?}", tips.len(), presence.addr);
 
+                    let conn = self.connections;
+                    self.connections += 1;
+
                     // hook up the packet output to us
-                    reader.redirect_to(self.sender.clone(), presence.id.clone(), task::current());
+                    let received = reader.received();
+                    reader.redirect_to(self.sender.clone(), presence.id.clone(), conn, task::current());
                     // ask for other peers if this is our contact
-                    if self.books.is_empty() {
+                    if !self.writer.book.lock().unwrap().any_connected() {
                         writer.buffer(Packet::GetPeers(None));
                         writer.poll_flush().unwrap();
                     }
 
-                    // if everything is fine, send new transitions for this peer
-                    for transition in self.inspector.lock().unwrap().subgraph(tips) {
-                        writer.buffer(Packet::Push(transition));
-
-                        // write everything to the peer
-                        writer.poll_flush().unwrap();
-
-                    }
+                    // if everything is fine, compare our transitions with the peer
+                    writer.buffer(reconcile::start(&*self.inspector.lock().unwrap()));
+                    writer.poll_flush().unwrap();
 
-                    self.books.insert(presence.id.clone(), presence.clone());
+                    let protocol = writer.protocol();
+                    self.writer.book.lock().unwrap().connect(&presence, conn, protocol, self.transport.now(), received);
 
                     // empty a new log entry for our peer
                     let idx = self.writer.add_peer(&presence.id, writer);
                     presence.writer = Some(idx);
 
+                    // the new peer may have files we are waiting for
+                    let packets = self.writer.downloads.lock().unwrap().connected(&presence.id);
+                    self.writer.spread_all(packets);
+
                     // the connection is established
                     //return Ok(Async::Ready(Some((presence.id, Vec::new()))));
                 }
@@ -404,40 +604,51 @@ impl<T: Inspector> Stream for Gossip<T> {
 
         // now try to get a new packet from the hooked peers
         loop {
+        if let Some(transition) = self.arrived.pop_front() {
+            return Ok(Async::Ready(Some(Packet::Push(transition))));
+        }
+
         let res = self.recv.poll();
-        let (id, packet) = try_ready!(res.map_err(|_| io::ErrorKind::Other)).unwrap();
-        
+        let (id, conn, packet) = try_ready!(res.map_err(|_| io::ErrorKind::Other)).unwrap();
+
+        // every packet shows that the peer is still alive
+        self.writer.book.lock().unwrap().seen(&id, self.transport.now());
+
         // and process it with some logic
         match packet {
             Packet::GetPeers(None) => {
-                let mut list: Vec<PeerPresence> = self.books.values().cloned()
-                    .filter_map(|mut x| {
-                        if x.id != id {
-                            x.writer = None;
-                            return Some(x);
-                        }
-                        
-                        return None;
-                    }).collect();
+                let list = self.writer.book.lock().unwrap().presences(&id);
 
                 self.writer.spread(Packet::GetPeers(Some(list)), SpreadTo::Peer(id));
             },
             Packet::GetPeers(Some(peers)) => {
                 for presence in peers {
-                    if !self.books.contains_key(&presence.id) && !self.resolve.has_peer(&presence.id) {
+                    let known = self.writer.book.lock().unwrap().contains(&presence.id);
+                    if !known && !self.resolve.has_peer(&presence.id) && presence.id != self.myself.id {
                         info!("Add peer {:?} in {:?}", presence.id, self.myself.id);
-                        let tips = self.inspector.lock().unwrap().tips();
-                        let tips = self.inspector.lock().unwrap().restore(tips).unwrap();
-                        self.resolve.add_peer(Peer::connect(&presence.addr, self.key, self.myself.clone(), tips));
+                        // remember the peer, in case the first attempt fails
+                        self.writer.book.lock().unwrap().insert(&presence, self.transport.now());
+
+                        let tips = self.tips();
+                        self.resolve.add_peer(Peer::connect(&self.transport, &presence.addr, self.keyring.clone(), self.myself.clone(), tips));
                     }
                 }
             },
             Packet::Push(transition) => {
+                self.writer.book.lock().unwrap().arrived(&id, &[transition.key.clone()]);
+
                 if !self.inspector.lock().unwrap().approve(&transition) {
                     error!("Received wrong transition!");
                 } else if !self.inspector.lock().unwrap().has(&transition.key()) {
                     self.inspector.lock().unwrap().store(transition.clone());
 
+                    // ask the peer for references we don't know yet
+                    let missing = reconcile::missing_refs(&*self.inspector.lock().unwrap(), &transition);
+                    if !missing.is_empty() {
+                        self.writer.book.lock().unwrap().requested(&id, &missing);
+                        self.writer.spread(Packet::GetTransitions(missing), SpreadTo::Peer(id.clone()));
+                    }
+
                     // forward to everyone else :(
                     self.writer.spread(Packet::Push(transition.clone()), SpreadTo::Everyone);
 
@@ -447,17 +658,61 @@ impl<T: Inspector> Stream for Gossip<T> {
                     trace!("Got a well-known transition!");
                 }
             },
-            Packet::File(file_id, data) => {
-                if let Some(data) = data {
-                    return Ok(Async::Ready(Some(Packet::File(file_id, Some(data)))));
-                } else {
-                    if let Some(data) = self.inspector.lock().unwrap().get_file(&file_id) {
-                        self.writer.spread(Packet::File(file_id, Some(data)), SpreadTo::Peer(id));
+            packet @ Packet::Reconcile(_) | packet @ Packet::GetTransitions(_) | packet @ Packet::Transitions(_) => {
+                if let Packet::Transitions(ref transitions) = packet {
+                    let keys: Vec<TransitionKey> = transitions.iter().map(|x| x.key.clone()).collect();
+                    self.writer.book.lock().unwrap().arrived(&id, &keys);
+                }
+
+                let (answers, new) = reconcile::process(&*self.inspector.lock().unwrap(), packet);
+
+                for answer in answers {
+                    if let Packet::GetTransitions(ref keys) = answer {
+                        self.writer.book.lock().unwrap().requested(&id, keys);
                     }
+
+                    self.writer.spread(answer, SpreadTo::Peer(id.clone()));
+                }
+
+                // return the new transitions one after another
+                self.arrived.extend(new);
+            },
+            Packet::FileInfo(file_id, None) => {
+                // hashing the file would block the protocol, the answer is sent once it's done
+                if let Some(job) = self.inspector.lock().unwrap().file_info(&file_id) {
+                    let answer = self.answer.clone();
+
+                    thread::spawn(move || {
+                        if let Some(info) = job() {
+                            let _ = answer.unbounded_send((Packet::FileInfo(file_id, Some(info)), SpreadTo::Peer(id)));
+                        }
+                    });
                 }
             },
+            Packet::FileInfo(file_id, Some(info)) => {
+                let packets = self.writer.downloads.lock().unwrap()
+                    .info(&*self.inspector.lock().unwrap(), id, file_id, info);
+
+                self.writer.spread_all(packets);
+            },
+            Packet::GetChunk(file_id, offset) => {
+                let data = self.inspector.lock().unwrap().read_chunk(&file_id, offset);
+
+                self.writer.spread(Packet::Chunk(file_id, offset, data), SpreadTo::Peer(id));
+            },
+            Packet::Chunk(file_id, offset, data) => {
+                let connected = self.writer.connected();
+                let packets = self.writer.downloads.lock().unwrap()
+                    .chunk(&*self.inspector.lock().unwrap(), id, file_id, offset, data, &connected);
+
+                self.writer.spread_all(packets);
+            },
+            Packet::Heartbeat => {
+                // the peer is already marked as seen
+            },
             Packet::Close => {
-                self.books.remove(&id);
+                // an earlier connection to the peer may close after it was replaced
+                self.disconnect(&id, Some(conn));
 
                 info!("Connection to {:?} closed", id);
             },
@@ -0,0 +1,508 @@
+//! Simulated network connecting peers in memory
+//!
+//! All peers of a `Network` share a simulated clock, which only advances with `Network::advance`.
+//! Bytes written to a connection arrive after the latency of the link. A lost segment is sent
+//! again after a round trip, like TCP would do, so loss shows up as delay but never breaks a
+//! stream. Links between partitioned peers hold back all segments until the partition heals, and
+//! no new connections can be established across them. Losses are drawn from a seeded generator,
+//! so a simulation is reproducible. Peers of the network take their time from the simulated clock.
+//!
+//! Datagrams, as used to discover peers, are not sent again. A lost datagram or one sent across
+//! partitions never arrives. A datagram sent to the broadcast address arrives at all sockets bound
+//! to its port, including the sender.
+
+use std::cmp;
+use std::fmt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+
+use futures::{task, Async, Poll, Stream, future::{self, FutureResult}};
+use futures::task::Task;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use transport::Transport;
+use discover::Datagram;
+
+/// Conditions of a link between two peers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    /// Time in milliseconds until a segment arrives
+    pub latency: u64,
+    /// Probability that a segment is lost and has to be sent again, below one
+    pub loss: f64
+}
+
+impl Link {
+    pub fn new(latency: u64, loss: f64) -> Link {
+        assert!(loss >= 0.0 && loss < 1.0, "loss has to be a probability below one");
+
+        Link { latency, loss }
+    }
+}
+
+impl Default for Link {
+    fn default() -> Link {
+        Link::new(0, 0.0)
+    }
+}
+
+/// One direction of a connection
+struct Pipe {
+    /// Segments together with their time of arrival
+    segments: VecDeque<(u64, Vec<u8>)>,
+    /// The reading task, notified when a segment could have arrived
+    task: Option<Task>,
+    closed: bool
+}
+
+/// Accepts connections at a certain address
+struct Listener {
+    queue: VecDeque<MemoryStream>,
+    task: Option<Task>
+}
+
+/// Datagrams sent to a socket, together with their time of arrival and sender
+struct Mailbox {
+    datagrams: Vec<(u64, SocketAddr, Vec<u8>)>,
+    task: Option<Task>
+}
+
+struct State {
+    now: u64,
+    seed: u64,
+    default: Link,
+    links: HashMap<(SocketAddr, SocketAddr), Link>,
+    partitions: Vec<HashSet<SocketAddr>>,
+    listeners: HashMap<SocketAddr, Listener>,
+    mailboxes: HashMap<SocketAddr, Mailbox>,
+    pipes: Vec<Pipe>
+}
+
+impl State {
+    /// Draw a number from [0, 1) with xorshift
+    fn random(&mut self) -> f64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+
+        (self.seed >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Peers in different partitions can't reach each other
+    fn separated(&self, a: &SocketAddr, b: &SocketAddr) -> bool {
+        let partition = |x: &SocketAddr| self.partitions.iter().position(|p| p.contains(x));
+
+        partition(a) != partition(b)
+    }
+
+    /// Time of arrival of a segment sent now from `from` to `to`
+    fn arrival(&mut self, from: SocketAddr, to: SocketAddr) -> u64 {
+        let link = self.links.get(&(from, to)).cloned().unwrap_or(self.default.clone());
+
+        let mut at = self.now + link.latency;
+        while self.random() < link.loss {
+            at += 2 * cmp::max(link.latency, 1);
+        }
+
+        at
+    }
+
+    /// Wake all tasks waiting for the network
+    fn notify(&self) {
+        let tasks = self.pipes.iter().filter_map(|x| x.task.as_ref())
+            .chain(self.listeners.values().filter_map(|x| x.task.as_ref()))
+            .chain(self.mailboxes.values().filter_map(|x| x.task.as_ref()));
+
+        for task in tasks {
+            task.notify();
+        }
+    }
+}
+
+/// A simulated network with a clock of its own
+#[derive(Clone)]
+pub struct Network(Arc<Mutex<State>>);
+
+impl Network {
+    /// Create a network without latency and loss, losses are drawn with `seed`
+    pub fn new(seed: u64) -> Network {
+        Network(Arc::new(Mutex::new(State {
+            now: 0,
+            seed: cmp::max(seed, 1),
+            default: Link::default(),
+            links: HashMap::new(),
+            partitions: Vec::new(),
+            listeners: HashMap::new(),
+            mailboxes: HashMap::new(),
+            pipes: Vec::new()
+        })))
+    }
+
+    /// Transport for the peer listening at `addr`
+    pub fn transport(&self, addr: SocketAddr) -> Memory {
+        Memory { network: self.clone(), addr }
+    }
+
+    /// Set the conditions of all links without conditions of their own
+    pub fn set_default(&self, link: Link) {
+        self.0.lock().unwrap().default = link;
+    }
+
+    /// Set the conditions of the link between `a` and `b` in both directions
+    pub fn set_link(&self, a: SocketAddr, b: SocketAddr, link: Link) {
+        let mut state = self.0.lock().unwrap();
+        state.links.insert((a, b), link.clone());
+        state.links.insert((b, a), link);
+    }
+
+    /// Split the network into partitions, peers not listed form a partition of their own
+    pub fn partition(&self, partitions: Vec<Vec<SocketAddr>>) {
+        self.0.lock().unwrap().partitions = partitions.into_iter()
+            .map(|x| x.into_iter().collect())
+            .collect();
+    }
+
+    /// Reconnect all partitions, held back segments arrive immediately
+    pub fn heal(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.partitions.clear();
+        state.notify();
+    }
+
+    /// Current time in milliseconds since the start of the simulation
+    pub fn now(&self) -> u64 {
+        self.0.lock().unwrap().now
+    }
+
+    /// Advance the clock and wake all tasks waiting for the network
+    pub fn advance(&self, ms: u64) {
+        let mut state = self.0.lock().unwrap();
+        state.now += ms;
+        state.notify();
+    }
+}
+
+/// Connect to other peers of a simulated network
+#[derive(Clone)]
+pub struct Memory {
+    network: Network,
+    addr: SocketAddr
+}
+
+impl Memory {
+    /// Bind a datagram socket to `port` at the address of the peer
+    pub fn datagram(&self, port: u16) -> io::Result<MemoryDatagram> {
+        let addr = SocketAddr::new(self.addr.ip(), port);
+
+        let mut state = self.network.0.lock().unwrap();
+        if state.mailboxes.contains_key(&addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+
+        state.mailboxes.insert(addr, Mailbox { datagrams: Vec::new(), task: None });
+
+        Ok(MemoryDatagram { network: self.network.clone(), addr })
+    }
+}
+
+impl Transport for Memory {
+    type Stream = MemoryStream;
+    type Connect = FutureResult<MemoryStream, io::Error>;
+    type Incoming = Incoming;
+
+    fn bind(&self, addr: &SocketAddr) -> io::Result<(Incoming, SocketAddr)> {
+        let mut state = self.network.0.lock().unwrap();
+        if state.listeners.contains_key(addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+
+        state.listeners.insert(*addr, Listener { queue: VecDeque::new(), task: None });
+
+        Ok((Incoming { network: self.network.clone(), addr: *addr }, *addr))
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> FutureResult<MemoryStream, io::Error> {
+        let mut state = self.network.0.lock().unwrap();
+        if !state.listeners.contains_key(addr) || state.separated(&self.addr, addr) {
+            return future::err(io::ErrorKind::ConnectionRefused.into());
+        }
+
+        // one pipe for each direction
+        let (forward, backward) = (state.pipes.len(), state.pipes.len() + 1);
+        for _ in 0..2 {
+            state.pipes.push(Pipe { segments: VecDeque::new(), task: None, closed: false });
+        }
+
+        let network = self.network.clone();
+        let client = MemoryStream { network: network.clone(), local: self.addr, remote: *addr, read: backward, write: forward };
+        let server = MemoryStream { network, local: *addr, remote: self.addr, read: forward, write: backward };
+
+        let listener = state.listeners.get_mut(addr).unwrap();
+        listener.queue.push_back(server);
+        if let Some(ref task) = listener.task {
+            task.notify();
+        }
+
+        future::ok(client)
+    }
+
+    fn now(&self) -> u64 {
+        self.network.now()
+    }
+}
+
+/// Connections opened by other peers of the network
+pub struct Incoming {
+    network: Network,
+    addr: SocketAddr
+}
+
+impl Stream for Incoming {
+    type Item = MemoryStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<MemoryStream>, io::Error> {
+        let mut state = self.network.0.lock().unwrap();
+        let listener = state.listeners.get_mut(&self.addr).unwrap();
+
+        listener.task = Some(task::current());
+        match listener.queue.pop_front() {
+            Some(stream) => Ok(Async::Ready(Some(stream))),
+            None => Ok(Async::NotReady)
+        }
+    }
+}
+
+/// A connection between two peers of the network
+pub struct MemoryStream {
+    network: Network,
+    local: SocketAddr,
+    remote: SocketAddr,
+    read: usize,
+    write: usize
+}
+
+impl fmt::Debug for MemoryStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MemoryStream({} -> {})", self.local, self.remote)
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.network.0.lock().unwrap();
+        let (now, separated) = (state.now, state.separated(&self.local, &self.remote));
+
+        let pipe = &mut state.pipes[self.read];
+        let arrived = match pipe.segments.front() {
+            Some(&(at, _)) => at <= now && !separated,
+            None => false
+        };
+
+        if arrived {
+            let n = {
+                let data = &mut pipe.segments.front_mut().unwrap().1;
+                let n = cmp::min(buf.len(), data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                data.drain(..n);
+
+                n
+            };
+
+            if pipe.segments.front().map(|x| x.1.is_empty()).unwrap_or(false) {
+                pipe.segments.pop_front();
+            }
+
+            Ok(n)
+        } else if pipe.segments.is_empty() && pipe.closed {
+            Ok(0)
+        } else {
+            pipe.task = Some(task::current());
+
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+impl AsyncRead for MemoryStream {}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.network.0.lock().unwrap();
+        if state.pipes[self.write].closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        // a stream keeps the order of its segments
+        let at = state.arrival(self.local, self.remote);
+        let pipe = &mut state.pipes[self.write];
+        let at = cmp::max(at, pipe.segments.back().map(|x| x.0).unwrap_or(0));
+
+        pipe.segments.push_back((at, buf.to_vec()));
+        if let Some(ref task) = pipe.task {
+            task.notify();
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let mut state = self.network.0.lock().unwrap();
+        let pipe = &mut state.pipes[self.write];
+        pipe.closed = true;
+        if let Some(ref task) = pipe.task {
+            task.notify();
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        let mut state = self.network.0.lock().unwrap();
+        for &idx in &[self.read, self.write] {
+            state.pipes[idx].closed = true;
+            if let Some(ref task) = state.pipes[idx].task {
+                task.notify();
+            }
+        }
+    }
+}
+
+/// A datagram socket of a peer in the network
+pub struct MemoryDatagram {
+    network: Network,
+    addr: SocketAddr
+}
+
+impl Datagram for MemoryDatagram {
+    fn poll_send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> Poll<usize, io::Error> {
+        let mut state = self.network.0.lock().unwrap();
+
+        let receivers: Vec<SocketAddr> = if addr.ip() == Ipv4Addr::BROADCAST {
+            state.mailboxes.keys().filter(|x| x.port() == addr.port()).cloned().collect()
+        } else {
+            state.mailboxes.keys().filter(|x| *x == addr).cloned().collect()
+        };
+
+        for receiver in receivers {
+            if state.separated(&self.addr, &receiver) {
+                continue;
+            }
+
+            let link = state.links.get(&(self.addr, receiver)).cloned().unwrap_or(state.default.clone());
+            if state.random() < link.loss {
+                continue;
+            }
+
+            let at = state.now + link.latency;
+            let mailbox = state.mailboxes.get_mut(&receiver).unwrap();
+            mailbox.datagrams.push((at, self.addr, buf.to_vec()));
+            if let Some(ref task) = mailbox.task {
+                task.notify();
+            }
+        }
+
+        Ok(Async::Ready(buf.len()))
+    }
+
+    fn poll_recv_from(&mut self, buf: &mut [u8]) -> Poll<(usize, SocketAddr), io::Error> {
+        let mut state = self.network.0.lock().unwrap();
+        let now = state.now;
+        let mailbox = state.mailboxes.get_mut(&self.addr).unwrap();
+
+        // datagrams don't keep their order, the first one arriving is received
+        let next = mailbox.datagrams.iter().enumerate()
+            .filter(|(_, x)| x.0 <= now)
+            .min_by_key(|(_, x)| x.0)
+            .map(|(i, _)| i);
+
+        match next {
+            Some(i) => {
+                let (_, from, data) = mailbox.datagrams.remove(i);
+                let n = cmp::min(buf.len(), data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+
+                Ok(Async::Ready((n, from)))
+            },
+            None => {
+                mailbox.task = Some(task::current());
+
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl Drop for MemoryDatagram {
+    fn drop(&mut self) {
+        self.network.0.lock().unwrap().mailboxes.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write, ErrorKind};
+    use std::net::SocketAddr;
+
+    use futures::{Future, Stream, Async, future};
+    use transport::Transport;
+    use super::{Network, Link};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn latency_and_partitions() {
+        let network = Network::new(1);
+        network.set_link(addr(1), addr(2), Link::new(10, 0.5));
+
+        // poll inside a task, because reading registers the current task
+        future::lazy(|| {
+            let (mut incoming, _) = network.transport(addr(2)).bind(&addr(2)).unwrap();
+            let mut client = network.transport(addr(1)).connect(&addr(2)).wait().unwrap();
+            let mut server = match incoming.poll().unwrap() {
+                Async::Ready(Some(stream)) => stream,
+                _ => panic!("connection not accepted")
+            };
+
+            // segments arrive in order, but only after the latency
+            let mut buf = [0u8; 4];
+            client.write_all(&[1, 2]).unwrap();
+            client.write_all(&[3, 4]).unwrap();
+            assert_eq!(server.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+            network.advance(1000);
+            assert_eq!(server.read(&mut buf).unwrap(), 2);
+            assert_eq!(server.read(&mut buf[2..]).unwrap(), 2);
+            assert_eq!(buf, [1, 2, 3, 4]);
+
+            // partitioned peers can't reach each other until healed
+            network.partition(vec![vec![addr(1)], vec![addr(2)]]);
+            server.write_all(&[5]).unwrap();
+            network.advance(1000);
+            assert_eq!(client.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+            assert!(network.transport(addr(1)).connect(&addr(2)).wait().is_err());
+
+            network.heal();
+            assert_eq!(client.read(&mut buf).unwrap(), 1);
+
+            // a closed stream signals the end
+            drop(server);
+            assert_eq!(client.read(&mut buf).unwrap(), 0);
+            assert!(client.write_all(&[6]).is_err());
+
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+}
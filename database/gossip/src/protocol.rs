@@ -4,63 +4,122 @@ use std::net::SocketAddr;
 use std::mem;
 use std::fmt::Debug;
 use std::io::ErrorKind;
+use std::cmp;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use futures::task::Task;
 use futures::sync::mpsc::Sender;
 use futures::stream::{futures_unordered, FuturesUnordered};
 use tokio::prelude::*;
 use tokio::{self, io, io::ReadHalf, io::WriteHalf};
-use tokio::net::{TcpStream, tcp::ConnectFuture};
 use bytes::{BytesMut, BufMut};
 use bincode::{deserialize, serialize};
-use ring::{aead, rand, rand::SecureRandom};
+use ring::aead;
 
 use crate::{PeerId, PeerPresence, Error, Result};
-use transition::Transition;
-
-/// The network key will be shared between all peers and contains 
-/// a 256bit key, encrypting and signing every transition send through the network
+use transition::{Transition, TransitionKey};
+use reconcile::Range;
+use transfer::FileInfo;
+use transport::Transport;
+use session::{Handshake, Hello, Keyring};
+
+/// The network key will be shared between all peers and contains a 256bit key, encrypting and
+/// signing the handshake of every connection, see `session`
 pub type NetworkKey = [u8; 32];
 
+/// Newest version of the protocol we speak
+///
+/// Version 3 introduced Ed25519 signatures of 512bits in transitions, version 4 the chunked
+/// file transfer, version 5 the reconciliation of transitions, version 6 heartbeats, version 7
+/// the negotiation of the version in the `Join` message and version 8 the session keys.
+pub const VERSION: u8 = 8;
+
+/// Oldest version of the protocol we speak, our `Hello` and `Join` message are sent with it
+pub const MIN_VERSION: u8 = 8;
+
+/// Flags of the optional features we support, none are defined yet
+pub const FEATURES: u32 = 0;
+
+/// Protocol versions and optional features of a peer, exchanged in the `Join` message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    pub min_version: u8,
+    pub max_version: u8,
+    pub features: u32
+}
+
+impl Capabilities {
+    pub fn ours() -> Capabilities {
+        Capabilities { min_version: MIN_VERSION, max_version: VERSION, features: FEATURES }
+    }
+
+    /// Highest version and the features both peers support, `None` if they have no common version
+    pub fn negotiate(&self, other: &Capabilities) -> Option<(u8, u32)> {
+        let version = cmp::min(self.max_version, other.max_version);
+
+        if version < cmp::max(self.min_version, other.min_version) {
+            None
+        } else {
+            Some((version, self.features & other.features))
+        }
+    }
+}
+
 /// Peer-to-Peer message
 /// 
-/// The protocol is not very complex. After establishing a connection
-/// every peer should send a Join message as a handshake. The peers
+/// The protocol is not very complex. After establishing a connection and exchanging the session
+/// keys every peer should send a Join message as a handshake. The peers
 /// can then be requested with the GetPeers message. A push transmits
 /// a new transition of the database state
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Packet {
-    /// We ask to join in the network with a identity and our tips of the data graph
-    Join(PeerPresence, Vec<Transition>),
+    /// We ask to join in the network with our capabilities, identity and tips of the data graph
+    ///
+    /// Peers read the Join message before they agreed on a version, therefore it has to stay the
+    /// first variant and must not change its layout in newer versions.
+    Join(Capabilities, PeerPresence, Vec<Transition>),
     /// Ask for the current vector of peers
     GetPeers(Option<Vec<PeerPresence>>),
     /// Push a new packet into the network with reference to received transitions
     Push(Transition),
-    /// Ask for a file
-    File(Vec<u8>, Option<Vec<u8>>),
+    /// Compare ranges of transition keys with a peer, see `reconcile`
+    Reconcile(Vec<Range>),
+    /// Ask a peer for transitions by their keys
+    GetTransitions(Vec<TransitionKey>),
+    /// Transitions requested by a peer, not forwarded to anyone else
+    Transitions(Vec<Transition>),
+    /// Ask for the description of a file, only peers having the file answer
+    FileInfo(Vec<u8>, Option<FileInfo>),
+    /// Ask a single peer for the chunk of a file beginning at an offset
+    GetChunk(Vec<u8>, u64),
+    /// Chunk of a file, `None` if the peer can't read it
+    Chunk(Vec<u8>, u64, Option<Vec<u8>>),
+    /// Keep an idle connection alive, see `book::HEARTBEAT`
+    Heartbeat,
     Close
 }
 
 /// List of peers to be resolved
-pub struct ResolvePeers {
-    awaiting: FuturesUnordered<Peer>,
+pub struct ResolvePeers<N: Transport> {
+    awaiting: FuturesUnordered<Peer<N>>,
     ids: HashMap<PeerId, ()>
 }
 
-impl ResolvePeers {
-    pub fn new(peers: Vec<Peer>) -> ResolvePeers {
+impl<N: Transport> ResolvePeers<N> {
+    pub fn new(peers: Vec<Peer<N>>) -> ResolvePeers<N> {
         ResolvePeers {
             awaiting: futures_unordered(peers),
             ids: HashMap::new()
         }
     }
 
-    pub fn add_peer(&mut self, peer: Peer) {
+    pub fn add_peer(&mut self, peer: Peer<N>) {
         self.awaiting.push(peer);
     }
 
-    pub fn poll(&mut self) -> Poll<Option<(PeerCodecRead<TcpStream>, PeerCodecWrite<TcpStream>, PeerPresence, Vec<Transition>)>, io::Error> {
+    pub fn poll(&mut self) -> Poll<Option<(PeerCodecRead<N::Stream>, PeerCodecWrite<N::Stream>, PeerPresence, Vec<Transition>)>, io::Error> {
         if let Some((read, write, presence, transitions)) = try_ready!(self.awaiting.poll()) {
             self.ids.insert(presence.id.clone(), ());
 
@@ -77,46 +136,45 @@ impl ResolvePeers {
 
 /// Represent an emerging connection to a peer
 ///
-/// There are two phases in the protocol, first the TCP connection should exist
-/// then a Join message should tell something about the other peer. The resolved Future
-/// gives the PeerCodec, the socket addr and the Join message.
-
-pub enum Peer {
-    Connecting((ConnectFuture, NetworkKey, PeerPresence, Vec<Transition>)),
-    SendJoin((PeerCodecRead<TcpStream>, PeerCodecWrite<TcpStream>)),
-    WaitForJoin((PeerCodecRead<TcpStream>, PeerCodecWrite<TcpStream>)),
+/// There are three phases in the protocol, first the connection of the transport should exist,
+/// then both peers exchange a `Hello` to derive the session keys and finally a Join message
+/// should tell something about the other peer. The resolved Future gives the PeerCodec, the socket
+/// addr and the Join message.
+
+pub enum Peer<N: Transport> {
+    Connecting((N::Connect, Handshake, PeerPresence, Vec<Transition>)),
+    SendHello((PeerCodecRead<N::Stream>, PeerCodecWrite<N::Stream>, Handshake, PeerPresence, Vec<Transition>)),
+    /// Wait for the `Hello` of the other peer, the flag tells whether we have to answer it
+    WaitForHello((PeerCodecRead<N::Stream>, PeerCodecWrite<N::Stream>, Handshake, bool, PeerPresence, Vec<Transition>)),
+    SendJoin((PeerCodecRead<N::Stream>, PeerCodecWrite<N::Stream>, PeerId)),
+    WaitForJoin((PeerCodecRead<N::Stream>, PeerCodecWrite<N::Stream>, PeerId)),
     Ready
 }
 
-impl Peer {
+impl<N: Transport> Peer<N> {
     /// Initialise a full peer connection with just the address
-    pub fn connect(addr: &SocketAddr, key: NetworkKey, myself: PeerPresence, tips: Vec<Transition>) -> Peer {
-        let addr = addr.clone();
-
+    pub fn connect(transport: &N, addr: &SocketAddr, keyring: Keyring, myself: PeerPresence, tips: Vec<Transition>) -> Peer<N> {
         trace!("Connect to {:?} with {} tips", addr, tips.len());
 
-        Peer::Connecting((TcpStream::connect(&addr), key, myself, tips))
+        Peer::Connecting((transport.connect(addr), Handshake::new(keyring), myself, tips))
     }
 
-    /// Initialise a full peer connection with a connected TcpStream
-    pub fn send_join(socket: TcpStream, key: NetworkKey, myself: PeerPresence, tips: Vec<Transition>) -> Peer {
-        let addr = socket.peer_addr().unwrap();
-        let (read, mut write) = new(socket, key);
-
-        trace!("Send JOIN to {:?} with {} tips", addr, tips.len());
+    /// Initialise a full peer connection with an accepted stream, the other peer says hello first
+    pub fn accept(socket: N::Stream, keyring: Keyring, myself: PeerPresence, tips: Vec<Transition>) -> Peer<N> {
+        trace!("Accept connection {:?} with {} tips", socket, tips.len());
 
-        write.buffer(Packet::Join(myself, tips));
+        let (read, write) = new(socket);
 
-        Peer::SendJoin((read, write))
+        Peer::WaitForHello((read, write, Handshake::new(keyring), true, myself, tips))
     }
 }
 
 /// Resolve to a fully connected peer
 ///
-/// This future will ensure that 1. the TcpStream has been established and 2. the Join
-/// message is received and valid. It is encoded as a state machine.
-impl Future for Peer {
-    type Item=(PeerCodecRead<TcpStream>, PeerCodecWrite<TcpStream>, PeerPresence, Vec<Transition>);
+/// This future will ensure that 1. the connection has been established, 2. the session keys are
+/// derived and 3. the Join message is received and valid. It is encoded as a state machine.
+impl<N: Transport> Future for Peer<N> {
+    type Item=(PeerCodecRead<N::Stream>, PeerCodecWrite<N::Stream>, PeerPresence, Vec<Transition>);
     type Error=io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
@@ -124,34 +182,104 @@ impl Future for Peer {
         let val = mem::replace(self, Peer::Ready);
 
         let new_val = match val {
-            Peer::Connecting((mut socket_future, key, myself, tips)) => {
-                // We are here in the connecting state, the TcpStream has no connection yet. As
-                // soon as the connection is established we will send the join message and then
+            Peer::Connecting((mut socket_future, handshake, myself, tips)) => {
+                // We are here in the connecting state, the stream has no connection yet. As
+                // soon as the connection is established we will send our hello message and then
                 // poll again.
                 match socket_future.poll()? {
-                    Async::Ready(socket) => {poll_again = true; Peer::send_join(socket, key, myself, tips)},
-                    Async::NotReady => Peer::Connecting((socket_future, key, myself, tips))
+                    Async::Ready(socket) => {
+                        let (read, mut write) = new(socket);
+
+                        // seal our greeting with every accepted network key
+                        write.buffer_hello(&handshake.hello(None));
+
+                        poll_again = true;
+                        Peer::SendHello((read, write, handshake, myself, tips))
+                    },
+                    Async::NotReady => Peer::Connecting((socket_future, handshake, myself, tips))
+                }
+            },
+
+            Peer::SendHello((read, mut write, handshake, myself, tips)) => {
+                match write.poll_flush()? {
+                    Async::Ready(_) => {poll_again = true; Peer::WaitForHello((read, write, handshake, false, myself, tips))},
+                    Async::NotReady => Peer::SendHello((read, write, handshake, myself, tips))
+                }
+            },
+
+            Peer::WaitForHello((mut read, mut write, handshake, respond, myself, tips)) => {
+                match read.poll_hello()? {
+                    Async::Ready(Some(hello)) => {
+                        let (key, greeting) = handshake.open(&hello).map_err(|err| {
+                            warn!("Handshake failed, probably a connection attempt with a wrong network key: {:?}", err);
+
+                            io::Error::new(io::ErrorKind::InvalidData, "handshake failed")
+                        })?;
+
+                        // answer with the network key the other peer used
+                        if respond {
+                            write.buffer_hello(&handshake.hello(Some(&key)));
+                        }
+
+                        let (opening, sealing) = handshake.finish(&key, &greeting)
+                            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "key agreement failed"))?;
+
+                        read.set_key(opening);
+                        write.set_key(sealing);
+                        write.buffer(Packet::Join(Capabilities::ours(), myself, tips));
+
+                        poll_again = true;
+                        Peer::SendJoin((read, write, greeting.id))
+                    },
+                    Async::Ready(None) => return {
+                        error!("Got an invalid connection attempt!");
+
+                        Err(io::Error::new(io::ErrorKind::ConnectionAborted, "no hello received"))
+                    },
+                    Async::NotReady => Peer::WaitForHello((read, write, handshake, respond, myself, tips))
                 }
             },
 
-            Peer::SendJoin((read, mut write)) => {
+            Peer::SendJoin((read, mut write, id)) => {
                 match write.poll_flush()? {
-                    Async::Ready(_) => {poll_again = true; Peer::WaitForJoin((read, write))},
-                    Async::NotReady => Peer::SendJoin((read, write))
+                    Async::Ready(_) => {poll_again = true; Peer::WaitForJoin((read, write, id))},
+                    Async::NotReady => Peer::SendJoin((read, write, id))
                 }
             },
 
-            Peer::WaitForJoin((mut read, write)) => {
+            Peer::WaitForJoin((mut read, mut write, id)) => {
                 // Poll the underlying socket through the PeerCodec for a Join message. If one
-                // arrives, we can resolve the future.
+                // arrives and we have a common version, we can resolve the future.
                 match read.poll()? {
-                    Async::Ready(Some(Packet::Join(presence, new_transitions))) => return Ok(Async::Ready((read, write, presence, new_transitions))),
+                    Async::Ready(Some(Packet::Join(capabilities, presence, new_transitions))) => {
+                        // the identity was proven in the handshake, the Join has to stick with it
+                        if presence.id != id {
+                            warn!("Peer at {:?} joins with another identity than in its greeting", presence.addr);
+
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, "identity differs from greeting"));
+                        }
+
+                        let (version, features) = match Capabilities::ours().negotiate(&capabilities) {
+                            Some(negotiated) => negotiated,
+                            None => {
+                                warn!("Peer at {:?} speaks versions {}..{}, but we {}..{}", presence.addr,
+                                    capabilities.min_version, capabilities.max_version, MIN_VERSION, VERSION);
+
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, "no common protocol version"));
+                            }
+                        };
+
+                        read.set_version(version);
+                        write.set_protocol(version, features);
+
+                        return Ok(Async::Ready((read, write, presence, new_transitions)));
+                    },
                     Async::Ready(None) => return {
                         error!("Got an invalid connection attempt!");
                         
                         Err(io::Error::new(io::ErrorKind::ConnectionAborted, "test"))
                     },
-                    _ => Peer::WaitForJoin((read, write))
+                    _ => Peer::WaitForJoin((read, write, id))
                 }
             },
             _ => {
@@ -174,11 +302,18 @@ impl Future for Peer {
 /// Read half of the PeerCodec
 ///
 /// The read half allows you to read arriving messages from the peer connection. It wraps a 
-/// TcpStream and converts the byte stream to a message stream by implementing the Stream trait.
+/// stream of the transport and converts the byte stream to a message stream by implementing the Stream trait.
 pub struct PeerCodecRead<T: Debug + AsyncRead> {
     read: ReadHalf<T>,
     rd: BytesMut,
-    key: aead::OpeningKey
+    /// Session key of packets from the peer, `None` until the handshake is done
+    key: Option<aead::OpeningKey>,
+    /// Number of the next packet from the peer
+    counter: u64,
+    /// Negotiated version, `None` until the `Join` message arrived
+    version: Option<u8>,
+    /// Bytes read from the stream, shared with the peer book
+    received: Arc<AtomicUsize>
 }
 
 /// Write half of the PeerCodec
@@ -188,48 +323,65 @@ pub struct PeerCodecRead<T: Debug + AsyncRead> {
 pub struct PeerCodecWrite<T: Debug + AsyncWrite> {
     write: WriteHalf<T>,
     wr: BytesMut,
-    key: aead::SealingKey,
-    rng: rand::SystemRandom
+    /// Session key of packets to the peer, `None` until the handshake is done
+    key: Option<aead::SealingKey>,
+    /// Number of the next packet to the peer
+    counter: u64,
+    /// Bytes written to the stream
+    written: u64,
+    /// Version in the header of written packets and the features both peers support
+    version: u8,
+    features: u32
 }
 
-/// The version field to prevent incompatible peer protocols
-const VERSION: u8 = 2;
+/// Nonce of the n-th packet in a connection, every session key is used for a single connection
+fn counter_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    for i in 0..8 {
+        nonce[4 + i] = (counter >> (56 - 8 * i)) as u8;
+    }
 
-pub fn new<T: AsyncRead + AsyncWrite + Debug>(socket: T, key: NetworkKey) -> (PeerCodecRead<T>, PeerCodecWrite<T>) {
-    let (read, write) = socket.split();
+    nonce
+}
 
-    // create opening/sealing keys (128bit network key)
-    let read_key = aead::OpeningKey::new(&aead::AES_256_GCM, &key).unwrap();
-    let write_key = aead::SealingKey::new(&aead::AES_256_GCM, &key).unwrap();
+/// Split a stream into the codec halves, the session keys are set after the handshake
+pub fn new<T: AsyncRead + AsyncWrite + Debug>(socket: T) -> (PeerCodecRead<T>, PeerCodecWrite<T>) {
+    let (read, write) = socket.split();
 
     (
         PeerCodecRead {
             read: read,
             rd: BytesMut::new(),
-            key: read_key
+            key: None,
+            counter: 0,
+            version: None,
+            received: Arc::new(AtomicUsize::new(0))
         },
         PeerCodecWrite {
             write: write,
             wr: BytesMut::new(),
-            key: write_key,
-            rng: rand::SystemRandom::new()
+            key: None,
+            counter: 0,
+            written: 0,
+            version: MIN_VERSION,
+            features: 0
         }
     )
 }
 
-impl PeerCodecRead<TcpStream> {
+impl<T: Debug + AsyncRead + Send + 'static> PeerCodecRead<T> {
     /// Redirect the message stream to a channel
     ///
     /// This will allow to connect many peers to a single GossipCodec. The channel unifies every
-    /// arriving messages by wrapping it with the PeerId. The GossipCodec can then process the
-    /// arriving messages according to the identification.
-    pub fn redirect_to(self, mut sender: Sender<(PeerId, Packet)>, id: PeerId, task: Task) {
+    /// arriving messages by wrapping it with the PeerId and the number of the connection. The
+    /// GossipCodec can then process the arriving messages according to the identification.
+    pub fn redirect_to(self, mut sender: Sender<(PeerId, usize, Packet)>, id: PeerId, conn: usize, task: Task) {
         let (task2, mut sender2, id2) = (task.clone(), sender.clone(), id.clone());
         let mut sender3 = sender.clone();
 
         let stream = self.map_err(|_| ())
         .and_then(move |x| {
-            sender.start_send((id.clone(), x)).map_err(|err| {println!("Send error: {}", err); ()})
+            sender.start_send((id.clone(), conn, x)).map_err(|err| {println!("Send error: {}", err); ()})
         })    
         .and_then(move |_| {
             task.notify();
@@ -238,7 +390,7 @@ impl PeerCodecRead<TcpStream> {
         .for_each(move |_| Ok(()))
         .then(move |_| {
             // ugh
-            sender2.try_send((id2.clone(), Packet::Close)).unwrap();
+            sender2.try_send((id2.clone(), conn, Packet::Close)).unwrap();
             task2.notify();
 
             let res: Result<()> = Ok(());
@@ -252,11 +404,26 @@ impl PeerCodecRead<TcpStream> {
 }
 
 impl<T: Debug + AsyncRead> PeerCodecRead<T> {
+    /// Counter of the bytes read, which stays valid after the reader is redirected
+    pub fn received(&self) -> Arc<AtomicUsize> {
+        self.received.clone()
+    }
+
+    /// Accept only packets of the negotiated version from now on
+    pub fn set_version(&mut self, version: u8) {
+        self.version = Some(version);
+    }
+
+    /// Open all following packets with the session key of the peer
+    pub fn set_key(&mut self, key: aead::OpeningKey) {
+        self.key = Some(key);
+    }
+
     /// Process a stream of bytes by decrypting, checking signature and unpacking the inner message
     ///
     /// The header provides version checking and data encryption in the network. It provides for
-    /// this a `nonce` generated by AEAD as a unique encryption nonce and the `version` field to
-    /// distinguish between different protocol versions. Finally the length field 
+    /// this a `nonce` containing the number of the packet in the connection and the `version`
+    /// field to distinguish between different protocol versions. Finally the length field 
     ///
     /// It has the following structure:
     /// ----------------------------------------------
@@ -298,53 +465,83 @@ impl<T: Debug + AsyncRead> PeerCodecRead<T> {
         Some((version, length, self.rd.len() - meta_length as usize - 14))
     }
 
-    pub fn parse_packet(&mut self) -> Result<Packet> {
+    /// Split the next frame off the buffer, returns the nonce, the payload and the header length
+    fn parse_frame(&mut self) -> Result<([u8; 12], BytesMut, usize)> {
         // read the header
         let (version, required_length, buffer_length) = match self.version_length() {
             Some((a,b,c)) => (a,b,c),
             None => return Err(Error::NotEnoughBytes)
         };
 
-        // check the version
-        if version != VERSION {
-            trace!("Parse packet with invalid version {} != {}", version, VERSION);
-            return Err(Error::WrongVersion);
+        // check the version, before the handshake every version is fine for the `Join` message
+        if let Some(expected) = self.version {
+            if version != expected {
+                trace!("Parse packet with invalid version {} != {}", version, expected);
+                return Err(Error::WrongVersion);
+            }
         }
 
-        //println!("Requied length: {}", required_length);
-
         // continue till we have enough bytes
         if required_length as usize > buffer_length {
             return Err(Error::NotEnoughBytes);
         }
 
         // if we have reached the required byte number, read in the buffer
-        let meta_length = (self.rd[12] & 0b00000011) as usize;
-        let mut buf = self.rd.split_to(required_length as usize + 14 + meta_length);
+        let header_length = 14 + (self.rd[12] & 0b00000011) as usize;
+        let buf = self.rd.split_to(required_length as usize + header_length);
+
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&buf[0..12]);
+
+        Ok((nonce, buf, header_length))
+    }
+
+    /// Parse the unencrypted `Hello` message, which starts every connection
+    pub fn parse_hello(&mut self) -> Result<Hello> {
+        let (_, buf, header_length) = self.parse_frame()?;
+
+        deserialize::<Hello>(&buf[header_length..]).map_err(|_| Error::Deserialize)
+    }
 
-        // get once and create a copy
-        let nonce = Vec::from(&buf[0..12]);
+    pub fn parse_packet(&mut self) -> Result<Packet> {
+        let (nonce, mut buf, header_length) = self.parse_frame()?;
+
+        // every packet has to arrive exactly once and in order
+        if nonce != counter_nonce(self.counter) {
+            error!("Packet has number {:?}, but expected {}, probably a replay!", nonce, self.counter);
 
-        //println!("Nonce {:?}", nonce);
-        //println!("Read buf {:?}", buf.len());
+            return Err(Error::Replay);
+        }
 
         // decrypt and check signature
-        let buf = aead::open_in_place(
-            &self.key,
-            &nonce,
-            &[],
-            14+meta_length,
-            &mut buf
-        ).map_err(|_| {
-            error!("Cryptographic failure, probably connection attempt with wrong network key!");
-
-            Error::Cryptography
-        })?;
+        let buf = match self.key {
+            Some(ref key) => aead::open_in_place(key, &nonce, &[], header_length, &mut buf).map_err(|_| {
+                error!("Cryptographic failure, packet was not sealed with the session key!");
+
+                Error::Cryptography
+            })?,
+            None => return Err(Error::Handshake)
+        };
+
+        self.counter += 1;
 
         // now try to deserialise it to a message, we have to skip the header bytes
         deserialize::<Packet>(&buf).map_err(|_| Error::Deserialize)
     }
 
+    /// Poll the byte stream for the `Hello` of the peer, `None` if the connection is unusable
+    pub fn poll_hello(&mut self) -> Poll<Option<Hello>, io::Error> {
+        if self.fill_read_buf()?.is_ready() {
+            return Ok(Async::Ready(None));
+        }
+
+        match self.parse_hello() {
+            Ok(hello) => Ok(Async::Ready(Some(hello))),
+            Err(Error::NotEnoughBytes) => Ok(Async::NotReady),
+            Err(_) => Ok(Async::Ready(None))
+        }
+    }
+
     /// Try to read in some data from the byte stream
     fn fill_read_buf(&mut self) -> Poll<(), io::Error> {
         loop {
@@ -367,6 +564,8 @@ impl<T: Debug + AsyncRead> PeerCodecRead<T> {
             if n == 0 {
                 return Ok(Async::Ready(()));
             }
+
+            self.received.fetch_add(n, Ordering::Relaxed);
         }
     }
 }
@@ -376,64 +575,84 @@ impl<T: Debug + AsyncWrite> PeerCodecWrite<T> {
     ///
     /// First we serialise the message to a byte representation, and then
     /// calculates the metadata values. After this we can push the block to the
-    /// data stream.
+    /// data stream. Messages can only be sent after the session keys are exchanged.
     pub fn buffer(&mut self, message: Packet) {
         //println!("Buffer: {:?}", message);
-        if let Ok(mut buf) = serialize(&message) {
-            // encrypt and sign our data with the network key
-            // TODO generate nonce
-            let mut nonce = [0u8; 12];
-
-            self.rng.fill(&mut nonce).unwrap();
+        let nonce = counter_nonce(self.counter);
+
+        let buf = match (serialize(&message), &self.key) {
+            (Ok(mut buf), &Some(ref key)) => {
+                // enlarge buffer for additional 128bits
+                let tag_len = key.algorithm().tag_len();
+
+                buf.append(&mut vec![0u8; tag_len]);
+
+                // encrypt and sign our data with the session key
+                let buf_len = aead::seal_in_place(
+                    key, // our session key
+                    &nonce, // the number of this packet in the connection, used only once per key
+                    &[], // additional data to be signed, none here
+                    &mut buf, // buffer which will be overwritten with the encrypted and signed message
+                    tag_len // additional suffix capcity in the buffer, at least 128bit here
+                ).unwrap();
+
+                buf.truncate(buf_len);
+                buf
+            },
+            _ => return
+        };
 
-            // enlarge buffer for additional 128bits
-            let tag_len = self.key.algorithm().tag_len() ;
+        if self.put_frame(&nonce, &buf) {
+            self.counter += 1;
+        }
+    }
 
-            buf.append(&mut vec![0u8; tag_len]);
+    /// Buffer our `Hello` message, it is sealed with the network key instead of a session key
+    pub fn buffer_hello(&mut self, hello: &Hello) {
+        if let Ok(buf) = serialize(hello) {
+            self.put_frame(&[0u8; 12], &buf);
+        }
+    }
 
-            let buf_len = aead::seal_in_place(
-                &self.key, // our network key
-                &nonce, // a unique and random key created for each message
-                &[], // additional data to be signed, none here
-                &mut buf, // buffer which will be overwritten with the encrypted and signed message
-                tag_len // additional suffix capcity in the buffer, at least 128bit here
-            ).unwrap();
+    /// Put a frame with header to the write buffer, returns false if it is too large
+    fn put_frame(&mut self, nonce: &[u8; 12], buf: &[u8]) -> bool {
+        let buf_len = buf.len();
 
-            // calculate the value of the `additional` field by couting the zeros of the buffer
-            // length
-            let length = (32 - (buf_len as u32).leading_zeros()) as u8 / 8;
+        // calculate the value of the `additional` field by couting the zeros of the buffer
+        // length
+        let length = (32 - (buf_len as u32).leading_zeros()) as u8 / 8;
 
+        // we can't transmit more than 4G at once, should never happen anyway
+        if length > 4 {
+            return false;
+        }
 
-            // we can't transmit more than 4G at once, should never happen anyway
-            if length > 4 {
-                return;
-            }
+        // check if remaining space is sufficient
+        let rem = self.wr.capacity() - self.wr.len();
 
-            // check if remaining space is sufficient
-            let rem = self.wr.capacity() - self.wr.len();
+        if rem < length as usize + 14 + buf_len {
+            let new_size = self.wr.len() + rem + length as usize + 14 + buf_len;
+            self.wr.reserve(new_size);
+        }
 
-            if rem < length as usize + 14 + buf_len {
-                let new_size = self.wr.len() + rem + length as usize + 14 + buf_len;
-                self.wr.reserve(new_size);
-            }
+        // write the nonce 
+        self.wr.put(&nonce[..]);
 
-            // write the nonce 
-            self.wr.put(&nonce[..]);
+        // put the `version` and `additional` field to the write buffer
+        self.wr.put_u8(self.version << 2 | length);
 
-            // put the `version` and `additional` field to the write buffer
-            self.wr.put_u8(VERSION << 2 | length);
+        // write the buffer length
+        let mut buf_length = buf_len;
+        for _ in 0..length+1 {
+            self.wr.put_u8((buf_length & 0xFF) as u8);
 
-            // write the buffer length
-            let mut buf_length = buf_len;
-            for _ in 0..length+1 {
-                self.wr.put_u8((buf_length & 0xFF) as u8);
+            buf_length = buf_length >> 8;
+        }
 
-                buf_length = buf_length >> 8;
-            }
+        // put the message itself to the buffer
+        self.wr.put(buf);
 
-            // put the message itself to the buffer
-            self.wr.put(&buf[0..buf_len]);
-        }
+        true
     }
 
         /// Flush the whole write buffer to the underlying socket
@@ -446,6 +665,7 @@ impl<T: Debug + AsyncWrite> PeerCodecWrite<T> {
             assert!(n > 0);
 
             self.wr.split_to(n);
+            self.written += n as u64;
         }
 
         self.write.poll_flush()
@@ -458,6 +678,27 @@ impl<T: Debug + AsyncWrite> PeerCodecWrite<T> {
     pub fn is_empty(&self) -> bool {
         self.wr.len() == 0
     }
+
+    /// Bytes written to the stream so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Seal all following packets with our session key
+    pub fn set_key(&mut self, key: aead::SealingKey) {
+        self.key = Some(key);
+    }
+
+    /// Write packets with the negotiated version and remember the common features
+    pub fn set_protocol(&mut self, version: u8, features: u32) {
+        self.version = version;
+        self.features = features;
+    }
+
+    /// Negotiated version and features, see `Capabilities::negotiate`
+    pub fn protocol(&self) -> (u8, u32) {
+        (self.version, self.features)
+    }
 }
 
 /// Packet stream consuming the underlying byte stream. bytes_stream -> message_stream
@@ -490,6 +731,12 @@ impl<T: Debug + AsyncRead> Stream for PeerCodecRead<T> {
             Err(Error::WrongVersion) => Ok(Async::Ready(None)),
             // peer sent probably a wrong key, close connection
             Err(Error::Cryptography) => Ok(Async::Ready(None)),
+            // a replayed or reordered packet, close connection
+            Err(Error::Replay) => Ok(Async::Ready(None)),
+            // there is no session yet, close connection
+            Err(Error::Handshake) => Ok(Async::Ready(None)),
+            // the handshake of an incompatible peer, close connection
+            Err(Error::Deserialize) if self.version.is_none() => Ok(Async::Ready(None)),
             // in all other cases we await more bytes
             Err(Error::Deserialize) => Ok(Async::NotReady),
             Err(Error::NotEnoughBytes) => Ok(Async::NotReady),
@@ -500,12 +747,24 @@ impl<T: Debug + AsyncRead> Stream for PeerCodecRead<T> {
 
 #[cfg(test)]
 mod tests {
-    use super::{new, Packet, Transition, PeerId};
+    use super::{new, Packet, Transition, PeerId, Capabilities, PeerCodecRead, PeerCodecWrite, VERSION, MIN_VERSION, FEATURES};
+    use error::Error;
+    use transition::Keypair;
     use std::io::Cursor;
     use bytes::BufMut;
+    use ring::aead;
     use ring::rand::{SecureRandom, SystemRandom};
     use test::Bencher;
 
+    /// Create a codec on a buffer, which opens its own packets
+    fn codec(buf: &mut Cursor<Vec<u8>>, key: [u8; 32]) -> (PeerCodecRead<&mut Cursor<Vec<u8>>>, PeerCodecWrite<&mut Cursor<Vec<u8>>>) {
+        let (mut read, mut write) = new(buf);
+        read.set_key(aead::OpeningKey::new(&aead::AES_256_GCM, &key).unwrap());
+        write.set_key(aead::SealingKey::new(&aead::AES_256_GCM, &key).unwrap());
+
+        (read, write)
+    }
+
     #[test]
     fn read_write() {
         let mut buf = Cursor::new(Vec::new());
@@ -514,12 +773,12 @@ mod tests {
         let mut tmp = vec![0u8; 65536];
         rng.fill(&mut tmp).unwrap();
 
-        let packet = Packet::Push(Transition::new(Vec::new(), Vec::new(), tmp));
+        let packet = Packet::Push(Transition::new(&Keypair::from_seed(&[0; 32]), Vec::new(), tmp));
 
         let mut key = [0u8; 32];
         rng.fill(&mut key).unwrap();
 
-        let (mut read, mut write) = new(&mut buf, key);
+        let (mut read, mut write) = codec(&mut buf, key);
 
         write.buffer(packet.clone());
 
@@ -527,6 +786,48 @@ mod tests {
         read.rd.put_slice(&write.wr.as_ref());
 
         assert_eq!(read.parse_packet().unwrap(), packet);
+
+        // the same packet is rejected a second time
+        read.rd.reserve(write.wr.len());
+        read.rd.put_slice(&write.wr.as_ref());
+
+        match read.parse_packet() {
+            Err(Error::Replay) => {},
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn negotiate_version() {
+        let ours = Capabilities::ours();
+        let newer = Capabilities { min_version: MIN_VERSION, max_version: VERSION + 2, features: 0b11 };
+        let older = Capabilities { min_version: MIN_VERSION - 2, max_version: MIN_VERSION - 1, features: 0 };
+
+        assert_eq!(ours.negotiate(&newer), Some((VERSION, FEATURES & 0b11)));
+        assert_eq!(newer.negotiate(&ours), ours.negotiate(&newer));
+        assert_eq!(ours.negotiate(&older), None);
+
+        // a packet of another version is only accepted before the handshake
+        let mut buf = Cursor::new(Vec::new());
+        let (mut read, mut write) = codec(&mut buf, [0u8; 32]);
+        write.set_protocol(VERSION + 1, 0);
+
+        for handshake in &[true, false] {
+            write.buffer(Packet::GetPeers(None));
+            let data = write.wr.split_off(0);
+            read.rd.reserve(data.len());
+            read.rd.put_slice(&data);
+
+            if !handshake {
+                read.set_version(VERSION);
+            }
+
+            match read.parse_packet() {
+                Ok(Packet::GetPeers(None)) => assert!(handshake),
+                Err(Error::WrongVersion) => assert!(!handshake),
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
     }
 
     // size of a random payload
@@ -540,12 +841,12 @@ mod tests {
         let mut tmp = vec![0u8; BUF_SIZE];
         rng.fill(&mut tmp).unwrap();
 
-        let packet = Packet::Push(Transition::new(Vec::new(), Vec::new(), tmp));
+        let packet = Packet::Push(Transition::new(&Keypair::from_seed(&[0; 32]), Vec::new(), tmp));
 
         let mut key = [0u8; 32];
         rng.fill(&mut key).unwrap();
 
-        let (_, mut write) = new(&mut buf, key);
+        let (_, mut write) = codec(&mut buf, key);
 
         b.iter(|| write.buffer(packet.clone()));
     }
@@ -558,12 +859,12 @@ mod tests {
         let mut tmp = vec![0u8; BUF_SIZE];
         rng.fill(&mut tmp).unwrap();
 
-        let packet = Packet::Push(Transition::new(Vec::new(), Vec::new(), tmp));
+        let packet = Packet::Push(Transition::new(&Keypair::from_seed(&[0; 32]), Vec::new(), tmp));
 
         let mut key = [0u8; 32];
         rng.fill(&mut key).unwrap();
 
-        let (mut read, mut write) = new(&mut buf, key);
+        let (mut read, mut write) = codec(&mut buf, key);
 
         write.buffer(packet.clone());
 
@@ -573,6 +874,8 @@ mod tests {
             read.rd.reserve(write.wr.len());
             read.rd.put_slice(&write.wr.as_ref());
 
+            // open the same packet again
+            read.counter = 0;
             read.parse_packet().unwrap();
         });
 
@@ -0,0 +1,267 @@
+//! Anti-entropy reconciliation of the transition graph between two peers
+//!
+//! Both peers compare their sets of transition keys with range-based set reconciliation. A range
+//! of keys is summarised by the number of keys and a hash over them. If the summary of a peer
+//! differs from our own, we split our part of the range at the median key and answer with the
+//! summaries of both halves. Once a range contains only a few keys, the keys are sent in full and
+//! both peers exchange the transitions missing on the other side. Every round either resolves a
+//! range or halves the keys of the splitting peer, so the reconciliation converges after a
+//! logarithmic number of rounds, regardless how long two peers were separated. A reconciliation
+//! starts when two peers connect and is repeated regularly, in case a pushed transition got lost.
+
+use std::collections::HashSet;
+
+use ring::digest;
+
+use protocol::Packet;
+use transition::{Inspector, Transition, TransitionKey};
+
+/// Ranges with at most this number of keys are sent in full
+pub const MAX_KEYS: usize = 16;
+/// Maximal number of transitions in a single packet
+pub const MAX_TRANSITIONS: usize = 256;
+/// Interval between two reconciliations with all connected peers in milliseconds
+pub const INTERVAL: u64 = 60_000;
+
+/// Summary of the keys in a range
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Summary {
+    /// Number of keys and the hash over all of them
+    Fingerprint(u64, [u8; 32]),
+    /// All keys
+    Keys(Vec<TransitionKey>)
+}
+
+/// A range of transition keys from `lower` (inclusive) to `upper` (exclusive), `None` is unbounded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Range {
+    pub lower: Option<TransitionKey>,
+    pub upper: Option<TransitionKey>,
+    pub summary: Summary
+}
+
+/// Hash over a sorted list of keys
+fn fingerprint(keys: &[TransitionKey]) -> Summary {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    for key in keys {
+        ctx.update(&key.0);
+    }
+
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(ctx.finish().as_ref());
+
+    Summary::Fingerprint(keys.len() as u64, hash)
+}
+
+/// Summarise the keys of a range, small ranges contain the keys itself
+fn summarise(lower: Option<TransitionKey>, upper: Option<TransitionKey>, keys: &[TransitionKey]) -> Range {
+    let summary = if keys.len() <= MAX_KEYS {
+        Summary::Keys(keys.to_vec())
+    } else {
+        fingerprint(keys)
+    };
+
+    Range { lower, upper, summary }
+}
+
+/// Start a reconciliation with a summary of all our keys
+pub fn start<T: Inspector>(inspector: &T) -> Packet {
+    Packet::Reconcile(vec![summarise(None, None, &inspector.keys(None, None))])
+}
+
+/// References of a transition which we don't know yet
+pub fn missing_refs<T: Inspector>(inspector: &T, transition: &Transition) -> Vec<TransitionKey> {
+    transition.refs.iter().filter(|x| !inspector.has(x)).cloned().collect()
+}
+
+/// Read the transitions we have and split them into packets
+fn transitions<T: Inspector>(inspector: &T, keys: Vec<TransitionKey>) -> Vec<Packet> {
+    let keys: Vec<TransitionKey> = keys.into_iter().filter(|x| inspector.has(x)).collect();
+
+    keys.chunks(MAX_TRANSITIONS)
+        .filter_map(|x| inspector.restore(x.to_vec()))
+        .map(|x| Packet::Transitions(x))
+        .collect()
+}
+
+/// Process a reconciliation packet of a peer
+///
+/// Returns the packets answering the peer and all transitions new to us.
+pub fn process<T: Inspector>(inspector: &T, packet: Packet) -> (Vec<Packet>, Vec<Transition>) {
+    match packet {
+        Packet::Reconcile(ranges) => {
+            let mut answer = Vec::new();
+            let mut missing = Vec::new();
+            let mut unknown = Vec::new();
+
+            for Range { lower, upper, summary } in ranges {
+                let ours = inspector.keys(lower.as_ref(), upper.as_ref());
+
+                match summary {
+                    Summary::Fingerprint(..) if summary == fingerprint(&ours) => {},
+                    Summary::Fingerprint(..) if ours.len() <= MAX_KEYS => {
+                        answer.push(Range { lower, upper, summary: Summary::Keys(ours) });
+                    },
+                    Summary::Fingerprint(..) => {
+                        // split our part of the range at the median key
+                        let (left, right) = ours.split_at(ours.len() / 2);
+                        let median = right[0].clone();
+
+                        answer.push(summarise(lower, Some(median.clone()), left));
+                        answer.push(summarise(Some(median), upper, right));
+                    },
+                    Summary::Keys(theirs) => {
+                        let their_set: HashSet<&TransitionKey> = theirs.iter().collect();
+                        let our_set: HashSet<&TransitionKey> = ours.iter().collect();
+
+                        // keys may be known without being listed, e.g. if they were pruned
+                        missing.extend(theirs.iter().filter(|x| !our_set.contains(x) && !inspector.has(x)).cloned());
+                        unknown.extend(ours.iter().filter(|x| !their_set.contains(x)).cloned());
+                    }
+                }
+            }
+
+            let mut packets = Vec::new();
+            if !answer.is_empty() {
+                packets.push(Packet::Reconcile(answer));
+            }
+            if !missing.is_empty() {
+                packets.push(Packet::GetTransitions(missing));
+            }
+            packets.extend(transitions(inspector, unknown));
+
+            (packets, Vec::new())
+        },
+        Packet::GetTransitions(keys) => (transitions(inspector, keys), Vec::new()),
+        Packet::Transitions(transitions) => {
+            let mut new = Vec::new();
+            let mut missing = Vec::new();
+
+            for transition in transitions {
+                if inspector.has(&transition.key) {
+                    continue;
+                }
+
+                if !inspector.approve(&transition) {
+                    warn!("Received wrong transition {}", transition.key.to_string());
+                    continue;
+                }
+
+                inspector.store(transition.clone());
+                new.push(transition);
+            }
+
+            // ask for references which are still unknown, otherwise the transitions stay pending
+            for transition in &new {
+                missing.extend(missing_refs(inspector, transition));
+            }
+            missing.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            missing.dedup();
+
+            if missing.is_empty() {
+                (Vec::new(), new)
+            } else {
+                (vec![Packet::GetTransitions(missing)], new)
+            }
+        },
+        _ => (Vec::new(), Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use harness::Graph;
+    use protocol::Packet;
+    use transition::{Inspector, Keypair};
+    use super::{process, start};
+
+    /// Deliver packets between connected peers until no packet is left, returns the number of
+    /// delivered packets
+    fn run(peers: &[Graph], links: &[(usize, usize)]) -> usize {
+        let mut queue: VecDeque<(usize, usize, Packet)> = VecDeque::new();
+        for &(a, b) in links {
+            queue.push_back((a, b, start(&peers[a])));
+        }
+
+        let mut delivered = 0;
+        while let Some((from, to, packet)) = queue.pop_front() {
+            delivered += 1;
+            assert!(delivered < 10000, "reconciliation doesn't terminate");
+
+            let (answers, _) = process(&peers[to], packet);
+            for answer in answers {
+                queue.push_back((to, from, answer));
+            }
+        }
+
+        delivered
+    }
+
+    #[test]
+    fn partitioned_peers_converge() {
+        let keypairs: Vec<Keypair> = (0..4u8).map(|i| Keypair::from_seed(&[i; 32])).collect();
+        let peers: Vec<Graph> = (0..4).map(|_| Graph::new()).collect();
+
+        // a common history known to everyone
+        for i in 0..50 {
+            peers[0].commit(&keypairs[0], i);
+        }
+        run(&peers, &[(0, 1), (0, 2), (0, 3)]);
+        assert!(peers.iter().all(|x| x.keys(None, None).len() == 50));
+
+        // the network is split into {0, 1} and {2, 3} for a long time, both sides diverge
+        for i in 0..300 {
+            let peer = (i % 4) as usize;
+            peers[peer].commit(&keypairs[peer], 1000 + i);
+
+            if i % 50 == 0 {
+                run(&peers, &[(0, 1), (2, 3)]);
+            }
+        }
+        run(&peers, &[(0, 1), (2, 3)]);
+        assert_eq!(peers[0].keys(None, None), peers[1].keys(None, None));
+        assert_eq!(peers[2].keys(None, None), peers[3].keys(None, None));
+        assert!(peers[0].keys(None, None) != peers[2].keys(None, None));
+
+        // after healing a single link between the partitions suffices
+        run(&peers, &[(1, 2)]);
+        run(&peers, &[(0, 1), (2, 3)]);
+
+        let all = peers[0].keys(None, None);
+        assert_eq!(all.len(), 350);
+        for peer in &peers {
+            assert_eq!(peer.keys(None, None), all);
+            assert_eq!(peer.pending(), 0);
+        }
+
+        // nothing is exchanged between converged peers
+        assert_eq!(run(&peers, &[(0, 3)]), 1);
+    }
+
+    #[test]
+    fn request_missing_refs() {
+        let keypair = Keypair::from_seed(&[0; 32]);
+        let (a, b) = (Graph::new(), Graph::new());
+        for i in 0..3 {
+            a.commit(&keypair, i);
+        }
+
+        // only the newest transition is pushed, its history is requested
+        let tip = a.restore(a.tips()).unwrap();
+        let (answers, new) = process(&b, Packet::Transitions(tip));
+        assert_eq!(new.len(), 1);
+        assert_eq!(b.pending(), 1);
+
+        // answers of `b` are sent to `a` and the other way around
+        let mut queue: VecDeque<(bool, Packet)> = answers.into_iter().map(|x| (false, x)).collect();
+        while let Some((to_b, packet)) = queue.pop_front() {
+            let (answers, _) = process(if to_b { &b } else { &a }, packet);
+            queue.extend(answers.into_iter().map(|x| (!to_b, x)));
+        }
+
+        assert_eq!(b.keys(None, None), a.keys(None, None));
+        assert_eq!(b.pending(), 0);
+    }
+}
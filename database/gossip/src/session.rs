@@ -0,0 +1,229 @@
+//! Key exchange and session keys of a connection
+//!
+//! The long-lived network key only protects the handshake. Both peers send an ephemeral X25519
+//! key in a `Hello` message, signed with their identity key and sealed with the network key. The
+//! shared secret of both ephemeral keys is expanded with HKDF to a separate key for each
+//! direction. Afterwards every packet is sealed with its number in the connection as nonce. A
+//! frame replayed from another connection can't be opened with the session keys, a frame replayed
+//! within the same connection has the wrong number.
+//!
+//! ## Rotation of the network key
+//! A peer can accept several network keys. The connecting peer seals its greeting with all of
+//! them and the accepting peer answers with the first one it knows. Two peers sharing any key can
+//! therefore connect. A new key is rolled out by adding it to all devices, one after another, and
+//! removing the old one once every device has the new key.
+
+use ring::{aead, agreement, digest, hkdf, hmac, rand, rand::SecureRandom};
+use untrusted;
+use bincode::{serialize, deserialize};
+
+use PeerId;
+use error::{Error, Result};
+use protocol::NetworkKey;
+use transition::{Keypair, Signature};
+
+/// Separates our signatures and derived keys from other uses of the same keys
+const CONTEXT: &[u8] = b"hex-gossip session";
+
+/// Identity and network keys securing the connections of a peer
+#[derive(Clone)]
+pub struct Keyring {
+    pub identity: Keypair,
+    /// Accepted network keys, starting with our current key
+    pub network: Vec<NetworkKey>
+}
+
+/// Ephemeral key of a peer, signed with its identity key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Greeting {
+    pub id: PeerId,
+    pub ephemeral: Vec<u8>,
+    pub signature: Signature
+}
+
+/// First message of a connection, contains the greeting sealed with one or more network keys
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hello(Vec<Vec<u8>>);
+
+/// Our side of the key exchange
+pub struct Handshake {
+    keyring: Keyring,
+    private: agreement::EphemeralPrivateKey,
+    greeting: Greeting
+}
+
+impl Handshake {
+    /// Create a new ephemeral key and sign it
+    pub fn new(keyring: Keyring) -> Handshake {
+        let rng = rand::SystemRandom::new();
+        let private = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng).unwrap();
+
+        let mut ephemeral = vec![0u8; private.public_key_len()];
+        private.compute_public_key(&mut ephemeral).unwrap();
+
+        let signature = keyring.identity.sign(&[CONTEXT, &ephemeral[..]].concat());
+        let greeting = Greeting { id: keyring.identity.id(), ephemeral, signature };
+
+        Handshake { keyring, private, greeting }
+    }
+
+    /// Seal our greeting with a certain network key, or with all of them if `None`
+    pub fn hello(&self, key: Option<&NetworkKey>) -> Hello {
+        let keys = match key {
+            Some(key) => vec![*key],
+            None => self.keyring.network.clone()
+        };
+
+        let rng = rand::SystemRandom::new();
+        let greeting = serialize(&self.greeting).unwrap();
+
+        Hello(keys.iter().map(|key| seal(&rng, key, &greeting)).collect())
+    }
+
+    /// Open the greeting of the other peer and check its signature
+    ///
+    /// Returns the greeting together with the network key it was sealed with. Fails with
+    /// `Error::Cryptography` if we have no common network key.
+    pub fn open(&self, hello: &Hello) -> Result<(NetworkKey, Greeting)> {
+        for sealed in &hello.0 {
+            for key in &self.keyring.network {
+                let greeting = match open(key, sealed) {
+                    Some(greeting) => greeting,
+                    None => continue
+                };
+
+                let greeting: Greeting = deserialize(&greeting).map_err(|_| Error::Deserialize)?;
+
+                // a reflected greeting of ourself would result in the same key for both directions
+                let signed = [CONTEXT, &greeting.ephemeral[..]].concat();
+                if !greeting.signature.verify(&greeting.id, &signed) || greeting.ephemeral == self.greeting.ephemeral {
+                    return Err(Error::Handshake);
+                }
+
+                return Ok((*key, greeting));
+            }
+        }
+
+        Err(Error::Cryptography)
+    }
+
+    /// Derive the keys opening and sealing the packets of this connection
+    pub fn finish(self, key: &NetworkKey, peer: &Greeting) -> Result<(aead::OpeningKey, aead::SealingKey)> {
+        let salt = hmac::SigningKey::new(&digest::SHA256, key);
+        let ours = self.greeting.ephemeral;
+
+        agreement::agree_ephemeral(self.private, &agreement::X25519, untrusted::Input::from(&peer.ephemeral), Error::Handshake, |secret: &[u8]| {
+            let (mut opening, mut sealing) = ([0u8; 32], [0u8; 32]);
+
+            // each direction has a key of its own
+            hkdf::extract_and_expand(&salt, secret, &[CONTEXT, &peer.ephemeral[..], &ours[..]].concat(), &mut opening);
+            hkdf::extract_and_expand(&salt, secret, &[CONTEXT, &ours[..], &peer.ephemeral[..]].concat(), &mut sealing);
+
+            Ok((
+                aead::OpeningKey::new(&aead::AES_256_GCM, &opening).unwrap(),
+                aead::SealingKey::new(&aead::AES_256_GCM, &sealing).unwrap()
+            ))
+        })
+    }
+}
+
+/// Seal a message with a network key and a random nonce, which is put in front of it
+fn seal(rng: &rand::SystemRandom, key: &NetworkKey, msg: &[u8]) -> Vec<u8> {
+    let key = aead::SealingKey::new(&aead::AES_256_GCM, key).unwrap();
+    let tag_len = key.algorithm().tag_len();
+
+    let mut nonce = [0u8; 12];
+    rng.fill(&mut nonce).unwrap();
+
+    let mut buf = msg.to_vec();
+    buf.extend(vec![0u8; tag_len]);
+    let len = aead::seal_in_place(&key, &nonce, &[], &mut buf, tag_len).unwrap();
+    buf.truncate(len);
+
+    [&nonce[..], &buf[..]].concat()
+}
+
+/// Open a message sealed by `seal`, `None` if it was sealed with another key
+fn open(key: &NetworkKey, sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < 12 {
+        return None;
+    }
+
+    let key = aead::OpeningKey::new(&aead::AES_256_GCM, key).unwrap();
+    let mut buf = sealed[12..].to_vec();
+
+    aead::open_in_place(&key, &sealed[..12], &[], 0, &mut buf).ok().map(|x| x.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::aead;
+    use transition::Keypair;
+    use error::Error;
+    use super::{Handshake, Keyring};
+
+    fn keyring(seed: u8, network: Vec<[u8; 32]>) -> Keyring {
+        Keyring { identity: Keypair::from_seed(&[seed; 32]), network }
+    }
+
+    /// Run the key exchange and check that the keys of both directions match
+    fn exchange(initiator: Keyring, responder: Keyring) -> Result<(), Error> {
+        let (a, b) = (Handshake::new(initiator), Handshake::new(responder));
+
+        let (key, greeting_a) = b.open(&a.hello(None))?;
+        let (key_a, greeting_b) = a.open(&b.hello(Some(&key)))?;
+        assert_eq!(key, key_a);
+        assert_eq!(greeting_b.id, Keypair::from_seed(&[2; 32]).id());
+
+        let (open_a, seal_a) = a.finish(&key, &greeting_b)?;
+        let (open_b, seal_b) = b.finish(&key, &greeting_a)?;
+
+        for (seal, open) in vec![(seal_a, open_b), (seal_b, open_a)] {
+            let nonce = [0u8; 12];
+            let mut buf = b"hello".to_vec();
+            buf.extend(vec![0u8; 16]);
+
+            let len = aead::seal_in_place(&seal, &nonce, &[], &mut buf, 16).unwrap();
+            assert_eq!(aead::open_in_place(&open, &nonce, &[], 0, &mut buf[..len]).unwrap(), b"hello");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn session_keys() {
+        let (old, new) = ([1u8; 32], [2u8; 32]);
+
+        exchange(keyring(1, vec![old]), keyring(2, vec![old])).unwrap();
+
+        // during a rotation peers with a common key connect
+        exchange(keyring(1, vec![new, old]), keyring(2, vec![old])).unwrap();
+        exchange(keyring(1, vec![old]), keyring(2, vec![new, old])).unwrap();
+
+        match exchange(keyring(1, vec![new]), keyring(2, vec![old])) {
+            Err(Error::Cryptography) => {},
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn forged_greeting() {
+        let (a, b) = (Handshake::new(keyring(1, vec![[1; 32]])), Handshake::new(keyring(2, vec![[1; 32]])));
+
+        // a greeting can't claim a different identity
+        let mut forged = a.greeting.clone();
+        forged.id = Keypair::from_seed(&[3; 32]).id();
+        let hello = super::Hello(vec![super::seal(&::ring::rand::SystemRandom::new(), &[1; 32], &::bincode::serialize(&forged).unwrap())]);
+
+        match b.open(&hello) {
+            Err(Error::Handshake) => {},
+            x => panic!("Unexpected result {:?}", x)
+        }
+
+        // and our own greeting is not accepted back
+        match a.open(&a.hello(None)) {
+            Err(Error::Handshake) => {},
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+}
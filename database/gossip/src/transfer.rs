@@ -0,0 +1,498 @@
+//! Chunked file transfer between peers
+//!
+//! A download runs in two phases. First we ask a peer known to have the file, or everyone if
+//! there is none, for the `FileInfo` of it. This contains the size and a hash of every chunk. The
+//! first peer answering becomes the source and we request the chunks one after another from it.
+//! Every chunk is verified against its hash before the inspector appends it to the partial file.
+//! If the source disconnects or sends a corrupted chunk, we ask another holder and resume after
+//! the chunks it describes like the source the partial file was written from. As the partial file
+//! is kept by the inspector, a download even survives a restart. The complete file is checked by
+//! the inspector on a worker thread, the hashes of the source only protect single chunks.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::thread;
+
+use futures::{oneshot, Oneshot, Complete};
+use ring::digest;
+
+use {PeerId, SpreadTo};
+use protocol::Packet;
+use transition::Inspector;
+
+/// Size of a single chunk (64KiB)
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Identification of a file
+pub type FileId = Vec<u8>;
+
+/// Packets to send with their destination
+pub type Outgoing = Vec<(Packet, SpreadTo)>;
+
+/// Size and chunk hashes of a file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    /// Size of the file in bytes
+    pub size: u64,
+    /// SHA256 hash of every chunk
+    pub hashes: Vec<[u8; 32]>
+}
+
+impl FileInfo {
+    /// Read a file chunk by chunk and hash every one
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<FileInfo> {
+        let mut info = FileInfo { size: 0, hashes: Vec::new() };
+        let mut buf = vec![0u8; CHUNK_SIZE as usize];
+
+        loop {
+            let n = read_full(&mut reader, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+
+            info.size += n as u64;
+            info.hashes.push(hash(&buf[..n]));
+
+            if n < buf.len() {
+                break;
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Check that `data` is the complete chunk beginning at `offset`
+    pub fn verify(&self, offset: u64, data: &[u8]) -> bool {
+        if !offset.is_multiple_of(CHUNK_SIZE) || offset >= self.size {
+            return false;
+        }
+
+        let length = cmp::min(CHUNK_SIZE, self.size - offset);
+
+        match self.hashes.get((offset / CHUNK_SIZE) as usize) {
+            Some(expected) => data.len() as u64 == length && hash(data) == *expected,
+            None => false
+        }
+    }
+}
+
+/// Read until the buffer is full or the reader is exhausted, returns the number of bytes read
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..])? {
+            0 => break,
+            x => n += x
+        }
+    }
+
+    Ok(n)
+}
+
+fn hash(data: &[u8]) -> [u8; 32] {
+    let mut tmp = [0u8; 32];
+    tmp.copy_from_slice(digest::digest(&digest::SHA256, data).as_ref());
+
+    tmp
+}
+
+/// A running download
+struct Download {
+    /// Peer sending us the chunks together with the description it sent
+    source: Option<(PeerId, FileInfo)>,
+    /// Requests waiting for the complete file
+    waiting: Vec<Complete<()>>
+}
+
+/// All running downloads and the peers known to have a certain file
+pub struct Downloads {
+    running: HashMap<FileId, Download>,
+    holders: HashMap<FileId, Vec<PeerId>>
+}
+
+impl Default for Downloads {
+    fn default() -> Downloads {
+        Downloads::new()
+    }
+}
+
+impl Downloads {
+    pub fn new() -> Downloads {
+        Downloads {
+            running: HashMap::new(),
+            holders: HashMap::new()
+        }
+    }
+
+    /// Request a file, the returned future resolves once the inspector has the complete file
+    pub fn request(&mut self, id: FileId, connected: &[PeerId]) -> (Oneshot<()>, Outgoing) {
+        let (c, p) = oneshot();
+
+        if let Some(download) = self.running.get_mut(&id) {
+            download.waiting.push(c);
+
+            return (p, Vec::new());
+        }
+
+        self.running.insert(id.clone(), Download { source: None, waiting: vec![c] });
+
+        (p, self.ask(&id, connected))
+    }
+
+    /// Ask a connected holder of the file for its description, or everyone if there is none
+    fn ask(&self, id: &FileId, connected: &[PeerId]) -> Outgoing {
+        let holder = self.holders.get(id)
+            .and_then(|x| x.iter().find(|x| connected.contains(x)));
+
+        let dest = match holder {
+            Some(peer) => SpreadTo::Peer(peer.clone()),
+            None => SpreadTo::Everyone
+        };
+
+        vec![(Packet::FileInfo(id.clone(), None), dest)]
+    }
+
+    /// A new peer is connected, ask it for all files without a source
+    pub fn connected(&self, peer: &PeerId) -> Outgoing {
+        self.running.iter()
+            .filter(|(_, download)| download.source.is_none())
+            .map(|(id, _)| (Packet::FileInfo(id.clone(), None), SpreadTo::Peer(peer.clone())))
+            .collect()
+    }
+
+    /// A peer is disconnected, continue its downloads with another holder
+    pub fn disconnected(&mut self, peer: &PeerId, connected: &[PeerId]) -> Outgoing {
+        self.forget(peer);
+
+        let stalled: Vec<FileId> = self.running.iter_mut()
+            .filter(|(_, download)| download.source.as_ref().map(|x| x.0 == *peer).unwrap_or(false))
+            .map(|(id, download)| {
+                download.source = None;
+
+                id.clone()
+            })
+            .collect();
+
+        stalled.iter()
+            .flat_map(|id| self.ask(id, connected))
+            .collect()
+    }
+
+    /// Peers known to have a file, which are still connected
+    pub fn holders(&self, id: &[u8]) -> Vec<PeerId> {
+        self.holders.get(id).cloned().unwrap_or_default()
+    }
+
+    /// Forget that a peer has any file
+    fn forget(&mut self, peer: &PeerId) {
+        for holders in self.holders.values_mut() {
+            holders.retain(|x| x != peer);
+        }
+    }
+
+    /// A peer described a file, which means that it has the file
+    pub fn info<T: Inspector>(&mut self, inspector: &T, peer: PeerId, id: FileId, info: FileInfo) -> Outgoing {
+        {
+            let holders = self.holders.entry(id.clone()).or_default();
+            if !holders.contains(&peer) {
+                holders.push(peer.clone());
+            }
+        }
+
+        match self.running.get(&id) {
+            Some(download) if download.source.is_none() => {},
+            _ => return Vec::new()
+        }
+
+        // keep the complete chunks which the new source describes like the previous one, the
+        // last chunk is always requested again
+        let offset = match inspector.partial(&id) {
+            Some((previous, received)) => {
+                let complete = cmp::min(received / CHUNK_SIZE, info.hashes.len().saturating_sub(1) as u64);
+
+                previous.hashes.iter().zip(info.hashes.iter())
+                    .take(complete as usize)
+                    .take_while(|&(a, b)| a == b)
+                    .count() as u64 * CHUNK_SIZE
+            },
+            None => 0
+        };
+
+        if !inspector.begin_file(&id, &info) {
+            error!("Could not begin file {:?}, abort download", id);
+
+            self.running.remove(&id);
+
+            return Vec::new();
+        }
+
+        if let Some(download) = self.running.get_mut(&id) {
+            download.source = Some((peer, info));
+        }
+
+        self.next(inspector, id, offset)
+    }
+
+    /// A peer sent us a chunk of a file
+    pub fn chunk<T: Inspector>(&mut self, inspector: &T, peer: PeerId, id: FileId, offset: u64, data: Option<Vec<u8>>, connected: &[PeerId]) -> Outgoing {
+        let valid = match self.running.get(&id).and_then(|x| x.source.as_ref()) {
+            Some(&(ref source, ref info)) if *source == peer => {
+                data.as_ref().map(|x| info.verify(offset, x)).unwrap_or(false)
+            },
+            // we didn't ask this peer
+            _ => return Vec::new()
+        };
+
+        if !valid {
+            warn!("Peer {:?} sent an invalid chunk at {} of file {:?}", peer, offset, id);
+
+            // the peer has lost the file or sent garbage, try another holder
+            self.forget(&peer);
+            if let Some(download) = self.running.get_mut(&id) {
+                download.source = None;
+            }
+
+            let connected: Vec<PeerId> = connected.iter().filter(|x| **x != peer).cloned().collect();
+
+            return self.ask(&id, &connected);
+        }
+
+        let data = data.unwrap();
+        if !inspector.write_chunk(&id, offset, &data) {
+            error!("Could not write chunk at {} of file {:?}, abort download", offset, id);
+
+            // dropping the waiting requests cancels them
+            self.running.remove(&id);
+
+            return Vec::new();
+        }
+
+        self.next(inspector, id, offset + data.len() as u64)
+    }
+
+    /// Request the chunk at `offset`, or finish the download if there is none
+    fn next<T: Inspector>(&mut self, inspector: &T, id: FileId, offset: u64) -> Outgoing {
+        let (peer, size) = match self.running.get(&id).and_then(|x| x.source.as_ref()) {
+            Some(&(ref peer, ref info)) => (peer.clone(), info.size),
+            None => return Vec::new()
+        };
+
+        if offset < size {
+            return vec![(Packet::GetChunk(id, offset), SpreadTo::Peer(peer))];
+        }
+
+        if let Some(download) = self.running.remove(&id) {
+            let job = inspector.finish_file(&id);
+
+            // the waiting requests are completed once the inspector has checked the file
+            thread::spawn(move || {
+                if job() {
+                    for c in download.waiting {
+                        let _ = c.send(());
+                    }
+                } else {
+                    error!("Could not finish file {:?}", id);
+                }
+            });
+        }
+
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use futures::Future;
+
+    use {PeerId, SpreadTo};
+    use protocol::Packet;
+    use transition::{Inspector, Job, Transition, TransitionKey};
+    use super::{Downloads, FileInfo, FileId, Outgoing, CHUNK_SIZE};
+
+    /// Keeps the complete and partial files in memory, the jobs are done in advance
+    struct Files {
+        complete: RefCell<HashMap<FileId, Vec<u8>>>,
+        partial: RefCell<HashMap<FileId, (FileInfo, Vec<u8>)>>
+    }
+
+    impl Inspector for Files {
+        fn approve(&self, _: &Transition) -> bool { true }
+        fn store(&self, _: Transition) {}
+        fn restore(&self, _: Vec<TransitionKey>) -> Option<Vec<Transition>> { None }
+        fn tips(&self) -> Vec<TransitionKey> { Vec::new() }
+        fn has(&self, _: &TransitionKey) -> bool { false }
+        fn keys(&self, _: Option<&TransitionKey>, _: Option<&TransitionKey>) -> Vec<TransitionKey> { Vec::new() }
+
+        fn file_info(&self, id: &[u8]) -> Option<Job<Option<FileInfo>>> {
+            self.complete.borrow().get(id).map(|x| {
+                let info = FileInfo::from_reader(Cursor::new(x)).ok();
+
+                Box::new(move || info) as Job<Option<FileInfo>>
+            })
+        }
+
+        fn read_chunk(&self, id: &[u8], offset: u64) -> Option<Vec<u8>> {
+            self.complete.borrow().get(id)
+                .map(|x| x.iter().skip(offset as usize).take(CHUNK_SIZE as usize).cloned().collect())
+        }
+
+        fn partial(&self, id: &[u8]) -> Option<(FileInfo, u64)> {
+            self.partial.borrow().get(id).map(|x| (x.0.clone(), x.1.len() as u64))
+        }
+
+        fn begin_file(&self, id: &[u8], info: &FileInfo) -> bool {
+            let mut partial = self.partial.borrow_mut();
+            partial.entry(id.to_vec()).or_insert_with(|| (info.clone(), Vec::new())).0 = info.clone();
+
+            true
+        }
+
+        fn write_chunk(&self, id: &[u8], offset: u64, data: &[u8]) -> bool {
+            let mut partial = self.partial.borrow_mut();
+            let file = &mut partial.get_mut(id).unwrap().1;
+            file.truncate(offset as usize);
+            file.extend_from_slice(data);
+
+            true
+        }
+
+        fn finish_file(&self, id: &[u8]) -> Job<bool> {
+            let (_, file) = self.partial.borrow_mut().remove(id).unwrap();
+            self.complete.borrow_mut().insert(id.to_vec(), file);
+
+            Box::new(|| true)
+        }
+    }
+
+    /// Description of a complete file
+    fn describe(files: &Files) -> FileInfo {
+        files.file_info(&[1]).unwrap()().unwrap()
+    }
+
+    /// Number of bytes received of the partial file
+    fn received(files: &Files) -> u64 {
+        files.partial(&[1]).map(|x| x.1).unwrap_or(0)
+    }
+
+    fn files(content: Option<&Vec<u8>>) -> Files {
+        let mut complete = HashMap::new();
+        if let Some(content) = content {
+            complete.insert(vec![1], content.clone());
+        }
+
+        Files { complete: RefCell::new(complete), partial: RefCell::new(HashMap::new()) }
+    }
+
+    /// Answer a chunk request, like the gossip protocol would
+    fn answer(source: &Files, packets: Outgoing) -> Option<(PeerId, Packet)> {
+        match packets.into_iter().next() {
+            Some((Packet::GetChunk(id, offset), SpreadTo::Peer(peer))) => Some((peer, Packet::Chunk(id.clone(), offset, source.read_chunk(&id, offset)))),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn resume_with_other_holder() {
+        // a file of three and a half chunks on two peers
+        let content: Vec<u8> = (0..CHUNK_SIZE * 7 / 2).map(|x| (x % 251) as u8).collect();
+        let (a, b) = (vec![1u8], vec![2u8]);
+        let sources = vec![files(Some(&content)), files(Some(&content))];
+        let own = files(None);
+
+        let mut downloads = Downloads::new();
+        let connected = vec![a.clone(), b.clone()];
+
+        // nobody is known to have the file, so everyone is asked
+        let (done, packets) = downloads.request(vec![1], &connected);
+        match packets[0] {
+            (Packet::FileInfo(_, None), SpreadTo::Everyone) => {},
+            _ => panic!("unexpected request")
+        }
+
+        // the first peer answers and sends two chunks before it is disconnected
+        let info = describe(&sources[0]);
+        assert_eq!(info.hashes.len(), 4);
+        let mut packets = downloads.info(&own, a.clone(), vec![1], info.clone());
+        for _ in 0..2 {
+            match answer(&sources[0], packets) {
+                Some((peer, Packet::Chunk(id, offset, data))) => packets = downloads.chunk(&own, peer, id, offset, data, &connected),
+                _ => panic!("expected a chunk")
+            }
+        }
+        assert_eq!(received(&own), 2 * CHUNK_SIZE);
+
+        // the other peer describes the file, which makes it a known holder
+        assert!(downloads.info(&own, b.clone(), vec![1], info.clone()).is_empty());
+        let packets = downloads.disconnected(&a, &[b.clone()]);
+        match packets[0] {
+            (Packet::FileInfo(_, None), SpreadTo::Peer(ref peer)) => assert_eq!(*peer, b),
+            _ => panic!("expected a request to the holder")
+        }
+
+        // a corrupted chunk is refused, the download resumes after the received chunks
+        let packets = downloads.info(&own, b.clone(), vec![1], info.clone());
+        match packets[0] {
+            (Packet::GetChunk(_, offset), _) => assert_eq!(offset, 2 * CHUNK_SIZE),
+            _ => panic!("expected a chunk request")
+        }
+        let packets = downloads.chunk(&own, b.clone(), vec![1], 2 * CHUNK_SIZE, Some(vec![0; CHUNK_SIZE as usize]), &[b.clone()]);
+        assert_eq!(received(&own), 2 * CHUNK_SIZE);
+
+        // no other holder is known, so everyone is asked again and the download is finished
+        match packets[0] {
+            (Packet::FileInfo(_, None), SpreadTo::Everyone) => {},
+            _ => panic!("expected a request to everyone")
+        }
+        let mut packets = downloads.info(&own, b.clone(), vec![1], info);
+        while let Some((peer, Packet::Chunk(id, offset, data))) = answer(&sources[1], packets) {
+            packets = downloads.chunk(&own, peer, id, offset, data, &[b.clone()]);
+        }
+
+        assert_eq!(done.wait(), Ok(()));
+        assert_eq!(own.complete.borrow().get(&vec![1]), Some(&content));
+    }
+
+    #[test]
+    fn resume_with_changed_file() {
+        // the holders have different files with the same first chunk
+        let content: Vec<u8> = (0..CHUNK_SIZE * 3).map(|x| (x % 251) as u8).collect();
+        let mut changed = content.clone();
+        changed[CHUNK_SIZE as usize + 1] ^= 0xFF;
+
+        let (a, b) = (vec![1u8], vec![2u8]);
+        let (source_a, source_b) = (files(Some(&content)), files(Some(&changed)));
+        let own = files(None);
+
+        let mut downloads = Downloads::new();
+        let (done, _) = downloads.request(vec![1], &[a.clone(), b.clone()]);
+
+        // two chunks are received from the first peer before it is disconnected
+        let mut packets = downloads.info(&own, a.clone(), vec![1], describe(&source_a));
+        for _ in 0..2 {
+            match answer(&source_a, packets) {
+                Some((peer, Packet::Chunk(id, offset, data))) => packets = downloads.chunk(&own, peer, id, offset, data, &[a.clone(), b.clone()]),
+                _ => panic!("expected a chunk")
+            }
+        }
+        assert_eq!(received(&own), 2 * CHUNK_SIZE);
+        downloads.disconnected(&a, &[b.clone()]);
+
+        // the second chunk differs, so the download resumes after the first one
+        let mut packets = downloads.info(&own, b.clone(), vec![1], describe(&source_b));
+        match packets[0] {
+            (Packet::GetChunk(_, offset), _) => assert_eq!(offset, CHUNK_SIZE),
+            _ => panic!("expected a chunk request")
+        }
+        while let Some((peer, Packet::Chunk(id, offset, data))) = answer(&source_b, packets) {
+            packets = downloads.chunk(&own, peer, id, offset, data, &[b.clone()]);
+        }
+
+        assert_eq!(done.wait(), Ok(()));
+        assert_eq!(own.complete.borrow().get(&vec![1]), Some(&changed));
+    }
+}
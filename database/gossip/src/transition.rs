@@ -1,9 +1,11 @@
-use std::collections::HashSet;
-use std::collections::VecDeque;
-use std::iter::FromIterator;
-use ring::digest;
+use std::sync::Arc;
+use std::{fmt, hash, result};
+use ring::{digest, rand, signature, rand::SecureRandom};
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de::Error as DeError};
+use untrusted;
 
 use PeerId;
+use transfer::FileInfo;
 
 ///
 /// Inspector for incoming transitions. 
@@ -17,71 +19,33 @@ pub trait Inspector {
     fn restore(&self, keys: Vec<TransitionKey>) -> Option<Vec<Transition>>;
     fn tips(&self) -> Vec<TransitionKey>;
     fn has(&self, key: &TransitionKey) -> bool;
-    fn get_file(&self, key: &[u8]) -> Option<Vec<u8>>;
-
-    fn subgraph(&self, mut tips: Vec<Transition>) -> Vec<Transition> {
-        // create a sample of the subgraph, starting by the given tips
-        let mut in_transitions: HashSet<Transition> = HashSet::from_iter(tips.iter().cloned());
-
-        while in_transitions.len() < 64 && !tips.is_empty() {
-            tips = tips.into_iter()
-                .map(|x| {
-                    let refs = x.refs.into_iter().filter(|x| self.has(&x)).collect();
-
-                    self.restore(refs).unwrap()
-                }).flatten().collect();
-
-            for tip in &tips {
-                in_transitions.insert(tip.clone());
-            }
-        }
-        
-        trace!("Got {} transitions for checking", in_transitions.len());
-
-        // start at our tips and run till we reach the sampled transitions
-        let tips = self.restore(self.tips()).unwrap();
-        let mut queue = VecDeque::from_iter(tips.iter().cloned());
-        let mut transitions = Vec::new();
-        let mut inserted = HashSet::new();
-
-        while !queue.is_empty() {
-            let a = match queue.pop_front() {
-                Some(x) => {
-                    if inserted.contains(&x.key) {
-                        continue;
-                    }
-
-                    if !in_transitions.contains(&x) {
-                        trace!("Transition {}", x.key.to_string());
-                        transitions.push(x.clone());
-                        inserted.insert(x.key.clone());
-                    }
-
-                    x
-                },
-                None => break
-            };
-
-
-            let refs = a.refs.into_iter().filter(|x| self.has(&x)).collect();
-            for b in self.restore(refs).unwrap() {
-                if !in_transitions.contains(&b) {
-                    queue.push_back(b);
-                }
-            }
-        }
-
-        transitions.sort_unstable_by_key(|x| x.key.0);
-        transitions.dedup();
-
-        trace!("Returning {} transitions", transitions.len());
-
-        transitions
-    }
+    /// All keys in the range from `lower` (inclusive) to `upper` (exclusive) in ascending order,
+    /// `None` is unbounded
+    fn keys(&self, lower: Option<&TransitionKey>, upper: Option<&TransitionKey>) -> Vec<TransitionKey>;
+
+    /// Describe a complete file, `None` if we don't have it
+    ///
+    /// Describing reads the whole file, therefore the returned job runs on a worker thread.
+    fn file_info(&self, id: &[u8]) -> Option<Job<Option<FileInfo>>>;
+    /// Read the chunk of a complete file beginning at `offset`
+    fn read_chunk(&self, id: &[u8], offset: u64) -> Option<Vec<u8>>;
+    /// Description of the source a partial file was written from and the number of bytes
+    /// already received, `None` if there is no partial file
+    fn partial(&self, id: &[u8]) -> Option<(FileInfo, u64)>;
+    /// Continue a partial file with a new source, which is described by `info`
+    fn begin_file(&self, id: &[u8], info: &FileInfo) -> bool;
+    /// Write a verified chunk to a partial file, discarding everything after `offset`
+    fn write_chunk(&self, id: &[u8], offset: u64, data: &[u8]) -> bool;
+    /// The partial file is complete, the job checks it on a worker thread and returns whether it
+    /// can be used
+    fn finish_file(&self, id: &[u8]) -> Job<bool>;
 }
 
+/// Work of the inspector which takes too long for the thread driving the protocol
+pub type Job<T> = Box<dyn FnOnce() -> T + Send>;
+
 /// Transition key is the 256bit hash of the body
-#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TransitionKey(pub [u8; 32]);
 
 impl TransitionKey {
@@ -103,6 +67,105 @@ impl TransitionKey {
     }
 
 }
+
+/// Ed25519 keypair of a peer, the public key is used as `PeerId`
+#[derive(Clone)]
+pub struct Keypair(Arc<signature::Ed25519KeyPair>);
+
+impl Keypair {
+    /// Derive the keypair from a secret seed (256bits)
+    pub fn from_seed(seed: &[u8; 32]) -> Keypair {
+        let pair = signature::Ed25519KeyPair::from_seed_unchecked(untrusted::Input::from(seed))
+            .expect("Ed25519 seed has the wrong length!");
+
+        Keypair(Arc::new(pair))
+    }
+
+    /// Generate a new random seed, which can be stored to create the keypair with `from_seed`
+    pub fn generate_seed() -> [u8; 32] {
+        let mut seed = [0u8; 32];
+        rand::SystemRandom::new().fill(&mut seed).unwrap();
+
+        seed
+    }
+
+    /// The public key identifying this peer
+    pub fn id(&self) -> PeerId {
+        self.0.public_key_bytes().to_vec()
+    }
+
+    /// Sign a message with the private key
+    pub fn sign(&self, msg: &[u8]) -> Signature {
+        Signature::from_vec(self.0.sign(msg).as_ref())
+    }
+}
+
+/// Ed25519 signature (512bits) of a transition key
+#[derive(Clone, Copy)]
+pub struct Signature(pub [u8; 64]);
+
+impl Signature {
+    /// Create a signature from a buffer, which results in an invalid signature for a wrong length
+    pub fn from_vec(buf: &[u8]) -> Signature {
+        let mut sign = Signature([0; 64]);
+
+        if buf.len() == 64 {
+            sign.0.copy_from_slice(buf);
+        }
+
+        sign
+    }
+
+    /// Check whether this signature of `msg` was created by the owner of `pk`
+    pub fn verify(&self, pk: &[u8], msg: &[u8]) -> bool {
+        signature::verify(
+            &signature::ED25519,
+            untrusted::Input::from(pk),
+            untrusted::Input::from(msg),
+            untrusted::Input::from(&self.0)
+        ).is_ok()
+    }
+}
+
+// arrays with more than 32 elements don't implement the standard traits, so do it by hand
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Signature({:?})", &self.0[..])
+    }
+}
+
+impl PartialEq for Signature {
+    fn eq(&self, other: &Signature) -> bool {
+        self.0[..] == other.0[..]
+    }
+}
+
+impl Eq for Signature {}
+
+impl hash::Hash for Signature {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.0[..].hash(state);
+    }
+}
+
+impl Serialize for Signature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        self.0[..].serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Signature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Signature, D::Error> {
+        let buf = Vec::<u8>::deserialize(deserializer)?;
+
+        if buf.len() != 64 {
+            return Err(D::Error::invalid_length(buf.len(), &"a signature of 64 bytes"));
+        }
+
+        Ok(Signature::from_vec(&buf))
+    }
+}
+
 /// A signed transition in a DAG
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Transition {
@@ -110,28 +173,38 @@ pub struct Transition {
     pub pk: PeerId,
     pub refs: Vec<TransitionKey>,
     pub body: Option<Vec<u8>>,
-    pub sign: [u8; 32],
+    pub sign: Signature,
     pub state: u8
 }
 
 impl Transition {
-    /// Ignore signature for now
-    pub fn new(pk: PeerId, refs: Vec<TransitionKey>, data: Vec<u8>) -> Transition {
+    /// Create a new transition and sign its key with our keypair
+    pub fn new(keypair: &Keypair, refs: Vec<TransitionKey>, data: Vec<u8>) -> Transition {
 
         let mut tmp = Transition {
             key: TransitionKey([0u8; 32]),
-            pk,
+            pk: keypair.id(),
             refs,
             body: Some(data),
-            sign: [0; 32],
+            sign: Signature([0; 64]),
             state: 2
         };
 
         tmp.key = tmp.key();
+        tmp.sign = keypair.sign(&tmp.key.0);
 
         tmp
     }
 
+    /// Check that the key matches the content and is signed by the author `pk`
+    pub fn verify(&self) -> bool {
+        if self.body.is_none() || self.key != self.key() {
+            return false;
+        }
+
+        self.sign.verify(&self.pk, &self.key.0)
+    }
+
     pub fn key(&self) -> TransitionKey {
         let mut key = TransitionKey([0u8; 32]);
 
@@ -150,3 +223,32 @@ impl Transition {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Keypair, Transition, TransitionKey};
+
+    #[test]
+    fn sign_verify() {
+        let keypair = Keypair::from_seed(&[1; 32]);
+        let transition = Transition::new(&keypair, vec![TransitionKey([2; 32])], vec![1, 2, 3]);
+
+        assert_eq!(transition.pk, keypair.id());
+        assert!(transition.verify());
+
+        // a modified body doesn't match the key anymore
+        let mut tmp = transition.clone();
+        tmp.body = Some(vec![1, 2, 4]);
+        assert!(!tmp.verify());
+
+        // a forged author doesn't match the signature
+        let mut tmp = transition.clone();
+        tmp.pk = Keypair::from_seed(&[2; 32]).id();
+        assert!(!tmp.verify());
+
+        // neither does a signature of someone else
+        let mut tmp = transition.clone();
+        tmp.sign = Keypair::from_seed(&[2; 32]).sign(&tmp.key.0);
+        assert!(!tmp.verify());
+    }
+}
@@ -0,0 +1,68 @@
+//! Byte streams connecting two peers
+//!
+//! The gossip protocol runs over any transport providing bidirectional byte streams, the packets
+//! are encrypted and framed by the `PeerCodec` on top of it. In practice peers are connected over
+//! TCP and find each other with an UDP broadcast. The tests connect them over the simulated links
+//! of `memory::Network` instead.
+
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::{Future, Stream};
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, tcp::{ConnectFuture, Incoming}};
+
+use discover::Beacon;
+use protocol::NetworkKey;
+
+/// Establish connections between peers
+pub trait Transport: Clone + Send + 'static {
+    /// A connection to a single peer
+    type Stream: AsyncRead + AsyncWrite + Debug + Send + 'static;
+    /// Resolves once a connection is established
+    type Connect: Future<Item = Self::Stream, Error = io::Error> + Send + 'static;
+    /// Connections opened by other peers
+    type Incoming: Stream<Item = Self::Stream, Error = io::Error> + Send + 'static;
+
+    /// Listen for connections at `addr`, returns them together with the bound address
+    fn bind(&self, addr: &SocketAddr) -> io::Result<(Self::Incoming, SocketAddr)>;
+
+    /// Open a connection to a peer listening at `addr`
+    fn connect(&self, addr: &SocketAddr) -> Self::Connect;
+
+    /// Look for a contact in the network, if none is configured
+    fn discover(&self, _key: NetworkKey, _port: u16) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Current time in milliseconds, used for heartbeats and reconnection attempts
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+    }
+}
+
+/// Connect peers over TCP and discover them in the local network
+#[derive(Debug, Clone)]
+pub struct Tcp;
+
+impl Transport for Tcp {
+    type Stream = TcpStream;
+    type Connect = ConnectFuture;
+    type Incoming = Incoming;
+
+    fn bind(&self, addr: &SocketAddr) -> io::Result<(Incoming, SocketAddr)> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+
+        Ok((listener.incoming(), addr))
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> ConnectFuture {
+        TcpStream::connect(addr)
+    }
+
+    fn discover(&self, key: NetworkKey, port: u16) -> Option<SocketAddr> {
+        Beacon::new(1, key, port).wait(2)
+    }
+}
@@ -1,72 +1,109 @@
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::fs::{self, File};
 use std::time::{SystemTime, UNIX_EPOCH};
-use futures::{Sink, Stream, Future, IntoFuture, oneshot, Oneshot, Complete};
+use futures::{Sink, Stream, Future, IntoFuture, Oneshot};
 use futures::sync::mpsc::{channel, Sender, Receiver};
-use rusqlite::{self, Statement, OpenFlags};
+use rusqlite::{self, Statement, OpenFlags, types::ToSql};
 use tokio;
 use std::thread;
 
 use error::{Error, Result};
 use search::SearchQuery;
+use migration;
+use permission::{Permissions, Role};
+use replication::{Policy, Replication};
+use events::{Event, EventFilter};
 use objects::*;
 
-use hex_gossip::{Gossip, PeerId, GossipConf, Spread, Transition, Inspector, Discover, Packet, SpreadTo};
-use transition::{Storage, TransitionAction, transition_from_sql};
+#[cfg(feature="hex-music-container")]
+use hex_music_container::{self, Container};
+use hex_gossip::{Gossip, PeerId, GossipConf, Keypair, Spread, Transition, Discover, Packet, PeerStatus};
+use transition::{Storage, TransitionAction, Snapshot, transition_from_sql, sign_legacy_transitions};
+#[cfg(feature="hex-music-container")]
+use transition::content_hash;
 
-type Awaiting = Arc<Mutex<HashMap<Vec<u8>, Complete<Vec<u8>>>>>;
 /// Instance of the database
 pub struct Instance {
     gossip: Option<(Spread<Storage>, PeerId, Sender<TransitionAction>)>,
-    storage: Option<(Arc<Mutex<Storage>>, PeerId, Sender<TransitionAction>)>,
+    storage: Option<(Arc<Mutex<Storage>>, Keypair, Sender<TransitionAction>)>,
     path: PathBuf,
-    receiver: Option<Receiver<TransitionAction>>,
-    awaiting: Awaiting
+    receiver: Option<Receiver<TransitionAction>>
 }
 
 impl Instance {
-    pub fn from_file<T: AsRef<Path>>(path: T, conf: GossipConf) -> Instance {
+    /// Open the database at `path` and upgrade its schema to the current version
+    ///
+    /// The database is created if not existing. Fails if the schema can't be migrated, for example
+    /// because the database was created by a newer version.
+    pub fn from_file<T: AsRef<Path>>(path: T, conf: GossipConf) -> Result<Instance> {
+        Instance::with_permissions(path, conf, Permissions::new())
+    }
+
+    /// Open the database like `from_file`, but only approve transitions of trusted peers
+    ///
+    /// Our own peer is always trusted fully. Known peers are kept next to the database with the
+    /// extension `peers`, unless the configuration sets a peer book of its own.
+    pub fn with_permissions<T: AsRef<Path>>(path: T, mut conf: GossipConf, mut permissions: Permissions) -> Result<Instance> {
         let path = path.as_ref();
 
         // try to create the database, if not existing
         {
-            let socket = rusqlite::Connection::open(path).unwrap();
+            let mut socket = rusqlite::Connection::open(path).map_err(|e| Error::Sqlite(e))?;
     
-            // create the necessary tables (if not already existing)
-            socket.execute_batch(include_str!("create_db.sql")).unwrap();
+            // create or upgrade the necessary tables
+            migration::migrate(&mut socket)?;
+
+            if let Some(ref keypair) = conf.keypair {
+                let signed = sign_legacy_transitions(&mut socket, keypair)?;
+                if signed > 0 {
+                    info!("Signed {} transitions stored before transitions were signed", signed);
+                }
+            }
         }
 
-        let awaiting: Awaiting = Arc::new(Mutex::new(HashMap::new()));
-        if let Some(id) = conf.id.clone() {
+        if let Some(keypair) = conf.keypair.clone() {
+            let id = keypair.id();
+
+            if !permissions.is_empty() {
+                permissions = permissions.trust(id.clone(), Role::Full);
+            }
             let (sender, receiver) = channel(1024);
 
             if conf.addr.is_some() {
-                let storage = Storage::new(path);
+                if conf.book.is_none() {
+                    conf = conf.peer_book(path.with_extension("peers"));
+                }
+
+                let storage = Storage::new(path, permissions)?;
                 let gossip = Gossip::new(conf, storage);
                 let writer = gossip.writer();
                 let my_sender = sender.clone();
-                let tmp_awaiting = awaiting.clone();
                 let (network, addr) = (gossip.network(), gossip.addr());
 
                 let gossip = gossip
-                    .map_err(|e| eprintln!("Gossip err: {}", e))
+                    .map_err(|e| error!("Gossip err: {}", e))
                     .and_then(move |x| {
                         trace!("Got a new transition!");
 
                         match x {
                             Packet::Push(x) => {
-                                let action = TransitionAction::from_vec(&x.body.unwrap());
-
-                                let tmp = sender.clone();
-                                tmp.send(action)
-                                    .map_err(|_| ()).map(|_| ());
-                            },
-                            Packet::File(id, data) => {
-                                if let Some(shot) = tmp_awaiting.lock().unwrap().remove(&id) {
-                                    if let Err(err) = shot.send(data.unwrap()) {
-                                        eprintln!("Oneshot err = {:?}", err);
-                                    }
+                                let action = x.body.ok_or(Error::NotFound)
+                                    .and_then(|x| TransitionAction::from_vec(&x));
+
+                                match action {
+                                    // checkpoints only concern the storage and may be large
+                                    Ok(TransitionAction::Checkpoint(_)) => {},
+                                    Ok(action) => {
+                                        let tmp = sender.clone();
+                                        tmp.send(action)
+                                            .map_err(|_| ()).map(|_| ());
+                                    },
+                                    // applied by the storage once the library is updated
+                                    Err(Error::UnsupportedVersion(_)) => {},
+                                    Err(err) => warn!("Could not decode transition: {:?}", err)
                                 }
                             },
                             _ => {
@@ -79,7 +116,7 @@ impl Instance {
                     .for_each(|_| Ok(())).into_future();
 
                 let discover = Discover::new(1, network, addr.port())
-                    .map_err(|e| eprintln!("Discover err = {:?}", e))
+                    .map_err(|e| error!("Discover err = {:?}", e))
                     .for_each(|_| Ok(())).into_future();
 
                 let spread = writer.get();
@@ -88,13 +125,13 @@ impl Instance {
                     tokio::run(Future::join3(gossip, discover, spread).map(|_| ()));
                 });
 
-                Instance { gossip: Some((writer, id, my_sender)), storage: None, path: path.to_path_buf(), receiver: Some(receiver), awaiting }
+                Ok(Instance { gossip: Some((writer, id, my_sender)), storage: None, path: path.to_path_buf(), receiver: Some(receiver) })
             } else {
-                let storage = Storage::new(path);
-                Instance { gossip: None, storage: Some((Arc::new(Mutex::new(storage)), id, sender)), path: path.to_path_buf(), receiver: Some(receiver), awaiting }
+                let storage = Storage::new(path, permissions)?;
+                Ok(Instance { gossip: None, storage: Some((Arc::new(Mutex::new(storage)), keypair, sender)), path: path.to_path_buf(), receiver: Some(receiver) })
             }
         } else {
-            Instance { gossip: None, storage: None, path: path.to_path_buf(), receiver: None, awaiting }
+            Ok(Instance { gossip: None, storage: None, path: path.to_path_buf(), receiver: None })
         }
     }
 
@@ -102,71 +139,123 @@ impl Instance {
         self.receiver.take().unwrap()
     }
 
-    pub fn ask_for_file(&mut self, file_id: Vec<u8>) -> Oneshot<Vec<u8>> {
+    /// Download a file from a peer having it, resolves once the file is stored in the data section
+    ///
+    /// The file is transferred in verified chunks, an interrupted download resumes where it
+    /// stopped. Fails if the instance is not connected to the gossip network.
+    pub fn ask_for_file(&mut self, file_id: Vec<u8>) -> Result<Oneshot<()>> {
         match self.gossip {
-            Some((ref spread, _, _)) => {
-                let (c, p) = oneshot();
-
-                self.awaiting.lock().unwrap().insert(file_id.clone(), c);
+            Some((ref spread, _, _)) => Ok(spread.request_file(file_id)),
+            None => Err(Error::Offline)
+        }
+    }
 
-                spread.spread(Packet::File(file_id, None), SpreadTo::Everyone);
+    /// Replicate files from other peers in the background, according to `policy`
+    ///
+    /// The files are stored in the data section next to the database. Fails if the instance is
+    /// not connected to the gossip network.
+    pub fn replicate(&self, policy: Policy) -> Result<Replication> {
+        match self.gossip {
+            Some((ref spread, _, _)) => {
+                let data_path = self.path.parent().unwrap_or(Path::new("")).join("data");
 
-                return p;
+                Ok(Replication::start(self.view()?, spread.clone(), data_path, policy))
             },
-            _ => panic!("I'm not in p2p mode!")
+            None => Err(Error::Offline)
         }
     }
 
-    pub fn view(&self) -> View {
-        let socket = rusqlite::Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+    /// Open a read-only connection to the database, writes are forwarded to the storage
+    pub fn view(&self) -> Result<View> {
+        let socket = rusqlite::Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| Error::Sqlite(e))?;
 
         match (&self.gossip, &self.storage) {
             (Some((ref writer, ref peer_id, ref sender)), None) => {
-                View { 
+                Ok(View { 
                     socket, 
                     writer: Some(writer.clone()), 
                     peer_id: Some(peer_id.clone()), 
+                    keypair: None,
                     storage: None,
                     sender: Some(sender.clone())
-                }
+                })
             },
-            (None, Some((ref storage, ref peer_id, ref sender))) => {
-                View { socket, writer: None, peer_id: Some(peer_id.clone()), storage: Some(storage.clone()), sender: Some(sender.clone()) }
+            (None, Some((ref storage, ref keypair, ref sender))) => {
+                Ok(View { socket, writer: None, peer_id: Some(keypair.id()), keypair: Some(keypair.clone()), storage: Some(storage.clone()), sender: Some(sender.clone()) })
             },
             _ => {
-                View { socket, writer: None, peer_id: None, storage: None, sender: None }
+                Ok(View { socket, writer: None, peer_id: None, keypair: None, storage: None, sender: None })
             }
         }
     }
 }
 
+/// A prepared search statement together with the values bound to its placeholders
+pub struct SearchStatement<'a> {
+    stmt: Statement<'a>,
+    params: Vec<String>
+}
+
 /// Represents an open connection to a database
 pub struct View {
     socket: rusqlite::Connection,
     peer_id: Option<PeerId>,
+    keypair: Option<Keypair>,
     writer: Option<Spread<Storage>>,
     storage: Option<Arc<Mutex<Storage>>>,
     sender: Option<Sender<TransitionAction>>
 
 }
 
+/// Condition selecting all plays in a range of dates `?1..?2` from an optional origin `?3`
+static PLAYS: &str = "Events.Tag = 'playsong' AND Events.Date >= ?1 AND Events.Date < ?2 AND (?3 IS NULL OR Events.Origin = ?3)";
+
+/// Counter distinguishing playlist entries created at the same time
+static ENTRY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Maximal number of elements in a single part of a checkpoint, keeps each transition small
+/// enough to be pushed to other peers in one packet
+const CHECKPOINT_PART_SIZE: usize = 5000;
+
+/// Collect all rows of a query, fails if a single row can't be read
+fn collect_rows<T, I>(rows: I) -> Result<Vec<T>>
+    where I: Iterator<Item = rusqlite::Result<rusqlite::Result<T>>> {
+
+    rows.map(|x| x.and_then(|x| x))
+        .collect::<rusqlite::Result<Vec<T>>>()
+        .map_err(|e| Error::Sqlite(e))
+}
+
 impl View {
     pub fn commit(&self, transition: TransitionAction) -> Result<()> {
         trace!("Commit new transition {:?}", transition);
 
-        match (&self.writer, &self.storage, &self.peer_id, &self.sender) {
+        // checkpoints only concern the storage and may be large
+        let notify = match transition {
+            TransitionAction::Checkpoint(_) => false,
+            _ => true
+        };
+
+        match (&self.writer, &self.storage, &self.keypair, &self.sender) {
             (Some(ref writer), _, _, Some(ref sender)) => { 
-                sender.clone().send(transition.clone()).wait().unwrap();
+                if notify {
+                    sender.clone().send(transition.clone()).wait()
+                        .map_err(|_| Error::ChannelClosed)?;
+                }
                 writer.push(transition.to_vec()); 
                 Ok(()) 
             },
-            (None, Some(ref storage), Some(ref id), Some(ref sender)) => {
-                sender.clone().send(transition.clone()).wait().unwrap();
-                let tips = storage.lock().unwrap().tips();
-                let transition = Transition::new(id.clone(), tips, transition.to_vec());
-                storage.lock().unwrap().store(transition);
+            (None, Some(ref storage), Some(ref keypair), Some(ref sender)) => {
+                if notify {
+                    sender.clone().send(transition.clone()).wait()
+                        .map_err(|_| Error::ChannelClosed)?;
+                }
+                let storage = storage.lock().unwrap();
+                let tips = storage.get_tips()?;
+                let transition = Transition::new(keypair, tips, transition.to_vec());
 
-                Ok(())
+                storage.insert(transition)
             },
             _ => {
                 Err(Error::ReadOnly)
@@ -174,73 +263,100 @@ impl View {
         }
     }
 
-    pub fn id(&self) -> PeerId {
-        self.peer_id.clone().unwrap()
+    /// Return our own peer id, fails for a read-only view
+    pub fn id(&self) -> Result<PeerId> {
+        self.peer_id.clone().ok_or(Error::ReadOnly)
+    }
+
+    /// Status of all peers known to the gossip network, fails if we are not connected to it
+    pub fn peers(&self) -> Result<Vec<PeerStatus>> {
+        self.writer.as_ref().map(|x| x.peers()).ok_or(Error::Offline)
     }
 
     /// Prepare a search with a provided query and translate it to SQL. This method fails in case
     /// of an invalid query.
-    pub fn search_prep(&self, query: SearchQuery) -> Result<Statement> {
-        let query = query.to_sql_query();
+    pub fn search_prep(&self, query: SearchQuery) -> Result<SearchStatement> {
+        let (query, params) = query.to_sql_query();
 
-        self.socket.prepare(&query).map_err(|e| Error::Sqlite(e))
+        let stmt = self.socket.prepare(&query).map_err(|e| Error::Sqlite(e))?;
+
+        Ok(SearchStatement { stmt, params })
     }
 
     /// Execute the prepared search and return an iterator over all results
-    pub fn search<'a>(&self, stmt: &'a mut Statement) -> impl Iterator<Item = Track> + 'a {
-        stmt.query_map(&[], |row| Track::from_row(row)).unwrap().filter_map(|x| x.ok()).filter_map(|x| x.ok())
+    pub fn search<'a>(&self, search: &'a mut SearchStatement) -> Result<impl Iterator<Item = Result<Track>> + 'a> {
+        let SearchStatement { ref mut stmt, ref params } = *search;
+        let params: Vec<&ToSql> = params.iter().map(|x| x as &ToSql).collect();
+
+        let rows = stmt.query_map(&params, |row| Track::from_row(row))
+            .map_err(|e| Error::Sqlite(e))?;
+
+        Ok(rows.map(|x| x.and_then(|x| x).map_err(|e| Error::Sqlite(e))))
     }
 
     /// Search for a query and returns 50 tracks starting at `start`
     pub fn search_limited(&self, query: &str, start: usize) -> Result<Vec<Track>> {
-        let query = SearchQuery::new(query);
+        let query = SearchQuery::new(query)
+            .map_err(|e| Error::Search(e))?
+            .page(start, 50);
 
         let mut stmt = self.search_prep(query)?;
-        let res = self.search(&mut stmt).skip(start).take(50).collect();
+        let res = self.search(&mut stmt)?.collect();
 
-        Ok(res)
+        res
     }
 
     /// Get all available playlists and return their metadata
-    pub fn get_playlists(&self) -> Vec<Playlist> {
-        let mut stmt = self.socket.prepare("SELECT * FROM Playlists").unwrap();
+    pub fn get_playlists(&self) -> Result<Vec<Playlist>> {
+        let mut stmt = self.socket.prepare("SELECT * FROM Playlists")
+            .map_err(|e| Error::Sqlite(e))?;
 
-        let vec = stmt.query_map(&[], |row| Playlist::from_row(row)).unwrap().filter_map(|x| x.ok()).filter_map(|x| x.ok()).collect();
+        let rows = stmt.query_map(&[], |row| Playlist::from_row(row))
+            .map_err(|e| Error::Sqlite(e))?;
 
-        vec
+        collect_rows(rows)?.into_iter()
+            .map(|x| self.with_entries(x))
+            .collect()
     }
 
     /// Get all available tracks and return their metadata
-    pub fn get_tracks(&self) -> Vec<Track> {
-        let mut stmt = self.socket.prepare("SELECT * FROM Tracks").unwrap();
+    pub fn get_tracks(&self) -> Result<Vec<Track>> {
+        let mut stmt = self.socket.prepare("SELECT * FROM Tracks")
+            .map_err(|e| Error::Sqlite(e))?;
 
-        let vec = stmt.query_map(&[], |row| Track::from_row(row)).unwrap().filter_map(|x| x.ok()).filter_map(|x| x.ok()).collect();
+        let rows = stmt.query_map(&[], |row| Track::from_row(row))
+            .map_err(|e| Error::Sqlite(e))?;
 
-        vec
+        collect_rows(rows)
     }
 
-    pub fn get_num_tracks(&self) -> i64 {
-        let mut stmt = self.socket.prepare("SELECT COUNT(*) FROM Tracks").unwrap();
-        let count: i64 = stmt.query_map(&[], |row| row.get(0)).unwrap().next().unwrap().unwrap();
-
-        count
+    pub fn get_num_tracks(&self) -> Result<i64> {
+        self.socket.query_row("SELECT COUNT(*) FROM Tracks", &[], |row| row.get_checked(0))
+            .and_then(|x| x)
+            .map_err(|e| Error::Sqlite(e))
     }
 
     /// Get a track with key `key`
     pub fn get_track(&self, key: TrackKey) -> Result<Track> {
-        let mut stmt = self.socket.prepare("SELECT * FROM Tracks WHERE Key = ?").unwrap();
+        let mut stmt = self.socket.prepare("SELECT * FROM Tracks WHERE Key = ?")
+            .map_err(|e| Error::Sqlite(e))?;
 
-        let mut stream = stmt.query_map(&[&key.to_vec()], |row| Track::from_row(row)).unwrap()
-            .filter_map(|x| x.ok()).filter_map(|x| x.ok());
+        let mut query = stmt.query(&[&key.to_vec()])
+            .map_err(|e| Error::Sqlite(e))?;
 
-        stream.next().ok_or(Error::NotFound)
+        query.next()
+            .ok_or(Error::NotFound)
+            .and_then(|x| x.map_err(|e| Error::Sqlite(e)))
+            .and_then(|row| Track::from_row(&row).map_err(|e| Error::Sqlite(e)))
     }
 
     /// Get the metadata and tracks for a certain playlist
     pub fn get_token(&self, token: TokenId) -> Result<(Token, Option<(Playlist, Vec<Track>)>)> {
-        let mut stmt = self.socket.prepare("SELECT * FROM Tokens WHERE Token=?;").unwrap();
+        let mut stmt = self.socket.prepare("SELECT * FROM Tokens WHERE Token=?;")
+            .map_err(|e| Error::Sqlite(e))?;
             
-        let mut query = stmt.query(&[&token]).unwrap();
+        let mut query = stmt.query(&[&token])
+            .map_err(|e| Error::Sqlite(e))?;
 
         let token = query.next()
             .ok_or(Error::NotFound)
@@ -257,27 +373,83 @@ impl View {
     }
 
     /// Get a playlist with a certain key and return the metadata and tracks
+    ///
+    /// The tracks of a smart playlist are the current results of its search query.
     pub fn get_playlist(&self, key: PlaylistKey) -> Result<(Playlist, Vec<Track>)> {
-        let mut stmt = self.socket.prepare("SELECT * FROM Playlists WHERE Key=?;").unwrap();
+        let mut stmt = self.socket.prepare("SELECT * FROM Playlists WHERE Key=?;")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let mut query = stmt.query(&[&key])
+            .map_err(|e| Error::Sqlite(e))?;
 
-        let mut query = stmt.query(&[&key]).unwrap();
         let playlist = query.next()
             .ok_or(Error::NotFound)
             .and_then(|x| x.map_err(|e| Error::Sqlite(e)))
             .and_then(|row| Playlist::from_row(&row).map_err(|e| Error::Sqlite(e)))?;
 
-        let query = format!("SELECT * FROM Tracks WHERE hex(key) in ({});", playlist.tracks.iter().map(|key| format!("\"{}\"", key.to_string())).collect::<Vec<String>>().join(","));
-        let mut stmt = self.socket.prepare(&query).unwrap();
-        let res = self.search(&mut stmt).collect();
+        if let Some(query) = playlist.query.clone() {
+            let tracks = self.evaluate_playlist_query(query)?;
+
+            let mut playlist = playlist;
+            playlist.tracks = tracks.iter().map(|x| x.key).collect();
+
+            return Ok((playlist, tracks));
+        }
+
+        let playlist = self.with_entries(playlist)?;
+
+        let mut stmt = self.socket.prepare("SELECT Tracks.* FROM PlaylistEntries INNER JOIN Tracks ON Tracks.Key = PlaylistEntries.Track WHERE PlaylistEntries.Playlist = ? ORDER BY PlaylistEntries.Position, PlaylistEntries.Id")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[&playlist.key], |row| Track::from_row(row))
+            .map_err(|e| Error::Sqlite(e))?;
+
+        Ok((playlist, collect_rows(rows)?))
+    }
+
+    /// Get the entries of a playlist in their order
+    pub fn get_playlist_entries(&self, key: PlaylistKey) -> Result<Vec<PlaylistEntry>> {
+        let mut stmt = self.socket.prepare("SELECT Id, Position, Track FROM PlaylistEntries WHERE Playlist = ? ORDER BY Position, Id")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[&key], |row| PlaylistEntry::from_row(row))
+            .map_err(|e| Error::Sqlite(e))?;
+
+        collect_rows(rows)
+    }
+
+    /// Fill in the track keys of a playlist from its entries
+    fn with_entries(&self, mut playlist: Playlist) -> Result<Playlist> {
+        playlist.tracks = self.get_playlist_entries(playlist.key)?
+            .into_iter().map(|x| x.track).collect();
 
-        Ok((playlist, res))
+        Ok(playlist)
+    }
+
+    /// Search for all tracks of a smart playlist
+    fn evaluate_playlist_query(&self, query: PlaylistQuery) -> Result<Vec<Track>> {
+        let mut search = SearchQuery::new(&query.query)
+            .map_err(|e| Error::Search(e))?
+            .order(query.order);
+
+        if let Some(limit) = query.limit {
+            search = search.page(0, limit as usize);
+        }
+
+        let mut stmt = self.search_prep(search)?;
+        let tracks = self.search(&mut stmt)?.collect();
+
+        tracks
     }
 
     /// Get the last used token
     pub fn get_last_used_token(&self) -> Result<(Token, Option<(Playlist, Vec<Track>)>)> {
-        let mut stmt = self.socket.prepare("SELECT * FROM Tokens ORDER BY lastuse DESC Limit 1").unwrap();
+        let mut stmt = self.socket.prepare("SELECT * FROM Tokens ORDER BY lastuse DESC Limit 1")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let mut query = stmt.query(&[])
+            .map_err(|e| Error::Sqlite(e))?;
 
-        let mut query = stmt.query(&[]).unwrap();
         let token = query.next()
             .ok_or(Error::NotFound)
             .and_then(|x| x.map_err(|e| Error::Sqlite(e)))
@@ -295,39 +467,157 @@ impl View {
     /// Look all playlists up belonging to a certain track
     pub fn get_playlists_of_track(&self, key: TrackKey) -> Result<Vec<Playlist>> {
         let tmp = key.to_vec();
-        let mut stmt = self.socket.prepare("SELECT * FROM Playlists WHERE INSTR(Tracks, ?) > 0").unwrap();
+        let mut stmt = self.socket.prepare("SELECT * FROM Playlists WHERE Key IN (SELECT Playlist FROM PlaylistEntries WHERE Track = ?)")
+            .map_err(|e| Error::Sqlite(e))?;
 
-        let res = stmt.query_map(&[&tmp], |row| Playlist::from_row(row)).unwrap().filter_map(|x| x.ok()).filter_map(|x| x.ok()).collect();
+        let rows = stmt.query_map(&[&tmp], |row| Playlist::from_row(row))
+            .map_err(|e| Error::Sqlite(e))?;
 
-        Ok(res)
+        collect_rows(rows)?.into_iter()
+            .map(|x| self.with_entries(x))
+            .collect()
     }
 
     /// Return all database transitions
-    pub fn get_transitions(&self) -> Vec<Transition> {
-        let mut stmt = self.socket.prepare("SELECT * FROM Transitions;").unwrap();
+    pub fn get_transitions(&self) -> Result<Vec<Transition>> {
+        let mut stmt = self.socket.prepare("SELECT * FROM Transitions;")
+            .map_err(|e| Error::Sqlite(e))?;
 
+        let rows = stmt.query_map(&[], |x| transition_from_sql(x))
+            .map_err(|e| Error::Sqlite(e))?;
 
-        let rows = stmt.query_map(&[], |x| transition_from_sql(x)).unwrap().filter_map(|x| x.ok()).collect();
+        collect_rows(rows)
+    }
 
-        rows
+    pub fn get_num_transitions(&self, days: u32) -> Result<u64> {
+        Ok(0)
     }
 
-    pub fn get_num_transitions(&self, days: u32) -> u64 {
-        0
+    /// Count all stored transitions
+    pub fn count_transitions(&self) -> Result<u64> {
+        self.socket.query_row("SELECT COUNT(*) FROM Transitions", &[], |row| row.get_checked(0))
+            .and_then(|x| x)
+            .map(|x: i64| x as u64)
+            .map_err(|e| Error::Sqlite(e))
     }
 
-    pub fn last_playlist_key(&self) -> Result<PlaylistKey> {
-        let mut stmt = self.socket.prepare("SELECT Key FROM Playlists ORDER BY Key DESC LIMIT 1").unwrap();
-        let res: i64 = stmt.query_map(&[], |row| row.get(0)).unwrap().filter_map(|x| x.ok()).next().unwrap_or(0);
+    /// Read the whole state of the database together with the keys of all applied transitions
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        // read everything at the same cut, even if transitions are applied in the meantime
+        self.socket.execute_batch("BEGIN").map_err(|e| Error::Sqlite(e))?;
+        let snapshot = self.read_snapshot();
+        self.socket.execute_batch("END").map_err(|e| Error::Sqlite(e))?;
 
-        Ok(res)
+        snapshot
     }
 
-    pub fn last_token_id(&self) -> Result<TokenId> {
-        let mut stmt = self.socket.prepare("SELECT token FROM Tokens ORDER BY Key DESC LIMIT 1").unwrap();
-        let res: i64 = stmt.query_map(&[], |row| row.get(0)).unwrap().filter_map(|x| x.ok()).next().unwrap_or(0);
+    fn read_snapshot(&self) -> Result<Snapshot> {
+        let playlists = {
+            let mut stmt = self.socket.prepare("SELECT * FROM Playlists")
+                .map_err(|e| Error::Sqlite(e))?;
+
+            let rows = stmt.query_map(&[], |row| Playlist::from_row(row))
+                .map_err(|e| Error::Sqlite(e))?;
+
+            collect_rows(rows)?
+        };
+
+        let entries = {
+            let mut stmt = self.socket.prepare("SELECT Id, Position, Track, Playlist, Stamp FROM PlaylistEntries")
+                .map_err(|e| Error::Sqlite(e))?;
 
-        Ok(res)
+            let rows = stmt.query_map(&[], |row| -> rusqlite::Result<(PlaylistKey, PlaylistEntry, EntryId)> {
+                Ok((row.get_checked(3)?, PlaylistEntry::from_row(row)?, row.get_checked(4)?))
+            }).map_err(|e| Error::Sqlite(e))?;
+
+            collect_rows(rows)?
+        };
+
+        let tokens = {
+            let mut stmt = self.socket.prepare("SELECT * FROM Tokens")
+                .map_err(|e| Error::Sqlite(e))?;
+
+            let rows = stmt.query_map(&[], |row| Token::from_row(row))
+                .map_err(|e| Error::Sqlite(e))?;
+
+            collect_rows(rows)?
+        };
+
+        let albums = {
+            let mut stmt = self.socket.prepare("SELECT Key, Title, Artist FROM Albums")
+                .map_err(|e| Error::Sqlite(e))?;
+
+            let rows = stmt.query_map(&[], |row| Album::from_row(row))
+                .map_err(|e| Error::Sqlite(e))?;
+
+            collect_rows(rows)?
+        };
+
+        // pending transitions are not part of the state yet, transitions pruned by an earlier
+        // checkpoint are already known to all peers
+        let pruned = {
+            let mut stmt = self.socket.prepare("SELECT Key FROM Transitions WHERE State != 2")
+                .map_err(|e| Error::Sqlite(e))?;
+
+            let rows = stmt.query_map(&[], |row| -> rusqlite::Result<[u8; 32]> {
+                let key: Vec<u8> = row.get_checked(0)?;
+                let mut buf = [0u8; 32];
+                buf.copy_from_slice(&key);
+
+                Ok(buf)
+            }).map_err(|e| Error::Sqlite(e))?;
+
+            collect_rows(rows)?
+        };
+
+        Ok(Snapshot {
+            tracks: self.get_tracks()?,
+            playlists, entries, tokens, albums,
+            artists: self.get_artists()?,
+            pruned,
+            part: 0,
+            parts: 1
+        })
+    }
+
+    /// Commit a checkpoint with the current state, all transitions before it are pruned
+    ///
+    /// Peers joining later bootstrap from the checkpoint instead of replaying the whole history.
+    /// Only peers with full permissions can create a checkpoint.
+    pub fn create_checkpoint(&self) -> Result<()> {
+        self.commit_checkpoint(CHECKPOINT_PART_SIZE)
+    }
+
+    /// Commit the snapshot in parts of at most `size` elements, each on top of the previous one
+    fn commit_checkpoint(&self, size: usize) -> Result<()> {
+        for part in self.snapshot()?.split(size) {
+            self.commit(TransitionAction::Checkpoint(part))?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a checkpoint once more than `threshold` transitions are stored
+    ///
+    /// Returns whether a checkpoint was created.
+    pub fn checkpoint_if_needed(&self, threshold: u64) -> Result<bool> {
+        if self.count_transitions()? <= threshold {
+            return Ok(false);
+        }
+
+        self.create_checkpoint().map(|_| true)
+    }
+
+    pub fn last_playlist_key(&self) -> Result<PlaylistKey> {
+        self.socket.query_row("SELECT IFNULL(MAX(Key), 0) FROM Playlists", &[], |row| row.get_checked(0))
+            .and_then(|x| x)
+            .map_err(|e| Error::Sqlite(e))
+    }
+
+    pub fn last_token_id(&self) -> Result<TokenId> {
+        self.socket.query_row("SELECT IFNULL(MAX(Token), 0) FROM Tokens", &[], |row| row.get_checked(0))
+            .and_then(|x| x)
+            .map_err(|e| Error::Sqlite(e))
     }
 
     /// Create a empty playlist with a `title` and `origin`
@@ -335,36 +625,30 @@ impl View {
     /// The `origin` field is only used when the playlist originates from a different server and
     /// should therefore be updated after a new version appears.
     pub fn add_playlist(&self, mut playlist: Playlist) -> Result<()> {
-        let has_playlist = self.get_playlist(playlist.key).is_ok();
-
-        if !has_playlist {
-            // get highest id
+        match self.get_playlist(playlist.key) {
+            Ok(_) => Err(Error::AlreadyExists),
+            Err(Error::NotFound) => {
+                playlist.origin = self.id()?;
 
-            if !self.peer_id.is_some() {
-                return Err(Error::ReadOnly);
-            }
-
-            playlist.origin = self.peer_id.clone().unwrap();
+                if let Some(ref query) = playlist.query {
+                    SearchQuery::new(&query.query).map_err(|e| Error::Search(e))?;
+                }
 
-            self.commit(TransitionAction::UpsertPlaylist(playlist))
-        } else {
-            Err(Error::AlreadyExists)
+                self.commit(TransitionAction::UpsertPlaylist(playlist))
+            },
+            Err(err) => Err(err)
         }
     }
 
     /// Deletes a playlist with key `key`
     pub fn delete_playlist(&self, key: PlaylistKey) -> Result<()> {
-        let has_playlist = self.get_playlist(key).is_ok();
+        self.get_playlist(key)?;
 
-        if has_playlist {
-            self.commit(TransitionAction::DeletePlaylist(key))
-        } else {
-            Err(Error::NotFound)
-        }
+        self.commit(TransitionAction::DeletePlaylist(key))
     }
 
     pub fn update_playlist(&self, key: PlaylistKey, title: Option<String>, desc: Option<String>) -> Result<()> {
-        let (mut playlist, _) = self.get_playlist(key).unwrap();
+        let (mut playlist, _) = self.get_playlist(key)?;
 
         if let Some(title) = title {
             playlist.title = title;
@@ -377,57 +661,187 @@ impl View {
         self.commit(TransitionAction::UpsertPlaylist(playlist))
     }
 
-    /// Add a track to a certain playlist
+    /// Turn a playlist into a smart playlist, or back into a normal one with `None`
     ///
-    /// It is important that `playlist` is the title of the playlist and not the key. This method
-    /// returns the updated playlist.
-    pub fn add_to_playlist(&self, key: TrackKey, playlist: PlaylistKey) -> Result<()> {
-        let (mut playlist, _) = self.get_playlist(playlist).unwrap();
+    /// Fails if the search query has a syntax error.
+    pub fn set_playlist_query(&self, key: PlaylistKey, query: Option<PlaylistQuery>) -> Result<()> {
+        self.get_playlist(key)?;
 
-        playlist.tracks.push(key);
+        if let Some(ref query) = query {
+            SearchQuery::new(&query.query).map_err(|e| Error::Search(e))?;
+        }
 
-        self.commit(TransitionAction::UpsertPlaylist(playlist))
+        self.commit(TransitionAction::SetPlaylistQuery(key, query))
     }
 
-    /// Remove a track to a certain playlist
+    /// Add a track to the end of a certain playlist
+    pub fn add_to_playlist(&self, key: TrackKey, playlist: PlaylistKey) -> Result<()> {
+        let len = self.get_playlist_entries(playlist)?.len();
+
+        self.insert_into_playlist(playlist, len, key)
+    }
+
+    /// Remove the first occurrence of a track from a certain playlist
     pub fn delete_from_playlist(&self, key: TrackKey, playlist: PlaylistKey) -> Result<()> {
-        let (mut playlist, _) = self.get_playlist(playlist).unwrap();
+        let index = self.get_playlist_entries(playlist)?.iter()
+            .position(|x| x.track == key)
+            .ok_or(Error::NotFound)?;
+
+        self.remove_from_playlist(playlist, index)
+    }
+
+    /// Insert a track at `index` into a playlist, the following tracks move back by one
+    pub fn insert_into_playlist(&self, playlist: PlaylistKey, index: usize, key: TrackKey) -> Result<()> {
+        self.get_playlist(playlist)?;
+
+        let entries = self.get_playlist_entries(playlist)?;
+        if index > entries.len() {
+            return Err(Error::NotFound);
+        }
 
-        let index = playlist.tracks.iter().position(|x| x.to_vec() == key.to_vec())
+        let id = self.new_entry_id()?;
+        let position = Position::between(
+            index.checked_sub(1).map(|x| &entries[x].position),
+            entries.get(index).map(|x| &x.position),
+            &id
+        );
+
+        self.commit(TransitionAction::InsertIntoPlaylist { playlist, id, position, track: key })
+    }
+
+    /// Remove the track at `index` from a playlist
+    pub fn remove_from_playlist(&self, playlist: PlaylistKey, index: usize) -> Result<()> {
+        let entry = self.get_playlist_entries(playlist)?
+            .into_iter().nth(index)
             .ok_or(Error::NotFound)?;
 
-        playlist.tracks.remove(index);
+        self.commit(TransitionAction::RemoveFromPlaylist { playlist, id: entry.id })
+    }
 
-        self.commit(TransitionAction::UpsertPlaylist(playlist))
+    /// Move the track at index `from` to index `to` of a playlist
+    pub fn move_in_playlist(&self, playlist: PlaylistKey, from: usize, to: usize) -> Result<()> {
+        let mut entries = self.get_playlist_entries(playlist)?;
+        if from >= entries.len() || to >= entries.len() {
+            return Err(Error::NotFound);
+        }
+
+        // find the new neighbours without the moved entry
+        let entry = entries.remove(from);
+        let stamp = self.new_entry_id()?;
+        let position = Position::between(
+            to.checked_sub(1).map(|x| &entries[x].position),
+            entries.get(to).map(|x| &x.position),
+            &stamp
+        );
+
+        self.commit(TransitionAction::MoveInPlaylist { playlist, id: entry.id, position, stamp })
+    }
+
+    /// Create a new id for a playlist entry, see `EntryId`
+    fn new_entry_id(&self) -> Result<EntryId> {
+        let peer_id = self.id()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let count = ENTRY_COUNTER.fetch_add(1, Ordering::SeqCst);
+
+        let mut id = format!("{:016X}{:08X}", now.as_secs() * 1_000_000_000 + now.subsec_nanos() as u64, count as u32);
+        for x in peer_id.iter() {
+            id.push_str(&format!("{:02X}", x));
+        }
+
+        Ok(id)
     }
 
     /// Insert a new track into the database
     pub fn add_track(&self, track: Track) -> Result<()> {
-        let has_track = self.get_track(track.key).is_ok();
-
-        if !has_track {
-            self.commit(TransitionAction::UpsertTrack(track))
-        } else {
-            Err(Error::AlreadyExists)
+        match self.get_track(track.key) {
+            Ok(_) => Err(Error::AlreadyExists),
+            Err(Error::NotFound) => self.commit(TransitionAction::UpsertTrack(track)),
+            Err(err) => Err(err)
         }
     }
 
     /// Delete a track with key `key`
     pub fn delete_track(&self, key: TrackKey) -> Result<()> {
-        let has_track = self.get_track(key).is_ok();
+        self.get_track(key)?;
 
-        if has_track {
-            self.commit(TransitionAction::DeleteTrack(key))
-        } else {
-            Err(Error::NotFound)
+        self.commit(TransitionAction::DeleteTrack(key))
+    }
+
+    /// Add the audio files of a data section, which are missing in the database
+    ///
+    /// The tracks are created from the metadata embedded in the files. Files without metadata or
+    /// with a fingerprint not matching their name are skipped. This rebuilds a lost database from
+    /// its data section. Returns the keys of all added tracks.
+    #[cfg(feature="hex-music-container")]
+    pub fn import_data(&self, data_path: &Path) -> Result<Vec<TrackKey>> {
+        let mut keys = Vec::new();
+
+        for entry in fs::read_dir(data_path).map_err(|e| Error::Io(e))? {
+            let path = entry.map_err(|e| Error::Io(e))?.path();
+
+            // skip partial and other files, which are not named after a track key
+            let key = match path.file_name().and_then(|x| x.to_str()) {
+                Some(name) if name.len() == 32 && name.chars().all(|x| x.is_digit(16)) => TrackKey::from_str(name),
+                _ => continue
+            };
+
+            match self.get_track(key) {
+                Ok(_) => continue,
+                Err(Error::NotFound) => {},
+                Err(err) => return Err(err)
+            }
+
+            let res = File::open(&path)
+                .map_err(|e| hex_music_container::error::Error::File(e))
+                .and_then(|file| Container::load(file))
+                .and_then(|mut container| {
+                    let (samples, loudness) = (container.samples(), container.loudness());
+
+                    container.metadata().map(|x| (samples, loudness, x))
+                });
+
+            let (samples, loudness, metadata) = match res {
+                Ok((samples, loudness, Some(metadata))) => (samples, loudness, metadata),
+                Ok((_, _, None)) => continue,
+                Err(err) => {
+                    warn!("Could not read metadata of {}: {:?}", key, err);
+                    continue;
+                }
+            };
+
+            // the track key is derived from the fingerprint
+            let mut track = match metadata.fingerprint {
+                Some(fingerprint) => Track::empty(fingerprint, samples as f64 / 48000.0),
+                None => continue
+            };
+
+            if track.key != key {
+                warn!("Fingerprint of {} belongs to track {}", key, track.key);
+                continue;
+            }
+
+            track.title = metadata.title;
+            track.album = metadata.album;
+            track.interpret = metadata.interpret;
+            track.people = metadata.people;
+            track.composer = metadata.composer;
+            track.loudness = loudness.map(|x| x.integrated as f64);
+            track.true_peak = loudness.map(|x| x.true_peak as f64);
+            track.content_hash = File::open(&path).map_err(|e| Error::Io(e))
+                .and_then(|file| content_hash(file)).ok();
+
+            self.add_track(track)?;
+            keys.push(key);
         }
+
+        Ok(keys)
     }
 
     /// Update the metadata of tracks
     ///
     /// In case none of the parameters is Option::Some, then no field is updated.
     pub fn update_track(&self, key: TrackKey, title: Option<&str>, album: Option<&str>, interpret: Option<&str>, people: Option<&str>, composer: Option<&str>) -> Result<TrackKey> {
-        let mut track = self.get_track(key).unwrap();
+        let mut track = self.get_track(key)?;
 
         if let Some(title) = title {
             track.title = Some(title.into());
@@ -453,9 +867,177 @@ impl View {
             .map(|_| key)
     }
 
+    /// Update the album, artist and position of a track
+    ///
+    /// In case none of the parameters is Option::Some, then no field is updated.
+    pub fn update_track_album(&self, key: TrackKey, album: Option<AlbumKey>, artist: Option<ArtistKey>, track_number: Option<u32>, disc_number: Option<u32>, year: Option<u32>) -> Result<TrackKey> {
+        let mut track = self.get_track(key)?;
+
+        if let Some(album) = album {
+            self.get_album(album)?;
+            track.album_key = Some(album);
+        }
+
+        if let Some(artist) = artist {
+            self.get_artist(artist)?;
+            track.artist_key = Some(artist);
+        }
+
+        if let Some(track_number) = track_number {
+            track.track_number = Some(track_number);
+        }
+
+        if let Some(disc_number) = disc_number {
+            track.disc_number = Some(disc_number);
+        }
+
+        if let Some(year) = year {
+            track.year = Some(year);
+        }
+
+        self.commit(TransitionAction::UpsertTrack(track))
+            .map(|_| key)
+    }
+
+    /// Get all artists ordered by name
+    pub fn get_artists(&self) -> Result<Vec<Artist>> {
+        let mut stmt = self.socket.prepare("SELECT Key, Name FROM Artists ORDER BY Name")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[], |row| Artist::from_row(row))
+            .map_err(|e| Error::Sqlite(e))?;
+
+        collect_rows(rows)
+    }
+
+    /// Get an artist with key `key`
+    pub fn get_artist(&self, key: ArtistKey) -> Result<Artist> {
+        let mut stmt = self.socket.prepare("SELECT Key, Name FROM Artists WHERE Key = ?")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let mut query = stmt.query(&[&key])
+            .map_err(|e| Error::Sqlite(e))?;
+
+        query.next()
+            .ok_or(Error::NotFound)
+            .and_then(|x| x.map_err(|e| Error::Sqlite(e)))
+            .and_then(|row| Artist::from_row(&row).map_err(|e| Error::Sqlite(e)))
+    }
+
+    /// Get an album with key `key`
+    pub fn get_album(&self, key: AlbumKey) -> Result<Album> {
+        let mut stmt = self.socket.prepare("SELECT Key, Title, Artist FROM Albums WHERE Key = ?")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let mut query = stmt.query(&[&key])
+            .map_err(|e| Error::Sqlite(e))?;
+
+        query.next()
+            .ok_or(Error::NotFound)
+            .and_then(|x| x.map_err(|e| Error::Sqlite(e)))
+            .and_then(|row| Album::from_row(&row).map_err(|e| Error::Sqlite(e)))
+    }
+
+    /// Get all albums of an artist ordered by title
+    pub fn get_albums_of_artist(&self, key: ArtistKey) -> Result<Vec<Album>> {
+        let mut stmt = self.socket.prepare("SELECT Key, Title, Artist FROM Albums WHERE Artist = ? ORDER BY Title")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[&key], |row| Album::from_row(row))
+            .map_err(|e| Error::Sqlite(e))?;
+
+        collect_rows(rows)
+    }
+
+    /// Get all tracks of an album ordered by disc and track number
+    ///
+    /// Tracks without a number come last.
+    pub fn get_tracks_of_album(&self, key: AlbumKey) -> Result<Vec<Track>> {
+        let mut stmt = self.socket.prepare(
+            "SELECT * FROM Tracks WHERE AlbumKey = ?
+                ORDER BY DiscNumber IS NULL, DiscNumber, TrackNumber IS NULL, TrackNumber, Title")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[&key], |row| Track::from_row(row))
+            .map_err(|e| Error::Sqlite(e))?;
+
+        collect_rows(rows)
+    }
+
+    /// Insert a new artist into the database and return its key
+    ///
+    /// The key is derived from the name, so every peer adding the same artist derives the same key.
+    pub fn add_artist(&self, name: String) -> Result<ArtistKey> {
+        let key = Artist::key_from_name(&name);
+
+        match self.get_artist(key) {
+            Ok(_) => Err(Error::AlreadyExists),
+            Err(Error::NotFound) => self.commit(TransitionAction::UpsertArtist(Artist { key, name })).map(|_| key),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Rename an artist
+    pub fn update_artist(&self, key: ArtistKey, name: String) -> Result<()> {
+        let mut artist = self.get_artist(key)?;
+        artist.name = name;
+
+        self.commit(TransitionAction::UpsertArtist(artist))
+    }
+
+    /// Delete an artist, the tracks and albums of the artist are kept
+    pub fn delete_artist(&self, key: ArtistKey) -> Result<()> {
+        self.get_artist(key)?;
+
+        self.commit(TransitionAction::DeleteArtist(key))
+    }
+
+    /// Insert a new album into the database and return its key
+    ///
+    /// The key is derived from the title and artist, so every peer adding the same album derives
+    /// the same key.
+    pub fn add_album(&self, title: String, artist: Option<ArtistKey>) -> Result<AlbumKey> {
+        if let Some(artist) = artist {
+            self.get_artist(artist)?;
+        }
+
+        let key = Album::key_from_title(&title, artist);
+
+        match self.get_album(key) {
+            Ok(_) => Err(Error::AlreadyExists),
+            Err(Error::NotFound) => self.commit(TransitionAction::UpsertAlbum(Album { key, title, artist })).map(|_| key),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Update the title and artist of an album
+    ///
+    /// In case none of the parameters is Option::Some, then no field is updated.
+    pub fn update_album(&self, key: AlbumKey, title: Option<String>, artist: Option<ArtistKey>) -> Result<()> {
+        let mut album = self.get_album(key)?;
+
+        if let Some(title) = title {
+            album.title = title;
+        }
+
+        if let Some(artist) = artist {
+            self.get_artist(artist)?;
+            album.artist = Some(artist);
+        }
+
+        self.commit(TransitionAction::UpsertAlbum(album))
+    }
+
+    /// Delete an album, the tracks of the album are kept
+    pub fn delete_album(&self, key: AlbumKey) -> Result<()> {
+        self.get_album(key)?;
+
+        self.commit(TransitionAction::DeleteAlbum(key))
+    }
+
     /// Increment the favourite count for a track
     pub fn vote_for_track(&self, key: TrackKey) -> Result<()> {
-        let mut track = self.get_track(key).unwrap();
+        let mut track = self.get_track(key)?;
 
         track.favs_count += 1;
 
@@ -463,7 +1045,7 @@ impl View {
     }
 
     pub fn use_token(&self, token: TokenId) -> Result<()> {
-        let (mut token, _) = self.get_token(token).unwrap();
+        let (mut token, _) = self.get_token(token)?;
 
         let start = SystemTime::now();
         let since_the_epoch = start.duration_since(UNIX_EPOCH)
@@ -486,7 +1068,7 @@ impl View {
     ///
     /// When no parameter is Option::Some no metadata will be updated.
     pub fn update_token(&self, token: TokenId, key: Option<PlaylistKey>, played: Option<Vec<TrackKey>>, pos: Option<f64>) -> Result<()> {
-        let (mut token, _) = self.get_token(token).unwrap();
+        let (mut token, _) = self.get_token(token)?;
 
         if let Some(key) = key {
             token.key = Some(key);
@@ -503,6 +1085,123 @@ impl View {
         self.commit(TransitionAction::UpsertToken(token))
     }
 
+    /// Log an event, for example a played track
+    pub fn add_event(&self, event: Event) -> Result<()> {
+        self.commit(TransitionAction::AddEvent(event))
+    }
+
+    /// Get all events in a range of dates (seconds since UNIX epoch) matching the filter
+    ///
+    /// The events are ordered by date, beginning with the oldest.
+    pub fn get_events(&self, range: Range<i64>, filter: EventFilter) -> Result<Vec<Event>> {
+        let EventFilter { origin, tag, data } = filter;
+
+        let mut stmt = self.socket.prepare(
+            "SELECT Date, Origin, Tag, Data FROM Events
+                WHERE Date >= ?1 AND Date < ?2
+                    AND (?3 IS NULL OR Origin = ?3)
+                    AND (?4 IS NULL OR Tag = ?4)
+                    AND (?5 IS NULL OR Data = ?5)
+                ORDER BY Date, rowid;")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[&range.start, &range.end, &origin, &tag, &data], |row| -> rusqlite::Result<Event> {
+            Event::from(row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?, row.get_checked(3)?)
+        }).map_err(|e| Error::Sqlite(e))?;
+
+        collect_rows(rows)
+    }
+
+    /// Get the most played tracks together with their number of plays
+    ///
+    /// Only plays in `range` (seconds since UNIX epoch) are counted. If `origin` is set, only
+    /// plays from this origin are considered.
+    pub fn get_top_tracks(&self, range: Range<i64>, origin: Option<&str>, limit: u32) -> Result<Vec<(Track, u32)>> {
+        let mut stmt = self.socket.prepare(&format!(
+            "SELECT Tracks.*, COUNT(*) AS Plays FROM Events JOIN Tracks ON Tracks.Key = Events.Track
+                WHERE {} GROUP BY Tracks.Key ORDER BY Plays DESC LIMIT ?4;", PLAYS))
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[&range.start, &range.end, &origin.map(|x| x.to_string()), &limit], |row| -> rusqlite::Result<(Track, u32)> {
+            Ok((Track::from_row(row)?, row.get_checked("Plays")?))
+        }).map_err(|e| Error::Sqlite(e))?;
+
+        collect_rows(rows)
+    }
+
+    /// Get all played tracks together with the date of their last play, the most recent first
+    pub fn get_recently_played(&self) -> Result<Vec<(TrackKey, i64)>> {
+        let mut stmt = self.socket.prepare(
+            "SELECT Tracks.Key, MAX(Events.Date) AS Played FROM Events JOIN Tracks ON Tracks.Key = Events.Track
+                WHERE Events.Tag = 'playsong' GROUP BY Tracks.Key ORDER BY Played DESC, MAX(Events.rowid) DESC;")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[], |row| -> rusqlite::Result<(TrackKey, i64)> {
+            Ok((TrackKey::from_vec(&row.get_checked::<_, Vec<u8>>(0)?), row.get_checked(1)?))
+        }).map_err(|e| Error::Sqlite(e))?;
+
+        collect_rows(rows)
+    }
+
+    /// Get the most played interprets together with their number of plays
+    pub fn get_top_interprets(&self, range: Range<i64>, origin: Option<&str>, limit: u32) -> Result<Vec<(String, u32)>> {
+        self.get_top_column("Interpret", range, origin, limit)
+    }
+
+    /// Get the most played albums together with their number of plays
+    pub fn get_top_albums(&self, range: Range<i64>, origin: Option<&str>, limit: u32) -> Result<Vec<(String, u32)>> {
+        self.get_top_column("Album", range, origin, limit)
+    }
+
+    fn get_top_column(&self, column: &str, range: Range<i64>, origin: Option<&str>, limit: u32) -> Result<Vec<(String, u32)>> {
+        let mut stmt = self.socket.prepare(&format!(
+            "SELECT Tracks.{0}, COUNT(*) AS Plays FROM Events JOIN Tracks ON Tracks.Key = Events.Track
+                WHERE {1} AND Tracks.{0} IS NOT NULL GROUP BY Tracks.{0} ORDER BY Plays DESC LIMIT ?4;", column, PLAYS))
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[&range.start, &range.end, &origin.map(|x| x.to_string()), &limit], |row| -> rusqlite::Result<(String, u32)> {
+            Ok((row.get_checked(0)?, row.get_checked(1)?))
+        }).map_err(|e| Error::Sqlite(e))?;
+
+        collect_rows(rows)
+    }
+
+    /// Get the listening time in seconds for each day (`%Y-%m-%d` in UTC) with at least one play
+    ///
+    /// Every play is counted with the full duration of the track.
+    pub fn get_listening_time(&self, range: Range<i64>, origin: Option<&str>) -> Result<Vec<(String, f64)>> {
+        let mut stmt = self.socket.prepare(&format!(
+            "SELECT date(Events.Date, 'unixepoch') AS Day, SUM(Tracks.Duration) FROM Events JOIN Tracks ON Tracks.Key = Events.Track
+                WHERE {} GROUP BY Day ORDER BY Day;", PLAYS))
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[&range.start, &range.end, &origin.map(|x| x.to_string())], |row| -> rusqlite::Result<(String, f64)> {
+            Ok((row.get_checked(0)?, row.get_checked(1)?))
+        }).map_err(|e| Error::Sqlite(e))?;
+
+        collect_rows(rows)
+    }
+
+    /// Count the plays for each weekday and hour (in UTC), the first weekday is Sunday
+    pub fn get_heatmap(&self, range: Range<i64>, origin: Option<&str>) -> Result<[[u32; 24]; 7]> {
+        let mut stmt = self.socket.prepare(&format!(
+            "SELECT CAST(strftime('%w', Events.Date, 'unixepoch') AS INTEGER) AS Weekday,
+                    CAST(strftime('%H', Events.Date, 'unixepoch') AS INTEGER) AS Hour, COUNT(*) FROM Events
+                WHERE {} GROUP BY Weekday, Hour;", PLAYS))
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[&range.start, &range.end, &origin.map(|x| x.to_string())], |row| -> rusqlite::Result<(u32, u32, u32)> {
+            Ok((row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?))
+        }).map_err(|e| Error::Sqlite(e))?;
+
+        let mut heatmap = [[0u32; 24]; 7];
+        for (weekday, hour, plays) in collect_rows(rows)? {
+            heatmap[weekday as usize % 7][hour as usize % 24] = plays;
+        }
+
+        Ok(heatmap)
+    }
+
     /// Summarise a day (used by `nightly-worker`)
     pub fn summarise_day(&self, day: String, transitions: u32, tracks: u32) -> Result<()> {
         self.socket.execute(
@@ -512,38 +1211,53 @@ impl View {
     }
 
     /// Get a summarise of all days since beginning of use
-    pub fn get_complete_summary(&self) -> Vec<(String, u32, u32)> {
+    pub fn get_complete_summary(&self) -> Result<Vec<(String, u32, u32)>> {
         let mut stmt = self.socket.prepare(
-            "SELECT Day, Transitions, Tracks FROM Summary;").unwrap();
+            "SELECT Day, Transitions, Tracks FROM Summary;")
+            .map_err(|e| Error::Sqlite(e))?;
 
-        let rows = stmt.query_map(&[], |x| {
-            (x.get(0), x.get(1), x.get(2))
-        }).unwrap().filter_map(|x| x.ok()).collect();
+        let rows = stmt.query_map(&[], |x| -> rusqlite::Result<(String, u32, u32)> {
+            Ok((x.get_checked(0)?, x.get_checked(1)?, x.get_checked(2)?))
+        }).map_err(|e| Error::Sqlite(e))?;
 
-        rows
+        collect_rows(rows)
     }
 
     /// Find the latest summarise day in the database
     pub fn get_latest_summary_day(&self) -> Result<String> {
         let mut stmt = self.socket.prepare(
-            "SELECT day FROM Summary order by Day desc limit 1;").unwrap();
+            "SELECT day FROM Summary order by Day desc limit 1;")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let mut query = stmt.query(&[])
+            .map_err(|e| Error::Sqlite(e))?;
 
-        let mut query = stmt.query(&[]).unwrap();
         let row = query.next().ok_or(Error::NotFound)?
             .map_err(|e| Error::Sqlite(e))?;
 
-        Ok(row.get(0))
+        row.get_checked(0).map_err(|e| Error::Sqlite(e))
     }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use std::fs;
+    use std::path::Path;
     use super::Instance;
-    use hex_gossip::{GossipConf, PeerId};
-    use objects::{Playlist, Track, Token};
+    #[cfg(feature="hex-music-container")]
+    use super::content_hash;
+    use hex_gossip::{GossipConf, Keypair, Inspector, Transition, TransitionKey};
+    use objects::{Playlist, PlaylistQuery, Track, TrackKey, Token, Album, Artist};
+    use search::Order;
     use search::SearchQuery;
-    use transition::TransitionAction;
+    use error::Error;
+    use events::{Action, EventFilter};
+    use transition::{TransitionAction, Snapshot};
+    #[cfg(feature="hex-music-container")]
+    use transition::store_metadata;
+    use permission::{Permissions, Role};
+    use replication::{self, Policy};
     use futures::{Stream, IntoFuture, Future, Async};
 
     fn gen_track() -> Track {
@@ -554,31 +1268,81 @@ mod tests {
         track
     }
 
+    fn keypair() -> Keypair {
+        Keypair::from_seed(&[0; 32])
+    }
+
     fn gossip() -> GossipConf {
-        GossipConf::new().id(PeerId(vec![0; 16]))
+        GossipConf::new().keypair(keypair())
     }
 
     #[test]
     pub fn test_search() {
-        let instance = Instance::from_file("/tmp/test.db", gossip());
-        let view = instance.view();
+        let instance = Instance::from_file("/tmp/test.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
 
         let track = gen_track();
 
         view.add_track(track.clone()).unwrap();
 
         // create a new search query
-        let query = SearchQuery::new("title:Blue");
+        let query = SearchQuery::new("title:Blue").unwrap();
 
         // initiate the search
         let mut stmt = view.search_prep(query).unwrap();
-        assert_eq!(track, view.search(&mut stmt).next().unwrap());
+        assert_eq!(track, view.search(&mut stmt).unwrap().next().unwrap().unwrap());
+    }
+
+    #[test]
+    pub fn test_search_quotes() {
+        let instance = Instance::from_file("/tmp/test6.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+
+        let mut track = gen_track();
+        track.title = Some("Don't Stop Me Now".into());
+
+        view.add_track(track.clone()).unwrap();
+
+        // a quote in the query must neither break the statement nor escape from the LIKE pattern
+        let mut stmt = view.search_prep(SearchQuery::new("title:Don't").unwrap()).unwrap();
+        assert_eq!(track, view.search(&mut stmt).unwrap().next().unwrap().unwrap());
+
+        let mut stmt = view.search_prep(SearchQuery::new("title:\"' OR 1=1 --\"").unwrap()).unwrap();
+        assert_eq!(view.search(&mut stmt).unwrap().count(), 0);
+    }
+
+    #[test]
+    pub fn test_search_index() {
+        let instance = Instance::from_file("/tmp/test7.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+
+        let mut track = gen_track();
+        track.interpret = Some("Björk".into());
+        track.title = Some("Hyperballad".into());
+        view.add_track(track.clone()).unwrap();
+
+        // diacritics are folded and the last word is matched as prefix
+        let mut stmt = view.search_prep(SearchQuery::new("bjork hyper").unwrap()).unwrap();
+        assert_eq!(view.search(&mut stmt).unwrap().next().unwrap().unwrap().key, track.key);
+
+        // the index follows metadata updates and deletions
+        view.update_track(track.key, Some("Army of Me"), None, None, None, None).unwrap();
+        let mut stmt = view.search_prep(SearchQuery::new("title:hyperballad").unwrap()).unwrap();
+        assert_eq!(view.search(&mut stmt).unwrap().count(), 0);
+
+        // the entry is replaced in its row, so there is a single row per track
+        let rows: i64 = view.socket.query_row("SELECT COUNT(*) FROM TracksIndex", &[], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 1);
+
+        view.delete_track(track.key).unwrap();
+        let mut stmt = view.search_prep(SearchQuery::new("bjork").unwrap()).unwrap();
+        assert_eq!(view.search(&mut stmt).unwrap().count(), 0);
     }
 
     #[test]
     pub fn test_playlist() {
-        let mut instance = Instance::from_file("/tmp/test2.db", gossip());
-        let view = instance.view();
+        let mut instance = Instance::from_file("/tmp/test2.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
 
         let track = gen_track();
         let playlist = Playlist {
@@ -586,15 +1350,16 @@ mod tests {
             title: "My very own playlist".into(),
             desc: Some("".into()),
             tracks: vec![],
-            origin: PeerId(vec![0; 16])
+            origin: keypair().id(),
+            query: None
         };
 
         // check if there are no playlists in the database
-        assert_eq!(view.get_playlists().len(), 0);
+        assert_eq!(view.get_playlists().unwrap().len(), 0);
 
         // add a new playlist to the database
         view.add_playlist(playlist.clone()).unwrap();
-        assert_eq!(view.get_playlists(), vec![playlist.clone()]);
+        assert_eq!(view.get_playlists().unwrap(), vec![playlist.clone()]);
         assert_eq!(view.get_playlist(playlist.key).unwrap().0, playlist);
 
         // update the playlist, add a desc
@@ -613,10 +1378,435 @@ mod tests {
         assert_eq!(view.get_playlists_of_track(track.key).unwrap().len(), 0);
     }
 
+    #[test]
+    pub fn test_legacy_playlist() {
+        let _ = fs::remove_file("/tmp/test21.db");
+        let instance = Instance::from_file("/tmp/test21.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+
+        let (a, b) = (gen_track(), Track::empty(vec![2u32; 10], 100.0));
+        view.add_track(a.clone()).unwrap();
+        view.add_track(b.clone()).unwrap();
+
+        // the first release upserted playlists with all their tracks, its bodies are plain tuples
+        // of the variant index and fields
+        let legacy = |tracks: Vec<TrackKey>| ::bincode::serialize(&(1u32, 30i64, "Legacy".to_string(), None::<String>, tracks, keypair().id())).unwrap();
+
+        let storage = instance.storage.as_ref().unwrap().0.lock().unwrap();
+        let refs = view.get_transitions().unwrap().into_iter().map(|x| x.key).collect();
+        let first = Transition::new(&keypair(), refs, legacy(vec![a.key, b.key]));
+        storage.insert(first.clone()).unwrap();
+        assert_eq!(view.get_playlist(30).unwrap().0.tracks, vec![a.key, b.key]);
+
+        // an update replaces the entries, like the migration splits the last state
+        storage.insert(Transition::new(&keypair(), vec![first.key.clone()], legacy(vec![b.key, b.key, a.key]))).unwrap();
+        assert_eq!(view.get_playlist(30).unwrap().0.tracks, vec![b.key, b.key, a.key]);
+        assert_eq!(view.get_playlist_entries(30).unwrap()[2].id, format!("{:016X}{:08X}", 30, 2));
+        drop(storage);
+
+        // while the tracks of a current upsert are ignored for an existing playlist
+        let (mut playlist, _) = view.get_playlist(30).unwrap();
+        playlist.tracks = vec![a.key];
+        view.commit(TransitionAction::UpsertPlaylist(playlist)).unwrap();
+        assert_eq!(view.get_playlist(30).unwrap().0.tracks, vec![b.key, b.key, a.key]);
+    }
+
+    #[test]
+    pub fn test_sign_legacy_transitions() {
+        let _ = fs::remove_file("/tmp/test22.db");
+        let transition = Transition::new(&Keypair::from_seed(&[5; 32]), Vec::new(), TransitionAction::DeleteToken(1).to_vec());
+
+        {
+            Instance::from_file("/tmp/test22.db", GossipConf::new()).unwrap();
+
+            // transitions stored before they were signed carry the public id and a zeroed signature
+            let socket = ::rusqlite::Connection::open("/tmp/test22.db").unwrap();
+            socket.execute("INSERT INTO Transitions (Key, PublicKey, Signature, Refs, State, Data, Created) VALUES (?1, ?2, ?3, X'', 1, ?4, DATETIME('NOW'))",
+                &[&transition.key.0.as_ref(), &vec![7u8; 32], &vec![0u8; 32], &transition.body]).unwrap();
+        }
+
+        // opening the database with a keypair signs them, keeping their key
+        let instance = Instance::from_file("/tmp/test22.db", gossip()).unwrap();
+        let transitions = instance.view().unwrap().get_transitions().unwrap();
+
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].key, transition.key);
+        assert_eq!(transitions[0].pk, keypair().id());
+        assert!(transitions[0].verify());
+    }
+
+    /// Exchange all missing transitions in both directions, like the gossip protocol would
+    fn sync(a: &Instance, b: &Instance) {
+        for (from, to) in vec![(a, b), (b, a)] {
+            let storage = to.storage.as_ref().unwrap().0.lock().unwrap();
+
+            for transition in from.view().unwrap().get_transitions().unwrap() {
+                if !storage.contains(&transition.key).unwrap() {
+                    storage.insert(transition).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_playlist_convergence() {
+        let _ = fs::remove_file("/tmp/test13a.db");
+        let _ = fs::remove_file("/tmp/test13b.db");
+        let a = Instance::from_file("/tmp/test13a.db", GossipConf::new().keypair(Keypair::from_seed(&[1; 32]))).unwrap();
+        let b = Instance::from_file("/tmp/test13b.db", GossipConf::new().keypair(Keypair::from_seed(&[2; 32]))).unwrap();
+        let (view_a, view_b) = (a.view().unwrap(), b.view().unwrap());
+
+        let tracks: Vec<Track> = (0..4u32).map(|i| Track::empty(vec![i; 10], 100.0)).collect();
+        for track in &tracks {
+            view_a.add_track(track.clone()).unwrap();
+        }
+
+        // the playlist is created on the first peer and then known to both
+        let mut playlist = Playlist::new(1, "Shared".into(), Keypair::from_seed(&[1; 32]).id());
+        playlist.tracks = vec![tracks[0].key, tracks[1].key];
+        view_a.add_playlist(playlist).unwrap();
+        sync(&a, &b);
+        assert_eq!(view_b.get_playlist(1).unwrap().0.tracks, vec![tracks[0].key, tracks[1].key]);
+        assert_eq!(view_b.get_playlist(1).unwrap().1.len(), 2);
+
+        // concurrent inserts at the same place both survive
+        view_a.insert_into_playlist(1, 1, tracks[2].key).unwrap();
+        view_b.insert_into_playlist(1, 1, tracks[3].key).unwrap();
+        sync(&a, &b);
+
+        let merged = view_a.get_playlist(1).unwrap().0.tracks;
+        assert_eq!(merged, view_b.get_playlist(1).unwrap().0.tracks);
+        assert_eq!(merged.len(), 4);
+        assert_eq!((merged[0], merged[3]), (tracks[0].key, tracks[1].key));
+        assert!(merged.contains(&tracks[2].key) && merged.contains(&tracks[3].key));
+
+        // a removal wins over a concurrent move of the same entry
+        view_a.move_in_playlist(1, 0, 3).unwrap();
+        view_b.remove_from_playlist(1, 0).unwrap();
+        sync(&a, &b);
+
+        let merged = view_a.get_playlist(1).unwrap().0.tracks;
+        assert_eq!(merged, view_b.get_playlist(1).unwrap().0.tracks);
+        assert_eq!(merged.len(), 3);
+        assert!(!merged.contains(&tracks[0].key));
+
+        // of two concurrent moves of the same entry the later one wins
+        let moved = merged[0];
+        view_a.move_in_playlist(1, 0, 2).unwrap();
+        view_b.move_in_playlist(1, 0, 1).unwrap();
+        sync(&a, &b);
+
+        let merged = view_a.get_playlist(1).unwrap().0.tracks;
+        assert_eq!(merged, view_b.get_playlist(1).unwrap().0.tracks);
+        assert_eq!(merged.iter().position(|x| *x == moved), Some(1));
+
+        // deleting a track removes only its first occurrence
+        view_b.add_to_playlist(moved, 1).unwrap();
+        view_b.delete_from_playlist(moved, 1).unwrap();
+        sync(&a, &b);
+        assert_eq!(view_a.get_playlist(1).unwrap().0.tracks, vec![merged[0], merged[2], moved]);
+    }
+
+    #[test]
+    pub fn test_checkpoint() {
+        let _ = fs::remove_file("/tmp/test17a.db");
+        let _ = fs::remove_file("/tmp/test17b.db");
+        let a = Instance::from_file("/tmp/test17a.db", GossipConf::new().keypair(Keypair::from_seed(&[1; 32]))).unwrap();
+        let b = Instance::from_file("/tmp/test17b.db", GossipConf::new().keypair(Keypair::from_seed(&[2; 32]))).unwrap();
+        let (view_a, view_b) = (a.view().unwrap(), b.view().unwrap());
+
+        let tracks: Vec<Track> = (0..3u32).map(|i| Track::empty(vec![i; 10], 100.0)).collect();
+        for track in &tracks {
+            view_a.add_track(track.clone()).unwrap();
+        }
+
+        let mut playlist = Playlist::new(1, "Compacted".into(), Keypair::from_seed(&[1; 32]).id());
+        playlist.tracks = vec![tracks[0].key, tracks[1].key];
+        view_a.add_playlist(playlist).unwrap();
+        view_a.move_in_playlist(1, 0, 1).unwrap();
+        view_a.add_event(Action::PlaySong(tracks[0].key).with_origin("zyklop".into())).unwrap();
+
+        let old = view_a.get_transitions().unwrap();
+        let snapshot = view_a.snapshot().unwrap();
+        assert_eq!(snapshot.pruned.len(), old.len());
+
+        // the checkpoint replaces all transitions before it, without changing the state
+        view_a.create_checkpoint().unwrap();
+        assert_eq!(view_a.count_transitions().unwrap(), 1);
+        assert_eq!(view_a.get_tracks().unwrap().len(), 3);
+        assert_eq!(view_a.get_playlist(1).unwrap().0.tracks, vec![tracks[1].key, tracks[0].key]);
+
+        {
+            let storage = a.storage.as_ref().unwrap().0.lock().unwrap();
+            assert!(old.iter().all(|x| storage.contains(&x.key).unwrap() && storage.is_pruned(&x.key).unwrap()));
+
+            // pruned transitions are not compared with other peers, even if sent again
+            storage.insert(old[0].clone()).unwrap();
+            assert_eq!(storage.keys(None, None).len(), 1);
+        }
+
+        // a new peer bootstraps from the checkpoint and applies newer transitions on top of it
+        view_a.delete_track(tracks[2].key).unwrap();
+        sync(&a, &b);
+        assert_eq!(view_b.count_transitions().unwrap(), 2);
+        assert_eq!(view_b.get_tracks().unwrap(), view_a.get_tracks().unwrap());
+        assert_eq!(view_b.get_playlists().unwrap(), view_a.get_playlists().unwrap());
+
+        // events are not part of the snapshot, they stay with the peer which logged them
+        assert_eq!(view_a.get_events(0..i64::max_value(), EventFilter::new()).unwrap().len(), 1);
+        assert_eq!(view_b.get_events(0..i64::max_value(), EventFilter::new()).unwrap().len(), 0);
+
+        // the old history is known to the new peer, without being stored
+        let storage = b.storage.as_ref().unwrap().0.lock().unwrap();
+        assert!(old.iter().all(|x| storage.has(&x.key)));
+        assert_eq!(storage.restore(old.iter().map(|x| x.key.clone()).collect()), Some(Vec::new()));
+    }
+
+    #[test]
+    pub fn test_checkpoint_parts() {
+        let _ = fs::remove_file("/tmp/test24a.db");
+        let _ = fs::remove_file("/tmp/test24b.db");
+        let a = Instance::from_file("/tmp/test24a.db", GossipConf::new().keypair(Keypair::from_seed(&[1; 32]))).unwrap();
+        let b = Instance::from_file("/tmp/test24b.db", GossipConf::new().keypair(Keypair::from_seed(&[2; 32]))).unwrap();
+        let (view_a, view_b) = (a.view().unwrap(), b.view().unwrap());
+
+        let tracks: Vec<Track> = (0..7u32).map(|i| Track::empty(vec![i; 10], 100.0)).collect();
+        for track in &tracks[..5] {
+            view_a.add_track(track.clone()).unwrap();
+        }
+
+        // a checkpoint is only created once enough transitions are stored
+        assert!(!view_a.checkpoint_if_needed(5).unwrap());
+        assert!(view_a.checkpoint_if_needed(4).unwrap());
+        assert_eq!(view_a.count_transitions().unwrap(), 1);
+
+        // the next checkpoint only prunes the transitions since the previous one
+        for track in &tracks[5..] {
+            view_a.add_track(track.clone()).unwrap();
+        }
+        let old = view_a.get_transitions().unwrap();
+        assert_eq!(view_a.snapshot().unwrap().pruned.len(), 3);
+
+        // seven tracks and three keys are split into three parts
+        view_a.commit_checkpoint(4).unwrap();
+        assert_eq!(view_a.count_transitions().unwrap(), 3);
+        assert_eq!(view_a.get_tracks().unwrap().len(), 7);
+
+        // a new peer keeps its events and bootstraps once all parts have arrived
+        view_b.add_event(Action::PlaySong(tracks[0].key).with_origin("zyklop".into())).unwrap();
+        sync(&a, &b);
+        assert_eq!(view_b.get_tracks().unwrap(), view_a.get_tracks().unwrap());
+        assert_eq!(view_b.get_events(0..i64::max_value(), EventFilter::new()).unwrap().len(), 1);
+        assert_eq!(view_a.get_events(0..i64::max_value(), EventFilter::new()).unwrap().len(), 1);
+
+        let storage = b.storage.as_ref().unwrap().0.lock().unwrap();
+        assert!(old.iter().all(|x| storage.is_pruned(&x.key).unwrap()));
+    }
+
+    #[test]
+    pub fn test_long_pending_chain() {
+        let _ = fs::remove_file("/tmp/test18a.db");
+        let _ = fs::remove_file("/tmp/test18b.db");
+        let a = Instance::from_file("/tmp/test18a.db", gossip()).unwrap();
+        let b = Instance::from_file("/tmp/test18b.db", gossip()).unwrap();
+
+        let view = a.view().unwrap();
+        for i in 0..2000 {
+            view.add_event(Action::PlaySong(gen_track().key).with_origin(format!("{}", i))).unwrap();
+        }
+
+        // the newest transition arrives first, the whole chain is applied with the oldest
+        let storage = b.storage.as_ref().unwrap().0.lock().unwrap();
+        for transition in view.get_transitions().unwrap().into_iter().rev() {
+            storage.insert(transition).unwrap();
+        }
+
+        assert_eq!(storage.get_tips().unwrap().len(), 1);
+        assert_eq!(b.view().unwrap().get_events(0..i64::max_value(), EventFilter::new()).unwrap().len(), 2000);
+    }
+
+    #[test]
+    pub fn test_signatures() {
+        let _ = fs::remove_file("/tmp/test14.db");
+        let instance = Instance::from_file("/tmp/test14.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+
+        // our own transitions are stored together with their signature
+        view.add_track(gen_track()).unwrap();
+        let transitions = view.get_transitions().unwrap();
+        assert_eq!(transitions.len(), 1);
+        assert!(transitions[0].verify());
+
+        let storage = instance.storage.as_ref().unwrap().0.lock().unwrap();
+        let transition = Transition::new(&keypair(), transitions.iter().map(|x| x.key.clone()).collect(), TransitionAction::DeleteToken(1).to_vec());
+        assert!(storage.approve(&transition));
+
+        // a transition in the name of a different peer is refused
+        let mut forged = transition.clone();
+        forged.pk = Keypair::from_seed(&[1; 32]).id();
+        assert!(!storage.approve(&forged));
+    }
+
+    #[test]
+    pub fn test_action_version() {
+        let _ = fs::remove_file("/tmp/test19.db");
+        let instance = Instance::from_file("/tmp/test19.db", gossip()).unwrap();
+        let storage = instance.storage.as_ref().unwrap().0.lock().unwrap();
+
+        // plain bodies written before the envelope are still understood
+        let plain = ::bincode::serialize(&TransitionAction::DeleteToken(1)).unwrap();
+        assert_eq!(TransitionAction::from_vec(&plain).unwrap(), TransitionAction::DeleteToken(1));
+
+        // an `UpsertTrack` body of the first release, which had neither albums, artists nor
+        // loudness in its tracks
+        let plain = [
+            0x00, 0x00, 0x00, 0x00,
+            0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+            0x01, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x4a, 0xc3, 0xb3, 0x67, 0x61,
+            0x01, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x48, 0x6f, 0x6d, 0x6f, 0x67, 0x65, 0x6e, 0x69, 0x63,
+            0x01, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x42, 0x6a, 0xc3, 0xb6, 0x72, 0x6b,
+            0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x73, 0x40,
+            0x03, 0x00, 0x00, 0x00
+        ];
+
+        let mut track = Track::empty(vec![1, 2], 305.0);
+        track.key = TrackKey::from_vec(&[1; 16]);
+        track.title = Some("Jóga".into());
+        track.album = Some("Homogenic".into());
+        track.interpret = Some("Björk".into());
+        track.favs_count = 3;
+        assert_eq!(TransitionAction::from_vec(&plain).unwrap(), TransitionAction::UpsertTrack(track));
+
+        // tracks of version 2 bodies have no content hash, which is encoded as `None` at the end
+        let track = gen_track();
+        let mut body = TransitionAction::UpsertTrack(track.clone()).to_vec();
+        body[1] = 2;
+        body.truncate(body.len() - 1);
+        assert_eq!(TransitionAction::from_vec(&body).unwrap(), TransitionAction::UpsertTrack(track.clone()));
+
+        // and tracks of version 1 bodies have no loudness either
+        body[1] = 1;
+        body.truncate(body.len() - 2);
+        assert_eq!(TransitionAction::from_vec(&body).unwrap(), TransitionAction::UpsertTrack(track));
+
+        // checkpoints of version 3 bodies have a single part and carry the events
+        let mut body = vec![0xE7, 3];
+        body.extend(::bincode::serialize(&(15u32, [0u64; 7], vec![[7u8; 32]])).unwrap());
+        let snapshot = Snapshot { pruned: vec![[7u8; 32]], part: 0, parts: 1, ..Snapshot::default() };
+        assert_eq!(TransitionAction::from_vec(&body).unwrap(), TransitionAction::Checkpoint(snapshot));
+
+        // an action of a newer library is stored, but not applied
+        let mut body = TransitionAction::DeleteToken(1).to_vec();
+        body[1] += 1;
+        let newer = Transition::new(&keypair(), Vec::new(), body);
+        assert!(storage.approve(&newer));
+        storage.insert(newer.clone()).unwrap();
+
+        // and transitions referencing it wait as well
+        let child = Transition::new(&keypair(), vec![newer.key.clone()], TransitionAction::DeleteToken(2).to_vec());
+        storage.insert(child.clone()).unwrap();
+
+        let state = |key: &TransitionKey| storage.get_transitions(vec![key.clone()]).unwrap().unwrap()[0].state;
+        assert_eq!((state(&newer.key), state(&child.key)), (2, 2));
+
+        // after an update the library understands the action and applies both
+        let socket = ::rusqlite::Connection::open("/tmp/test19.db").unwrap();
+        socket.execute("UPDATE Transitions SET Data=?1 WHERE Key=?2", &[&TransitionAction::DeleteToken(1).to_vec(), &newer.key.0.as_ref()]).unwrap();
+        storage.resume().unwrap();
+
+        assert_eq!((state(&newer.key), state(&child.key)), (0, 1));
+    }
+
+    #[test]
+    pub fn test_permissions() {
+        let (reader, zyklop, stranger) = (Keypair::from_seed(&[1; 32]), Keypair::from_seed(&[2; 32]), Keypair::from_seed(&[3; 32]));
+        let permissions = Permissions::new()
+            .trust(reader.id(), Role::ReadOnly)
+            .trust(zyklop.id(), Role::TokenOnly);
+
+        let _ = fs::remove_file("/tmp/test15.db");
+        let instance = Instance::with_permissions("/tmp/test15.db", gossip(), permissions).unwrap();
+        let storage = instance.storage.as_ref().unwrap().0.lock().unwrap();
+
+        let delete_track = TransitionAction::DeleteTrack(gen_track().key).to_vec();
+        let update_token = TransitionAction::DeleteToken(1).to_vec();
+        let approve = |keypair: &Keypair, body: &Vec<u8>| storage.approve(&Transition::new(keypair, Vec::new(), body.clone()));
+
+        // we trust ourselves fully
+        assert!(approve(&keypair(), &delete_track));
+
+        // a read-only peer can't change anything
+        assert!(!approve(&reader, &delete_track));
+        assert!(!approve(&reader, &update_token));
+
+        // a token-only peer can only change tokens
+        assert!(!approve(&zyklop, &delete_track));
+        assert!(approve(&zyklop, &update_token));
+
+        // a peer not in the trust list can't change anything
+        assert!(!approve(&stranger, &update_token));
+    }
+
+    #[test]
+    pub fn test_smart_playlist() {
+        let _ = fs::remove_file("/tmp/test11.db");
+        let instance = Instance::from_file("/tmp/test11.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+
+        let mut playlist = Playlist::new(1, "Best of Bach".into(), keypair().id());
+        playlist.query = Some(PlaylistQuery {
+            query: "interpret:Bach".into(),
+            order: Order::ByFavs,
+            limit: Some(2)
+        });
+        view.add_playlist(playlist.clone()).unwrap();
+
+        // the query is part of the single transition creating the playlist
+        let transitions = view.get_transitions().unwrap();
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(TransitionAction::from_vec(transitions[0].body.as_ref().unwrap()).unwrap(),
+            TransitionAction::UpsertPlaylist(playlist.clone()));
+
+        // the playlist is empty without any matching track
+        assert_eq!(view.get_playlist(1).unwrap().1.len(), 0);
+
+        let mut tracks = Vec::new();
+        for i in 0..3 {
+            let mut track = Track::empty(vec![i as u32 + 10; 10], 100.0);
+            track.interpret = Some("Bach".into());
+            track.favs_count = i;
+            view.add_track(track.clone()).unwrap();
+            tracks.push(track);
+        }
+
+        let mut other = gen_track();
+        other.interpret = Some("Händel".into());
+        view.add_track(other).unwrap();
+
+        // tracks added after the playlist are part of it, ordered by favs and limited
+        let (tmp, res) = view.get_playlist(1).unwrap();
+        assert_eq!(tmp.query, playlist.query);
+        assert_eq!(res, vec![tracks[2].clone(), tracks[1].clone()]);
+        assert_eq!(tmp.tracks, vec![tracks[2].key, tracks[1].key]);
+
+        // turn it back into a normal playlist
+        view.set_playlist_query(1, None).unwrap();
+        assert_eq!(view.get_playlist(1).unwrap().1.len(), 0);
+
+        // invalid queries are refused
+        let query = PlaylistQuery { query: "title:(".into(), order: Order::ByDate, limit: None };
+        match view.set_playlist_query(1, Some(query)) {
+            Err(Error::Search(_)) => {},
+            x => panic!("Expected a search error, got {:?}", x)
+        }
+    }
+
     #[test]
     pub fn test_tracks() {
-        let instance = Instance::from_file("/tmp/test3.db", gossip());
-        let view = instance.view();
+        let instance = Instance::from_file("/tmp/test3.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
 
         // create a new track
         let track = gen_track();
@@ -646,13 +1836,65 @@ mod tests {
 
         view.delete_track(track.key).unwrap();
 
-        assert_eq!(view.get_tracks().len(), 0);
+        assert_eq!(view.get_tracks().unwrap().len(), 0);
+    }
+
+    #[test]
+    pub fn test_albums() {
+        let _ = fs::remove_file("/tmp/test12.db");
+        let instance = Instance::from_file("/tmp/test12.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+
+        let artist = Artist { key: Artist::key_from_name("The Beatles"), name: "The Beatles".into() };
+        let album = Album { key: Album::key_from_title("Abbey Road", Some(artist.key)), title: "Abbey Road".into(), artist: Some(artist.key) };
+
+        // an album can't reference an unknown artist
+        match view.add_album(album.title.clone(), album.artist) {
+            Err(Error::NotFound) => {},
+            x => panic!("Expected NotFound, got {:?}", x)
+        }
+
+        // the keys are derived from the content, independent of the local state
+        assert_eq!(view.add_artist(artist.name.clone()).unwrap(), artist.key);
+        assert_eq!(view.add_album(album.title.clone(), album.artist).unwrap(), album.key);
+        assert_eq!(view.get_albums_of_artist(artist.key).unwrap(), vec![album.clone()]);
+
+        match view.add_artist("the  beatles".into()) {
+            Err(Error::AlreadyExists) => {},
+            x => panic!("Expected AlreadyExists, got {:?}", x)
+        }
+
+        // the same title by another artist is a different album
+        let other = view.add_artist("Various Artists".into()).unwrap();
+        assert!(view.add_album(album.title.clone(), Some(other)).unwrap() != album.key);
+
+        // add tracks in the wrong order
+        let mut tracks = Vec::new();
+        for (i, &(disc, number)) in [(2, 1), (1, 2), (1, 1)].iter().enumerate() {
+            let track = Track::empty(vec![i as u32 + 20; 10], 100.0);
+            view.add_track(track.clone()).unwrap();
+            view.update_track_album(track.key, Some(album.key), Some(artist.key), Some(number), Some(disc), Some(1969)).unwrap();
+
+            tracks.push(view.get_track(track.key).unwrap());
+        }
+
+        assert_eq!(tracks[0].year, Some(1969));
+        assert_eq!(view.get_tracks_of_album(album.key).unwrap(), vec![tracks[2].clone(), tracks[1].clone(), tracks[0].clone()]);
+
+        // deleting the artist keeps the album
+        view.delete_artist(artist.key).unwrap();
+        assert_eq!(view.get_album(album.key).unwrap().artist, None);
+        assert_eq!(view.get_track(tracks[0].key).unwrap().artist_key, None);
+
+        view.delete_album(album.key).unwrap();
+        assert_eq!(view.get_tracks_of_album(album.key).unwrap().len(), 0);
+        assert_eq!(view.get_track(tracks[0].key).unwrap().track_number, Some(1));
     }
 
     #[test]
     pub fn test_tokens() {
-        let instance = Instance::from_file("/tmp/test4.db", gossip());
-        let view = instance.view();
+        let instance = Instance::from_file("/tmp/test4.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
 
         //create a track and playlist
         let track = gen_track();
@@ -661,7 +1903,8 @@ mod tests {
             title: "My very own playlist".into(),
             desc: Some("".into()),
             tracks: vec![],
-            origin: PeerId(vec![0u8; 16])
+            origin: keypair().id(),
+            query: None
         };
 
         // setup up track and plalist
@@ -696,10 +1939,150 @@ mod tests {
         assert_eq!(view.get_token(1).unwrap().0, last_token);
     }
 
+    #[test]
+    pub fn test_missing_keys() {
+        let instance = Instance::from_file("/tmp/test8.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+
+        let track = gen_track();
+
+        // unknown keys are reported as errors instead of panicking
+        match view.update_playlist(1000, Some("Unknown".into()), None) {
+            Err(Error::NotFound) => {},
+            x => panic!("Expected NotFound, got {:?}", x)
+        }
+
+        match view.add_to_playlist(track.key, 1000) {
+            Err(Error::NotFound) => {},
+            x => panic!("Expected NotFound, got {:?}", x)
+        }
+
+        match view.vote_for_track(track.key) {
+            Err(Error::NotFound) => {},
+            x => panic!("Expected NotFound, got {:?}", x)
+        }
+
+        match view.use_token(1000) {
+            Err(Error::NotFound) => {},
+            x => panic!("Expected NotFound, got {:?}", x)
+        }
+    }
+
+    #[test]
+    pub fn test_events() {
+        let instance = Instance::from_file("/tmp/test9.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+
+        let track = gen_track();
+
+        let play = Action::PlaySong(track.key).with_origin("127.0.0.1".into());
+        let delete = Action::DeleteSong(track.key).with_origin("zyklop".into());
+
+        view.add_event(play.clone()).unwrap();
+        view.add_event(delete.clone()).unwrap();
+
+        let all = 0..i64::max_value();
+        assert_eq!(view.get_events(all.clone(), EventFilter::new()).unwrap(), vec![play.clone(), delete.clone()]);
+        assert_eq!(view.get_events(all.clone(), EventFilter::new().tag("playsong")).unwrap(), vec![play.clone()]);
+        assert_eq!(view.get_events(all.clone(), EventFilter::new().origin("zyklop").track(track.key)).unwrap(), vec![delete]);
+
+        // nothing happened before the events were added
+        assert_eq!(view.get_events(0..play.date(), EventFilter::new()).unwrap().len(), 0);
+    }
+
+    #[test]
+    pub fn test_statistics() {
+        let instance = Instance::from_file("/tmp/test10.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+
+        let mut track_a = gen_track();
+        track_a.interpret = Some("Air".into());
+        track_a.album = Some("Moon Safari".into());
+        let mut track_b = Track::empty(vec![2u32; 10], 50.0);
+        track_b.interpret = Some("Daft Punk".into());
+
+        view.add_track(track_a.clone()).unwrap();
+        view.add_track(track_b.clone()).unwrap();
+
+        // play the first track twice on the server and the second one on zyklop
+        for (track, origin) in &[(&track_a, "127.0.0.1"), (&track_a, "127.0.0.1"), (&track_b, "zyklop")] {
+            view.add_event(Action::PlaySong(track.key).with_origin(origin.to_string())).unwrap();
+        }
+
+        let all = 0..i64::max_value();
+
+        let top = view.get_top_tracks(all.clone(), None, 10).unwrap();
+        assert_eq!(top, vec![(track_a.clone(), 2), (track_b.clone(), 1)]);
+        assert_eq!(view.get_top_tracks(all.clone(), Some("zyklop"), 10).unwrap(), vec![(track_b.clone(), 1)]);
+        assert_eq!(view.get_top_tracks(all.clone(), None, 1).unwrap().len(), 1);
+
+        assert_eq!(view.get_top_interprets(all.clone(), None, 10).unwrap(),
+            vec![("Air".to_string(), 2), ("Daft Punk".to_string(), 1)]);
+        assert_eq!(view.get_top_albums(all.clone(), None, 10).unwrap(), vec![("Moon Safari".to_string(), 2)]);
+
+        let time = view.get_listening_time(all.clone(), None).unwrap();
+        assert_eq!(time.len(), 1);
+        assert_eq!(time[0].1, 250.0);
+
+        let heatmap = view.get_heatmap(all.clone(), Some("127.0.0.1")).unwrap();
+        assert_eq!(heatmap.iter().map(|x| x.iter().sum::<u32>()).sum::<u32>(), 2);
+    }
+
+    #[test]
+    pub fn test_replication_policy() {
+        let _ = fs::remove_file("/tmp/test16.db");
+        let instance = Instance::from_file("/tmp/test16.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+
+        let tracks: Vec<Track> = (0..3u32).map(|i| Track::empty(vec![i; 10], 100.0)).collect();
+        for track in &tracks {
+            view.add_track(track.clone()).unwrap();
+        }
+
+        let mut playlist = Playlist::new(1, "Selected".into(), keypair().id());
+        playlist.tracks = vec![tracks[1].key, tracks[0].key];
+        view.add_playlist(playlist).unwrap();
+        view.add_token(Token { token: 1, key: Some(1), played: Vec::new(), pos: None, last_use: 0 }).unwrap();
+
+        // the first track is played before the last one
+        for track in &[&tracks[0], &tracks[2]] {
+            view.add_event(Action::PlaySong(track.key).with_origin("zyklop".into())).unwrap();
+        }
+
+        assert_eq!(Policy::new(false, Vec::new(), Vec::new(), None).tracks(&view).unwrap(), vec![]);
+        assert_eq!(Policy::new(true, vec![1], Vec::new(), Some(10)).tracks(&view).unwrap().len(), 3);
+
+        // unknown playlists are skipped and tracks of the token only appear once
+        let selected = Policy::new(false, vec![1, 99], vec![1], None);
+        assert_eq!(selected.tracks(&view).unwrap(), vec![tracks[1].key, tracks[0].key]);
+
+        let cache = Policy::new(false, vec![1], Vec::new(), Some(15));
+        assert_eq!(cache, Policy::Cache { quota: 15 });
+        assert_eq!(cache.tracks(&view).unwrap(), vec![tracks[2].key, tracks[0].key]);
+
+        // only the most recently played file fits into the cache, partial files are kept
+        let data_path = Path::new("/tmp/test16-data");
+        let _ = fs::remove_dir_all(data_path);
+        fs::create_dir(data_path).unwrap();
+        for name in &[tracks[0].key.to_string(), tracks[2].key.to_string(), "partial.part".into()] {
+            fs::write(data_path.join(name), &[0u8; 10]).unwrap();
+        }
+
+        // but the other file is kept as long as no other peer is known to have it
+        let played = view.get_recently_played().unwrap().into_iter().collect();
+        assert_eq!(replication::evict(data_path, 15, &played, |_| false).unwrap(), (0, vec![tracks[0].key]));
+        assert!(data_path.join(tracks[0].key.to_path()).exists());
+
+        assert_eq!(replication::evict(data_path, 15, &played, |_| true).unwrap(), (1, vec![]));
+        assert!(!data_path.join(tracks[0].key.to_path()).exists());
+        assert!(data_path.join(tracks[2].key.to_path()).exists());
+        assert!(data_path.join("partial.part").exists());
+    }
+
     #[test]
     pub fn recv_stream() {
-        let mut instance = Instance::from_file("/tmp/test5.db", gossip());
-        let view = instance.view();
+        let mut instance = Instance::from_file("/tmp/test5.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
 
         let track = gen_track();
 
@@ -712,4 +2095,134 @@ mod tests {
             _ => { panic!("Wrong result!"); }
         }
     }
+
+    #[test]
+    #[cfg(feature="hex-music-container")]
+    pub fn test_import_data() {
+        use std::fs::OpenOptions;
+        use hex_music_container::{Container, Configuration, Metadata};
+
+        let _ = fs::remove_file("/tmp/test20.db");
+        let _ = fs::remove_dir_all("/tmp/test20");
+        fs::create_dir_all("/tmp/test20").unwrap();
+
+        // an audio file named after the track key with embedded metadata
+        let track = gen_track();
+        let path = Path::new("/tmp/test20").join(track.key.to_path());
+        let file = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+
+        let mut container = Container::save_pcm(Configuration::Stereo, &vec![0i16; 2 * 48000], file, None).unwrap();
+        container.set_metadata(&Metadata {
+            title: track.title.clone(),
+            composer: track.composer.clone(),
+            fingerprint: Some(track.fingerprint.clone()),
+            ..Metadata::default()
+        }).unwrap();
+
+        // a partial file is ignored
+        fs::write(path.with_extension("part"), b"incomplete").unwrap();
+
+        let instance = Instance::from_file("/tmp/test20.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+
+        assert_eq!(view.import_data(Path::new("/tmp/test20")).unwrap(), vec![track.key]);
+        let hash = content_hash(fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(view.get_track(track.key).unwrap(), Track { duration: 1.0, content_hash: Some(hash), ..track.clone() });
+
+        // known tracks are not added again
+        assert_eq!(view.import_data(Path::new("/tmp/test20")).unwrap(), vec![]);
+    }
+
+    #[test]
+    #[cfg(feature="hex-music-container")]
+    pub fn test_finish_file() {
+        use std::fs::OpenOptions;
+        use hex_music_container::{Container, Configuration};
+
+        let _ = fs::remove_dir_all("/tmp/test23");
+        fs::create_dir_all("/tmp/test23/data").unwrap();
+
+        // a downloaded audio file of a track
+        let mut track = gen_track();
+        let path = Path::new("/tmp/test23/data").join(track.key.to_path());
+        let download = || {
+            let file = OpenOptions::new().read(true).write(true).create(true).open(path.with_extension("part")).unwrap();
+            let container = Container::save_pcm(Configuration::Stereo, &vec![0i16; 2 * 48000], file, None).unwrap();
+
+            content_hash(container.into_inner()).unwrap()
+        };
+        let hash = download();
+
+        let instance = Instance::from_file("/tmp/test23/test23.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+        let key = track.key.to_vec();
+        let finish = || {
+            let job = instance.storage.as_ref().unwrap().0.lock().unwrap().finish_file(&key);
+
+            job()
+        };
+
+        // the audio data doesn't match the hash of the track, the file is discarded
+        track.content_hash = Some(vec![0; 32]);
+        view.add_track(track.clone()).unwrap();
+        assert!(!finish());
+        assert!(!path.exists() && !path.with_extension("part").exists());
+
+        // the file matches the hash and is moved to the data section
+        assert_eq!(download(), hash);
+        track.content_hash = Some(hash);
+        view.commit(TransitionAction::UpsertTrack(track.clone())).unwrap();
+        assert!(finish());
+        assert!(path.exists() && !path.with_extension("part").exists());
+    }
+
+    #[test]
+    #[cfg(feature="hex-music-container")]
+    pub fn test_store_metadata() {
+        use std::fs::OpenOptions;
+        use hex_music_container::{Container, Configuration, Metadata};
+
+        let _ = fs::remove_dir_all("/tmp/test25");
+        fs::create_dir_all("/tmp/test25/data").unwrap();
+
+        // an audio file with a cover, which is not part of the track
+        let track = gen_track();
+        let path = Path::new("/tmp/test25/data").join(track.key.to_path());
+        let file = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let mut container = Container::save_pcm(Configuration::Stereo, &vec![0i16; 2 * 48000], file, None).unwrap();
+        container.set_metadata(&Metadata { cover: Some(vec![1u8; 100]), ..Metadata::default() }).unwrap();
+        let hash = content_hash(container.into_inner()).unwrap();
+
+        let instance = Instance::from_file("/tmp/test25/test25.db", gossip()).unwrap();
+        let view = instance.view().unwrap();
+        let metadata = || {
+            let file = fs::File::open(&path).unwrap();
+
+            Container::load(file).unwrap().metadata().unwrap().unwrap()
+        };
+
+        // applying a track leaves the audio file alone
+        view.add_track(track.clone()).unwrap();
+        assert_eq!(metadata().title, None);
+
+        // storing the metadata replaces the file with an updated copy
+        let track = view.get_track(track.key).unwrap();
+        assert!(store_metadata(&path, &track).unwrap());
+        assert_eq!(metadata().title, track.title);
+        assert_eq!(metadata().fingerprint, Some(track.fingerprint.clone()));
+        assert_eq!(metadata().cover, Some(vec![1u8; 100]));
+        assert!(!path.with_extension("tmp").exists());
+
+        // an unchanged track doesn't rewrite the file
+        assert!(!store_metadata(&path, &track).unwrap());
+
+        view.update_track(track.key, Some("Renamed"), None, None, None, None).unwrap();
+        assert!(store_metadata(&path, &view.get_track(track.key).unwrap()).unwrap());
+        assert_eq!(metadata().title, Some("Renamed".into()));
+        assert_eq!(metadata().cover, Some(vec![1u8; 100]));
+
+        // the audio data stays the same
+        assert_eq!(content_hash(fs::File::open(&path).unwrap()).unwrap(), hash);
+    }
 }
+
@@ -1,18 +1,44 @@
-use std::result;
+use std::{io, result};
 #[cfg(feature = "rusqlite")]
 use hex_gossip;
 #[cfg(feature = "rusqlite")]
 use rusqlite;
+#[cfg(feature = "bincode")]
+use bincode;
+#[cfg(feature = "hex-music-container")]
+use hex_music_container;
+use search::ParseError;
 
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
+    /// Failed to prepare or execute a SQLite statement, or to read a row
     #[cfg(feature = "rusqlite")]
     Sqlite(rusqlite::Error),
+    /// Failure in the gossip network
     #[cfg(feature = "rusqlite")]
     Gossip(hex_gossip::Error),
+    /// Could not decode a transition body
+    #[cfg(feature = "bincode")]
+    Decode(bincode::Error),
+    /// A file received from a peer is not a valid audio file
+    #[cfg(feature = "hex-music-container")]
+    MusicContainer(hex_music_container::error::Error),
+    /// The audio data of a file received from a peer doesn't match the hash of its track
+    ContentMismatch,
+    /// A transition body was written by a newer version of the library, contains its version
+    UnsupportedVersion(u8),
+    /// Input/Output error, e.g. could not read a file from the data section
+    Io(io::Error),
+    /// The channel to the gossip network is closed
+    ChannelClosed,
+    Search(ParseError),
+    /// The database was created by a newer version, contains the schema version
+    SchemaTooNew(u32),
     AlreadyExists,
     NotFound,
-    ReadOnly
+    ReadOnly,
+    /// The instance is not connected to the gossip network
+    Offline
 }
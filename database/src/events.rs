@@ -5,6 +5,8 @@
 //! and wrapped inside `Event`
 //! 
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
 #[cfg(feature="rusqlite")]
 use rusqlite::{Error, Result};
 
@@ -12,15 +14,16 @@ use objects::TrackKey;
 
 /// An Event occurs from an origin and contains an action. The origin is most of the time an IP
 /// address.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
 pub struct Event {
+    date: i64,
     origin: String,
     action: Action
 }
 
 /// All possible actions
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
 pub enum Action {
     Connect(f32),
@@ -30,9 +33,14 @@ pub enum Action {
 }
 
 impl Action {
-    /// Tag the `Action` with an origin and return an `Event`
+    /// Tag the `Action` with an origin and return an `Event` happening now
     pub fn with_origin(self, origin: String) -> Event {
+        let date = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as i64)
+            .unwrap_or(0);
+
         Event {
+            date: date,
             origin: origin,
             action: self
         }
@@ -50,6 +58,11 @@ impl Event {
         self.origin.clone()
     }
 
+    /// Seconds since the UNIX epoch when the event occured
+    pub fn date(&self) -> i64 {
+        self.date
+    }
+
     /// Convert the action tag to string
     pub fn tag(&self) -> &str {
         match self.action {
@@ -60,6 +73,14 @@ impl Event {
         }
     }
 
+    /// Key of the track this event refers to, if any
+    pub fn track_key(&self) -> Option<TrackKey> {
+        match self.action {
+            Action::Connect(_) => None,
+            Action::PlaySong(x) | Action::AddSong(x) | Action::DeleteSong(x) => Some(x)
+        }
+    }
+
     /// Converts the underlying data to string
     pub fn data_to_string(&self) -> String {
         match &self.action {
@@ -72,7 +93,7 @@ impl Event {
 
     #[cfg(feature="rusqlite")]
     /// Convenient function to create an `Event`
-    pub fn from(origin: String, tag: String, data: String) -> Result<Event> {
+    pub fn from(date: i64, origin: String, tag: String, data: String) -> Result<Event> {
         let action = match tag.as_ref() {
             "connect" => Action::Connect(data.parse::<f32>().map_err(|_| Error::InvalidQuery)?),
             "playsong" => Action::PlaySong(TrackKey::from_str(&data)),
@@ -82,9 +103,47 @@ impl Event {
         };
 
         Ok(Event {
+            date: date,
             origin: origin,
             action: action
         })
     }
 
 }
+
+/// Restrict the events returned by `View::get_events`
+///
+/// An empty filter matches all events, every set field has to match.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub origin: Option<String>,
+    pub tag: Option<String>,
+    pub data: Option<String>
+}
+
+impl EventFilter {
+    pub fn new() -> EventFilter {
+        EventFilter::default()
+    }
+
+    /// Only events from a certain origin
+    pub fn origin(mut self, origin: &str) -> EventFilter {
+        self.origin = Some(origin.into());
+
+        self
+    }
+
+    /// Only events with a certain tag, e.g. `playsong`
+    pub fn tag(mut self, tag: &str) -> EventFilter {
+        self.tag = Some(tag.into());
+
+        self
+    }
+
+    /// Only events concerning a single track
+    pub fn track(mut self, key: TrackKey) -> EventFilter {
+        self.data = Some(key.to_string());
+
+        self
+    }
+}
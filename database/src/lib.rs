@@ -15,9 +15,9 @@
 //! use hex_gossip::GossipConf;
 //!
 //! pub fn main() {
-//!     let instance = Instance::from_file("/opt/music/music.db", GossipConf::new());
-//!     let view = instance.view();
-//!     for playlist in view.get_playlists() {
+//!     let instance = Instance::from_file("/opt/music/music.db", GossipConf::new()).unwrap();
+//!     let view = instance.view().unwrap();
+//!     for playlist in view.get_playlists().unwrap() {
 //!         println!("{:#?}", playlist);
 //!     }
 //! }
@@ -38,6 +38,8 @@ extern crate bincode;
 extern crate futures;
 #[cfg(feature="rusqlite")]
 extern crate tokio;
+#[cfg(feature="hex-music-container")]
+extern crate hex_music_container;
 #[macro_use]
 extern crate log;
 
@@ -46,19 +48,35 @@ pub mod objects;
 pub mod search;
 pub mod events;
 #[cfg(feature="rusqlite")]
+pub mod migration;
+#[cfg(feature="rusqlite")]
+pub mod permission;
+#[cfg(feature="rusqlite")]
 mod database;
+pub mod replication;
 mod transition;
 
 pub use error::{Result, Error};
-pub use events::{Action, Event};
-pub use transition::TransitionAction;
-pub use objects::{Track, Playlist, Token, TrackKey, PlaylistKey, TokenId};
+pub use events::{Action, Event, EventFilter};
+pub use transition::{TransitionAction, Snapshot};
+#[cfg(all(feature="rusqlite", feature="hex-music-container"))]
+pub use transition::content_hash;
+#[cfg(feature="hex-music-container")]
+pub use transition::store_metadata;
+#[cfg(feature="rusqlite")]
+pub use permission::{Permissions, Role};
+pub use replication::{Policy, Progress};
+#[cfg(feature="rusqlite")]
+pub use replication::Replication;
+pub use objects::{Track, Playlist, PlaylistQuery, PlaylistEntry, Position, EntryId, Token, Album, Artist, TrackKey, PlaylistKey, TokenId, AlbumKey, ArtistKey};
 #[cfg(feature="rusqlite")]
 pub use database::*;
 #[cfg(feature="hex-gossip")]
-pub use hex_gossip::{GossipConf, Transition};
+pub use hex_gossip::{GossipConf, Transition, Keypair, PeerStatus};
 #[cfg(not(feature = "hex-gossip"))]
 mod GossipDummy {
+    use std::net::SocketAddr;
+
     pub type PeerId = Vec<u8>;
 
     /// Transition key is the 256bit hash of the body
@@ -72,9 +90,23 @@ mod GossipDummy {
         pub pk: PeerId,
         pub refs: Vec<TransitionKey>,
         pub body: Option<Vec<u8>>,
-        pub sign: [u8; 32],
+        pub sign: Vec<u8>,
         pub state: u8
     }
+
+    /// Status of a known peer, all times are in milliseconds
+    #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+    pub struct PeerStatus {
+        pub id: PeerId,
+        pub addr: SocketAddr,
+        pub connected_since: Option<u64>,
+        pub last_seen: Option<u64>,
+        pub bytes_in: u64,
+        pub bytes_out: u64,
+        pub pending: usize,
+        pub version: Option<u8>,
+        pub features: u32
+    }
 }
 #[cfg(not(feature = "hex-gossip"))]
-pub use GossipDummy::Transition;
+pub use GossipDummy::{Transition, PeerStatus};
@@ -0,0 +1,83 @@
+//! Versioned schema migrations
+//!
+//! The schema version of a database is stored in the `user_version` pragma of SQLite. Every
+//! migration is a batch of SQL statements in `src/migrations/` and upgrades the schema by exactly
+//! one version. Pending migrations are applied in order, each inside its own transaction together
+//! with the new version number, so an interrupted upgrade never leaves a half migrated database.
+//!
+//! A database without a version (created before versioning was introduced) has version 0. The
+//! first migration therefore only creates tables which don't exist yet.
+//!
+//! Keys which have to be derived the same way as in the library are computed by SQL functions,
+//! registered on the connection before migrating.
+
+use rusqlite::Connection;
+
+use error::{Error, Result};
+use objects::{Artist, Album};
+
+/// All migrations, the migration at index `i` upgrades the schema from version `i` to `i+1`
+const MIGRATIONS: &[&str] = &[
+    include_str!("migrations/001_create_db.sql"),
+    include_str!("migrations/002_tracks_index.sql"),
+    include_str!("migrations/003_events.sql"),
+    include_str!("migrations/004_smart_playlists.sql"),
+    include_str!("migrations/005_albums_artists.sql"),
+    include_str!("migrations/006_playlist_entries.sql"),
+    include_str!("migrations/007_pruned_transitions.sql"),
+    include_str!("migrations/008_track_loudness.sql"),
+    include_str!("migrations/009_tracks_index_rows.sql"),
+    include_str!("migrations/010_events_track.sql"),
+    include_str!("migrations/011_content_keys.sql"),
+    include_str!("migrations/012_track_content_hash.sql"),
+];
+
+/// Schema version supported by this binary
+pub const VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Read the schema version of a database
+pub fn version(socket: &Connection) -> Result<u32> {
+    socket.query_row("PRAGMA user_version", &[], |row| row.get::<_, i64>(0))
+        .map(|x| x as u32)
+        .map_err(|e| Error::Sqlite(e))
+}
+
+/// Apply all pending migrations and return the new schema version
+///
+/// Fails with `Error::SchemaTooNew` if the database was created by a newer version of this
+/// crate. In this case nothing is changed.
+pub fn migrate(socket: &mut Connection) -> Result<u32> {
+    let current = version(socket)?;
+
+    if current > VERSION {
+        return Err(Error::SchemaTooNew(current));
+    }
+
+    register_functions(socket)?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let version = i as u32 + 1;
+
+        info!("Migrate database schema to version {}", version);
+
+        let tx = socket.transaction().map_err(|e| Error::Sqlite(e))?;
+        tx.execute_batch(migration).map_err(|e| Error::Sqlite(e))?;
+        tx.execute_batch(&format!("PRAGMA user_version = {};", version)).map_err(|e| Error::Sqlite(e))?;
+        tx.commit().map_err(|e| Error::Sqlite(e))?;
+    }
+
+    Ok(VERSION)
+}
+
+/// Register the functions `artist_key(name)` and `album_key(title, artist)` used by migrations
+fn register_functions(socket: &Connection) -> Result<()> {
+    socket.create_scalar_function("artist_key", 1, true, |ctx| {
+        Ok(ctx.get::<Option<String>>(0)?.map(|name| Artist::key_from_name(&name)))
+    }).map_err(|e| Error::Sqlite(e))?;
+
+    socket.create_scalar_function("album_key", 2, true, |ctx| {
+        let artist = ctx.get::<Option<i64>>(1)?;
+
+        Ok(ctx.get::<Option<String>>(0)?.map(|title| Album::key_from_title(&title, artist)))
+    }).map_err(|e| Error::Sqlite(e))
+}
@@ -0,0 +1,14 @@
+-- Full-text index over the track metadata, used by the search (see `search.rs`)
+CREATE VIRTUAL TABLE IF NOT EXISTS TracksIndex USING fts5(
+    Key UNINDEXED,
+    Title,
+    Album,
+    Interpret,
+    People,
+    Composer,
+    tokenize = 'unicode61 remove_diacritics 1'
+);
+
+INSERT INTO TracksIndex(Key, Title, Album, Interpret, People, Composer)
+    SELECT Key, Title, Album, Interpret, People, Composer FROM Tracks
+        WHERE Key NOT IN (SELECT Key FROM TracksIndex);
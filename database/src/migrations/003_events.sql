@@ -0,0 +1,9 @@
+-- Listening history and other events, see `events.rs`
+CREATE TABLE IF NOT EXISTS Events (
+    Date    INTEGER NOT NULL,
+    Origin  TEXT NOT NULL,
+    Tag     TEXT NOT NULL,
+    Data    TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS EventsDate ON Events(Date);
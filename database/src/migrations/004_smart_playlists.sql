@@ -0,0 +1,4 @@
+-- Smart playlists store a search query instead of a fixed list of tracks
+ALTER TABLE Playlists ADD COLUMN Query TEXT;
+ALTER TABLE Playlists ADD COLUMN SortOrder TEXT;
+ALTER TABLE Playlists ADD COLUMN QueryLimit INTEGER;
@@ -0,0 +1,33 @@
+-- Albums and artists as entities of their own, derived from the free-text fields of the tracks
+CREATE TABLE IF NOT EXISTS Artists (
+    Key     INTEGER PRIMARY KEY,
+    Name    TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS Albums (
+    Key     INTEGER PRIMARY KEY,
+    Title   TEXT NOT NULL,
+    Artist  INTEGER
+);
+
+ALTER TABLE Tracks ADD COLUMN AlbumKey INTEGER;
+ALTER TABLE Tracks ADD COLUMN ArtistKey INTEGER;
+ALTER TABLE Tracks ADD COLUMN TrackNumber INTEGER;
+ALTER TABLE Tracks ADD COLUMN DiscNumber INTEGER;
+ALTER TABLE Tracks ADD COLUMN Year INTEGER;
+
+-- the keys are assigned in alphabetical order, so peers with the same tracks derive the same keys
+INSERT INTO Artists (Name)
+    SELECT DISTINCT Interpret FROM Tracks WHERE Interpret IS NOT NULL ORDER BY Interpret;
+
+-- tracks with the same album title form an album, the artist is only known if all tracks share it
+INSERT INTO Albums (Title, Artist)
+    SELECT Grouped.Album, Artists.Key FROM (
+        SELECT Album, CASE WHEN COUNT(DISTINCT Interpret) = 1 THEN MAX(Interpret) END AS Name
+            FROM Tracks WHERE Album IS NOT NULL GROUP BY Album
+    ) AS Grouped LEFT JOIN Artists ON Artists.Name = Grouped.Name
+    ORDER BY Grouped.Album;
+
+UPDATE Tracks SET
+    ArtistKey = (SELECT Key FROM Artists WHERE Name = Tracks.Interpret),
+    AlbumKey = (SELECT Key FROM Albums WHERE Title = Tracks.Album);
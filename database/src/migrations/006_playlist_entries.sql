@@ -0,0 +1,23 @@
+-- Playlist entries, ordered by their position and identified independently of it, so that
+-- concurrent edits of a playlist on different peers can be merged (see `objects::Position`)
+CREATE TABLE IF NOT EXISTS PlaylistEntries (
+    Id          TEXT PRIMARY KEY,
+    Playlist    INTEGER NOT NULL,
+    Position    TEXT NOT NULL,
+    Track       BLOB NOT NULL,
+    Stamp       TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS PlaylistEntriesOrder ON PlaylistEntries(Playlist, Position, Id);
+
+-- split the track vector of existing playlists into entries
+WITH RECURSIVE Split(Playlist, Idx, Track, Rest) AS (
+    SELECT Key, 0, substr(Tracks, 1, 16), substr(Tracks, 17) FROM Playlists WHERE length(Tracks) >= 16
+    UNION ALL
+    SELECT Playlist, Idx + 1, substr(Rest, 1, 16), substr(Rest, 17) FROM Split WHERE length(Rest) >= 16
+)
+INSERT INTO PlaylistEntries (Id, Playlist, Position, Track, Stamp)
+    SELECT printf('%016X%08X', Playlist, Idx), Playlist, printf('%08X', Idx + 1), Track, printf('%016X%08X', Playlist, Idx)
+        FROM Split;
+
+UPDATE Playlists SET Tracks = X'';
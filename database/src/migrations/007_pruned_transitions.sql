@@ -0,0 +1,4 @@
+-- Keys of transitions removed by a checkpoint, they still count as applied references
+CREATE TABLE IF NOT EXISTS PrunedTransitions (
+    Key     BLOB PRIMARY KEY
+);
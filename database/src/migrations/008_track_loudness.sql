@@ -0,0 +1,3 @@
+-- Loudness after EBU R128 measured while encoding, missing for tracks uploaded before
+ALTER TABLE Tracks ADD COLUMN Loudness REAL;
+ALTER TABLE Tracks ADD COLUMN TruePeak REAL;
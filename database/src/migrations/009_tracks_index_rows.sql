@@ -0,0 +1,14 @@
+-- Row of each track in the full-text index, the key column of the index is not indexed and
+-- entries are therefore deleted by their rowid (see `transition.rs`)
+CREATE TABLE IF NOT EXISTS TracksIndexRows (
+    Row     INTEGER PRIMARY KEY,
+    Key     BLOB NOT NULL UNIQUE
+);
+
+INSERT OR IGNORE INTO TracksIndexRows (Key) SELECT Key FROM Tracks;
+
+-- rebuild the index with the rows of the mapping
+DELETE FROM TracksIndex;
+INSERT INTO TracksIndex(rowid, Key, Title, Album, Interpret, People, Composer)
+    SELECT TracksIndexRows.Row, Tracks.Key, Title, Album, Interpret, People, Composer
+        FROM Tracks JOIN TracksIndexRows ON TracksIndexRows.Key = Tracks.Key;
@@ -0,0 +1,9 @@
+-- Store the key of the track an event refers to as BLOB, joining with `hex(Tracks.Key) =
+-- Events.Data` can't use an index
+ALTER TABLE Events ADD COLUMN Track BLOB;
+
+-- Events of tracks which are no longer in the library can't be linked and keep only their `Data`
+UPDATE Events SET Track = (SELECT Key FROM Tracks WHERE hex(Tracks.Key) = Events.Data)
+    WHERE Tag IN ('playsong', 'addsong', 'deletesong');
+
+CREATE INDEX IF NOT EXISTS EventsTrack ON Events(Track);
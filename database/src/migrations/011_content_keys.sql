@@ -0,0 +1,42 @@
+-- Derive the keys of artists and albums from their content instead of numbering them locally, so
+-- peers creating the same entity independently end up with the same key. The functions
+-- `artist_key` and `album_key` are registered by `migration.rs`.
+
+-- artists with the same name after normalization are merged
+CREATE TEMP TABLE ArtistKeys AS
+    SELECT Key AS Old, artist_key(Name) AS New, Name FROM Artists;
+
+DELETE FROM Artists;
+INSERT INTO Artists (Key, Name)
+    SELECT New, MIN(Name) FROM ArtistKeys GROUP BY New;
+
+UPDATE Tracks SET ArtistKey = (SELECT New FROM ArtistKeys WHERE Old = Tracks.ArtistKey)
+    WHERE ArtistKey IS NOT NULL;
+UPDATE Albums SET Artist = (SELECT New FROM ArtistKeys WHERE Old = Albums.Artist)
+    WHERE Artist IS NOT NULL;
+
+CREATE TEMP TABLE AlbumKeys AS
+    SELECT Key AS Old, album_key(Title, Artist) AS New, Title, Artist,
+        Key IN (SELECT AlbumKey FROM Tracks WHERE AlbumKey IS NOT NULL) AS Used
+    FROM Albums;
+
+-- albums derived from the album title of their tracks were grouped by title only, they are
+-- grouped by title and artist now
+UPDATE Tracks SET AlbumKey = CASE
+        WHEN Album = (SELECT Title FROM AlbumKeys WHERE Old = Tracks.AlbumKey) THEN album_key(Album, ArtistKey)
+        ELSE (SELECT New FROM AlbumKeys WHERE Old = Tracks.AlbumKey)
+    END
+    WHERE AlbumKey IS NOT NULL;
+
+DELETE FROM Albums;
+INSERT OR IGNORE INTO Albums (Key, Title, Artist)
+    SELECT DISTINCT AlbumKey, Album, ArtistKey FROM Tracks
+        WHERE AlbumKey = album_key(Album, ArtistKey);
+
+-- albums assigned by hand and albums without any tracks are kept
+INSERT OR IGNORE INTO Albums (Key, Title, Artist)
+    SELECT New, Title, Artist FROM AlbumKeys
+        WHERE NOT Used OR New IN (SELECT AlbumKey FROM Tracks WHERE AlbumKey IS NOT NULL);
+
+DROP TABLE ArtistKeys;
+DROP TABLE AlbumKeys;
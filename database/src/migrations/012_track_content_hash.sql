@@ -0,0 +1,3 @@
+-- Hash of the audio data, a downloaded file is checked against it. Tracks uploaded before have
+-- none and their files are only checked frame by frame.
+ALTER TABLE Tracks ADD COLUMN ContentHash BLOB;
@@ -13,6 +13,10 @@ use sha2::{Digest, Sha256};
 
 #[cfg(feature = "hex-gossip")]
 use hex_gossip::PeerId;
+#[cfg(feature = "hex-music-container")]
+use hex_music_container::Loudness;
+
+use search::Order;
 
 /// Peer id copy
 #[cfg(not(feature="hex-gossip"))]
@@ -95,7 +99,23 @@ pub struct Track {
     /// Duration in milliseconds
     pub duration: f64,
     /// Number of favs
-    pub favs_count: u32
+    pub favs_count: u32,
+    /// The album entity containing the track
+    pub album_key: Option<AlbumKey>,
+    /// The artist entity performing the track
+    pub artist_key: Option<ArtistKey>,
+    /// Position of the track on its disc
+    pub track_number: Option<u32>,
+    /// Disc of the album containing the track
+    pub disc_number: Option<u32>,
+    /// Year of the release
+    pub year: Option<u32>,
+    /// Integrated loudness in LUFS, measured while encoding
+    pub loudness: Option<f64>,
+    /// True peak in dBTP, measured while encoding
+    pub true_peak: Option<f64>,
+    /// SHA256 hash of the audio data, verifies a file downloaded from another peer
+    pub content_hash: Option<Vec<u8>>
 }
 
 impl Track {
@@ -123,7 +143,24 @@ impl Track {
             people: None,
             composer: None,
             duration: duration,
-            favs_count: 0
+            favs_count: 0,
+            album_key: None,
+            artist_key: None,
+            track_number: None,
+            disc_number: None,
+            year: None,
+            loudness: None,
+            true_peak: None,
+            content_hash: None
+        }
+    }
+
+    /// Loudness of the track, used to normalize its playback
+    #[cfg(feature="hex-music-container")]
+    pub fn measured_loudness(&self) -> Option<Loudness> {
+        match (self.loudness, self.true_peak) {
+            (Some(integrated), Some(true_peak)) => Some(Loudness { integrated: integrated as f32, true_peak: true_peak as f32 }),
+            _ => None
         }
     }
 
@@ -141,9 +178,104 @@ impl Track {
             people:     row.get_checked(5)?,
             composer:   row.get_checked(6)?,
             duration:   row.get_checked(7)?,
-            favs_count: row.get_checked(8)?
+            favs_count: row.get_checked(8)?,
+            album_key:  row.get_checked(10)?,
+            artist_key: row.get_checked(11)?,
+            track_number: row.get_checked(12)?,
+            disc_number: row.get_checked(13)?,
+            year:       row.get_checked(14)?,
+            loudness:   row.get_checked(15)?,
+            true_peak:  row.get_checked(16)?,
+            content_hash: row.get_checked(17)?
+        })
+    }
+}
+
+/// Artist identification
+pub type ArtistKey = i64;
+
+/// An artist, for example a band or a single interpret
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct Artist {
+    /// A unique key used to access the artist
+    pub key: ArtistKey,
+    /// Name of the artist
+    pub name: String
+}
+
+#[cfg(feature = "rusqlite")]
+impl Artist {
+    pub fn from_row(row: &Row) -> Result<Artist> {
+        Ok(Artist {
+            key:    row.get_checked(0)?,
+            name:   row.get_checked(1)?
+        })
+    }
+
+    /// Derive the key of an artist from its name, ignoring case and whitespace
+    pub fn key_from_name(name: &str) -> ArtistKey {
+        content_key(&["artist", &normalize(name)])
+    }
+}
+
+/// Normalize a name before deriving a key from it
+#[cfg(feature = "rusqlite")]
+fn normalize(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Derive a positive key from the hash of some strings
+///
+/// Peers creating the same entity independently derive the same key, so their transitions
+/// upsert a single entity instead of two with colliding keys.
+#[cfg(feature = "rusqlite")]
+fn content_key(parts: &[&str]) -> i64 {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.input(part.as_bytes());
+        hasher.input(&[0u8]);
+    }
+
+    let hash = hasher.result();
+    let key = hash[0..8].iter().fold(0u64, |acc, x| (acc << 8) | *x as u64);
+
+    (key >> 1) as i64
+}
+
+/// Album identification
+pub type AlbumKey = i64;
+
+/// An album containing many tracks
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct Album {
+    /// A unique key used to access the album
+    pub key: AlbumKey,
+    /// Title of the album
+    pub title: String,
+    /// The artist releasing the album, unknown for compilations
+    pub artist: Option<ArtistKey>
+}
+
+#[cfg(feature = "rusqlite")]
+impl Album {
+    pub fn from_row(row: &Row) -> Result<Album> {
+        Ok(Album {
+            key:    row.get_checked(0)?,
+            title:  row.get_checked(1)?,
+            artist: row.get_checked(2)?
         })
     }
+
+    /// Derive the key of an album from its title and artist, ignoring case and whitespace
+    ///
+    /// Albums with the same title by different artists have different keys.
+    pub fn key_from_title(title: &str, artist: Option<ArtistKey>) -> AlbumKey {
+        let artist = artist.map(|x| x.to_string()).unwrap_or_default();
+
+        content_key(&["album", &normalize(title), &artist])
+    }
 }
 
 /// Playlist identification
@@ -162,7 +294,24 @@ pub struct Playlist {
     /// Vector of all track keys
     pub tracks: Vec<TrackKey>,
     /// The author of this playlist
-    pub origin: PeerId
+    pub origin: PeerId,
+    /// Search query of a smart playlist, the tracks are evaluated on read
+    pub query: Option<PlaylistQuery>
+}
+
+/// Definition of a smart playlist
+///
+/// The tracks of a smart playlist are the results of a search, for example the query
+/// `interpret:Bach` ordered by favs and limited to 50 tracks.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct PlaylistQuery {
+    /// Search query in the syntax of `SearchQuery`
+    pub query: String,
+    /// Order of the tracks
+    pub order: Order,
+    /// Maximal number of tracks
+    pub limit: Option<u32>
 }
 
 #[cfg(feature = "rusqlite")]
@@ -171,7 +320,8 @@ impl Playlist {
         Playlist {
             key, title, origin,
             desc: None,
-            tracks: Vec::new()
+            tracks: Vec::new(),
+            query: None
         }
     }
 
@@ -180,15 +330,128 @@ impl Playlist {
         let keys: Vec<TrackKey> = keys.chunks(16)
             .map(|x| TrackKey::from_vec(x)).collect();
 
+        let query: Option<String> = row.get_checked(5)?;
+        let order: Option<String> = row.get_checked(6)?;
+        let query = match query {
+            Some(query) => Some(PlaylistQuery {
+                query: query,
+                order: order.and_then(|x| Order::from_name(&x)).unwrap_or(Order::ByRelevance),
+                limit: row.get_checked(7)?
+            }),
+            None => None
+        };
+
         Ok(Playlist {
             key:    row.get_checked(0)?,
             title:  row.get_checked(1)?,
             desc:   row.get_checked(2)?,
             tracks: keys,
-            origin: row.get_checked(4)?
+            origin: row.get_checked(4)?,
+            query:  query
+        })
+    }
+}
+
+/// Identification of a playlist entry
+///
+/// New ids begin with the creation time and end with the creating peer, so they are unique and
+/// later ids compare greater. They also serve as timestamp of the last move of an entry.
+pub type EntryId = String;
+
+/// Position of an entry in a playlist
+///
+/// A position is a sequence of digits compared lexicographically. There is always room for a new
+/// position between two others, so a track can be inserted anywhere without touching its
+/// neighbours and concurrent edits of different peers can be merged in any order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct Position(pub Vec<u32>);
+
+impl Position {
+    /// Create a new position between `before` and `after`, `None` being the beginning or end
+    ///
+    /// The last digit is derived from the `id` of the new entry, this keeps positions created
+    /// concurrently at the same place apart.
+    pub fn between(before: Option<&Position>, after: Option<&Position>, id: &str) -> Position {
+        // ignore an upper bound not greater than the lower bound
+        let after = after.filter(|after| before.map(|before| before < *after).unwrap_or(true));
+
+        let mut digits = Vec::new();
+        let mut bounded = after.is_some();
+        let mut i = 0;
+        loop {
+            let lo = before.and_then(|x| x.0.get(i)).map(|x| *x as u64).unwrap_or(0);
+            let hi = match after {
+                Some(after) if bounded => after.0.get(i).map(|x| *x as u64).unwrap_or(0),
+                _ => 1 << 32
+            };
+
+            // take the middle digit, if there is one between the bounds
+            if hi > lo + 1 {
+                digits.push(((lo + hi) / 2) as u32);
+                break;
+            }
+
+            // otherwise follow the lower bound, which is now below the upper bound
+            digits.push(lo as u32);
+            if hi > lo {
+                bounded = false;
+            }
+
+            i += 1;
+        }
+
+        // the last digit is never zero, so there is also room before each position
+        let site = id.bytes().fold(0u32, |hash, x| hash.wrapping_mul(31).wrapping_add(x as u32));
+        digits.push(site.max(1));
+
+        Position(digits)
+    }
+
+    /// Parse a position from its string representation
+    pub fn from_str(buf: &str) -> Option<Position> {
+        if buf.len() % 8 != 0 || !buf.is_ascii() {
+            return None;
+        }
+
+        (0..buf.len() / 8)
+            .map(|i| u32::from_str_radix(&buf[i*8..i*8+8], 16).ok())
+            .collect::<Option<Vec<u32>>>()
+            .map(|x| Position(x))
+    }
+
+    /// Convert to a string with eight hex characters per digit, which keeps the ordering
+    pub fn to_string(&self) -> String {
+        self.0.iter().map(|x| format!("{:08X}", x)).collect()
+    }
+}
+
+/// A single track in a playlist
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct PlaylistEntry {
+    /// A unique id of the entry
+    pub id: EntryId,
+    /// Position in the playlist, entries with the same position are ordered by their id
+    pub position: Position,
+    /// The track itself
+    pub track: TrackKey
+}
+
+#[cfg(feature = "rusqlite")]
+impl PlaylistEntry {
+    pub fn from_row(row: &Row) -> Result<PlaylistEntry> {
+        let position: String = row.get_checked(1)?;
+        let track: Vec<u8> = row.get_checked(2)?;
+
+        Ok(PlaylistEntry {
+            id:         row.get_checked(0)?,
+            position:   Position::from_str(&position).unwrap_or(Position(Vec::new())),
+            track:      TrackKey::from_vec(&track)
         })
     }
 }
+
 /// Token identification
 pub type TokenId = i64;
 
@@ -0,0 +1,113 @@
+//! Permissions of other peers to change the database
+//!
+//! Every peer knowing the network key can connect to us, but only peers in the trust list may
+//! change the database. The role of a peer decides which transitions of it are approved. An empty
+//! trust list approves every peer, like it was before roles were introduced.
+
+use std::collections::HashMap;
+
+use hex_gossip::{PeerId, Transition};
+use transition::TransitionAction;
+
+/// Role of a trusted peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Can change everything
+    Full,
+    /// Receives the library, but can't change anything
+    ReadOnly,
+    /// Can only update tokens and log events, for example a zyklop box
+    TokenOnly
+}
+
+impl Role {
+    /// Parse a role from its name in the configuration
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name {
+            "full" => Some(Role::Full),
+            "read-only" => Some(Role::ReadOnly),
+            "token-only" => Some(Role::TokenOnly),
+            _ => None
+        }
+    }
+
+    /// Check whether a peer with this role may commit `action`
+    pub fn allows(&self, action: &TransitionAction) -> bool {
+        match (self, action) {
+            (Role::Full, _) => true,
+            (Role::TokenOnly, TransitionAction::UpsertToken(_)) => true,
+            (Role::TokenOnly, TransitionAction::DeleteToken(_)) => true,
+            (Role::TokenOnly, TransitionAction::AddEvent(_)) => true,
+            _ => false
+        }
+    }
+}
+
+/// Trust list mapping the ids of other peers to their role
+#[derive(Debug, Clone, Default)]
+pub struct Permissions {
+    roles: HashMap<PeerId, Role>
+}
+
+impl Permissions {
+    /// Create an empty trust list, approving every peer
+    pub fn new() -> Permissions {
+        Permissions { roles: HashMap::new() }
+    }
+
+    /// Create a trust list from peer ids and the names of their roles, unknown roles are skipped
+    pub fn from_names(peers: Vec<(PeerId, String)>) -> Permissions {
+        let mut permissions = Permissions::new();
+
+        for (id, name) in peers {
+            match Role::from_name(&name) {
+                Some(role) => permissions = permissions.trust(id, role),
+                None => error!("Unknown role {} of peer {}", name, peer_to_string(&id))
+            }
+        }
+
+        permissions
+    }
+
+    /// Trust a peer with a certain role
+    pub fn trust(mut self, id: PeerId, role: Role) -> Permissions {
+        self.roles.insert(id, role);
+
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roles.is_empty()
+    }
+
+    /// Get the role of a peer, `None` if the peer is not trusted at all
+    pub fn role(&self, id: &PeerId) -> Option<Role> {
+        if self.roles.is_empty() {
+            return Some(Role::Full);
+        }
+
+        self.roles.get(id).cloned()
+    }
+
+    /// Check whether the author of `trans` may commit its `action`, logs the peer otherwise
+    pub fn approve(&self, trans: &Transition, action: &TransitionAction) -> bool {
+        match self.role(&trans.pk) {
+            Some(role) if role.allows(action) => true,
+            Some(role) => {
+                warn!("Refused transition {} of peer {} with role {:?}", trans.key.to_string(), peer_to_string(&trans.pk), role);
+
+                false
+            },
+            None => {
+                warn!("Refused transition {} of untrusted peer {}", trans.key.to_string(), peer_to_string(&trans.pk));
+
+                false
+            }
+        }
+    }
+}
+
+/// Format a peer id as hex string, like in the configuration
+pub fn peer_to_string(id: &PeerId) -> String {
+    id.iter().map(|x| format!("{:02X}", x)).collect()
+}
@@ -0,0 +1,347 @@
+//! Replicate audio files from other peers according to a policy
+//!
+//! Without replication a peer fetches files only on demand with `Instance::ask_for_file`. A
+//! replication runs in the background and fetches all files wanted by its `Policy`, once the
+//! database caught up with the network. We assume that this is the case when no new transition
+//! arrived during an interval. The files are fetched in small batches. A batch is given up after
+//! a timeout, so that files missing in the whole network don't block the others. They are
+//! requested again in the next round. A cache removes only files which another peer is known to
+//! have, so that no file is lost for the whole network.
+
+#[cfg(feature="rusqlite")]
+use std::collections::{HashMap, HashSet};
+#[cfg(feature="rusqlite")]
+use std::fs;
+#[cfg(feature="rusqlite")]
+use std::path::{Path, PathBuf};
+#[cfg(feature="rusqlite")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature="rusqlite")]
+use std::thread;
+#[cfg(feature="rusqlite")]
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+#[cfg(feature="rusqlite")]
+use hex_gossip::Spread;
+
+#[cfg(feature="rusqlite")]
+use error::{Error, Result};
+#[cfg(feature="rusqlite")]
+use database::View;
+#[cfg(feature="rusqlite")]
+use transition::Storage;
+#[cfg(feature="rusqlite")]
+use objects::TrackKey;
+use objects::{PlaylistKey, TokenId};
+
+/// Waiting time between two rounds of the replication
+#[cfg(feature="rusqlite")]
+const INTERVAL: Duration = Duration::from_secs(10);
+/// Number of files fetched at the same time
+#[cfg(feature="rusqlite")]
+const BATCH_SIZE: usize = 4;
+/// Give up a batch after this time and continue with the next one
+#[cfg(feature="rusqlite")]
+const BATCH_TIMEOUT: Duration = Duration::from_secs(120);
+/// Bytes per second of audio, if there is no file to estimate it from (maximal bitrate of Opus)
+#[cfg(feature="rusqlite")]
+const DEFAULT_RATE: f64 = 64_000.0;
+
+/// Which files a peer keeps in its data section
+#[derive(Debug, Clone, PartialEq)]
+pub enum Policy {
+    /// Fetch files only on demand
+    OnDemand,
+    /// Keep every file available in the network
+    MirrorAll,
+    /// Keep the files of certain playlists and tokens
+    Selected {
+        playlists: Vec<PlaylistKey>,
+        tokens: Vec<TokenId>
+    },
+    /// Keep the most recently played files, as long as they fit into `quota` bytes
+    Cache {
+        quota: u64
+    }
+}
+
+#[cfg(feature="rusqlite")]
+impl Policy {
+    /// Choose a policy from the peer configuration
+    ///
+    /// Mirroring everything takes precedence over a quota, which takes precedence over selected
+    /// playlists and tokens.
+    pub fn new(sync_all: bool, playlists: Vec<PlaylistKey>, tokens: Vec<TokenId>, quota: Option<u64>) -> Policy {
+        match quota {
+            _ if sync_all => Policy::MirrorAll,
+            Some(quota) => Policy::Cache { quota },
+            None if !playlists.is_empty() || !tokens.is_empty() => Policy::Selected { playlists, tokens },
+            None => Policy::OnDemand
+        }
+    }
+
+    /// All tracks wanted by the policy, the most important first
+    ///
+    /// The cache returns every played track, the quota is only considered by the replication.
+    pub fn tracks(&self, view: &View) -> Result<Vec<TrackKey>> {
+        let keys = match self {
+            Policy::OnDemand => Vec::new(),
+            Policy::MirrorAll => view.get_tracks()?.into_iter().map(|x| x.key).collect(),
+            Policy::Selected { playlists, tokens } => {
+                let mut keys = Vec::new();
+
+                // skip playlists and tokens which were deleted in the meantime
+                for key in playlists {
+                    match view.get_playlist(*key) {
+                        Ok((_, tracks)) => keys.extend(tracks.into_iter().map(|x| x.key)),
+                        Err(Error::NotFound) => {},
+                        Err(err) => return Err(err)
+                    }
+                }
+
+                for token in tokens {
+                    match view.get_token(*token) {
+                        Ok((_, Some((_, tracks)))) => keys.extend(tracks.into_iter().map(|x| x.key)),
+                        Ok((_, None)) | Err(Error::NotFound) => {},
+                        Err(err) => return Err(err)
+                    }
+                }
+
+                keys
+            },
+            Policy::Cache { .. } => view.get_recently_played()?.into_iter().map(|(key, _)| key).collect()
+        };
+
+        // remove duplicates, but keep the order
+        let mut seen = HashSet::new();
+
+        Ok(keys.into_iter().filter(|x| seen.insert(*x)).collect())
+    }
+}
+
+/// Progress of a replication
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct Progress {
+    /// The database caught up with the network and the replication started
+    pub caught_up: bool,
+    /// Number of files wanted in the last round
+    pub wanted: usize,
+    /// Number of wanted files not available yet
+    pub missing: usize,
+    /// Number of files fetched since the start
+    pub fetched: usize,
+    /// Number of files removed from the cache since the start
+    pub evicted: usize
+}
+
+/// Handle of a replication running in the background, which stops once all handles are dropped
+#[cfg(feature="rusqlite")]
+#[derive(Clone)]
+pub struct Replication {
+    progress: Arc<Mutex<Progress>>
+}
+
+#[cfg(feature="rusqlite")]
+impl Replication {
+    /// Start a replication of the files in `data_path`
+    pub(crate) fn start(view: View, spread: Spread<Storage>, data_path: PathBuf, policy: Policy) -> Replication {
+        let progress = Arc::new(Mutex::new(Progress::default()));
+
+        let replicator = Replicator { view, spread, data_path, policy, progress: progress.clone() };
+        thread::spawn(move || replicator.run());
+
+        Replication { progress }
+    }
+
+    /// Get the current progress
+    pub fn progress(&self) -> Progress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    /// Number of wanted files not available yet
+    pub fn missing(&self) -> usize {
+        self.progress.lock().unwrap().missing
+    }
+}
+
+#[cfg(feature="rusqlite")]
+struct Replicator {
+    view: View,
+    spread: Spread<Storage>,
+    data_path: PathBuf,
+    policy: Policy,
+    progress: Arc<Mutex<Progress>>
+}
+
+#[cfg(feature="rusqlite")]
+impl Replicator {
+    fn run(self) {
+        let mut last_count = None;
+
+        while !self.stopped() {
+            thread::sleep(INTERVAL);
+
+            // wait until no new transitions arrive
+            let count = match self.view.count_transitions() {
+                Ok(count) => count,
+                Err(err) => {
+                    error!("Could not count transitions: {:?}", err);
+                    continue;
+                }
+            };
+
+            if last_count.replace(count) != Some(count) {
+                continue;
+            }
+
+            self.progress.lock().unwrap().caught_up = true;
+
+            if let Err(err) = self.round() {
+                error!("Replication failed: {:?}", err);
+            }
+        }
+    }
+
+    /// The handle was dropped
+    fn stopped(&self) -> bool {
+        Arc::strong_count(&self.progress) == 1
+    }
+
+    fn path(&self, key: &TrackKey) -> PathBuf {
+        self.data_path.join(key.to_path())
+    }
+
+    /// Fetch all missing files wanted by the policy
+    fn round(&self) -> Result<()> {
+        let mut wanted = self.policy.tracks(&self.view)?;
+
+        // only fetch as many of the recently played tracks as fit into the cache
+        if let Policy::Cache { quota } = self.policy {
+            let sizes: Vec<Option<u64>> = wanted.iter()
+                .map(|key| fs::metadata(self.path(key)).map(|x| x.len()).ok())
+                .collect();
+            let durations: Vec<f64> = wanted.iter()
+                .map(|key| self.view.get_track(*key).map(|x| x.duration).unwrap_or(0.0))
+                .collect();
+
+            // the size of a missing file is estimated from its duration and the average rate of
+            // the files we have
+            let (bytes, seconds) = sizes.iter().zip(&durations)
+                .filter_map(|(size, duration)| size.map(|x| (x, *duration)))
+                .fold((0, 0.0), |(bytes, seconds), (size, duration)| (bytes + size, seconds + duration));
+            let rate = if bytes > 0 && seconds > 0.0 { bytes as f64 / seconds } else { DEFAULT_RATE };
+
+            let mut used = 0;
+            wanted = wanted.into_iter().zip(sizes.into_iter().zip(durations))
+                .take_while(|(_, (size, duration))| {
+                    used += size.unwrap_or((duration * rate) as u64);
+
+                    used < quota
+                })
+                .map(|(key, _)| key)
+                .collect();
+        }
+
+        let missing: Vec<TrackKey> = wanted.iter().filter(|x| !self.path(x).exists()).cloned().collect();
+
+        {
+            let mut progress = self.progress.lock().unwrap();
+            progress.wanted = wanted.len();
+            progress.missing = missing.len();
+        }
+
+        for batch in missing.chunks(BATCH_SIZE) {
+            if self.stopped() {
+                return Ok(());
+            }
+
+            for key in batch {
+                self.spread.request_file(key.to_vec());
+            }
+
+            let start = Instant::now();
+            let mut fetched = 0;
+            while start.elapsed() < BATCH_TIMEOUT {
+                fetched = batch.iter().filter(|x| self.path(x).exists()).count();
+                if fetched == batch.len() {
+                    break;
+                }
+
+                thread::sleep(Duration::from_millis(500));
+            }
+
+            let mut progress = self.progress.lock().unwrap();
+            progress.fetched += fetched;
+            progress.missing -= fetched;
+        }
+
+        if let Policy::Cache { quota } = self.policy {
+            let played = self.view.get_recently_played()?.into_iter().collect();
+            let (evicted, kept) = evict(&self.data_path, quota, &played, |key| !self.spread.holders(&key.to_vec()).is_empty())?;
+
+            // look for other peers having the files we had to keep, they are removed next round
+            for key in kept {
+                self.spread.locate_file(key.to_vec());
+            }
+
+            self.progress.lock().unwrap().evicted += evicted;
+        }
+
+        Ok(())
+    }
+}
+
+/// Remove the least recently used files until the data section fits into `quota` bytes
+///
+/// A file was used when its track was played last, or modified if it was never played. Files are
+/// only removed if another peer is known to have them, according to `shared`, otherwise they
+/// would be lost for the whole network. Returns the number of removed files and the keys of files
+/// kept for this reason.
+#[cfg(feature="rusqlite")]
+pub(crate) fn evict<F: Fn(&TrackKey) -> bool>(data_path: &Path, quota: u64, played: &HashMap<TrackKey, i64>, shared: F) -> Result<(usize, Vec<TrackKey>)> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(data_path).map_err(|e| Error::Io(e))? {
+        let entry = entry.map_err(|e| Error::Io(e))?;
+        let metadata = entry.metadata().map_err(|e| Error::Io(e))?;
+
+        // skip partial files and anything else not named by a track key
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !metadata.is_file() || name.len() != 32 || !name.chars().all(|x| x.is_ascii_hexdigit()) {
+            continue;
+        }
+
+        let used = match played.get(&TrackKey::from_str(&name)) {
+            Some(date) => *date,
+            None => metadata.modified().ok()
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .map(|x| x.as_secs() as i64)
+                .unwrap_or(0)
+        };
+
+        files.push((used, metadata.len(), TrackKey::from_str(&name), entry.path()));
+    }
+
+    // keep the most recently used files
+    files.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut used = 0;
+    let mut evicted = 0;
+    let mut kept = Vec::new();
+    for (_, size, key, path) in files {
+        used += size;
+
+        if used <= quota {
+            continue;
+        }
+
+        if shared(&key) {
+            fs::remove_file(path).map_err(|e| Error::Io(e))?;
+            evicted += 1;
+        } else {
+            kept.push(key);
+        }
+    }
+
+    Ok((evicted, kept))
+}
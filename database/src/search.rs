@@ -1,45 +1,139 @@
 //! Search query parser
 //!
 //! This module parses a query and converts it into a SQL statement. This statement can be used in
-//! the database to search for tracks.
+//! the database to search for tracks. The text part of a query is matched against the FTS5 index
+//! `TracksIndex`, which folds diacritics and allows prefix matches (e.g. "bjork" finds "Björk").
+//!
+//! ## Syntax
+//! A query consists of words, quoted phrases and fields, which are all combined with AND. Commas
+//! are optional separators, as used by older clients (e.g. `title:Crazy,order:favs`).
+//!
+//! | syntax                     | meaning                                             |
+//! |----------------------------|-----------------------------------------------------|
+//! | `blue sky`                 | both words (as prefix) in any text column           |
+//! | `"Daft Punk"`              | the exact phrase in any text column                 |
+//! | `-remix`, `NOT remix`      | the word must not occur                             |
+//! | `a OR b`, `a | b`          | either of both                                      |
+//! | `(a | b) c`                | grouping                                            |
+//! | `interpret:(Bach|Händel)`  | restrict words or groups to a single column         |
+//! | `duration:>300`            | compare with `>`, `>=`, `<`, `<=` or `=`            |
+//! | `favs:2..5`                | inclusive range                                     |
+//! | `created:>=2019-01-01`     | date comparison (`created:2019-05` is a prefix)     |
+//! | `order:favs`               | order by `relevance`, `date`, `title` or `favs`     |
+//!
+//! Syntax errors are returned as `ParseError` with the character position in the query.
+
+/// Columns which can be used in a field, like 'title:Crazy'
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Title,
+    Album,
+    Interpret,
+    People,
+    Composer,
+    Duration,
+    FavsCount,
+    Created
+}
+
+impl Field {
+    /// Get the field from its name in a search query
+    pub fn from_name(name: &str) -> Option<Field> {
+        match name {
+            "title" => Some(Field::Title),
+            "album" => Some(Field::Album),
+            "interpret" => Some(Field::Interpret),
+            "people" | "performer" => Some(Field::People),
+            "composer" => Some(Field::Composer),
+            "duration" => Some(Field::Duration),
+            "favs" => Some(Field::FavsCount),
+            "created" | "date" => Some(Field::Created),
+            _ => None
+        }
+    }
+
+    /// Stringify the enum to the column name
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Field::Title => "Title",
+            Field::Album => "Album",
+            Field::Interpret => "Interpret",
+            Field::People => "People",
+            Field::Composer => "Composer",
+            Field::Duration => "Duration",
+            Field::FavsCount => "FavsCount",
+            Field::Created => "Created"
+        }
+    }
+
+    /// Is the column part of the full-text index?
+    pub fn is_text(&self) -> bool {
+        match *self {
+            Field::Duration | Field::FavsCount | Field::Created => false,
+            _ => true
+        }
+    }
+}
 
-/// Enum providing allowed tags in the search query, like 'title:Crazy'
-pub enum Tag {
-    Any(String),
-    Title(String),
-    Album(String),
-    Interpret(String),
-    People(String),
-    Composer(String)
+/// Comparison operator of a range query, like 'duration:>300'
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Equal,
+    GreaterEqual,
+    Greater
+}
+
+impl Comparison {
+    /// Stringify the enum to a SQL operator
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Comparison::Less => "<",
+            Comparison::LessEqual => "<=",
+            Comparison::Equal => "=",
+            Comparison::GreaterEqual => ">=",
+            Comparison::Greater => ">"
+        }
+    }
 }
 
 /// Order by certain field
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
 pub enum Order {
+    ByRelevance,
     ByDate,
     ByTitle,
     ByFavs
 }
 
 impl Order {
-    /// Create a new ordering from a query
-    pub fn from_search_query(query: &str) -> Option<Order> {
-        let elms = query.split(':').collect::<Vec<&str>>();
-
-        if elms.len() == 2 && elms[0] == "order" {
-            return match elms[1] {
-                "date" => Some(Order::ByDate),
-                "title" => Some(Order::ByTitle),
-                "favs" => Some(Order::ByFavs),
-                _ => None
-            };
+    /// Create a new ordering from the value of an 'order:' field
+    pub fn from_name(name: &str) -> Option<Order> {
+        match name {
+            "relevance" => Some(Order::ByRelevance),
+            "date" => Some(Order::ByDate),
+            "title" => Some(Order::ByTitle),
+            "favs" => Some(Order::ByFavs),
+            _ => None
         }
+    }
 
-        None
+    /// Value of the 'order:' field, inverse of `from_name`
+    pub fn keyword(&self) -> &'static str {
+        match *self {
+            Order::ByRelevance => "relevance",
+            Order::ByDate => "date",
+            Order::ByTitle => "title",
+            Order::ByFavs => "favs"
+        }
     }
 
     /// Stringify the enum
     pub fn name(&self) -> String {
         let tmp = match *self {
+            Order::ByRelevance => "rank",
             Order::ByDate => "Created",
             Order::ByTitle => "Title",
             Order::ByFavs => "FavsCount"
@@ -47,73 +141,582 @@ impl Order {
 
         tmp.into()
     }
+
+    /// Converts the ordering to a SQL `ORDER BY` clause
+    ///
+    /// The relevance is only known for full-text searches, otherwise the newest tracks come first.
+    fn to_sql_query(&self, rank: Option<&str>) -> String {
+        match (*self, rank) {
+            (Order::ByRelevance, Some(rank)) => rank.into(),
+            (Order::ByRelevance, None) => "Tracks.Created DESC".into(),
+            _ => format!("Tracks.{} DESC", self.name())
+        }
+    }
+}
+
+/// Syntax error in a search query
+///
+/// All positions are counted in characters from the beginning of the query.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub enum ParseError {
+    /// The query ended, but something more was expected (e.g. after `OR`)
+    UnexpectedEnd,
+    /// A token which is not allowed at this position
+    UnexpectedToken { pos: usize, token: String },
+    /// A quoted phrase was never closed
+    UnclosedQuote { pos: usize },
+    /// A parenthesis was never closed
+    UnclosedGroup { pos: usize },
+    /// The field name is not known
+    UnknownField { pos: usize, field: String },
+    /// The order name is not known
+    UnknownOrder { pos: usize, order: String },
+    /// The value can not be compared with the field (e.g. 'favs:>many')
+    InvalidValue { pos: usize, value: String }
+}
+
+/// Abstract syntax tree of a search query
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// Match a word (as prefix) or a phrase, either in all text columns or a single one
+    Text {
+        field: Option<Field>,
+        text: String,
+        phrase: bool
+    },
+    /// Compare a column with a value
+    Compare {
+        field: Field,
+        op: Comparison,
+        value: String
+    },
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>)
+}
+
+impl Expr {
+    /// Converts a text match to a FTS5 expression
+    ///
+    /// Words and phrases are always quoted, so the user text can never be interpreted as FTS5
+    /// syntax. Words are matched as prefix.
+    fn text_query(field: &Option<Field>, text: &str, phrase: bool) -> String {
+        let mut tmp = format!("\"{}\"", text.replace('"', "\"\""));
+
+        if !phrase {
+            tmp.push('*');
+        }
+
+        match *field {
+            Some(ref field) => format!("{} : {}", field.name(), tmp),
+            None => tmp
+        }
+    }
+
+    /// Converts the expression to a single FTS5 query, if it only consists of text matches
+    /// combined with AND and OR
+    pub fn to_match_query(&self) -> Option<String> {
+        match *self {
+            Expr::Text { ref field, ref text, phrase } => Some(Expr::text_query(field, text, phrase)),
+            Expr::And(ref exprs) => exprs.iter().map(|x| x.to_match_query())
+                .collect::<Option<Vec<String>>>()
+                .map(|x| format!("({})", x.join(" AND "))),
+            Expr::Or(ref exprs) => exprs.iter().map(|x| x.to_match_query())
+                .collect::<Option<Vec<String>>>()
+                .map(|x| format!("({})", x.join(" OR "))),
+            _ => None
+        }
+    }
+
+    /// Converts the expression to a SQL condition and appends the bound values to `params`
+    pub fn to_sql_query(&self, params: &mut Vec<String>) -> String {
+        match *self {
+            Expr::Text { ref field, ref text, phrase } => {
+                params.push(Expr::text_query(field, text, phrase));
+
+                "Tracks.Key IN (SELECT Key FROM TracksIndex WHERE TracksIndex MATCH ?)".into()
+            },
+            Expr::Compare { field: Field::Created, op: Comparison::Equal, ref value } => {
+                params.push(format!("{}%", value));
+
+                "Tracks.Created LIKE ?".into()
+            },
+            Expr::Compare { ref field, ref op, ref value } => {
+                params.push(value.clone());
+
+                format!("Tracks.{} {} ?", field.name(), op.name())
+            },
+            Expr::Not(ref expr) => format!("NOT {}", expr.to_sql_query(params)),
+            Expr::And(ref exprs) => format!("({})", exprs.iter().map(|x| x.to_sql_query(params)).collect::<Vec<String>>().join(" AND ")),
+            Expr::Or(ref exprs) => format!("({})", exprs.iter().map(|x| x.to_sql_query(params)).collect::<Vec<String>>().join(" OR "))
+        }
+    }
+
+    /// Collect all text matches which are not negated, they are used to rank the results
+    fn positive_matches(&self, out: &mut Vec<String>) {
+        match *self {
+            Expr::Text { ref field, ref text, phrase } => out.push(Expr::text_query(field, text, phrase)),
+            Expr::And(ref exprs) | Expr::Or(ref exprs) => {
+                for expr in exprs {
+                    expr.positive_matches(out);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Tokens of a search query
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    Field(String),
+    Not,
+    And,
+    Or,
+    Open,
+    Close
+}
+
+impl Token {
+    fn to_string(&self) -> String {
+        match *self {
+            Token::Word(ref x) => x.clone(),
+            Token::Phrase(ref x) => format!("\"{}\"", x),
+            Token::Field(ref x) => format!("{}:", x),
+            Token::Not => "-".into(),
+            Token::And => ",".into(),
+            Token::Or => "|".into(),
+            Token::Open => "(".into(),
+            Token::Close => ")".into()
+        }
+    }
 }
 
-impl Tag {
-    /// Create a new tag from a search query
-    pub fn from_search_query(query: &str) -> Option<Tag> {
-        let elms = query.split(':').collect::<Vec<&str>>();
+/// Split a query into tokens together with their position
+///
+/// Words and phrases without any alphanumeric character are dropped. A colon only starts a field
+/// if everything in front of it is alphabetic, otherwise it is part of the word (e.g. 'Op. 27:2').
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens: Vec<(usize, Token)> = Vec::new();
+    let mut i = 0;
+
+    let is_special = |c: char| c.is_whitespace() || c == ',' || c == '|' || c == '(' || c == ')' || c == '"';
+
+    while i < chars.len() {
+        let start = i;
+        let after_field = match tokens.last() {
+            Some(&(_, Token::Field(_))) => true,
+            _ => false
+        };
+
+        match chars[i] {
+            c if c.is_whitespace() => { i += 1; },
+            ',' => { tokens.push((start, Token::And)); i += 1; },
+            '|' => { tokens.push((start, Token::Or)); i += 1; },
+            '(' => { tokens.push((start, Token::Open)); i += 1; },
+            ')' => { tokens.push((start, Token::Close)); i += 1; },
+            '"' => {
+                let len = chars[i+1..].iter().position(|x| *x == '"')
+                    .ok_or(ParseError::UnclosedQuote { pos: start })?;
+
+                let phrase: String = chars[i+1..i+1+len].iter().collect();
+                if phrase.chars().any(char::is_alphanumeric) {
+                    tokens.push((start, Token::Phrase(phrase)));
+                }
 
-        if elms.len() == 1 {
-            Some(Tag::Any(elms[0].into()))
+                i += len + 2;
+            },
+            '-' => {
+                // a single dash is most likely a separator (e.g. 'Daft Punk - Around the World')
+                if i + 1 < chars.len() && !is_special(chars[i+1]) {
+                    tokens.push((start, Token::Not));
+                }
+
+                i += 1;
+            },
+            _ => {
+                let mut word = String::new();
+
+                while i < chars.len() && !is_special(chars[i]) {
+                    if chars[i] == ':' && !word.is_empty() && !after_field && word.chars().all(char::is_alphabetic) {
+                        break;
+                    }
+
+                    word.push(chars[i]);
+                    i += 1;
+                }
+
+                if i < chars.len() && chars[i] == ':' {
+                    tokens.push((start, Token::Field(word.to_lowercase())));
+                    i += 1;
+                } else if after_field && word.chars().any(char::is_alphanumeric) {
+                    tokens.push((start, Token::Word(word)));
+                } else if word == "OR" {
+                    tokens.push((start, Token::Or));
+                } else if word == "AND" {
+                    tokens.push((start, Token::And));
+                } else if word == "NOT" {
+                    tokens.push((start, Token::Not));
+                } else if word.chars().any(char::is_alphanumeric) {
+                    tokens.push((start, Token::Word(word)));
+                }
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser over the tokens of a query
+///
+/// ```text
+/// or      := and (("OR" | "|") and)*
+/// and     := unary ([","] unary)*
+/// unary   := ("-" | "NOT") unary | primary
+/// primary := "(" or ")" | field ":" value | word | phrase
+/// ```
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|x| &x.1)
+    }
+
+    fn next(&mut self) -> Option<(usize, Token)> {
+        let tmp = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+
+        tmp
+    }
+
+    fn unexpected(&self) -> ParseError {
+        match self.tokens.get(self.pos) {
+            Some(&(pos, ref token)) => ParseError::UnexpectedToken { pos, token: token.to_string() },
+            None => ParseError::UnexpectedEnd
+        }
+    }
+
+    fn parse_or(&mut self, field: Option<Field>) -> Result<Expr, ParseError> {
+        let mut exprs = vec![self.parse_and(field)?];
+
+        while let Some(&Token::Or) = self.peek() {
+            self.next();
+            exprs.push(self.parse_and(field)?);
+        }
+
+        if exprs.len() == 1 {
+            Ok(exprs.remove(0))
         } else {
-            match elms[0] {
-                "title" | "TITLE" => Some(Tag::Title(elms[1].into())),
-                "album" | "ALBUM" => Some(Tag::Album(elms[1].into())),
-                "interpret" | "INTERPRET" => Some(Tag::Interpret(elms[1].into())),
-                "people" | "performer" | "PEOPLE" | "PERFORMER" => Some(Tag::People(elms[1].into())),
-                "composer" | "COMPOSER" => Some(Tag::Composer(elms[1].into())),
-                _ => return None
+            Ok(Expr::Or(exprs))
+        }
+    }
+
+    fn parse_and(&mut self, field: Option<Field>) -> Result<Expr, ParseError> {
+        let mut exprs = Vec::new();
+
+        loop {
+            match self.peek() {
+                None | Some(&Token::Or) | Some(&Token::Close) => break,
+                Some(&Token::And) => { self.next(); },
+                _ => exprs.push(self.parse_unary(field)?)
             }
         }
+
+        match exprs.len() {
+            0 => Err(self.unexpected()),
+            1 => Ok(exprs.remove(0)),
+            _ => Ok(Expr::And(exprs))
+        }
+    }
+
+    fn parse_unary(&mut self, field: Option<Field>) -> Result<Expr, ParseError> {
+        if let Some(&Token::Not) = self.peek() {
+            self.next();
+
+            return self.parse_unary(field).map(|x| Expr::Not(Box::new(x)));
+        }
+
+        self.parse_primary(field)
     }
 
-    /// Converts the tag to a SQL statement
-    pub fn to_sql_query(self) -> String {
-        match self {
-            Tag::Any(x) => format!("Title LIKE '%{}%' OR Album LIKE '%{}%' OR Interpret LIKE '%{}%' OR People LIKE '%{}' OR Composer LIKE '%{}%'", x, x, x, x, x),
-            Tag::Title(x) => format!("Title LIKE '%{}%'", x),
-            Tag::Album(x) => format!("Album LIKE '%{}%'", x),
-            Tag::Interpret(x) => format!("Interpret LIKE '%{}%'", x),
-            Tag::People(x) => format!("People LIKE '%{}%'", x),
-            Tag::Composer(x) => format!("Composer LIKE '%{}%'", x)
+    fn parse_primary(&mut self, field: Option<Field>) -> Result<Expr, ParseError> {
+        let (pos, token) = match self.next() {
+            Some(x) => x,
+            None => return Err(ParseError::UnexpectedEnd)
+        };
+
+        match token {
+            Token::Open => {
+                let expr = self.parse_or(field)?;
+
+                match self.next() {
+                    Some((_, Token::Close)) => Ok(expr),
+                    _ => Err(ParseError::UnclosedGroup { pos })
+                }
+            },
+            Token::Word(text) => Ok(Expr::Text { field, text, phrase: false }),
+            Token::Phrase(text) => Ok(Expr::Text { field, text, phrase: true }),
+            // nested fields like 'title:(album:x)' are not allowed
+            Token::Field(ref name) if field.is_none() => {
+                let new_field = Field::from_name(name)
+                    .ok_or(ParseError::UnknownField { pos, field: name.clone() })?;
+
+                if new_field.is_text() {
+                    match self.peek() {
+                        Some(&Token::Word(_)) | Some(&Token::Phrase(_)) | Some(&Token::Open) | Some(&Token::Not) => self.parse_unary(Some(new_field)),
+                        _ => Err(self.unexpected())
+                    }
+                } else {
+                    match self.next() {
+                        Some((pos, Token::Word(value))) => parse_range(new_field, pos, &value),
+                        Some((pos, token)) => Err(ParseError::UnexpectedToken { pos, token: token.to_string() }),
+                        None => Err(ParseError::UnexpectedEnd)
+                    }
+                }
+            },
+            token => Err(ParseError::UnexpectedToken { pos, token: token.to_string() })
         }
     }
 }
 
-/// A search query consists of serveral tags and an ordering
+/// Parse the value of a non-text field, like '>=300' or '2..5'
+fn parse_range(field: Field, pos: usize, value: &str) -> Result<Expr, ParseError> {
+    let valid = |x: &str| match field {
+        Field::Duration => x.parse::<f64>().is_ok(),
+        Field::FavsCount => x.parse::<u32>().is_ok(),
+        _ => !x.is_empty() && x.len() <= 10 && x.chars().enumerate().all(|(i, c)| {
+            if i == 4 || i == 7 { c == '-' } else { c.is_digit(10) }
+        })
+    };
+
+    let invalid = || ParseError::InvalidValue { pos, value: value.into() };
+
+    if let Some(idx) = value.find("..") {
+        let (from, to) = (&value[..idx], &value[idx+2..]);
+
+        if !valid(from) || !valid(to) {
+            return Err(invalid());
+        }
+
+        return Ok(Expr::And(vec![
+            Expr::Compare { field, op: Comparison::GreaterEqual, value: from.into() },
+            Expr::Compare { field, op: Comparison::LessEqual, value: to.into() }
+        ]));
+    }
+
+    let (op, value) = if value.starts_with(">=") {
+        (Comparison::GreaterEqual, &value[2..])
+    } else if value.starts_with("<=") {
+        (Comparison::LessEqual, &value[2..])
+    } else if value.starts_with('>') {
+        (Comparison::Greater, &value[1..])
+    } else if value.starts_with('<') {
+        (Comparison::Less, &value[1..])
+    } else if value.starts_with('=') {
+        (Comparison::Equal, &value[1..])
+    } else {
+        (Comparison::Equal, value)
+    };
+
+    if !valid(value) {
+        return Err(invalid());
+    }
+
+    Ok(Expr::Compare { field, op, value: value.into() })
+}
+
+/// A search query consists of an expression, an ordering and an optional page
 pub struct SearchQuery {
-    tags: Vec<Tag>,
-    order: Order
+    expr: Option<Expr>,
+    order: Order,
+    page: Option<(usize, usize)>
 }
 
 impl SearchQuery {
-    /// Create a new search query
-    pub fn new(input: &str) -> SearchQuery {
-        let tags = input.split(',').filter_map(Tag::from_search_query).collect();
-        let order = input.split(',').filter_map(Order::from_search_query).next().unwrap_or(Order::ByDate);
+    /// Parse a new search query
+    pub fn new(input: &str) -> Result<SearchQuery, ParseError> {
+        let mut tokens = tokenize(input)?;
+
+        // the ordering is not part of the expression, take it out beforehand
+        let mut order = Order::ByRelevance;
+        while let Some(idx) = tokens.iter().position(|x| x.1 == Token::Field("order".into())) {
+            let (pos, name) = match tokens.get(idx + 1) {
+                Some(&(pos, Token::Word(ref name))) => (pos, name.clone()),
+                Some(&(pos, ref token)) => return Err(ParseError::UnexpectedToken { pos, token: token.to_string() }),
+                None => return Err(ParseError::UnexpectedEnd)
+            };
+
+            order = Order::from_name(&name)
+                .ok_or(ParseError::UnknownOrder { pos, order: name })?;
+
+            tokens.drain(idx..idx+2);
+        }
+
+        let expr = if tokens.iter().all(|x| x.1 == Token::And) {
+            None
+        } else {
+            let mut parser = Parser { tokens, pos: 0 };
+            let expr = parser.parse_or(None)?;
 
-        SearchQuery { tags: tags, order: order }
+            // the only token which can stop the outer expression is a closing parenthesis
+            if parser.peek().is_some() {
+                return Err(parser.unexpected());
+            }
+
+            Some(expr)
+        };
+
+        Ok(SearchQuery { expr, order, page: None })
+    }
+
+    /// Replace the ordering of the results
+    pub fn order(mut self, order: Order) -> SearchQuery {
+        self.order = order;
+
+        self
+    }
+
+    /// Only return `count` tracks, starting with the `start`th result
+    pub fn page(mut self, start: usize, count: usize) -> SearchQuery {
+        self.page = Some((start, count));
+
+        self
+    }
+
+    /// Get the parsed expression
+    pub fn expr(&self) -> Option<&Expr> {
+        self.expr.as_ref()
     }
 
     /// Check for emptiness
     pub fn is_empty(&self) -> bool {
-        self.tags.is_empty()
+        self.expr.is_none()
     }
 
     /// Converts the search query to SQL
-    pub fn to_sql_query(self) -> String {
-        let mut tmp: String = "SELECT * FROM Tracks".into();
-        
-        if !self.tags.is_empty() {
-            tmp.push_str(" WHERE ");
-            tmp.push_str(&self.tags.into_iter().map(|x| x.to_sql_query()).collect::<Vec<String>>().join(" AND "));
+    ///
+    /// The user supplied text never ends up in the statement itself, but is returned as a list of
+    /// parameters which have to be bound to the placeholders in order.
+    pub fn to_sql_query(self) -> (String, Vec<String>) {
+        let mut params = Vec::new();
+        let mut tmp: String;
+        let order;
+
+        match self.expr {
+            None => {
+                tmp = "SELECT Tracks.* FROM Tracks".into();
+                order = self.order.to_sql_query(None);
+            },
+            Some(ref expr) => match expr.to_match_query() {
+                // pure text queries are answered by the full-text index alone
+                Some(query) => {
+                    tmp = "SELECT Tracks.* FROM TracksIndex JOIN Tracks ON Tracks.Key = TracksIndex.Key WHERE TracksIndex MATCH ?".into();
+                    params.push(query);
+                    order = self.order.to_sql_query(Some("TracksIndex.rank"));
+                },
+                // otherwise each text match is a subquery and the relevance is calculated from all
+                // text matches which are not negated
+                None => {
+                    let mut ranks = Vec::new();
+                    expr.positive_matches(&mut ranks);
+
+                    if !ranks.is_empty() && self.order == Order::ByRelevance {
+                        tmp = "SELECT Tracks.* FROM Tracks LEFT JOIN (SELECT Key, rank FROM TracksIndex WHERE TracksIndex MATCH ?) AS Relevance ON Relevance.Key = Tracks.Key".into();
+                        params.push(ranks.join(" OR "));
+                        order = self.order.to_sql_query(Some("Relevance.rank IS NULL, Relevance.rank"));
+                    } else {
+                        tmp = "SELECT Tracks.* FROM Tracks".into();
+                        order = self.order.to_sql_query(None);
+                    }
+
+                    tmp.push_str(" WHERE ");
+                    tmp.push_str(&expr.to_sql_query(&mut params));
+                }
+            }
         }
 
         tmp.push_str(" ORDER BY ");
-        tmp.push_str(&self.order.name());
-        tmp.push_str(" DESC");
+        tmp.push_str(&order);
 
-        tmp
+        if let Some((start, count)) = self.page {
+            tmp.push_str(&format!(" LIMIT {} OFFSET {}", count, start));
+        }
+
+        (tmp, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SearchQuery, Expr, Field, Comparison, ParseError};
+
+    #[test]
+    fn bind_parameters() {
+        let (sql, params) = SearchQuery::new("title:Don't,order:favs").unwrap().to_sql_query();
+
+        assert_eq!(sql, "SELECT Tracks.* FROM TracksIndex JOIN Tracks ON Tracks.Key = TracksIndex.Key WHERE TracksIndex MATCH ? ORDER BY Tracks.FavsCount DESC");
+        assert_eq!(params, vec!["Title : \"Don't\"*".to_string()]);
+    }
+
+    #[test]
+    fn escape_match_syntax() {
+        let (_, params) = SearchQuery::new("Daft OR* \"Punk\" - *").unwrap().to_sql_query();
+
+        assert_eq!(params, vec!["(\"Daft\"* AND \"OR*\"* AND \"Punk\")".to_string()]);
+    }
+
+    #[test]
+    fn empty_query() {
+        let (sql, params) = SearchQuery::new(",").unwrap().page(50, 50).to_sql_query();
+
+        assert_eq!(sql, "SELECT Tracks.* FROM Tracks ORDER BY Tracks.Created DESC LIMIT 50 OFFSET 50");
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn parse_grammar() {
+        let query = SearchQuery::new("\"Daft Punk\" -remix interpret:(Bach|Händel) duration:>300 favs:2..5").unwrap();
+
+        let text = |field, text: &str, phrase| Expr::Text { field, text: text.into(), phrase };
+        let compare = |field, op, value: &str| Expr::Compare { field, op, value: value.into() };
+
+        assert_eq!(query.expr(), Some(&Expr::And(vec![
+            text(None, "Daft Punk", true),
+            Expr::Not(Box::new(text(None, "remix", false))),
+            Expr::Or(vec![
+                text(Some(Field::Interpret), "Bach", false),
+                text(Some(Field::Interpret), "Händel", false)
+            ]),
+            compare(Field::Duration, Comparison::Greater, "300"),
+            Expr::And(vec![
+                compare(Field::FavsCount, Comparison::GreaterEqual, "2"),
+                compare(Field::FavsCount, Comparison::LessEqual, "5")
+            ])
+        ])));
+
+        let (sql, params) = query.to_sql_query();
+        assert_eq!(sql.matches('?').count(), params.len());
+        assert_eq!(params[0], "\"Daft Punk\" OR Interpret : \"Bach\"* OR Interpret : \"Händel\"*");
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let err = |x| SearchQuery::new(x).err().unwrap();
+
+        assert_eq!(err("\"Daft Punk"), ParseError::UnclosedQuote { pos: 0 });
+        assert_eq!(err("(a | b"), ParseError::UnclosedGroup { pos: 0 });
+        assert_eq!(err("a OR"), ParseError::UnexpectedEnd);
+        assert_eq!(err("a )"), ParseError::UnexpectedToken { pos: 2, token: ")".into() });
+        assert_eq!(err("genre:jazz"), ParseError::UnknownField { pos: 0, field: "genre".into() });
+        assert_eq!(err("favs:>=many"), ParseError::InvalidValue { pos: 5, value: ">=many".into() });
+        assert_eq!(err("order:random"), ParseError::UnknownOrder { pos: 6, order: "random".into() });
+        assert_eq!(err("title:(album:x)"), ParseError::UnexpectedToken { pos: 7, token: "album:".into() });
     }
 }
@@ -1,37 +1,89 @@
-use std::io::Read;
-use std::fs::File;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::fs::{self, File, OpenOptions};
 use std::path::{Path, PathBuf};
+#[cfg(feature="rusqlite")]
+use std::collections::VecDeque;
 
 #[cfg(feature="rusqlite")]
-use rusqlite::Row;
+use rusqlite::{self, Row};
 #[cfg(feature="rusqlite")]
 use bincode::{serialize, deserialize};
 #[cfg(feature="rusqlite")]
-use hex_gossip::{Inspector, Transition, TransitionKey};
+use hex_gossip::{Inspector, Job, Transition, TransitionKey, Signature, Keypair};
+#[cfg(feature="rusqlite")]
+use hex_gossip::transfer::{FileInfo, CHUNK_SIZE, read_full};
+#[cfg(feature="hex-music-container")]
+use hex_music_container::{Container, Metadata};
+#[cfg(feature="rusqlite")]
+use sha2::{Digest, Sha256};
 
-use objects::{self, Track, Playlist, Token, TrackKey, PlaylistKey, TokenId};
+use objects::{self, Track, Playlist, PlaylistQuery, PlaylistEntry, Token, Album, Artist, TrackKey, PlaylistKey, TokenId, AlbumKey, ArtistKey, EntryId, Position};
+use events::Event;
+#[cfg(feature="rusqlite")]
+use error::{Error, Result};
+#[cfg(feature="rusqlite")]
+use permission::Permissions;
 
 #[cfg(feature="rusqlite")]
 static UPSERT_TRACK: &str = r#"
-    INSERT INTO Tracks(Key, Fingerprint, Title, Album, Interpret, People, Composer, Duration, FavsCount, Created)
-        VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, date('now'))
+    INSERT INTO Tracks(Key, Fingerprint, Title, Album, Interpret, People, Composer, Duration, FavsCount, Created, AlbumKey, ArtistKey, TrackNumber, DiscNumber, Year, Loudness, TruePeak, ContentHash)
+        VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, date('now'), ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
         ON CONFLICT(Key) DO UPDATE SET
             Title = excluded.Title,
             Album = excluded.Album,
             Interpret = excluded.Interpret,
             People = excluded.People,
             Composer = excluded.Composer,
-            FavsCount = excluded.FavsCount;
+            FavsCount = excluded.FavsCount,
+            AlbumKey = excluded.AlbumKey,
+            ArtistKey = excluded.ArtistKey,
+            TrackNumber = excluded.TrackNumber,
+            DiscNumber = excluded.DiscNumber,
+            Year = excluded.Year,
+            Loudness = excluded.Loudness,
+            TruePeak = excluded.TruePeak,
+            ContentHash = COALESCE(excluded.ContentHash, Tracks.ContentHash);
 "#;
 
 #[cfg(feature="rusqlite")]
-static UPSERT_PLAYLIST: &str = r#"
-    INSERT INTO Playlists(Key, Title, Desc, Tracks, Author)
-        VALUES(?1, ?2, ?3, ?4, ?5)
+static UPSERT_ARTIST: &str = r#"
+    INSERT INTO Artists(Key, Name)
+        VALUES(?1, ?2)
+        ON CONFLICT(Key) DO UPDATE SET
+            Name = excluded.Name;
+"#;
+
+#[cfg(feature="rusqlite")]
+static UPSERT_ALBUM: &str = r#"
+    INSERT INTO Albums(Key, Title, Artist)
+        VALUES(?1, ?2, ?3)
         ON CONFLICT(Key) DO UPDATE SET
             Title = excluded.Title,
-            Desc = excluded.Desc,
-            Tracks = excluded.Tracks;
+            Artist = excluded.Artist;
+"#;
+
+/// Each track keeps its row in the full-text index, also when the metadata is replaced
+#[cfg(feature="rusqlite")]
+static INSERT_TRACK_INDEX_ROW: &str = r#"
+    INSERT OR IGNORE INTO TracksIndexRows(Key) VALUES(?1);
+"#;
+
+#[cfg(feature="rusqlite")]
+static INSERT_TRACK_INDEX: &str = r#"
+    INSERT INTO TracksIndex(rowid, Key, Title, Album, Interpret, People, Composer)
+        SELECT TracksIndexRows.Row, Tracks.Key, Title, Album, Interpret, People, Composer
+            FROM Tracks JOIN TracksIndexRows ON TracksIndexRows.Key = Tracks.Key WHERE Tracks.Key = ?1;
+"#;
+
+#[cfg(feature="rusqlite")]
+static DELETE_TRACK_INDEX: &str = r#"
+    DELETE FROM TracksIndex WHERE rowid = (SELECT Row FROM TracksIndexRows WHERE Key = ?1);
+"#;
+
+#[cfg(feature="rusqlite")]
+static INSERT_PLAYLIST_ENTRY: &str = r#"
+    INSERT OR IGNORE INTO PlaylistEntries(Id, Playlist, Position, Track, Stamp)
+        VALUES(?1, ?2, ?3, ?4, ?1);
 "#;
 
 #[cfg(feature="rusqlite")]
@@ -45,6 +97,14 @@ static UPSERT_TOKEN: &str = r#"
             Lastuse = excluded.Lastuse;
 "#;
 
+/// Split a playlist query into the `Query`, `SortOrder` and `QueryLimit` columns
+#[cfg(feature="rusqlite")]
+fn query_columns(query: Option<PlaylistQuery>) -> (Option<String>, Option<String>, Option<u32>) {
+    match query {
+        Some(PlaylistQuery { query, order, limit }) => (Some(query), Some(order.keyword().to_string()), limit),
+        None => (None, None, None)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum TransitionAction {
@@ -57,35 +117,439 @@ pub enum TransitionAction {
     DeleteTrack(TrackKey),
     DeletePlaylist(PlaylistKey),
     DeleteToken(TokenId),
+
+    // create or delete an Album or Artist, deleting removes all references to it
+    UpsertAlbum(Album),
+    UpsertArtist(Artist),
+    DeleteAlbum(AlbumKey),
+    DeleteArtist(ArtistKey),
+
+    // log an event, e.g. a played track
+    AddEvent(Event),
+
+    // turn a playlist into a smart playlist, or back with `None`
+    SetPlaylistQuery(PlaylistKey, Option<PlaylistQuery>),
+
+    // edit single entries of a playlist, a move is only applied if its stamp is newer than the
+    // last one, so concurrent edits converge regardless of the order they arrive in
+    InsertIntoPlaylist { playlist: PlaylistKey, id: EntryId, position: Position, track: TrackKey },
+    RemoveFromPlaylist { playlist: PlaylistKey, id: EntryId },
+    MoveInPlaylist { playlist: PlaylistKey, id: EntryId, position: Position, stamp: EntryId },
+
+    // capture the whole state at a cut through the graph, transitions before it are pruned
+    Checkpoint(Snapshot),
+
+    // replace a playlist together with its entries, only decoded from bodies of the first
+    // release which stored all tracks in the playlist
+    ReplacePlaylist(Playlist),
 }
 
+/// Materialized state of the database at a cut through the transition graph
+///
+/// The snapshot lists the keys of all transitions between the previous checkpoint and the cut.
+/// Peers applying the checkpoint prune these transitions, peers which don't know them replace
+/// their state with the snapshot. Events are not part of the state, they stay with the peers
+/// which logged them.
+///
+/// A large snapshot is split into several parts, each committed as its own checkpoint on top of
+/// the previous part. Peers replace their state only once all parts have arrived.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Snapshot {
+    pub tracks: Vec<Track>,
+    pub playlists: Vec<Playlist>,
+    /// Entries of all playlists, together with their playlist and the stamp of their last move
+    pub entries: Vec<(PlaylistKey, PlaylistEntry, EntryId)>,
+    pub tokens: Vec<Token>,
+    pub albums: Vec<Album>,
+    pub artists: Vec<Artist>,
+    /// Keys of the transitions pruned by this part
+    pub pruned: Vec<[u8; 32]>,
+    /// Index of this part, counted from zero
+    pub part: u32,
+    /// Number of parts the snapshot was split into
+    pub parts: u32
+}
+
+impl Snapshot {
+    /// Split the snapshot into parts of at most `size` elements
+    pub fn split(self, size: usize) -> Vec<Snapshot> {
+        let mut parts = vec![Snapshot::default()];
+        let mut len = 0;
+
+        // fill the parts in order, so that playlists come after their tracks
+        macro_rules! fill {
+            ($field:ident) => {
+                for x in self.$field {
+                    if len == size {
+                        parts.push(Snapshot::default());
+                        len = 0;
+                    }
+
+                    parts.last_mut().unwrap().$field.push(x);
+                    len += 1;
+                }
+            }
+        }
+
+        fill!(tracks);
+        fill!(playlists);
+        fill!(entries);
+        fill!(tokens);
+        fill!(albums);
+        fill!(artists);
+        fill!(pruned);
+
+        let num = parts.len() as u32;
+        for (i, part) in parts.iter_mut().enumerate() {
+            part.part = i as u32;
+            part.parts = num;
+        }
+
+        parts
+    }
+
+    /// Whether this is the last part of a snapshot
+    pub fn is_last(&self) -> bool {
+        self.part + 1 == self.parts
+    }
+}
+
+/// Version of the `TransitionAction` layout written by this library
+///
+/// Bodies written before the envelope existed have version 0, they only know the actions of the
+/// first release. A version changing the layout has to keep decoding the older ones. Version 2
+/// added the loudness to `Track`, version 3 the hash of its audio data and version 4 split
+/// snapshots into parts without events.
+#[cfg(feature="rusqlite")]
+pub const ACTION_VERSION: u8 = 4;
+
+/// First byte of an enveloped body, plain bodies begin with the small variant index instead
+#[cfg(feature="rusqlite")]
+const ENVELOPE: u8 = 0xE7;
+
 #[cfg(feature="rusqlite")]
 impl TransitionAction {
-    pub fn from_vec(buf: &[u8]) -> TransitionAction {
-        deserialize(buf).unwrap()
+    /// Decode a transition body, fails with `UnsupportedVersion` if written by a newer library
+    pub fn from_vec(buf: &[u8]) -> Result<TransitionAction> {
+        let (version, buf) = match (buf.get(0), buf.get(1)) {
+            (Some(&ENVELOPE), Some(&version)) if version > ACTION_VERSION => return Err(Error::UnsupportedVersion(version)),
+            (Some(&ENVELOPE), Some(&version)) => (version, &buf[2..]),
+            // a plain body of version 0
+            _ => (0, buf)
+        };
+
+        if version == 0 {
+            return deserialize::<legacy::v0::TransitionAction>(buf)
+                .map(|x| x.into())
+                .map_err(|e| Error::Decode(e));
+        }
+
+        if version == 1 {
+            return deserialize::<legacy::TransitionAction<legacy::Track>>(buf)
+                .map(|x| x.into())
+                .map_err(|e| Error::Decode(e));
+        }
+
+        if version == 2 {
+            return deserialize::<legacy::TransitionAction<legacy::v2::Track>>(buf)
+                .map(|x| x.into())
+                .map_err(|e| Error::Decode(e));
+        }
+
+        if version == 3 {
+            return deserialize::<legacy::TransitionAction<Track>>(buf)
+                .map(|x| x.into())
+                .map_err(|e| Error::Decode(e));
+        }
+
+        deserialize(buf).map_err(|e| Error::Decode(e))
     }
 
+    /// Encode the action in an envelope with the current version
     pub fn to_vec(&self) -> Vec<u8> {
-        serialize(&self).unwrap()
+        let mut buf = vec![ENVELOPE, ACTION_VERSION];
+        buf.extend(serialize(&self).unwrap());
+
+        buf
+    }
+}
+
+/// Layout of version 1 to 3 bodies, they only differ in their tracks
+#[cfg(feature="rusqlite")]
+mod legacy {
+    use objects::{self, Playlist, PlaylistQuery, PlaylistEntry, Token, Album, Artist, TrackKey, PlaylistKey, TokenId, AlbumKey, ArtistKey, EntryId, Position, Fingerprint};
+    use events::Event;
+
+    /// Layout of version 0 bodies, written by the first release without an envelope
+    pub mod v0 {
+        use hex_gossip::PeerId;
+        use objects::{self, Token, TrackKey, PlaylistKey, TokenId, Fingerprint};
+
+        #[derive(Deserialize)]
+        pub struct Track {
+            key: TrackKey,
+            fingerprint: Fingerprint,
+            title: Option<String>,
+            album: Option<String>,
+            interpret: Option<String>,
+            people: Option<String>,
+            composer: Option<String>,
+            duration: f64,
+            favs_count: u32
+        }
+
+        #[derive(Deserialize)]
+        pub struct Playlist {
+            key: PlaylistKey,
+            title: String,
+            desc: Option<String>,
+            tracks: Vec<TrackKey>,
+            origin: PeerId
+        }
+
+        #[derive(Deserialize)]
+        pub enum TransitionAction {
+            UpsertTrack(Track),
+            UpsertPlaylist(Playlist),
+            UpsertToken(Token),
+            DeleteTrack(TrackKey),
+            DeletePlaylist(PlaylistKey),
+            DeleteToken(TokenId),
+        }
+
+        impl From<Track> for objects::Track {
+            fn from(x: Track) -> objects::Track {
+                objects::Track {
+                    key: x.key, fingerprint: x.fingerprint, title: x.title, album: x.album,
+                    interpret: x.interpret, people: x.people, composer: x.composer, duration: x.duration,
+                    favs_count: x.favs_count,
+                    album_key: None,
+                    artist_key: None,
+                    track_number: None,
+                    disc_number: None,
+                    year: None,
+                    loudness: None,
+                    true_peak: None,
+                    content_hash: None
+                }
+            }
+        }
+
+        impl From<Playlist> for objects::Playlist {
+            fn from(x: Playlist) -> objects::Playlist {
+                objects::Playlist {
+                    key: x.key, title: x.title, desc: x.desc, tracks: x.tracks, origin: x.origin,
+                    query: None
+                }
+            }
+        }
+
+        impl From<TransitionAction> for super::super::TransitionAction {
+            fn from(x: TransitionAction) -> super::super::TransitionAction {
+                use super::super::TransitionAction as T;
+
+                match x {
+                    TransitionAction::UpsertTrack(track) => T::UpsertTrack(track.into()),
+                    TransitionAction::UpsertPlaylist(playlist) => T::ReplacePlaylist(playlist.into()),
+                    TransitionAction::UpsertToken(token) => T::UpsertToken(token),
+                    TransitionAction::DeleteTrack(key) => T::DeleteTrack(key),
+                    TransitionAction::DeletePlaylist(key) => T::DeletePlaylist(key),
+                    TransitionAction::DeleteToken(id) => T::DeleteToken(id),
+                }
+            }
+        }
+    }
+
+    /// Tracks of version 2 bodies, before the hash of the audio data was recorded
+    pub mod v2 {
+        use objects::{self, TrackKey, AlbumKey, ArtistKey, Fingerprint};
+
+        #[derive(Deserialize)]
+        pub struct Track {
+            key: TrackKey,
+            fingerprint: Fingerprint,
+            title: Option<String>,
+            album: Option<String>,
+            interpret: Option<String>,
+            people: Option<String>,
+            composer: Option<String>,
+            duration: f64,
+            favs_count: u32,
+            album_key: Option<AlbumKey>,
+            artist_key: Option<ArtistKey>,
+            track_number: Option<u32>,
+            disc_number: Option<u32>,
+            year: Option<u32>,
+            loudness: Option<f64>,
+            true_peak: Option<f64>
+        }
+
+        impl From<Track> for objects::Track {
+            fn from(x: Track) -> objects::Track {
+                objects::Track {
+                    key: x.key, fingerprint: x.fingerprint, title: x.title, album: x.album,
+                    interpret: x.interpret, people: x.people, composer: x.composer, duration: x.duration,
+                    favs_count: x.favs_count, album_key: x.album_key, artist_key: x.artist_key,
+                    track_number: x.track_number, disc_number: x.disc_number, year: x.year,
+                    loudness: x.loudness, true_peak: x.true_peak,
+                    content_hash: None
+                }
+            }
+        }
+    }
+
+    /// Tracks of version 1 bodies, before tracks had a loudness
+    #[derive(Deserialize)]
+    pub struct Track {
+        key: TrackKey,
+        fingerprint: Fingerprint,
+        title: Option<String>,
+        album: Option<String>,
+        interpret: Option<String>,
+        people: Option<String>,
+        composer: Option<String>,
+        duration: f64,
+        favs_count: u32,
+        album_key: Option<AlbumKey>,
+        artist_key: Option<ArtistKey>,
+        track_number: Option<u32>,
+        disc_number: Option<u32>,
+        year: Option<u32>
+    }
+
+    /// Snapshots before version 4, always in a single part and with all events
+    #[derive(Deserialize)]
+    pub struct Snapshot<T> {
+        tracks: Vec<T>,
+        playlists: Vec<Playlist>,
+        entries: Vec<(PlaylistKey, PlaylistEntry, EntryId)>,
+        tokens: Vec<Token>,
+        albums: Vec<Album>,
+        artists: Vec<Artist>,
+        // only decoded to skip over them
+        #[allow(dead_code)]
+        events: Vec<Event>,
+        pruned: Vec<[u8; 32]>
+    }
+
+    #[derive(Deserialize)]
+    pub enum TransitionAction<T> {
+        UpsertTrack(T),
+        UpsertPlaylist(Playlist),
+        UpsertToken(Token),
+        DeleteTrack(TrackKey),
+        DeletePlaylist(PlaylistKey),
+        DeleteToken(TokenId),
+        UpsertAlbum(Album),
+        UpsertArtist(Artist),
+        DeleteAlbum(AlbumKey),
+        DeleteArtist(ArtistKey),
+        AddEvent(Event),
+        SetPlaylistQuery(PlaylistKey, Option<PlaylistQuery>),
+        InsertIntoPlaylist { playlist: PlaylistKey, id: EntryId, position: Position, track: TrackKey },
+        RemoveFromPlaylist { playlist: PlaylistKey, id: EntryId },
+        MoveInPlaylist { playlist: PlaylistKey, id: EntryId, position: Position, stamp: EntryId },
+        Checkpoint(Snapshot<T>),
+    }
+
+    impl From<Track> for objects::Track {
+        fn from(x: Track) -> objects::Track {
+            objects::Track {
+                key: x.key, fingerprint: x.fingerprint, title: x.title, album: x.album,
+                interpret: x.interpret, people: x.people, composer: x.composer, duration: x.duration,
+                favs_count: x.favs_count, album_key: x.album_key, artist_key: x.artist_key,
+                track_number: x.track_number, disc_number: x.disc_number, year: x.year,
+                loudness: None,
+                true_peak: None,
+                content_hash: None
+            }
+        }
+    }
+
+    impl<T: Into<objects::Track>> From<TransitionAction<T>> for super::TransitionAction {
+        fn from(x: TransitionAction<T>) -> super::TransitionAction {
+            use super::TransitionAction as T;
+
+            match x {
+                TransitionAction::UpsertTrack(track) => T::UpsertTrack(track.into()),
+                TransitionAction::UpsertPlaylist(playlist) => T::UpsertPlaylist(playlist),
+                TransitionAction::UpsertToken(token) => T::UpsertToken(token),
+                TransitionAction::DeleteTrack(key) => T::DeleteTrack(key),
+                TransitionAction::DeletePlaylist(key) => T::DeletePlaylist(key),
+                TransitionAction::DeleteToken(id) => T::DeleteToken(id),
+                TransitionAction::UpsertAlbum(album) => T::UpsertAlbum(album),
+                TransitionAction::UpsertArtist(artist) => T::UpsertArtist(artist),
+                TransitionAction::DeleteAlbum(key) => T::DeleteAlbum(key),
+                TransitionAction::DeleteArtist(key) => T::DeleteArtist(key),
+                TransitionAction::AddEvent(event) => T::AddEvent(event),
+                TransitionAction::SetPlaylistQuery(key, query) => T::SetPlaylistQuery(key, query),
+                TransitionAction::InsertIntoPlaylist { playlist, id, position, track } => T::InsertIntoPlaylist { playlist, id, position, track },
+                TransitionAction::RemoveFromPlaylist { playlist, id } => T::RemoveFromPlaylist { playlist, id },
+                TransitionAction::MoveInPlaylist { playlist, id, position, stamp } => T::MoveInPlaylist { playlist, id, position, stamp },
+                TransitionAction::Checkpoint(x) => T::Checkpoint(super::Snapshot {
+                    tracks: x.tracks.into_iter().map(|x| x.into()).collect(),
+                    playlists: x.playlists,
+                    entries: x.entries,
+                    tokens: x.tokens,
+                    albums: x.albums,
+                    artists: x.artists,
+                    pruned: x.pruned,
+                    part: 0,
+                    parts: 1
+                })
+            }
+        }
     }
 }
 
 #[cfg(feature="rusqlite")]
-pub fn transition_from_sql(row: &Row) -> Transition {
-    let a: Vec<u8> = row.get(0);
-    let b: Vec<u8> = row.get(1);
-    let c: Vec<u8> = row.get(3);
+pub fn transition_from_sql(row: &Row) -> rusqlite::Result<Transition> {
+    let a: Vec<u8> = row.get_checked(0)?;
+    let b: Vec<u8> = row.get_checked(1)?;
+    let sign: Vec<u8> = row.get_checked(2)?;
+    let c: Vec<u8> = row.get_checked(3)?;
 
     let key = TransitionKey::from_vec(&a);
     let pk = b;
     let refs = c.chunks(32).map(|x| TransitionKey::from_vec(x)).collect();
 
-    Transition {
+    Ok(Transition {
         key, pk, refs, 
-        body: row.get(5),
-        sign: [0; 32],
-        state: row.get(4)
+        body: row.get_checked(5)?,
+        sign: Signature::from_vec(&sign),
+        state: row.get_checked(4)?
+    })
+}
+
+/// Sign transitions stored before transitions were signed with our keypair
+///
+/// These transitions carry a zeroed signature of 32 bytes and would fail the verification of
+/// other peers. Their author can't be recovered, so the upgrading peer signs them as its own. The
+/// key of a transition doesn't include its signature and stays the same.
+#[cfg(feature="rusqlite")]
+pub fn sign_legacy_transitions(socket: &mut rusqlite::Connection, keypair: &Keypair) -> Result<usize> {
+    let tx = socket.transaction().map_err(|e| Error::Sqlite(e))?;
+
+    let keys = {
+        let mut stmt = tx.prepare("SELECT Key FROM Transitions WHERE length(Signature) != 64")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let rows = stmt.query_map(&[], |row| row.get_checked::<_, Vec<u8>>(0))
+            .map_err(|e| Error::Sqlite(e))?
+            .map(|x| x.and_then(|x| x))
+            .collect::<rusqlite::Result<Vec<Vec<u8>>>>();
+
+        rows.map_err(|e| Error::Sqlite(e))?
+    };
+
+    for key in &keys {
+        tx.execute("UPDATE Transitions SET PublicKey=?1, Signature=?2 WHERE Key=?3",
+            &[&keypair.id(), &keypair.sign(key).0.as_ref(), key]).map_err(|e| Error::Sqlite(e))?;
     }
+
+    tx.commit().map_err(|e| Error::Sqlite(e))?;
+
+    Ok(keys.len())
 }
 
 /// The inspector will open a write/read connection to the database and fill it with foreign and
@@ -93,156 +557,567 @@ pub fn transition_from_sql(row: &Row) -> Transition {
 #[cfg(feature="rusqlite")]
 pub struct Storage {
     socket: rusqlite::Connection,
-    data_path: PathBuf
+    data_path: PathBuf,
+    permissions: Permissions
 }
 
 #[cfg(feature="rusqlite")]
 impl Storage {
-    pub fn new<T: AsRef<Path>>(path: T) -> Storage {
-        Storage {
-            data_path: path.as_ref().parent().unwrap().join("data").to_path_buf(),
-            socket: rusqlite::Connection::open(path).unwrap()
+    pub fn new<T: AsRef<Path>>(path: T, permissions: Permissions) -> Result<Storage> {
+        let path = path.as_ref();
+
+        let storage = Storage {
+            data_path: path.parent().unwrap_or(Path::new("")).join("data"),
+            socket: rusqlite::Connection::open(path).map_err(|e| Error::Sqlite(e))?,
+            permissions
+        };
+
+        // transitions of a newer library may be applicable after an update
+        storage.resume()?;
+
+        Ok(storage)
+    }
+
+    /// Try to apply all pending transitions again
+    ///
+    /// A pending transition either misses a reference or was written by a newer version of the
+    /// library. Errors of single transitions are logged and skipped.
+    pub fn resume(&self) -> Result<()> {
+        let pending = {
+            let mut stmt = self.socket.prepare("SELECT * FROM Transitions WHERE State=2")
+                .map_err(|e| Error::Sqlite(e))?;
+
+            let pending = stmt.query_map(&[], |row| transition_from_sql(&row))
+                .map_err(|e| Error::Sqlite(e))?
+                .map(|x| x.and_then(|x| x))
+                .collect::<rusqlite::Result<Vec<Transition>>>()
+                .map_err(|e| Error::Sqlite(e))?;
+
+            pending
+        };
+
+        for trans in pending {
+            let key = trans.key.to_string();
+
+            if let Err(err) = self.apply(trans) {
+                warn!("Could not apply pending transition {}: {:?}", key, err);
+            }
         }
+
+        Ok(())
     }
 
-    pub fn apply(&self, trans: Transition) {
-        // check wether all referenced transitions are already applied
-        let all_applied = match self.restore(trans.refs.clone()) {
-            Some(x) => x.iter().all(|x| x.state != 2),
-            None => false
+    /// Apply a transition and all pending transitions waiting for it
+    ///
+    /// Waiting transitions are processed in a queue instead of recursively, so that a long chain
+    /// of pending transitions can't overflow the stack.
+    pub fn apply(&self, trans: Transition) -> Result<()> {
+        let mut queue = VecDeque::new();
+        queue.push_back(trans);
+
+        while let Some(trans) = queue.pop_front() {
+            queue.extend(self.apply_single(trans)?);
+        }
+
+        Ok(())
+    }
+
+    /// Apply a single transition and return the pending transitions referencing it
+    fn apply_single(&self, trans: Transition) -> Result<Vec<Transition>> {
+        // a transition may be queued by several references, but is only applied once
+        match self.get_transitions(vec![trans.key.clone()])? {
+            Some(ref x) if x[0].state == 2 => {},
+            _ => return Ok(Vec::new())
+        }
+
+        // parse the body to a transition action, an action of a newer library waits for an update
+        let res = match TransitionAction::from_vec(trans.body.as_ref().ok_or(Error::NotFound)?) {
+            Err(Error::UnsupportedVersion(version)) => {
+                warn!("Transition {} has the newer version {}, update to apply it", trans.key.to_string(), version);
+
+                return Ok(Vec::new());
+            },
+            res => res?
         };
 
-        // don't apply if at least one reference is not yet applied
-        if !all_applied {
-            return;
+        // the action may have been unknown when the transition was approved
+        if !self.permissions.approve(&trans, &res) {
+            return Ok(Vec::new());
+        }
+
+        // pruned references were applied before they were pruned
+        let mut refs = Vec::new();
+        for key in &trans.refs {
+            if !self.is_pruned(key)? {
+                refs.push(key.clone());
+            }
+        }
+
+        // don't apply if at least one reference is not yet applied, but the last part of a
+        // checkpoint replaces a history we don't know
+        match (self.get_transitions(refs)?, &res) {
+            (Some(ref x), _) if x.iter().all(|x| x.state != 2) => {},
+            (_, &TransitionAction::Checkpoint(ref snapshot)) if snapshot.is_last() => {
+                return match self.checkpoint_parts(&trans, snapshot)? {
+                    Some(parts) => self.bootstrap(&parts),
+                    None => Ok(Vec::new())
+                };
+            },
+            _ => return Ok(Vec::new())
         }
 
         // otherweise set refs to non-tip
-        let tips: Vec<TransitionKey> = self.tips().into_iter()
+        let tips: Vec<TransitionKey> = self.get_tips()?.into_iter()
             .filter(|x| trans.refs.contains(x))
             .collect();
 
         for key in tips {
-            self.socket.execute("UPDATE Transitions SET State=0 WHERE Key=?", &[&key.0.as_ref()]).unwrap();
+            self.socket.execute("UPDATE Transitions SET State=0 WHERE Key=?", &[&key.0.as_ref()])
+                .map_err(|e| Error::Sqlite(e))?;
         }
 
-        // parse the body to a transition action
-        let res: TransitionAction = deserialize(&trans.body.unwrap()).unwrap();
         trace!("Apply {:?}", res);
 
         // update database according to the change
         match res {
-            TransitionAction::UpsertTrack(track) => self.socket.execute(UPSERT_TRACK, 
-                &[
-                    &track.key.to_vec(), 
-                    &objects::u32_into_u8(track.fingerprint.clone()), 
-                    &track.title, &track.album, &track.interpret, &track.people, &track.composer, &track.duration, &track.favs_count
-                ]).unwrap(),
+            TransitionAction::UpsertTrack(track) => self.upsert_track(&track),
 
-            TransitionAction::UpsertPlaylist(playlist) => self.socket.execute(UPSERT_PLAYLIST, 
-                &[
-                    &playlist.key, &playlist.title, &playlist.desc, 
-                    &playlist.tracks.into_iter().map(|x| x.to_vec()).flatten().collect::<Vec<u8>>(), 
-                    &playlist.origin
-                ]).unwrap(),
+            TransitionAction::UpsertPlaylist(playlist) => self.upsert_playlist(playlist, false),
+            TransitionAction::ReplacePlaylist(playlist) => self.upsert_playlist(playlist, true),
 
-            TransitionAction::UpsertToken(token) => self.socket.execute(UPSERT_TOKEN, 
-                &[
-                    &token.token,
-                    &token.key, 
-                    &token.played.into_iter().map(|x| x.to_vec()).flatten().collect::<Vec<u8>>(), 
-                    &token.pos, &token.last_use
-                ]).unwrap(),
-
-            TransitionAction::DeleteTrack(track_key) => self.socket.execute("DELETE FROM Tracks WHERE Key=?", &[&track_key.to_vec()]).unwrap(),
-            TransitionAction::DeletePlaylist(playlist_key) => self.socket.execute("DELETE FROM Playlists WHERE Key=?", &[&playlist_key]).unwrap(),
-            TransitionAction::DeleteToken(token) => self.socket.execute("DELETE FROM Tokens WHERE token=?", &[&token]).unwrap()
-        };
+            TransitionAction::UpsertToken(token) => self.upsert_token(&token),
+
+            TransitionAction::DeleteTrack(track_key) => {
+                self.socket.execute(DELETE_TRACK_INDEX, &[&track_key.to_vec()])
+                    .map_err(|e| Error::Sqlite(e))?;
+                self.socket.execute("DELETE FROM TracksIndexRows WHERE Key=?", &[&track_key.to_vec()])
+                    .map_err(|e| Error::Sqlite(e))?;
+                self.socket.execute("DELETE FROM Tracks WHERE Key=?", &[&track_key.to_vec()])
+                    .map_err(|e| Error::Sqlite(e))
+            },
+            TransitionAction::DeletePlaylist(playlist_key) => {
+                self.socket.execute("DELETE FROM PlaylistEntries WHERE Playlist=?", &[&playlist_key])
+                    .map_err(|e| Error::Sqlite(e))?;
+                self.socket.execute("DELETE FROM Playlists WHERE Key=?", &[&playlist_key])
+                    .map_err(|e| Error::Sqlite(e))
+            },
+            TransitionAction::DeleteToken(token) => self.socket.execute("DELETE FROM Tokens WHERE token=?", &[&token]).map_err(|e| Error::Sqlite(e)),
+
+            TransitionAction::UpsertAlbum(album) => self.socket.execute(UPSERT_ALBUM,
+                &[&album.key, &album.title, &album.artist]).map_err(|e| Error::Sqlite(e)),
+
+            TransitionAction::UpsertArtist(artist) => self.socket.execute(UPSERT_ARTIST,
+                &[&artist.key, &artist.name]).map_err(|e| Error::Sqlite(e)),
+
+            TransitionAction::DeleteAlbum(album_key) => {
+                self.socket.execute("UPDATE Tracks SET AlbumKey=NULL WHERE AlbumKey=?", &[&album_key])
+                    .map_err(|e| Error::Sqlite(e))?;
+                self.socket.execute("DELETE FROM Albums WHERE Key=?", &[&album_key])
+                    .map_err(|e| Error::Sqlite(e))
+            },
+
+            TransitionAction::DeleteArtist(artist_key) => {
+                self.socket.execute("UPDATE Tracks SET ArtistKey=NULL WHERE ArtistKey=?", &[&artist_key])
+                    .map_err(|e| Error::Sqlite(e))?;
+                self.socket.execute("UPDATE Albums SET Artist=NULL WHERE Artist=?", &[&artist_key])
+                    .map_err(|e| Error::Sqlite(e))?;
+                self.socket.execute("DELETE FROM Artists WHERE Key=?", &[&artist_key])
+                    .map_err(|e| Error::Sqlite(e))
+            },
+
+            TransitionAction::SetPlaylistQuery(key, query) => {
+                let (query, order, limit) = query_columns(query);
+
+                self.socket.execute("UPDATE Playlists SET Query=?1, SortOrder=?2, QueryLimit=?3 WHERE Key=?4",
+                    &[&query, &order, &limit, &key]).map_err(|e| Error::Sqlite(e))
+            },
+
+            TransitionAction::InsertIntoPlaylist { playlist, id, position, track } => self.socket.execute(INSERT_PLAYLIST_ENTRY,
+                &[&id, &playlist, &position.to_string(), &track.to_vec()]).map_err(|e| Error::Sqlite(e)),
+
+            TransitionAction::RemoveFromPlaylist { playlist, id } => self.socket.execute("DELETE FROM PlaylistEntries WHERE Id=?1 AND Playlist=?2",
+                &[&id, &playlist]).map_err(|e| Error::Sqlite(e)),
+
+            TransitionAction::MoveInPlaylist { playlist, id, position, stamp } => self.socket.execute("UPDATE PlaylistEntries SET Position=?1, Stamp=?2 WHERE Id=?3 AND Playlist=?4 AND Stamp < ?2",
+                &[&position.to_string(), &stamp, &id, &playlist]).map_err(|e| Error::Sqlite(e)),
+
+            TransitionAction::AddEvent(event) => self.add_event(&event),
+
+            TransitionAction::Checkpoint(snapshot) => self.prune(&snapshot.pruned).map(|_| 0)
+        }?;
 
         // find references to this transitions and try to apply them too
-        let mut stmt = self.socket.prepare("SELECT * FROM Transitions WHERE INSTR(Refs, ?)").unwrap();
+        let vec: Vec<Transition> = {
+            let mut stmt = self.socket.prepare("SELECT * FROM Transitions WHERE INSTR(Refs, ?)")
+                .map_err(|e| Error::Sqlite(e))?;
 
-        let key = trans.key.0.to_vec();
-        let vec: Vec<Transition> = stmt.query_map(&[&key], |row| transition_from_sql(&row)).unwrap().filter_map(|x| x.ok()).collect();
+            let key = trans.key.0.to_vec();
+            let rows = stmt.query_map(&[&key], |row| transition_from_sql(&row))
+                .map_err(|e| Error::Sqlite(e))?
+                .map(|x| x.and_then(|x| x))
+                .collect::<rusqlite::Result<Vec<Transition>>>();
+
+            rows.map_err(|e| Error::Sqlite(e))?
+        };
 
         // if there is no reference to us, we are a tip, otherwise we're fully integrated
-        if vec.len() == 0 {
-            self.socket.execute("UPDATE Transitions SET State=1 WHERE Key=?", &[&trans.key.0.as_ref()]).unwrap();
+        let state = if vec.len() == 0 { 1 } else { 0 };
+        self.socket.execute("UPDATE Transitions SET State=? WHERE Key=?", &[&state, &trans.key.0.as_ref()])
+            .map_err(|e| Error::Sqlite(e))?;
+
+        Ok(vec.into_iter().filter(|x| x.state == 2).collect())
+    }
+
+    fn upsert_track(&self, track: &Track) -> Result<usize> {
+        self.socket.execute(UPSERT_TRACK, 
+            &[
+                &track.key.to_vec(), 
+                &objects::u32_into_u8(track.fingerprint.clone()), 
+                &track.title, &track.album, &track.interpret, &track.people, &track.composer, &track.duration, &track.favs_count,
+                &track.album_key, &track.artist_key, &track.track_number, &track.disc_number, &track.year,
+                &track.loudness, &track.true_peak, &track.content_hash
+            ]).map_err(|e| Error::Sqlite(e))?;
+
+        // replace the entry in the full-text index with the new metadata
+        self.socket.execute(DELETE_TRACK_INDEX, &[&track.key.to_vec()])
+            .map_err(|e| Error::Sqlite(e))?;
+        self.socket.execute(INSERT_TRACK_INDEX_ROW, &[&track.key.to_vec()])
+            .map_err(|e| Error::Sqlite(e))?;
+        self.socket.execute(INSERT_TRACK_INDEX, &[&track.key.to_vec()])
+            .map_err(|e| Error::Sqlite(e))
+    }
+
+    /// Insert or update a playlist
+    ///
+    /// The tracks are only used for a new playlist, afterwards they are edited entry by entry. With
+    /// `replace` the entries of an existing playlist are replaced by the tracks as well.
+    fn upsert_playlist(&self, playlist: Playlist, replace: bool) -> Result<usize> {
+        let exists = self.socket.prepare("SELECT Key FROM Playlists WHERE Key = ?")
+            .and_then(|mut stmt| stmt.exists(&[&playlist.key]))
+            .map_err(|e| Error::Sqlite(e))?;
+
+        if exists {
+            self.socket.execute("UPDATE Playlists SET Title=?1, Desc=?2 WHERE Key=?3",
+                &[&playlist.title, &playlist.desc, &playlist.key]).map_err(|e| Error::Sqlite(e))?;
+
+            if !replace {
+                return Ok(1);
+            }
+
+            self.socket.execute("DELETE FROM PlaylistEntries WHERE Playlist=?", &[&playlist.key])
+                .map_err(|e| Error::Sqlite(e))?;
         } else {
-            self.socket.execute("UPDATE Transitions SET State=0 WHERE Key=?", &[&trans.key.0.as_ref()]).unwrap();
+            let (query, order, limit) = query_columns(playlist.query);
+
+            self.socket.execute("INSERT INTO Playlists(Key, Title, Desc, Tracks, Author, Query, SortOrder, QueryLimit) VALUES(?1, ?2, ?3, X'', ?4, ?5, ?6, ?7)",
+                &[&playlist.key, &playlist.title, &playlist.desc, &playlist.origin, &query, &order, &limit])
+                .map_err(|e| Error::Sqlite(e))?;
         }
 
-        for t in vec {
-            if t.state == 2 {
-                self.apply(t);
+        // same ids and positions as the initial entries created by the migration
+        for (i, track) in playlist.tracks.into_iter().enumerate() {
+            self.socket.execute(INSERT_PLAYLIST_ENTRY,
+                &[
+                    &format!("{:016X}{:08X}", playlist.key, i),
+                    &playlist.key,
+                    &format!("{:08X}", i + 1),
+                    &track.to_vec()
+                ]).map_err(|e| Error::Sqlite(e))?;
+        }
+
+        Ok(1)
+    }
+
+    fn upsert_token(&self, token: &Token) -> Result<usize> {
+        self.socket.execute(UPSERT_TOKEN, 
+            &[
+                &token.token,
+                &token.key, 
+                &token.played.iter().map(|x| x.to_vec()).flatten().collect::<Vec<u8>>(), 
+                &token.pos, &token.last_use
+            ]).map_err(|e| Error::Sqlite(e))
+    }
+
+    fn add_event(&self, event: &Event) -> Result<usize> {
+        self.socket.execute("INSERT INTO Events (Date, Origin, Tag, Data, Track) VALUES (?1, ?2, ?3, ?4, ?5)",
+            &[
+                &event.date(), &event.origin(), &event.tag(), &event.data_to_string(),
+                &event.track_key().map(|x| x.to_vec())
+            ]).map_err(|e| Error::Sqlite(e))
+    }
+
+    /// Run `f` inside a savepoint, all its changes are rolled back if it fails
+    fn atomic<T, F: FnOnce() -> Result<T>>(&self, f: F) -> Result<T> {
+        self.socket.execute_batch("SAVEPOINT atomic").map_err(|e| Error::Sqlite(e))?;
+
+        let res = f();
+        let end = if res.is_ok() { "RELEASE atomic" } else { "ROLLBACK TO atomic; RELEASE atomic" };
+        self.socket.execute_batch(end).map_err(|e| Error::Sqlite(e))?;
+
+        res
+    }
+
+    /// Remove transitions covered by a checkpoint, only their keys are kept
+    fn prune(&self, keys: &[[u8; 32]]) -> Result<()> {
+        self.atomic(|| {
+            for key in keys {
+                self.socket.execute("INSERT OR IGNORE INTO PrunedTransitions (Key) VALUES (?)", &[&key.as_ref()])
+                    .map_err(|e| Error::Sqlite(e))?;
+                self.socket.execute("DELETE FROM Transitions WHERE Key=?", &[&key.as_ref()])
+                    .map_err(|e| Error::Sqlite(e))?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Collect all parts of a checkpoint, ending with its last part
+    ///
+    /// Returns `None` if a part is still missing or the history before the first part is known,
+    /// then the parts are applied one after another instead.
+    fn checkpoint_parts(&self, trans: &Transition, snapshot: &Snapshot) -> Result<Option<Vec<(TransitionKey, Snapshot)>>> {
+        let mut parts = vec![(trans.key.clone(), snapshot.clone())];
+        let mut refs = trans.refs.clone();
+
+        // every part is committed on top of the previous one
+        while parts[parts.len() - 1].1.part > 0 {
+            let part = parts[parts.len() - 1].1.part;
+
+            let mut previous = None;
+            for key in &refs {
+                let trans = match self.get_transitions(vec![key.clone()])? {
+                    Some(mut x) => x.remove(0),
+                    None => continue
+                };
+
+                match trans.body.as_ref().map(|x| TransitionAction::from_vec(x)) {
+                    Some(Ok(TransitionAction::Checkpoint(ref x))) if x.part + 1 == part && x.parts == snapshot.parts => {
+                        previous = Some((trans.clone(), x.clone()));
+                    },
+                    _ => {}
+                }
+            }
+
+            match previous {
+                Some((trans, x)) => {
+                    refs = trans.refs;
+                    parts.push((trans.key, x));
+                },
+                None => return Ok(None)
             }
         }
+
+        // pruned references were applied before they were pruned
+        let mut known = Vec::new();
+        for key in refs {
+            if !self.is_pruned(&key)? {
+                known.push(key);
+            }
+        }
+
+        if self.get_transitions(known)?.is_some() {
+            return Ok(None);
+        }
+
+        parts.reverse();
+
+        Ok(Some(parts))
     }
 
-}
+    /// Replace the state with the snapshot of a checkpoint, because we don't know its history
+    ///
+    /// Stored transitions not covered by the snapshot are applied again on top of it, they are
+    /// returned for the queue. Events are kept, because they are not part of the snapshot.
+    /// Transitions of the events are not applied again, which would log them twice.
+    fn bootstrap(&self, parts: &[(TransitionKey, Snapshot)]) -> Result<Vec<Transition>> {
+        let last = &parts[parts.len() - 1].0;
 
-#[cfg(feature="rusqlite")]
-impl Inspector for Storage {
-    fn approve(&self, trans: &Transition) -> bool {
-        deserialize::<TransitionAction>(&trans.body.clone().unwrap()).is_ok()
+        info!("Bootstrap from checkpoint {} in {} parts", last.to_string(), parts.len());
+
+        self.atomic(|| {
+            self.socket.execute_batch("DELETE FROM TracksIndex; DELETE FROM TracksIndexRows; DELETE FROM Tracks; DELETE FROM PlaylistEntries; DELETE FROM Playlists;
+                DELETE FROM Tokens; DELETE FROM Albums; DELETE FROM Artists;")
+                .map_err(|e| Error::Sqlite(e))?;
+
+            // the parts are split in order, so that playlists come after their tracks
+            for &(_, ref snapshot) in parts {
+                self.restore_snapshot(snapshot)?;
+            }
+
+            // events are kept, all other transitions are applied again on top of the snapshot
+            let applied = {
+                let mut stmt = self.socket.prepare("SELECT * FROM Transitions WHERE State != 2")
+                    .map_err(|e| Error::Sqlite(e))?;
+
+                let rows = stmt.query_map(&[], |row| transition_from_sql(&row))
+                    .map_err(|e| Error::Sqlite(e))?
+                    .map(|x| x.and_then(|x| x))
+                    .collect::<rusqlite::Result<Vec<Transition>>>();
+
+                rows.map_err(|e| Error::Sqlite(e))?
+            };
+
+            for trans in applied {
+                match trans.body.as_ref().map(|x| TransitionAction::from_vec(x)) {
+                    Some(Ok(TransitionAction::AddEvent(_))) => {},
+                    _ => {
+                        self.socket.execute("UPDATE Transitions SET State=2 WHERE Key=?", &[&trans.key.0.as_ref()])
+                            .map_err(|e| Error::Sqlite(e))?;
+                    }
+                }
+            }
+
+            // the last part is a tip until a transition on top of it is applied again
+            for &(ref key, _) in parts {
+                let state = if key == last { 1 } else { 0 };
+                self.socket.execute("UPDATE Transitions SET State=? WHERE Key=?", &[&state, &key.0.as_ref()])
+                    .map_err(|e| Error::Sqlite(e))?;
+            }
+
+            let mut stmt = self.socket.prepare("SELECT * FROM Transitions WHERE State=2")
+                .map_err(|e| Error::Sqlite(e))?;
+
+            let rows = stmt.query_map(&[], |row| transition_from_sql(&row))
+                .map_err(|e| Error::Sqlite(e))?
+                .map(|x| x.and_then(|x| x))
+                .collect::<rusqlite::Result<Vec<Transition>>>();
+
+            rows.map_err(|e| Error::Sqlite(e))
+        })
     }
 
-    fn store(&self, trans: Transition) {
+    /// Insert the state of a single part of a snapshot and prune its transitions
+    fn restore_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        for track in &snapshot.tracks {
+            self.upsert_track(track)?;
+        }
+
+        for playlist in &snapshot.playlists {
+            let (query, order, limit) = query_columns(playlist.query.clone());
+
+            self.socket.execute("INSERT INTO Playlists(Key, Title, Desc, Tracks, Author, Query, SortOrder, QueryLimit) VALUES(?1, ?2, ?3, X'', ?4, ?5, ?6, ?7)",
+                &[&playlist.key, &playlist.title, &playlist.desc, &playlist.origin, &query, &order, &limit])
+                .map_err(|e| Error::Sqlite(e))?;
+        }
+
+        for &(playlist, ref entry, ref stamp) in &snapshot.entries {
+            self.socket.execute("INSERT INTO PlaylistEntries(Id, Playlist, Position, Track, Stamp) VALUES(?1, ?2, ?3, ?4, ?5)",
+                &[&entry.id, &playlist, &entry.position.to_string(), &entry.track.to_vec(), stamp])
+                .map_err(|e| Error::Sqlite(e))?;
+        }
+
+        for token in &snapshot.tokens {
+            self.upsert_token(token)?;
+        }
+
+        for album in &snapshot.albums {
+            self.socket.execute(UPSERT_ALBUM, &[&album.key, &album.title, &album.artist])
+                .map_err(|e| Error::Sqlite(e))?;
+        }
+
+        for artist in &snapshot.artists {
+            self.socket.execute(UPSERT_ARTIST, &[&artist.key, &artist.name])
+                .map_err(|e| Error::Sqlite(e))?;
+        }
+
+        self.prune(&snapshot.pruned)
+    }
+
+    /// Insert a new transition and apply it to the database, if all references are known
+    pub fn insert(&self, trans: Transition) -> Result<()> {
         let Transition { key, pk, sign, refs, body, .. } = trans.clone();
 
+        // a pruned transition was already applied and would only come back into the reconciliation
+        if self.is_pruned(&key)? {
+            return Ok(());
+        }
+
         self.socket.execute("INSERT INTO Transitions (Key, PublicKey, Signature, Refs, State, Data, Created) VALUES (?1, ?2, ?3, ?4, ?5, ?6, DATETIME('NOW'))",
             &[
                 &key.0.as_ref(), 
                 &pk, 
-                &sign.as_ref(),
+                &sign.0.as_ref(),
                 &refs.into_iter().map(|x| x.0.to_vec()).flatten().collect::<Vec<u8>>(), 
                 &2,
-                &body.clone().unwrap()
-            ]).unwrap();
+                &body
+            ]).map_err(|e| Error::Sqlite(e))?;
 
-        self.apply(trans);
+        self.apply(trans)
     }
 
-    fn restore(&self, keys: Vec<TransitionKey>) -> Option<Vec<Transition>> {
+    /// Get transitions with certain keys, returns `None` if at least one is missing
+    pub fn get_transitions(&self, keys: Vec<TransitionKey>) -> Result<Option<Vec<Transition>>> {
         let key_len = keys.len();
 
         let stmt = format!("SELECT * FROM Transitions WHERE hex(key) IN ({});", keys.into_iter().map(|x| format!("\"{}\"", x.to_string())).collect::<Vec<String>>().join(","));
 
-        let mut stmt = self.socket.prepare(&stmt).unwrap();
+        let mut stmt = self.socket.prepare(&stmt).map_err(|e| Error::Sqlite(e))?;
 
-        let vec: Vec<Transition> = stmt.query_map(&[], |row| transition_from_sql(&row)).unwrap().filter_map(|x| x.ok()).collect();
+        let vec = stmt.query_map(&[], |row| transition_from_sql(&row))
+            .map_err(|e| Error::Sqlite(e))?
+            .map(|x| x.and_then(|x| x))
+            .collect::<rusqlite::Result<Vec<Transition>>>()
+            .map_err(|e| Error::Sqlite(e))?;
 
         if vec.len() != key_len {
-            None
+            Ok(None)
         } else {
-            Some(vec)
+            Ok(Some(vec))
         }
     }
 
-    fn tips(&self) -> Vec<TransitionKey> {
-        let mut stmt = self.socket.prepare("SELECT Key FROM Transitions WHERE State=1").unwrap();
+    /// Get the keys of all transitions without any reference to them
+    pub fn get_tips(&self) -> Result<Vec<TransitionKey>> {
+        let mut stmt = self.socket.prepare("SELECT Key FROM Transitions WHERE State=1")
+            .map_err(|e| Error::Sqlite(e))?;
 
         let vec = stmt.query_map(&[], |row| {
-            let key: Vec<u8> = row.get(0);
+            row.get_checked(0).map(|key: Vec<u8>| TransitionKey::from_vec(&key))
+        }).map_err(|e| Error::Sqlite(e))?
+            .map(|x| x.and_then(|x| x))
+            .collect::<rusqlite::Result<Vec<TransitionKey>>>();
+
+        vec.map_err(|e| Error::Sqlite(e))
+    }
 
-            TransitionKey::from_vec(&key)
-        }).unwrap()
-            .filter_map(|x| x.ok()).collect();
+    /// Check whether a transition with `key` is stored or was pruned
+    pub fn contains(&self, key: &TransitionKey) -> Result<bool> {
+        let mut stmt = self.socket.prepare("SELECT Key FROM Transitions WHERE Key = ?1 UNION ALL SELECT Key FROM PrunedTransitions WHERE Key = ?1")
+            .map_err(|e| Error::Sqlite(e))?;
 
-        vec
+        stmt.exists(&[&key.0.as_ref()]).map_err(|e| Error::Sqlite(e))
     }
 
-    fn has(&self, key: &TransitionKey) -> bool {
-        let mut stmt = self.socket.prepare("SELECT * FROM Transitions WHERE Key = ?").unwrap();
-        let mut stream = stmt.query_map(&[&key.0.as_ref()], |_| true).unwrap()
-            .filter_map(|x| x.ok());
+    /// Check whether a transition with `key` was pruned by a checkpoint
+    pub fn is_pruned(&self, key: &TransitionKey) -> Result<bool> {
+        let mut stmt = self.socket.prepare("SELECT Key FROM PrunedTransitions WHERE Key = ?")
+            .map_err(|e| Error::Sqlite(e))?;
 
-        stream.next().is_some()
+        stmt.exists(&[&key.0.as_ref()]).map_err(|e| Error::Sqlite(e))
     }
 
-    fn get_file(&self, id: &[u8]) -> Option<Vec<u8>> {
+    /// Get the keys of all transitions from `lower` (inclusive) to `upper` (exclusive), ordered
+    ///
+    /// Pruned transitions are left out, a peer which has pruned them can't send them anymore.
+    pub fn get_keys(&self, lower: Option<&TransitionKey>, upper: Option<&TransitionKey>) -> Result<Vec<TransitionKey>> {
+        let mut stmt = self.socket.prepare("SELECT Key FROM Transitions WHERE (?1 IS NULL OR Key >= ?1) AND (?2 IS NULL OR Key < ?2)
+            AND Key NOT IN (SELECT Key FROM PrunedTransitions) ORDER BY Key")
+            .map_err(|e| Error::Sqlite(e))?;
+
+        let (lower, upper) = (lower.map(|x| x.0.to_vec()), upper.map(|x| x.0.to_vec()));
+        let vec = stmt.query_map(&[&lower, &upper], |row| {
+            row.get_checked(0).map(|key: Vec<u8>| TransitionKey::from_vec(&key))
+        }).map_err(|e| Error::Sqlite(e))?
+            .map(|x| x.and_then(|x| x))
+            .collect::<rusqlite::Result<Vec<TransitionKey>>>();
+
+        vec.map_err(|e| Error::Sqlite(e))
+    }
+
+    /// Path of a file with a certain track key in the data section
+    pub fn file_path(&self, id: &[u8]) -> Result<PathBuf> {
         if id.len() != 16 {
-            return None;
+            return Err(Error::NotFound);
         }
 
         let mut tmp = String::new();
@@ -250,11 +1125,292 @@ impl Inspector for Storage {
             tmp.push_str(&format!("{:02X}", id[i]));
         }
 
-        let mut file = File::open(self.data_path.join(&tmp)).ok()?;
-        let mut content = vec![];
-        file.read_to_end(&mut content).unwrap();
+        Ok(self.data_path.join(&tmp))
+    }
+
+    /// Read the chunk beginning at `offset` of a file in the data section
+    pub fn read_file_chunk(&self, id: &[u8], offset: u64) -> Result<Vec<u8>> {
+        let mut file = File::open(self.file_path(id)?).map_err(|e| Error::Io(e))?;
+        file.seek(SeekFrom::Start(offset)).map_err(|e| Error::Io(e))?;
 
-        Some(content)
+        let mut buf = vec![0u8; CHUNK_SIZE as usize];
+        let n = read_full(&mut file, &mut buf).map_err(|e| Error::Io(e))?;
+        if n == 0 {
+            return Err(Error::NotFound);
+        }
+        buf.truncate(n);
+
+        Ok(buf)
+    }
+
+    /// Write a chunk to the partial file, which is renamed to the track key once complete
+    pub fn write_file_chunk(&self, id: &[u8], offset: u64, data: &[u8]) -> Result<()> {
+        let path = self.file_path(id)?.with_extension("part");
+        let mut file = OpenOptions::new().write(true).create(true).open(path)
+            .map_err(|e| Error::Io(e))?;
+
+        // discard a chunk which was only partially written
+        file.set_len(offset).map_err(|e| Error::Io(e))?;
+        file.seek(SeekFrom::Start(offset)).map_err(|e| Error::Io(e))?;
+        file.write_all(data).map_err(|e| Error::Io(e))
+    }
+
+    /// Hash of the audio data of a track, `None` for tracks uploaded before it was recorded
+    pub fn get_content_hash(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        let res = self.socket.query_row("SELECT ContentHash FROM Tracks WHERE Key = ?", &[&id],
+            |row| row.get_checked(0));
+
+        match res {
+            Ok(hash) => hash.map_err(|e| Error::Sqlite(e)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(Error::Sqlite(err))
+        }
     }
 }
 
+/// Hash the audio data of a track file
+///
+/// Only the data section is hashed, the metadata of a file changes whenever its track is edited.
+#[cfg(all(feature="rusqlite", feature="hex-music-container"))]
+pub fn content_hash<R: Read + Write + Seek>(mut inner: R) -> Result<Vec<u8>> {
+    inner.seek(SeekFrom::Start(0)).map_err(|e| Error::Io(e))?;
+
+    let mut container = Container::load(inner).map_err(|e| Error::MusicContainer(e))?;
+    let range = container.data_range().map_err(|e| Error::MusicContainer(e))?;
+
+    let mut inner = container.into_inner();
+    inner.seek(SeekFrom::Start(range.start)).map_err(|e| Error::Io(e))?;
+    let mut inner = inner.take(range.end - range.start);
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    loop {
+        let n = read_full(&mut inner, &mut buf).map_err(|e| Error::Io(e))?;
+        hasher.input(&buf[..n]);
+
+        if n < buf.len() {
+            break;
+        }
+    }
+
+    Ok(hasher.result().to_vec())
+}
+
+/// Describe a file of the data section by its size and chunk hashes
+#[cfg(feature="rusqlite")]
+fn describe_file(path: &Path) -> Result<FileInfo> {
+    let file = File::open(path).map_err(|e| Error::Io(e))?;
+
+    FileInfo::from_reader(file).map_err(|e| Error::Io(e))
+}
+
+/// Move a complete partial file to the data section
+///
+/// The file is checked frame by frame beforehand and its audio data against the hash of the
+/// track, because the chunk hashes were only sent by the source. A damaged file is removed, so
+/// that it is requested from scratch the next time.
+#[cfg(feature="rusqlite")]
+fn complete_file(path: &Path, expected: Option<Vec<u8>>) -> Result<()> {
+    let part = path.with_extension("part");
+    let verified = verify_file(&part, expected);
+
+    // the description of the source is not needed any longer
+    let _ = fs::remove_file(path.with_extension("info"));
+
+    if let Err(err) = verified {
+        fs::remove_file(&part).map_err(|e| Error::Io(e))?;
+
+        return Err(err);
+    }
+
+    fs::rename(part, path).map_err(|e| Error::Io(e))
+}
+
+/// Check a file frame by frame and its audio data against the expected hash
+#[cfg(all(feature="rusqlite", feature="hex-music-container"))]
+fn verify_file(path: &Path, expected: Option<Vec<u8>>) -> Result<()> {
+    File::open(path)
+        .map_err(|e| hex_music_container::error::Error::File(e))
+        .and_then(|file| Container::load(file))
+        .and_then(|mut container| container.verify())
+        .map_err(|e| Error::MusicContainer(e))
+        .and_then(|_| match expected {
+            Some(expected) => File::open(path).map_err(|e| Error::Io(e))
+                .and_then(|file| content_hash(file))
+                .and_then(|hash| if hash == expected { Ok(()) } else { Err(Error::ContentMismatch) }),
+            None => Ok(())
+        })
+}
+
+/// Files can't be checked without the music container, they are trusted as they are
+#[cfg(all(feature="rusqlite", not(feature="hex-music-container")))]
+fn verify_file(_path: &Path, _expected: Option<Vec<u8>>) -> Result<()> {
+    Ok(())
+}
+
+/// Embed the metadata of a track in its audio file, the database can be rebuilt from it
+///
+/// Returns `false` without touching the file if it already contains the metadata. Otherwise the
+/// metadata is written to a copy of the file, which then replaces it. A file is therefore never
+/// left half written and readers of the old file keep reading it undisturbed. Copying takes a
+/// while for long tracks, better call this outside of any request.
+#[cfg(feature="hex-music-container")]
+pub fn store_metadata(path: &Path, track: &Track) -> Result<bool> {
+    let current = File::open(path).map_err(|e| Error::Io(e))
+        .and_then(|file| Container::load(file)
+            .and_then(|mut container| container.metadata())
+            .map_err(|e| Error::MusicContainer(e)))?
+        .unwrap_or_default();
+
+    // keep fields which are not part of the track, like the cover
+    let mut metadata = current.clone();
+    metadata.title = track.title.clone();
+    metadata.album = track.album.clone();
+    metadata.interpret = track.interpret.clone();
+    metadata.people = track.people.clone();
+    metadata.composer = track.composer.clone();
+    metadata.fingerprint = Some(track.fingerprint.clone());
+    if let Some(loudness) = track.loudness {
+        metadata.loudness = Some(loudness as f32);
+    }
+
+    if metadata == current {
+        return Ok(false);
+    }
+
+    let tmp = path.with_extension("tmp");
+    fs::copy(path, &tmp).map_err(|e| Error::Io(e))?;
+
+    if let Err(err) = write_metadata(&tmp, &metadata) {
+        let _ = fs::remove_file(&tmp);
+
+        return Err(err);
+    }
+
+    fs::rename(tmp, path).map_err(|e| Error::Io(e))?;
+
+    Ok(true)
+}
+
+/// Replace the metadata section of an audio file
+#[cfg(feature="hex-music-container")]
+fn write_metadata(path: &Path, metadata: &Metadata) -> Result<()> {
+    let file = OpenOptions::new().read(true).write(true).open(path)
+        .map_err(|e| Error::Io(e))?;
+    let mut container = Container::load(file)
+        .map_err(|e| Error::MusicContainer(e))?;
+
+    let len = container.set_metadata(metadata)
+        .map_err(|e| Error::MusicContainer(e))?;
+
+    let file = container.into_inner();
+    file.set_len(len).map_err(|e| Error::Io(e))?;
+    file.sync_all().map_err(|e| Error::Io(e))
+}
+
+/// Errors are only logged here, because the gossip protocol can't handle them
+#[cfg(feature="rusqlite")]
+impl Inspector for Storage {
+    fn approve(&self, trans: &Transition) -> bool {
+        if !trans.verify() {
+            warn!("Transition {} has an invalid signature of peer {:?}", trans.key.to_string(), trans.pk);
+
+            return false;
+        }
+
+        let action = match trans.body.as_ref().map(|x| TransitionAction::from_vec(x)) {
+            Some(Ok(action)) => action,
+            // keep the action of a newer library for later, it is checked again before applying
+            Some(Err(Error::UnsupportedVersion(_))) => return self.permissions.role(&trans.pk).is_some(),
+            _ => return false
+        };
+
+        self.permissions.approve(trans, &action)
+    }
+
+    fn store(&self, trans: Transition) {
+        if let Err(err) = self.insert(trans) {
+            error!("Could not store transition: {:?}", err);
+        }
+    }
+
+    fn restore(&self, keys: Vec<TransitionKey>) -> Option<Vec<Transition>> {
+        // pruned transitions can't be restored, but are known to us
+        let keys = keys.into_iter()
+            .filter(|x| !self.is_pruned(x).unwrap_or(false))
+            .collect();
+
+        self.get_transitions(keys)
+            .map_err(|err| error!("Could not restore transitions: {:?}", err))
+            .ok().and_then(|x| x)
+    }
+
+    fn tips(&self) -> Vec<TransitionKey> {
+        self.get_tips()
+            .map_err(|err| error!("Could not get tips: {:?}", err))
+            .unwrap_or(Vec::new())
+    }
+
+    fn has(&self, key: &TransitionKey) -> bool {
+        self.contains(key)
+            .map_err(|err| error!("Could not look up transition: {:?}", err))
+            .unwrap_or(false)
+    }
+
+    fn keys(&self, lower: Option<&TransitionKey>, upper: Option<&TransitionKey>) -> Vec<TransitionKey> {
+        self.get_keys(lower, upper)
+            .map_err(|err| error!("Could not get keys: {:?}", err))
+            .unwrap_or(Vec::new())
+    }
+
+    fn file_info(&self, id: &[u8]) -> Option<Job<Option<FileInfo>>> {
+        let path = self.file_path(id).ok().filter(|x| x.exists())?;
+
+        Some(Box::new(move || {
+            describe_file(&path)
+                .map_err(|err| error!("Could not describe file: {:?}", err))
+                .ok()
+        }))
+    }
+
+    fn read_chunk(&self, id: &[u8], offset: u64) -> Option<Vec<u8>> {
+        self.read_file_chunk(id, offset).ok()
+    }
+
+    fn partial(&self, id: &[u8]) -> Option<(FileInfo, u64)> {
+        let path = self.file_path(id).ok()?;
+
+        // a partial file without the description of its source can't be resumed
+        let info = fs::read(path.with_extension("info")).ok()
+            .and_then(|x| deserialize(&x).ok())?;
+        let received = fs::metadata(path.with_extension("part"))
+            .map(|x| x.len())
+            .unwrap_or(0);
+
+        Some((info, received))
+    }
+
+    fn begin_file(&self, id: &[u8], info: &FileInfo) -> bool {
+        self.file_path(id)
+            .and_then(|x| fs::write(x.with_extension("info"), serialize(info).unwrap()).map_err(|e| Error::Io(e)))
+            .map_err(|err| error!("Could not store description of file: {:?}", err))
+            .is_ok()
+    }
+
+    fn write_chunk(&self, id: &[u8], offset: u64, data: &[u8]) -> bool {
+        self.write_file_chunk(id, offset, data)
+            .map_err(|err| error!("Could not write chunk: {:?}", err))
+            .is_ok()
+    }
+
+    fn finish_file(&self, id: &[u8]) -> Job<bool> {
+        let res = self.file_path(id)
+            .and_then(|path| self.get_content_hash(id).map(|hash| (path, hash)));
+
+        Box::new(move || {
+            res.and_then(|(path, hash)| complete_file(&path, hash))
+                .map_err(|err| error!("Could not finish file: {:?}", err))
+                .is_ok()
+        })
+    }
+}
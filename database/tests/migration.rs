@@ -0,0 +1,126 @@
+extern crate hex_database;
+extern crate hex_gossip;
+extern crate rusqlite;
+
+use std::fs;
+use std::path::Path;
+
+use hex_database::{Instance, Error, GossipConf, Artist, Album, migration};
+use hex_database::search::SearchQuery;
+use hex_gossip::Keypair;
+
+/// Create a database with the schema used before versioning was introduced
+fn legacy_database(path: &Path) {
+    let _ = fs::remove_file(path);
+
+    let socket = rusqlite::Connection::open(path).unwrap();
+    socket.execute_batch(include_str!("fixtures/legacy.sql")).unwrap();
+    socket.execute(
+        "INSERT INTO Tracks (Key, Fingerprint, Title, Album, Interpret, People, Composer, Duration, FavsCount, Created) VALUES (?1, ?2, 'Jóga', 'Homogenic', 'Björk', NULL, NULL, 305.0, 3, 1541000000);",
+        &[&vec![1u8; 16], &vec![0u8; 40]]
+    ).unwrap();
+    socket.execute(
+        "INSERT INTO Playlists (Key, Title, Desc, Tracks, Author) VALUES (1, 'Iceland', NULL, ?1, ?2);",
+        &[&vec![1u8; 32], &vec![0u8; 16]]
+    ).unwrap();
+}
+
+fn gossip() -> GossipConf {
+    GossipConf::new().keypair(Keypair::from_seed(&[0; 32]))
+}
+
+#[test]
+fn upgrade_legacy_database() {
+    let path = Path::new("/tmp/test_migration.db");
+    legacy_database(path);
+
+    let instance = Instance::from_file(path, gossip()).unwrap();
+
+    let socket = rusqlite::Connection::open(path).unwrap();
+    assert_eq!(migration::version(&socket).unwrap(), migration::VERSION);
+
+    // the existing track is part of the full-text index after the upgrade
+    let view = instance.view().unwrap();
+    let mut stmt = view.search_prep(SearchQuery::new("bjork").unwrap()).unwrap();
+    let tracks = view.search(&mut stmt).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].title, Some("Jóga".into()));
+
+    // album and artist entities are derived from the free-text fields
+    let artists = view.get_artists().unwrap();
+    assert_eq!(artists.len(), 1);
+    assert_eq!(artists[0].name, "Björk");
+
+    let albums = view.get_albums_of_artist(artists[0].key).unwrap();
+    assert_eq!(albums.len(), 1);
+    assert_eq!(albums[0].title, "Homogenic");
+    assert_eq!(tracks[0].album_key, Some(albums[0].key));
+    assert_eq!(tracks[0].artist_key, Some(artists[0].key));
+
+    // their keys are derived from the content, like the keys of entities created later on
+    assert_eq!(artists[0].key, Artist::key_from_name("Björk"));
+    assert_eq!(albums[0].key, Album::key_from_title("Homogenic", Some(artists[0].key)));
+
+    // the track vector of a playlist is split into single entries, keeping duplicates
+    let (playlist, playlist_tracks) = view.get_playlist(1).unwrap();
+    assert_eq!(playlist.tracks, vec![tracks[0].key; 2]);
+    assert_eq!(playlist_tracks.len(), 2);
+    assert_eq!(view.get_playlist_entries(1).unwrap().len(), 2);
+
+    // opening an up-to-date database again is a no-op
+    drop(stmt);
+    drop(view);
+    drop(instance);
+    Instance::from_file(path, gossip()).unwrap();
+}
+
+#[test]
+fn split_albums_by_artist() {
+    let path = Path::new("/tmp/test_migration_albums.db");
+    legacy_database(path);
+
+    {
+        let socket = rusqlite::Connection::open(path).unwrap();
+        for (i, interpret) in ["Queen", "ABBA"].iter().enumerate() {
+            socket.execute(
+                "INSERT INTO Tracks (Key, Fingerprint, Title, Album, Interpret, People, Composer, Duration, FavsCount, Created) VALUES (?1, ?2, NULL, 'Greatest Hits', ?3, NULL, NULL, 200.0, 0, 1541000000);",
+                &[&vec![i as u8 + 2; 16], &vec![0u8; 40], interpret]
+            ).unwrap();
+        }
+    }
+
+    let instance = Instance::from_file(path, gossip()).unwrap();
+    let view = instance.view().unwrap();
+
+    // albums with the same title by different artists are kept apart
+    for interpret in &["Queen", "ABBA"] {
+        let artist = Artist::key_from_name(interpret);
+        let albums = view.get_albums_of_artist(artist).unwrap();
+
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].key, Album::key_from_title("Greatest Hits", Some(artist)));
+        assert_eq!(view.get_tracks_of_album(albums[0].key).unwrap().len(), 1);
+    }
+}
+
+#[test]
+fn refuse_newer_database() {
+    let path = Path::new("/tmp/test_migration_newer.db");
+    legacy_database(path);
+
+    {
+        let socket = rusqlite::Connection::open(path).unwrap();
+        socket.execute_batch(&format!("PRAGMA user_version = {};", migration::VERSION + 1)).unwrap();
+    }
+
+    match Instance::from_file(path, gossip()) {
+        Err(Error::SchemaTooNew(version)) => assert_eq!(version, migration::VERSION + 1),
+        Err(err) => panic!("unexpected error {:?}", err),
+        Ok(_) => panic!("a database with a newer schema was opened")
+    }
+
+    // the database is left untouched
+    let socket = rusqlite::Connection::open(path).unwrap();
+    assert_eq!(migration::version(&socket).unwrap(), migration::VERSION + 1);
+}
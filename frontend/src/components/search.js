@@ -9,13 +9,14 @@ export default class Search extends Component {
         updating: false,
         finished: false,
         stream: null,
-        query: null
+        query: null,
+        error: null
     };
 
     update = (props) => {
         if(this.state.query != props.query) {
             let stream = Protocol.start_search(props.query);
-            this.setState({ stream: stream, query: props.query, tracks: [], updating: false, finished: false }, e => {this.more()})
+            this.setState({ stream: stream, query: props.query, tracks: [], error: null, updating: false, finished: false }, e => {this.more()})
         }
     }
 
@@ -26,6 +27,11 @@ export default class Search extends Component {
         this.setState({ updating: true });
 
         this.state.stream().then(res => {
+            if(res.error) {
+                this.setState({ error: res.error, finished: true, updating: false });
+                return;
+            }
+
             let tmp = this.state.tracks.slice();
             tmp.push.apply(tmp, res.answ);
 
@@ -43,8 +49,15 @@ export default class Search extends Component {
     }
 
 	// Note: `user` comes from the URL, courtesy of our router
-	render({}, { query, updating, stream, tracks }) {
-        if(stream && tracks.length > 0) {
+	render({}, { query, updating, stream, tracks, error }) {
+        if(error) {
+            const kind = typeof error === "string" ? error : Object.keys(error)[0];
+            const pos = error[kind] && error[kind].pos;
+
+            return (
+                <div class={style.search}>Invalid query: {kind}{pos != null ? " at position " + pos : ""}</div>
+            );
+        } else if(stream && tracks.length > 0) {
             return (
                 <div class={style.search}>
                     <TrackList loadMore={this.more.bind(this)} tracks={tracks} />
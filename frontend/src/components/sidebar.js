@@ -28,17 +28,27 @@ export default class Sidebar extends Component {
                 self.setState({playlists});
             }
 
-            if("UpsertPlaylist" in action["Transition"]) {
-                const index = self.state.playlists.findIndex(e => e.key == action["Transition"]["UpsertPlaylist"].key);
+            if("UpsertPlaylist" in action["Transition"] || "ReplacePlaylist" in action["Transition"]) {
+                const playlist = action["Transition"]["UpsertPlaylist"] || action["Transition"]["ReplacePlaylist"];
+                const index = self.state.playlists.findIndex(e => e.key == playlist.key);
 
                 let playlists = self.state.playlists;
                 if(index === -1)
-                    playlists.push(action["Transition"]["UpsertPlaylist"]);
+                    playlists.push(playlist);
                 else
-                    playlists[index] = action["Transition"]["UpsertPlaylist"];
+                    playlists[index] = playlist;
 
                 self.setState({playlists});
             }
+
+            if("InsertIntoPlaylist" in action["Transition"] || "RemoveFromPlaylist" in action["Transition"] || "MoveInPlaylist" in action["Transition"]) {
+                const edit = action["Transition"]["InsertIntoPlaylist"] || action["Transition"]["RemoveFromPlaylist"] || action["Transition"]["MoveInPlaylist"];
+
+                Protocol.get_playlist(edit.playlist).then(x => {
+                    let playlists = self.state.playlists.map(e => e.key == edit.playlist ? x[0] : e);
+                    self.setState({playlists});
+                });
+            }
         });
     }
 
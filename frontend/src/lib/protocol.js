@@ -17,6 +17,7 @@ const CALLS = {
     AddToPlaylist: ["key", "playlist"],
     DeleteFromPlaylist: ["key", "playlist"],
     UpdatePlaylist: ["key", "title", "desc"],
+    SetPlaylistQuery: ["key", "query"],
     GetPlaylists: [],
     GetPlaylist: ["key"],
     GetPlaylistsOfTrack: ["key"],
@@ -30,8 +31,11 @@ const CALLS = {
     LastToken: [],
     GetSummary: [],
     GetTransitions: [],
+    GetStatistics: ["query", "from", "to", "origin"],
     Download: ["format", "tracks"],
-    AskDownloadProgress: []
+    AskDownloadProgress: [],
+    AskReplicationProgress: [],
+    GetPeers: []
 }
 
 let proto = null;
@@ -94,6 +94,29 @@ impl Codec {
         Ok(harmonics)
     }
 
+    /// Largest magnitude of each SH channel, which raw audio at full scale can produce
+    pub fn harmonics_range(&self) -> Result<Vec<f32>> {
+        let num_channels = self.conf.num_channels() as usize;
+        let num_harmonics = self.conf.num_harmonics() as usize;
+
+        // fail early for unsupported configurations
+        self.to_harmonics(&vec![0; num_channels])?;
+
+        // every channel at its lower or upper limit
+        let limits: Vec<i16> = (0..1usize << num_channels)
+            .flat_map(|x| (0..num_channels).map(move |c| if x & (1 << c) != 0 { i16::max_value() } else { i16::min_value() }))
+            .collect();
+
+        let mut range = vec![0.0f32; num_harmonics];
+        for sample in self.to_harmonics(&limits)?.chunks(num_harmonics) {
+            for (limit, val) in range.iter_mut().zip(sample) {
+                *limit = limit.max(val.abs());
+            }
+        }
+
+        Ok(range)
+    }
+
     /// Converts SH representation to loudspeaker dependent representation
     pub fn to_channels(&self, harmonics: &[f32], from_harmonics: u8) -> Result<Vec<i16>> {
         let num_channels = self.conf.num_channels() as usize;
@@ -106,15 +129,15 @@ impl Codec {
         for sample in harmonics.chunks(num_from_harmonics) {
             match self.conf {
                 Configuration::Omnidirectional => {
-                    channels[i] = (sample[0] * 3.5449077) as i16;
+                    channels[i] = clip(sample[0] * 3.5449077);
                 },
                 Configuration::Stereo => {
                     if sample.len() == 1 {
-                        channels[i] = (1.7724538 * sample[0]) as i16;
-                        channels[i+1] = (1.7724538 * sample[0]) as i16;
+                        channels[i] = clip(1.7724538 * sample[0]);
+                        channels[i+1] = clip(1.7724538 * sample[0]);
                     } else {
-                        channels[i] = (1.4472025 * sample[1] + 1.7724538 * sample[0]) as i16;
-                        channels[i+1] = (1.4472025 * sample[3] + 1.7724538 * sample[0]) as i16;
+                        channels[i] = clip(1.4472025 * sample[1] + 1.7724538 * sample[0]);
+                        channels[i+1] = clip(1.4472025 * sample[3] + 1.7724538 * sample[0]);
                     }
                 },
                 _ => return Err(Error::NotSupported)
@@ -127,6 +150,10 @@ impl Codec {
     }
 }
 
+/// Convert to 16bit, clipping values outside of its range
+fn clip(x: f32) -> i16 {
+    x.max(-32768.0).min(32767.0) as i16
+}
 
 #[cfg(test)]
 mod test {
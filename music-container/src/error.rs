@@ -7,6 +7,8 @@ pub type Result<T> = result::Result<T, Error>;
 pub enum Error {
     File(io::Error),
     CorruptedFile,
+    /// A frame is damaged or missing, contains the number of the first bad frame
+    CorruptedFrame(usize),
     Opus(opus::Error),
     InvalidSize,
     InvalidRange,
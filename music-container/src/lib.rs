@@ -9,9 +9,27 @@
 //! ## File format
 //!
 //! The file format is the following:
-//! |       |    1    |     1    |     4    | (order+1)**2 * 4 | (order+1)**2 * samples * 2 |
-//! |-------|---------|----------|----------|------------------|----------------------------|
-//! | field | version | sh order | samples  | scales ..        | audio data ...             |
+//! |       |    1    |     1    |     4    | (order+1)**2 * 4 |    2     |    8     |      8      |    4     |     4     | ...        |    4    | entries * 8 |    4    | ...        |
+//! |-------|---------|----------|----------|------------------|----------|----------|-------------|----------|-----------|------------|---------|-------------|---------|------------|
+//! | field | version | sh order | samples  | scales ..        | interval | index at | metadata at | loudness | true peak | audio data | entries | offsets ..  | size    | chunks ..  |
+//!
+//! The audio data consists of frames with `RAW_BLOCK_SIZE` samples. Each frame starts with the
+//! size of the Opus packet of every SH channel, followed by the packets and a CRC-32 of the sizes
+//! and packets in four bytes. The seek index follows the audio data and contains the offset of
+//! every `interval`-th frame, relative to the beginning of the audio data. The header has a fixed
+//! size, because the `ContainerWriter` fills it in after the audio data is written. Version 1
+//! files have no seek index, version 1 to 3 files have no checksums. All of them are still
+//! supported. Version 2 files keep the seek index in the header instead, between the `interval`
+//! and the audio data, which lasts until the end of the file.
+//!
+//! The optional metadata section follows the seek index and is located by the `metadata at` field,
+//! which is zero if it is missing. It has a size field followed by tagged chunks, see `Metadata`.
+//! Keeping it at the end allows updates without rewriting the audio data. Files written before
+//! version 5 can't store any metadata.
+//!
+//! The integrated loudness (in LUFS) and true peak (in dBTP) are measured after EBU R128 while
+//! encoding, see the `loudness` module. They are NaN for silent audio and missing before version 6.
+//! The audio data keeps its original level, the loudness is used for a normalization gain.
 //!
 extern crate byteorder;
 extern crate opus;
@@ -19,33 +37,97 @@ extern crate futures;
 
 pub mod error;
 pub mod configuration;
+pub mod writer;
+pub mod metadata;
+pub mod loudness;
 
 use std::path::Path;
-use std::io::{Seek, SeekFrom};
+use std::ops::Range;
+use std::io::{Read, Seek, SeekFrom};
 use std::fs::File;
 
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 use futures::sync::mpsc::Sender;
-use opus::{Channels, Application};
+use opus::Channels;
 
 use error::{Error, Result};
 pub use configuration::Configuration;
+pub use writer::ContainerWriter;
+pub use metadata::Metadata;
+pub use loudness::{Loudness, LoudnessMeter};
 
 /// Size of a single raw audio block
 const RAW_BLOCK_SIZE: usize = 1920;
 
+/// Version of newly written files
+const VERSION: u8 = 6;
+
+/// Number of frames between two entries in the seek index (about 1.3s)
+const INDEX_INTERVAL: u16 = 32;
+
+/// Byte offsets of every `interval`-th frame, relative to the beginning of the data section
+struct SeekIndex {
+    interval: u16,
+    offsets: Vec<u64>,
+    /// Position of the index in the file, which is the end of the data section
+    position: u64
+}
+
+/// Update a CRC-32 (IEEE 802.3) checksum with some bytes, starting with zero
+fn crc32(crc: u32, buf: &[u8]) -> u32 {
+    let mut crc = !crc;
+
+    for byte in buf {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+/// Read the number of entries in a seek index followed by their offsets
+fn read_offsets<R: Read>(inner: &mut R, interval: u16, samples: u32) -> Result<Vec<u64>> {
+    let entries = inner.read_u32::<LittleEndian>().map_err(|err| Error::File(err))?;
+
+    // the index can't have more entries than frames
+    let frames = (samples as usize + RAW_BLOCK_SIZE - 1) / RAW_BLOCK_SIZE;
+    if interval == 0 || entries as usize > frames / interval as usize + 1 {
+        return Err(Error::CorruptedFile);
+    }
+
+    let mut offsets = Vec::with_capacity(entries as usize);
+    for _ in 0..entries {
+        offsets.push(inner.read_u64::<LittleEndian>().map_err(|err| Error::File(err))?);
+    }
+
+    Ok(offsets)
+}
+
 /// Represents an open audio file
 pub struct Container<T> {
     /// Each SH channel needs its own decoder
     decoder: Vec<opus::Decoder>,
     /// The underlying audio file
     inner: T,
+    /// Version of the file format
+    version: u8,
     /// Spherical Harmonic order (describing the spatial resolution)
     sh_order: u8,
     /// Number of samples in the audio file
     samples: u32,
     /// SH scales for each SH channel
-    scales: Vec<f32>
+    scales: Vec<f32>,
+    /// Seek index of the audio data, missing in version 1 files
+    index: Option<SeekIndex>,
+    /// Position of the metadata section, zero if there is none
+    metadata: u64,
+    /// Measured loudness, missing before version 6 and for silent audio
+    loudness: Option<Loudness>,
+    /// Samples to drop from the beginning of the next frame, after seeking into its middle
+    skip: usize
 }
 
 impl<T> Container<T> 
@@ -58,7 +140,12 @@ impl<T> Container<T>
             sh_order: sh_order,
             samples: samples,
             scales: scales,
-            inner: inner 
+            inner: inner,
+            version: 1,
+            index: None,
+            metadata: 0,
+            loudness: None,
+            skip: 0
         };
 
         ct.seek_to_data();
@@ -66,6 +153,15 @@ impl<T> Container<T>
         ct
     }
 
+    /// Attach a seek index of a certain file version and move to the beginning of the data section again
+    fn with_index(mut self, version: u8, index: SeekIndex) -> Container<T> {
+        self.version = version;
+        self.index = Some(index);
+        self.seek_to_data();
+
+        self
+    }
+
     /// Creates a new empty container
     pub fn empty(sh_order: u8, inner: T) -> Container<T> {
         Container::new(sh_order, 0, vec![1.0; (sh_order as usize+1)*(sh_order as usize+1)], inner)
@@ -86,8 +182,8 @@ impl<T> Container<T>
             scales.push(inner.read_f32::<LittleEndian>().map_err(|err| Error::File(err))?);
         }
 
-        // Only this version is supported at the moment
-        if version != 1 {
+        // Version 1 has no seek index and versions before 4 no checksums, but all are still supported
+        if version == 0 || version > VERSION {
             return Err(Error::CorruptedFile);
         }
 
@@ -96,6 +192,38 @@ impl<T> Container<T>
             return Err(Error::CorruptedFile);
         }
 
+        let (index, metadata, loudness) = if version == 2 {
+            // the seek index precedes the audio data, which lasts until the end of the file
+            let interval = inner.read_u16::<LittleEndian>().map_err(|err| Error::File(err))?;
+            let offsets = read_offsets(&mut inner, interval, samples)?;
+            let position = inner.seek(SeekFrom::End(0)).map_err(|err| Error::File(err))?;
+
+            (Some(SeekIndex { interval, offsets, position }), 0, None)
+        } else if version >= 3 {
+            let interval = inner.read_u16::<LittleEndian>().map_err(|err| Error::File(err))?;
+            let position = inner.read_u64::<LittleEndian>().map_err(|err| Error::File(err))?;
+            let metadata = if version >= 5 {
+                inner.read_u64::<LittleEndian>().map_err(|err| Error::File(err))?
+            } else {
+                0
+            };
+            let loudness = if version >= 6 {
+                let integrated = inner.read_f32::<LittleEndian>().map_err(|err| Error::File(err))?;
+                let true_peak = inner.read_f32::<LittleEndian>().map_err(|err| Error::File(err))?;
+
+                if integrated.is_nan() || true_peak.is_nan() { None } else { Some(Loudness { integrated, true_peak }) }
+            } else {
+                None
+            };
+
+            inner.seek(SeekFrom::Start(position)).map_err(|err| Error::File(err))?;
+            let offsets = read_offsets(&mut inner, interval, samples)?;
+
+            (Some(SeekIndex { interval, offsets, position }), metadata, loudness)
+        } else {
+            (None, 0, None)
+        };
+
         
         //let header_size = 6 + 4 * (sh_order as u64 + 1) * (sh_order as u64 + 1);
         //let mut rem = inner.seek(SeekFrom::End(0)).unwrap() -  header_size;
@@ -105,7 +233,16 @@ impl<T> Container<T>
 
         //println!("Compression ratio {}", samples as f32 * 2.0 / rem as f32);
 
-        Ok(Container::new(sh_order, samples, scales, inner))
+        let container = Container::new(sh_order, samples, scales, inner);
+
+        let mut container = match index {
+            Some(index) => container.with_index(version, index),
+            None => container
+        };
+        container.metadata = metadata;
+        container.loudness = loudness;
+
+        Ok(container)
     }
 
     /// Open a audio file from a certain path
@@ -120,9 +257,104 @@ impl<T> Container<T>
         self.samples
     }
 
+    /// Get the integrated loudness and true peak, `None` for silent audio or older files
+    pub fn loudness(&self) -> Option<Loudness> {
+        self.loudness
+    }
+
+    /// Returns the underlying audio file
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Read the embedded metadata, `None` if the file has none
+    pub fn metadata(&mut self) -> Result<Option<Metadata>> {
+        if self.metadata == 0 {
+            return Ok(None);
+        }
+
+        let current = self.inner.seek(SeekFrom::Current(0)).map_err(|err| Error::File(err))?;
+        let position = self.metadata;
+        self.inner.seek(SeekFrom::Start(position)).map_err(|err| Error::File(err))?;
+
+        let size = self.inner.read_u32::<LittleEndian>().map_err(|err| Error::File(err))?;
+        let mut buf = Vec::new();
+        (&mut self.inner).take(size as u64).read_to_end(&mut buf).map_err(|err| Error::File(err))?;
+
+        self.inner.seek(SeekFrom::Start(current)).map_err(|err| Error::File(err))?;
+
+        if buf.len() != size as usize {
+            return Err(Error::CorruptedFile);
+        }
+
+        Metadata::from_slice(&buf).map(Some)
+    }
+
+    /// Replace the embedded metadata and move to the beginning of the data section again
+    ///
+    /// The metadata section is written after the seek index. Returns the new length of the file,
+    /// a file is not truncated if the metadata shrinks, but the stale bytes are never read.
+    pub fn set_metadata(&mut self, metadata: &Metadata) -> Result<u64> {
+        let position = match self.index {
+            Some(ref index) if self.version >= 5 => index.position + 4 + 8 * index.offsets.len() as u64,
+            _ => return Err(Error::NotSupported)
+        };
+
+        let buf = metadata.to_vec();
+        self.inner.seek(SeekFrom::Start(position)).map_err(|err| Error::File(err))?;
+        self.inner.write_u32::<LittleEndian>(buf.len() as u32).map_err(|err| Error::File(err))?;
+        self.inner.write_all(&buf).map_err(|err| Error::File(err))?;
+
+        // point the header to the metadata section, the field follows the position of the index
+        let field = 6 + 4 * self.num_harmonics() as u64 + 10;
+        self.inner.seek(SeekFrom::Start(field)).map_err(|err| Error::File(err))?;
+        self.inner.write_u64::<LittleEndian>(position).map_err(|err| Error::File(err))?;
+
+        self.metadata = position;
+        self.seek_to_data();
+
+        Ok(position + 4 + buf.len() as u64)
+    }
+
+    /// Position of the data section in the file
+    fn data_offset(&self) -> u64 {
+        let header = 6 + 4 * (self.sh_order as u64 + 1) * (self.sh_order as u64 + 1);
+
+        match self.version {
+            1 => header,
+            // the interval is followed by the number of entries and the offsets
+            2 => header + 6 + 8 * self.index.as_ref().map(|x| x.offsets.len() as u64).unwrap_or(0),
+            3 | 4 => header + 10,
+            5 => header + 18,
+            _ => header + 26
+        }
+    }
+
+    /// Range of the audio data in the file, from the end of the header to the seek index
+    ///
+    /// Unlike the header and the metadata section, the audio data never changes after the file is
+    /// written. It therefore identifies the content of a file.
+    pub fn data_range(&mut self) -> Result<Range<u64>> {
+        let end = match self.index {
+            Some(ref index) => index.position,
+            None => {
+                let current = self.inner.seek(SeekFrom::Current(0)).map_err(|err| Error::File(err))?;
+                let end = self.inner.seek(SeekFrom::End(0)).map_err(|err| Error::File(err))?;
+                self.inner.seek(SeekFrom::Start(current)).map_err(|err| Error::File(err))?;
+
+                end
+            }
+        };
+
+        Ok(self.data_offset()..end)
+    }
+
     /// Seek to the beginning of the data section
     pub fn seek_to_data(&mut self) {
-        self.inner.seek(SeekFrom::Start(6 + 4 * (self.sh_order as u64 + 1) * (self.sh_order as u64 + 1))).unwrap();
+        let offset = self.data_offset();
+
+        self.inner.seek(SeekFrom::Start(offset)).unwrap();
+        self.skip = 0;
     }
 
     /*pub fn check_samplesize(&mut self) -> Result<()> {
@@ -145,19 +377,60 @@ impl<T> Container<T>
     */
 
     /// Seek to a certain sample in the underlying memory
+    ///
+    /// The next packet will start exactly at this sample. The frame before it is decoded as well,
+    /// because the decoders need some samples to settle after the jump.
     pub fn seek_to_sample(&mut self, sample: u32) {
-        self.seek_to_data();
+        let frame = sample as usize / RAW_BLOCK_SIZE;
+        let start = frame.saturating_sub(1);
+
+        self.seek_to_frame(start);
+
+        for decoder in &mut self.decoder {
+            decoder.reset_state().unwrap();
+        }
+
+        if start < frame {
+            // an error will show up again when the next packet is read
+            let _ = self.decode_frame();
+        }
+
+        self.skip = sample as usize % RAW_BLOCK_SIZE;
+    }
+
+    /// Seek to the beginning of a frame
+    ///
+    /// We jump to the closest entry in the seek index and skip the remaining frames by their
+    /// size. Version 1 files have to skip all frames from the beginning of the data section.
+    fn seek_to_frame(&mut self, frame: usize) {
+        let data = self.data_offset();
+
+        let (offset, mut pos) = match self.index {
+            Some(ref index) if !index.offsets.is_empty() => {
+                let entry = usize::min(frame / index.interval as usize, index.offsets.len() - 1);
+
+                (index.offsets[entry], entry * index.interval as usize)
+            },
+            _ => (0, 0)
+        };
 
-        let mut pos = 0;
-        while pos + RAW_BLOCK_SIZE < sample as usize {
-            let mut skip = 0i64;
-            for _ in 0..(self.sh_order as usize +1)*(self.sh_order as usize +1) {
-                skip += self.inner.read_u8().unwrap() as i64;
+        self.inner.seek(SeekFrom::Start(data + offset)).unwrap();
+        self.skip = 0;
+
+        while pos < frame && !self.at_end() {
+            // the checksum follows the packets
+            let mut skip = if self.version >= 4 { 4i64 } else { 0i64 };
+            for _ in 0..self.num_harmonics() {
+                match self.inner.read_u8() {
+                    Ok(size) => skip += size as i64,
+                    // we reached the end of the file
+                    Err(_) => return
+                }
             }
 
             self.inner.seek(SeekFrom::Current(skip)).unwrap();
 
-            pos += RAW_BLOCK_SIZE;
+            pos += 1;
         }
     }
 
@@ -167,128 +440,139 @@ impl<T> Container<T>
     }
 
     /// Decode a single raw audio buffer with a certain loudspeaker configuration
-    pub fn next_packet(&mut self, conf: Configuration) -> Result<Vec<i16>> {
-        let sizes: Vec<Result<u8>> = (0..self.num_harmonics()).map(|_| {
-            self.inner.read_u8().map_err(|_| Error::ReachedEnd)
-        }).collect();
-        let codec = conf.codec();
+    ///
+    /// The audio is amplified by a linear `gain`, e.g. the normalization gain of `Loudness`, and
+    /// clipped at the 16bit range. The buffer is shorter than a frame, if we seeked into the
+    /// middle of it.
+    pub fn next_packet(&mut self, conf: Configuration, gain: f32) -> Result<Vec<i16>> {
+        let mut harmonics = self.decode_frame()?;
+
+        // drop the samples before the seek position
+        let skip = self.skip * self.num_harmonics() as usize;
+        harmonics.drain(..skip);
+        self.skip = 0;
+
+        if gain != 1.0 {
+            for x in &mut harmonics {
+                *x *= gain;
+            }
+        }
 
-        let mut buf = vec![0u8; 256];
-        let mut harmonic_unscaled = vec![0i16; RAW_BLOCK_SIZE];
-        let mut harmonics = vec![0f32; RAW_BLOCK_SIZE * (self.sh_order as usize + 1) * (self.sh_order as usize + 1)];
+        conf.codec().to_channels(&harmonics, self.sh_order)
+    }
+
+    /// Whether we reached the seek index following the audio data
+    fn at_end(&mut self) -> bool {
+        match self.index.as_ref().map(|x| x.position) {
+            Some(end) => self.inner.seek(SeekFrom::Current(0)).map(|pos| pos >= end).unwrap_or(true),
+            None => false
+        }
+    }
 
-        let mut i = 0;
-        for size in sizes {
-            let size = size.map_err(|_| Error::ReachedEnd)?;
+    /// Read the Opus packets of the next frame and check its checksum
+    fn read_frame(&mut self) -> Result<Vec<Vec<u8>>> {
+        if self.at_end() {
+            return Err(Error::ReachedEnd);
+        }
+
+        // a version 1 file just ends after the last frame
+        let mut sizes = vec![0u8; self.num_harmonics() as usize];
+        self.inner.read_exact(&mut sizes).map_err(|_| Error::ReachedEnd)?;
+
+        let mut packets = Vec::with_capacity(sizes.len());
+        for size in &sizes {
+            let mut packet = vec![0u8; *size as usize];
+            self.inner.read_exact(&mut packet).map_err(|_| Error::CorruptedFile)?;
 
-            let nread = self.inner.read(&mut buf[0..size as usize])
-                .map_err(|_| Error::ReachedEnd)?;
+            packets.push(packet);
+        }
+
+        if self.version >= 4 {
+            let crc = self.inner.read_u32::<LittleEndian>().map_err(|_| Error::CorruptedFile)?;
 
-            if nread != size as usize {
+            if packets.iter().fold(crc32(0, &sizes), |crc, packet| crc32(crc, packet)) != crc {
                 return Err(Error::CorruptedFile);
             }
+        }
 
-            //println!("Size: {}", size);
-            let nwritten = self.decoder[i].decode(&buf[0..nread], &mut harmonic_unscaled, false).unwrap();
+        Ok(packets)
+    }
 
-            let harmonic_scaled: Vec<f32> = harmonic_unscaled.iter().map(|x| *x as f32 / self.scales[i]).collect();
+    /// Decode the next frame to its scaled SH channels
+    fn decode_frame(&mut self) -> Result<Vec<f32>> {
+        let packets = self.read_frame()?;
+        let num_harmonics = self.num_harmonics() as usize;
 
-            for j in 0..RAW_BLOCK_SIZE {
-                harmonics[j * self.num_harmonics() as usize + i] = harmonic_scaled[j];
-            }
+        let mut harmonic_unscaled = vec![0i16; RAW_BLOCK_SIZE];
+        let mut harmonics = vec![0f32; RAW_BLOCK_SIZE * num_harmonics];
+
+        for (i, packet) in packets.iter().enumerate() {
+            let nwritten = self.decoder[i].decode(packet, &mut harmonic_unscaled, false)
+                .map_err(|_| Error::CorruptedFile)?;
 
             if nwritten != RAW_BLOCK_SIZE {
                 return Err(Error::CorruptedFile);
             }
 
-            i += 1;
+            for j in 0..RAW_BLOCK_SIZE {
+                harmonics[j * num_harmonics + i] = harmonic_unscaled[j] as f32 / self.scales[i];
+            }
         }
 
-        codec.to_channels(&harmonics, self.sh_order)
+        Ok(harmonics)
     }
 
-    /// Converts raw audio with loudspeaker configuration to a new `Container`
+    /// Scan all frames of the file and check that they are intact
     ///
-    /// The `progress` field can be used to connect a channel to the convesion process and get live
-    /// updates of the progress.
-    pub fn save_pcm(conf: Configuration, pcm: &[i16], mut inner: T, mut progress: Option<Sender<f32>>) -> Result<Container<T>> {
-        inner.write_u8(1).map_err(|err| Error::File(err))?;
-        inner.write_u8(conf.sh_order()).map_err(|err| Error::File(err))?;
-    
-        let samples = pcm.len() as u32 / conf.num_channels();
-        inner.write_u32::<LittleEndian>(samples).map_err(|err| Error::File(err))?;
-
-        // scale the audio data to the max 16bit range
-        let max_value = pcm.iter().map(|x| *x).max().ok_or(Error::InvalidRange)?;
-        let scale = 32767.0 / max_value as f32;
-        let pcm: Vec<i16> = pcm.iter().map(|x| (*x as f32 * scale) as i16).collect();
-
-        // convert the raw audio channels to spherical harmonics
-        let sh_codec = conf.codec();
-        let harmonics: Vec<f32> = sh_codec.to_harmonics(&pcm)?;//vec![0f32; conf.num_harmonics() * samples];
-
-        // determine scale factor for each harmonic to fit them into 16bit
-        let mut sh_scales = vec![0.0; conf.num_harmonics() as usize];
-        for sample in 0..samples as usize {
-            for harmonic in 0..conf.num_harmonics() as usize {
-                let val = harmonics[sample * conf.num_harmonics() as usize + harmonic].abs();
-
-                if val > sh_scales[harmonic] {
-                    sh_scales[harmonic] = val;
-                }
-            }
+    /// Frames are checked by their checksum, or by decoding them in files without checksums.
+    /// Fails with `Error::CorruptedFrame` and the number of the first bad or missing frame.
+    /// Afterwards the container is at the beginning of the data section again.
+    pub fn verify(&mut self) -> Result<()> {
+        let frames = (self.samples as usize + RAW_BLOCK_SIZE - 1) / RAW_BLOCK_SIZE;
+
+        self.seek_to_data();
+        for decoder in &mut self.decoder {
+            decoder.reset_state().unwrap();
         }
 
-        for scale in &mut sh_scales {
-            if scale.abs() < std::f32::EPSILON {
-                *scale = 1.0;
-            }else {
-                *scale = 32767.0 / *scale;
+        let mut result = Ok(());
+        for frame in 0..frames {
+            let valid = if self.version >= 4 {
+                self.read_frame().is_ok()
+            } else {
+                self.decode_frame().is_ok()
+            };
+
+            if !valid {
+                result = Err(Error::CorruptedFrame(frame));
+                break;
             }
         }
 
-        // write the Spherical Harmonic scales to the file (the decoding process needs them to
-        // restore the original scaling)
-        for scale in &sh_scales {
-            inner.write_f32::<LittleEndian>(*scale).map_err(|err| Error::File(err))?;
+        self.seek_to_data();
+        for decoder in &mut self.decoder {
+            decoder.reset_state().unwrap();
         }
 
-        // compress with Opus and the proper scaling factor
-        let mut opus_result: Vec<Vec<u8>> = (0..conf.num_harmonics()).map(|_| vec![0u8; 256]).collect();
-
-        let mut encoders: Vec<opus::Encoder> = (0..conf.num_harmonics()).map(|_| {
-            let mut encoder = opus::Encoder::new(48000, Channels::Mono, Application::Audio).unwrap();
-            encoder.set_bitrate(opus::Bitrate::Max).unwrap();
-
-            encoder
-        }).collect();
-
-        let steps = (samples as f32 / RAW_BLOCK_SIZE as f32).ceil() as usize;
-        let mut nwritten = vec![0u16; conf.num_harmonics() as usize];
-
-        for i in 0..steps {
-            for j in 0..conf.num_harmonics() as usize {
-                // convert each channel seperately with the corresponding scaling factor
-                let mut source = vec![0i16; RAW_BLOCK_SIZE];
-
-                // assert that we don't read after the buffer
-                for k in 0..usize::min(RAW_BLOCK_SIZE, samples as usize - i * RAW_BLOCK_SIZE) {
-                    source[k] = (harmonics[j + k * conf.num_harmonics() as usize + i * conf.num_harmonics() as usize * RAW_BLOCK_SIZE] * sh_scales[j]) as i16;
-                }
+        result
+    }
 
-                nwritten[j] = encoders[j].encode(&source, &mut opus_result[j]).map_err(|err| Error::Opus(err))? as u16;
-            }
+    /// Converts raw audio with loudspeaker configuration to a new `Container`
+    ///
+    /// The `progress` field can be used to connect a channel to the convesion process and get live
+    /// updates of the progress. Use the `ContainerWriter` if the raw audio arrives in blocks.
+    pub fn save_pcm(conf: Configuration, pcm: &[i16], inner: T, mut progress: Option<Sender<f32>>) -> Result<Container<T>> {
+        if pcm.is_empty() {
+            return Err(Error::InvalidRange);
+        }
 
-            //println!("Loss: {:?}, Bitrate: {:?}, Bandwidth: {:?}, Written: {:?}", encoders[0].get_packet_loss_perc().unwrap(), encoders[0].get_bitrate().unwrap(), encoders[0].get_bandwidth().unwrap(), nwritten);
+        let block_size = RAW_BLOCK_SIZE * conf.num_channels() as usize;
+        let steps = (pcm.len() + block_size - 1) / block_size;
 
-            // write each harmonic 
-            for c in 0..conf.num_harmonics() as usize {
-                inner.write_u8(nwritten[c] as u8).map_err(|err| Error::File(err))?;
-            }
+        let mut writer = ContainerWriter::new(conf, inner)?;
 
-            for c in 0..conf.num_harmonics() as usize {
-                inner.write(&opus_result[c][0..nwritten[c] as usize]).map_err(|err| Error::File(err))?;
-            }
+        for (i, block) in pcm.chunks(block_size).enumerate() {
+            writer.write(block)?;
 
             if let Some(ref mut progress) = progress {
                 progress.try_send(i as f32 / steps as f32)
@@ -300,17 +584,24 @@ impl<T> Container<T>
                 .map_err(|_| Error::SendFailed)?;
         }
 
-        Ok(Container::new(conf.sh_order(), samples, sh_scales, inner))
+        writer.finish()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs::File;
-    use std::io::{Read, Write};
+    use std::io::{Read, Write, Cursor, Seek, SeekFrom};
     use std::slice;
 
-    use super::{Container, Configuration, RAW_BLOCK_SIZE};
+    use byteorder::{WriteBytesExt, LittleEndian};
+    use error::Error;
+    use super::{Container, Configuration, Metadata, RAW_BLOCK_SIZE, INDEX_INTERVAL, crc32};
+
+    /// Stereo sine wave with a certain number of samples
+    fn sine(samples: usize) -> Vec<i16> {
+        (0..samples * 2).map(|i| ((i / 2) as f32 * 0.05).sin() * 10000.0).map(|x| x as i16).collect()
+    }
 
     #[test]
     fn convert_pcm() {
@@ -328,14 +619,14 @@ mod tests {
 
         let opus_file = File::create("assets/crazy.opus").unwrap();
         
-        Container::save_pcm(Configuration::Stereo, pcm2, opus_file).unwrap();
+        Container::save_pcm(Configuration::Stereo, pcm2, opus_file, None).unwrap();
     }
 
     #[test]
     fn read_opus() {
         let mut opus_file = File::open("assets/crazy.opus").unwrap();
 
-        let mut file = Container::from_file(opus_file).unwrap();
+        let mut file = Container::load(opus_file).unwrap();
 
         //file.seek_to_sample(121 * 1000 * 1000);
 
@@ -347,7 +638,7 @@ mod tests {
 
         while samples < file.samples {    
             //file.next_packet(&mut buf);
-            let buf = file.next_packet(Configuration::Stereo).unwrap();
+            let buf = file.next_packet(Configuration::Stereo, 1.0).unwrap();
 
             let pcm: &[u8] = unsafe {
                 slice::from_raw_parts(
@@ -362,4 +653,186 @@ mod tests {
         }
 
     }
+
+    #[test]
+    fn seek_index() {
+        let mut container = Container::save_pcm(Configuration::Stereo, &sine(100 * RAW_BLOCK_SIZE + 500), Cursor::new(Vec::new()), None).unwrap();
+
+        // find the beginning of each frame by reading all of them
+        container.seek_to_data();
+        let mut frames = vec![container.inner.seek(SeekFrom::Current(0)).unwrap()];
+        while container.decode_frame().is_ok() {
+            frames.push(container.inner.seek(SeekFrom::Current(0)).unwrap());
+        }
+        assert_eq!(frames.len(), 102);
+
+        // the index points to the same frames
+        let data = container.data_offset();
+        for (i, offset) in container.index.as_ref().unwrap().offsets.iter().enumerate() {
+            assert_eq!(data + offset, frames[i * INDEX_INTERVAL as usize]);
+        }
+
+        // seeks land on the exact sample
+        for sample in &[0, 1919, 1920, 40 * RAW_BLOCK_SIZE + 7, 100 * RAW_BLOCK_SIZE + 499] {
+            container.seek_to_sample(*sample as u32);
+
+            let packet = container.next_packet(Configuration::Stereo, 1.0).unwrap();
+            assert_eq!(packet.len(), (RAW_BLOCK_SIZE - sample % RAW_BLOCK_SIZE) * 2);
+            assert_eq!(container.inner.seek(SeekFrom::Current(0)).unwrap(), frames[sample / RAW_BLOCK_SIZE + 1]);
+        }
+    }
+
+    /// Header of a file and its audio data without checksums, together with the frame offsets
+    fn without_checksums(container: Container<Cursor<Vec<u8>>>) -> (Vec<u8>, Vec<u8>, Vec<u64>) {
+        let data = container.data_offset() as usize;
+        let end = container.index.as_ref().unwrap().position as usize;
+        let buf = container.inner.into_inner();
+
+        let mut frames = Vec::new();
+        let mut audio = Vec::new();
+        let mut pos = data;
+        while pos < end {
+            let len = 4 + buf[pos..pos + 4].iter().map(|x| *x as usize).sum::<usize>();
+            frames.push(audio.len() as u64);
+            audio.extend_from_slice(&buf[pos..pos + len]);
+            pos += len + 4;
+        }
+
+        (buf[..6 + 4 * 4].to_vec(), audio, frames)
+    }
+
+    /// Both containers decode the same audio, also after seeking
+    fn assert_same_audio(mut new: Container<Cursor<Vec<u8>>>, mut old: Container<Cursor<Vec<u8>>>, samples: &[usize]) {
+        for sample in samples {
+            new.seek_to_sample(*sample as u32);
+            old.seek_to_sample(*sample as u32);
+
+            loop {
+                match (new.next_packet(Configuration::Stereo, 1.0), old.next_packet(Configuration::Stereo, 1.0)) {
+                    (Ok(a), Ok(b)) => assert_eq!(a, b),
+                    (Err(Error::ReachedEnd), Err(Error::ReachedEnd)) => break,
+                    x => panic!("Unexpected result {:?}", x)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn load_version_1() {
+        let container = Container::save_pcm(Configuration::Stereo, &sine(10 * RAW_BLOCK_SIZE), Cursor::new(Vec::new()), None).unwrap();
+        let buf = container.inner.get_ref().clone();
+
+        // a version 1 file has the same header and audio data without the seek index and checksums
+        let (mut old, audio, _) = without_checksums(container);
+        old[0] = 1;
+        old.extend_from_slice(&audio);
+
+        let new = Container::load(Cursor::new(buf)).unwrap();
+        let old = Container::load(Cursor::new(old)).unwrap();
+
+        assert_same_audio(new, old, &[0, 5 * RAW_BLOCK_SIZE + 100]);
+    }
+
+    #[test]
+    fn load_version_2() {
+        let container = Container::save_pcm(Configuration::Stereo, &sine(70 * RAW_BLOCK_SIZE), Cursor::new(Vec::new()), None).unwrap();
+        let buf = container.inner.get_ref().clone();
+
+        // a version 2 file has no checksums and its seek index between the header and audio data
+        let (mut old, audio, frames) = without_checksums(container);
+        old[0] = 2;
+        old.write_u16::<LittleEndian>(INDEX_INTERVAL).unwrap();
+
+        let offsets: Vec<u64> = frames.into_iter().step_by(INDEX_INTERVAL as usize).collect();
+        assert_eq!(offsets.len(), 3);
+        old.write_u32::<LittleEndian>(offsets.len() as u32).unwrap();
+        for offset in offsets {
+            old.write_u64::<LittleEndian>(offset).unwrap();
+        }
+        old.extend_from_slice(&audio);
+
+        let new = Container::load(Cursor::new(buf)).unwrap();
+        let mut old = Container::load(Cursor::new(old)).unwrap();
+        old.verify().unwrap();
+        assert_eq!(old.metadata().unwrap(), None);
+
+        assert_same_audio(new, old, &[0, 5 * RAW_BLOCK_SIZE + 100, 40 * RAW_BLOCK_SIZE + 7, 66 * RAW_BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn verify_frames() {
+        let mut container = Container::save_pcm(Configuration::Stereo, &sine(10 * RAW_BLOCK_SIZE), Cursor::new(Vec::new()), None).unwrap();
+        container.verify().unwrap();
+
+        // find the beginning of the fourth frame
+        container.seek_to_frame(3);
+        let pos = container.inner.seek(SeekFrom::Current(0)).unwrap() as usize;
+        let mut buf = container.inner.into_inner();
+
+        // damage a byte in its first packet
+        buf[pos + 4] ^= 0xff;
+        let mut container = Container::load(Cursor::new(buf.clone())).unwrap();
+
+        match container.verify() {
+            Err(Error::CorruptedFrame(3)) => {},
+            x => panic!("Unexpected result {:?}", x)
+        }
+
+        // decoding reports the damaged frame instead of panicking
+        for _ in 0..3 {
+            container.next_packet(Configuration::Stereo, 1.0).unwrap();
+        }
+        match container.next_packet(Configuration::Stereo, 1.0) {
+            Err(Error::CorruptedFile) => {},
+            x => panic!("Unexpected result {:?}", x)
+        }
+
+        // a header announcing more samples misses the frames after the last one
+        buf[pos + 4] ^= 0xff;
+        buf[2..6].copy_from_slice(&[0x00, 0x5a, 0x00, 0x00]);
+        let mut container = Container::load(Cursor::new(buf)).unwrap();
+        assert_eq!(container.samples(), 12 * RAW_BLOCK_SIZE as u32);
+
+        match container.verify() {
+            Err(Error::CorruptedFrame(10)) => {},
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn embedded_metadata() {
+        let mut container = Container::save_pcm(Configuration::Stereo, &sine(10 * RAW_BLOCK_SIZE), Cursor::new(Vec::new()), None).unwrap();
+        assert_eq!(container.metadata().unwrap(), None);
+        let data = container.data_range().unwrap();
+
+        let mut metadata = Metadata {
+            title: Some("Sine".into()),
+            fingerprint: Some(vec![1, 2, 3]),
+            cover: Some(vec![0u8; 1000]),
+            ..Metadata::default()
+        };
+        let long = container.set_metadata(&metadata).unwrap();
+
+        // a smaller section leaves stale bytes behind, which are cut off
+        metadata.cover = None;
+        let short = container.set_metadata(&metadata).unwrap();
+        assert!(short + 1000 <= long);
+
+        let mut buf = container.into_inner().into_inner();
+        buf.truncate(short as usize);
+
+        let mut container = Container::load(Cursor::new(buf)).unwrap();
+        assert_eq!(container.metadata().unwrap(), Some(metadata));
+        container.verify().unwrap();
+
+        // the audio data stays in place
+        assert_eq!(container.data_range().unwrap(), data);
+    }
 }
+
@@ -0,0 +1,284 @@
+//! Loudness measurement after EBU R128
+//!
+//! The integrated loudness follows ITU-R BS.1770. Every channel is K-weighted, which models the
+//! sensitivity of the human ear, and the mean square of all channels is taken over blocks of
+//! 400ms, overlapping by 75%. Blocks quieter than -70 LUFS are ignored, as well as blocks more
+//! than 10 LU below the loudness of the remaining blocks. The true peak is the largest sample of
+//! the signal oversampled four times, which includes peaks between two samples.
+//!
+//! The measured loudness is used to normalize the playback. Tracks keep their original level in
+//! the audio file and are brought to a common loudness by a gain applied while decoding.
+
+use std::{f32, f64};
+
+/// Loudness of the playback normalization in LUFS, the same as ReplayGain 2.0
+pub const REFERENCE_LOUDNESS: f32 = -18.0;
+
+/// Largest true peak after the normalization in dBTP
+const MAX_TRUE_PEAK: f32 = -1.0;
+
+/// Samples in a step of 100ms, a block consists of four steps
+const STEP_SIZE: usize = 4800;
+
+/// Taps of each phase of the oversampling filter
+const TAPS: usize = 12;
+
+/// Coefficients of the two K-weighting stages at 48kHz, a high shelf and a high pass
+const K_WEIGHTING: [([f64; 3], [f64; 2]); 2] = [
+    ([1.53512485958697, -2.69169618940638, 1.19839281085285], [-1.69065929318241, 0.73248077421585]),
+    ([1.0, -2.0, 1.0], [-1.99004745483398, 0.99007225036621])
+];
+
+/// Measured loudness of a track or a group of tracks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub integrated: f32,
+    /// True peak in dBTP
+    pub true_peak: f32
+}
+
+impl Loudness {
+    /// Linear gain bringing the audio to the reference loudness
+    ///
+    /// The gain is reduced if the true peak would exceed -1 dBTP afterwards.
+    pub fn gain(&self) -> f32 {
+        let gain = (REFERENCE_LOUDNESS - self.integrated).min(MAX_TRUE_PEAK - self.true_peak);
+
+        10f32.powf(gain / 20.0)
+    }
+
+    /// Combine the loudness of several tracks with their duration, e.g. of an album
+    ///
+    /// The integrated loudness is approximated by the mean energy of the tracks, weighted by their
+    /// duration. The true peak is the largest of all tracks.
+    pub fn combine(parts: &[(Loudness, f64)]) -> Option<Loudness> {
+        let duration: f64 = parts.iter().map(|x| x.1).sum();
+        if parts.is_empty() || duration <= 0.0 {
+            return None;
+        }
+
+        let energy: f64 = parts.iter()
+            .map(|&(ref loudness, duration)| 10f64.powf(loudness.integrated as f64 / 10.0) * duration)
+            .sum();
+
+        Some(Loudness {
+            integrated: (10.0 * (energy / duration).log10()) as f32,
+            true_peak: parts.iter().map(|x| x.0.true_peak).fold(f32::NEG_INFINITY, |a, b| a.max(b))
+        })
+    }
+}
+
+/// Second order IIR filter in direct form I
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2]
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+
+        y
+    }
+}
+
+/// Measures the loudness and true peak of raw audio with interleaved channels
+pub struct LoudnessMeter {
+    num_channels: usize,
+    /// Both K-weighting stages of each channel
+    filters: Vec<(Biquad, Biquad)>,
+    /// Energy of the current step and its number of samples
+    step: (f64, usize),
+    /// Energy of the last three complete steps
+    last_steps: Vec<f64>,
+    /// Mean square of every block
+    blocks: Vec<f64>,
+    /// Polyphase filter interpolating three samples between two samples
+    oversampling: Vec<[f64; TAPS]>,
+    /// Last samples of each channel, `TAPS` per channel
+    history: Vec<f64>,
+    /// Position of the newest sample in the history
+    pos: usize,
+    /// Largest absolute value of the oversampled signal
+    peak: f64
+}
+
+impl LoudnessMeter {
+    /// Create a new meter for a certain number of channels
+    pub fn new(num_channels: usize) -> LoudnessMeter {
+        let filters = (0..num_channels)
+            .map(|_| (Biquad::new(K_WEIGHTING[0].0, K_WEIGHTING[0].1), Biquad::new(K_WEIGHTING[1].0, K_WEIGHTING[1].1)))
+            .collect();
+
+        // Hann windowed sinc with a cutoff at the original Nyquist frequency
+        let len = (4 * TAPS) as f64;
+        let oversampling = (0..4).map(|phase| {
+            let mut taps = [0.0; TAPS];
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let n = (4 * k + phase) as f64 + 0.5;
+                let t = (n - len / 2.0) / 4.0;
+                let sinc = if t == 0.0 { 1.0 } else { (f64::consts::PI * t).sin() / (f64::consts::PI * t) };
+
+                *tap = sinc * 0.5 * (1.0 - (2.0 * f64::consts::PI * n / len).cos());
+            }
+
+            taps
+        }).collect();
+
+        LoudnessMeter {
+            num_channels,
+            filters,
+            step: (0.0, 0),
+            last_steps: Vec::new(),
+            blocks: Vec::new(),
+            oversampling,
+            history: vec![0.0; TAPS * num_channels],
+            pos: 0,
+            peak: 0.0
+        }
+    }
+
+    /// Feed a block of raw audio with interleaved channels
+    pub fn feed(&mut self, pcm: &[i16]) {
+        for sample in pcm.chunks(self.num_channels) {
+            self.pos = (self.pos + 1) % TAPS;
+
+            for (c, x) in sample.iter().enumerate() {
+                let x = *x as f64 / 32768.0;
+
+                let y = self.filters[c].0.process(x);
+                let y = self.filters[c].1.process(y);
+                self.step.0 += y * y;
+
+                self.history[c * TAPS + self.pos] = x;
+                self.peak = self.peak.max(x.abs());
+
+                for taps in &self.oversampling {
+                    let mut y = 0.0;
+                    for (k, tap) in taps.iter().enumerate() {
+                        y += tap * self.history[c * TAPS + (self.pos + TAPS - k) % TAPS];
+                    }
+
+                    self.peak = self.peak.max(y.abs());
+                }
+            }
+
+            self.step.1 += 1;
+            if self.step.1 == STEP_SIZE {
+                self.finish_step();
+            }
+        }
+    }
+
+    /// A block is complete with every step, once four steps are available
+    fn finish_step(&mut self) {
+        let energy = self.step.0;
+        self.step = (0.0, 0);
+
+        if self.last_steps.len() == 3 {
+            let sum: f64 = self.last_steps.iter().sum();
+            self.blocks.push((sum + energy) / (4 * STEP_SIZE) as f64);
+
+            self.last_steps.remove(0);
+        }
+
+        self.last_steps.push(energy);
+    }
+
+    /// Integrated loudness in LUFS, `None` if the audio is silent or shorter than a block
+    pub fn integrated(&self) -> Option<f32> {
+        let loudness = |x: f64| -0.691 + 10.0 * x.log10();
+        let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+        // absolute gate
+        let blocks: Vec<f64> = self.blocks.iter().cloned().filter(|x| loudness(*x) > -70.0).collect();
+        if blocks.is_empty() {
+            return None;
+        }
+
+        // relative gate
+        let threshold = loudness(mean(&blocks)) - 10.0;
+        let blocks: Vec<f64> = blocks.into_iter().filter(|x| loudness(*x) > threshold).collect();
+
+        Some(loudness(mean(&blocks)) as f32)
+    }
+
+    /// True peak in dBTP, `None` if the audio is silent
+    pub fn true_peak(&self) -> Option<f32> {
+        if self.peak == 0.0 {
+            return None;
+        }
+
+        Some((20.0 * self.peak.log10()) as f32)
+    }
+
+    /// Loudness and true peak of the audio so far
+    pub fn loudness(&self) -> Option<Loudness> {
+        match (self.integrated(), self.true_peak()) {
+            (Some(integrated), Some(true_peak)) => Some(Loudness { integrated, true_peak }),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoudnessMeter, Loudness, REFERENCE_LOUDNESS};
+
+    /// Stereo sine wave of 1kHz with an amplitude in dBFS
+    fn sine(dbfs: f64, seconds: usize) -> Vec<i16> {
+        let amplitude = 32768.0 * 10f64.powf(dbfs / 20.0);
+
+        (0..48000 * seconds * 2)
+            .map(|i| (amplitude * (2.0 * ::std::f64::consts::PI * 1000.0 * (i / 2) as f64 / 48000.0).sin()) as i16)
+            .collect()
+    }
+
+    #[test]
+    fn sine_loudness() {
+        // EBU Tech 3341, first test case
+        let mut meter = LoudnessMeter::new(2);
+        for block in sine(-23.0, 20).chunks(777 * 2) {
+            meter.feed(block);
+        }
+
+        let loudness = meter.loudness().unwrap();
+        assert!((loudness.integrated + 23.0).abs() < 0.1, "integrated loudness {}", loudness.integrated);
+        assert!((loudness.true_peak + 23.0).abs() < 0.5, "true peak {}", loudness.true_peak);
+
+        // the gain brings it to the reference loudness
+        assert!((loudness.gain() - 10f32.powf((REFERENCE_LOUDNESS + 23.0) / 20.0)).abs() < 0.05);
+
+        // silence is not measurable
+        let mut meter = LoudnessMeter::new(2);
+        meter.feed(&vec![0; 48000 * 2]);
+        assert_eq!(meter.loudness(), None);
+    }
+
+    #[test]
+    fn gain_and_album() {
+        // a loud master is turned down, a quiet recording is turned up to its true peak limit
+        let master = Loudness { integrated: -8.0, true_peak: 0.5 };
+        let quiet = Loudness { integrated: -30.0, true_peak: -6.0 };
+
+        assert!((master.gain() - 10f32.powf(-10.0 / 20.0)).abs() < 1e-4);
+        assert!((quiet.gain() - 10f32.powf(5.0 / 20.0)).abs() < 1e-4);
+
+        // the album shares a single gain
+        let album = Loudness::combine(&[(master, 100.0), (quiet, 100.0)]).unwrap();
+        assert!((album.integrated + 11.0).abs() < 0.1, "album loudness {}", album.integrated);
+        assert_eq!(album.true_peak, 0.5);
+        assert_eq!(Loudness::combine(&[]), None);
+    }
+}
@@ -0,0 +1,161 @@
+//! Descriptive information embedded in an audio file
+//!
+//! The metadata is stored as a sequence of tagged chunks. Each chunk starts with a four byte tag,
+//! followed by the length of its content in four bytes and the content itself. Chunks with an
+//! unknown tag are skipped, newer versions can therefore add further chunks. Strings are UTF-8
+//! encoded, numbers are little endian.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use error::{Error, Result};
+
+/// Metadata of a track, every field is optional
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub interpret: Option<String>,
+    pub people: Option<String>,
+    pub composer: Option<String>,
+    /// Acoustic fingerprint, the track key is derived from it
+    pub fingerprint: Option<Vec<u32>>,
+    /// Integrated loudness in LUFS
+    pub loudness: Option<f32>,
+    /// Cover image, e.g. a JPEG or PNG file
+    pub cover: Option<Vec<u8>>
+}
+
+impl Metadata {
+    /// Parse the tagged chunks of a metadata section
+    pub fn from_slice(buf: &[u8]) -> Result<Metadata> {
+        let mut metadata = Metadata::default();
+        let mut rem = buf;
+
+        while !rem.is_empty() {
+            if rem.len() < 8 {
+                return Err(Error::CorruptedFile);
+            }
+
+            let mut tag = [0u8; 4];
+            tag.copy_from_slice(&rem[..4]);
+            let len = LittleEndian::read_u32(&rem[4..8]) as usize;
+
+            if rem.len() - 8 < len {
+                return Err(Error::CorruptedFile);
+            }
+
+            let data = &rem[8..8 + len];
+            match &tag {
+                b"TITL" => metadata.title = Some(string(data)?),
+                b"ALBM" => metadata.album = Some(string(data)?),
+                b"INTP" => metadata.interpret = Some(string(data)?),
+                b"PEOP" => metadata.people = Some(string(data)?),
+                b"COMP" => metadata.composer = Some(string(data)?),
+                b"FPRT" => {
+                    if data.len() % 4 != 0 {
+                        return Err(Error::CorruptedFile);
+                    }
+
+                    metadata.fingerprint = Some(data.chunks(4).map(|x| LittleEndian::read_u32(x)).collect());
+                },
+                b"LOUD" => {
+                    if data.len() != 4 {
+                        return Err(Error::CorruptedFile);
+                    }
+
+                    metadata.loudness = Some(LittleEndian::read_f32(data));
+                },
+                b"COVR" => metadata.cover = Some(data.to_vec()),
+                _ => {}
+            }
+
+            rem = &rem[8 + len..];
+        }
+
+        Ok(metadata)
+    }
+
+    /// Write all fields which are set as tagged chunks
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        let strings = [
+            (b"TITL", &self.title), (b"ALBM", &self.album), (b"INTP", &self.interpret),
+            (b"PEOP", &self.people), (b"COMP", &self.composer)
+        ];
+
+        for &(tag, value) in &strings {
+            if let Some(ref value) = *value {
+                put_chunk(&mut buf, tag, value.as_bytes());
+            }
+        }
+
+        if let Some(ref fingerprint) = self.fingerprint {
+            let mut data = vec![0u8; fingerprint.len() * 4];
+            for (chunk, x) in data.chunks_mut(4).zip(fingerprint) {
+                LittleEndian::write_u32(chunk, *x);
+            }
+
+            put_chunk(&mut buf, b"FPRT", &data);
+        }
+
+        if let Some(loudness) = self.loudness {
+            let mut data = [0u8; 4];
+            LittleEndian::write_f32(&mut data, loudness);
+
+            put_chunk(&mut buf, b"LOUD", &data);
+        }
+
+        if let Some(ref cover) = self.cover {
+            put_chunk(&mut buf, b"COVR", cover);
+        }
+
+        buf
+    }
+}
+
+/// Append a single chunk to the buffer
+fn put_chunk(buf: &mut Vec<u8>, tag: &[u8; 4], data: &[u8]) {
+    let mut len = [0u8; 4];
+    LittleEndian::write_u32(&mut len, data.len() as u32);
+
+    buf.extend_from_slice(tag);
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(data);
+}
+
+fn string(data: &[u8]) -> Result<String> {
+    String::from_utf8(data.to_vec()).map_err(|_| Error::CorruptedFile)
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use super::{Metadata, put_chunk};
+
+    #[test]
+    fn chunks() {
+        let metadata = Metadata {
+            title: Some("Crazy".into()),
+            interpret: Some("Gnarls Barkley".into()),
+            fingerprint: Some(vec![1, 2, 0xdeadbeef]),
+            loudness: Some(-14.5),
+            cover: Some(vec![0xff, 0xd8, 0xff]),
+            ..Metadata::default()
+        };
+
+        let mut buf = metadata.to_vec();
+        assert_eq!(Metadata::from_slice(&buf).unwrap(), metadata);
+
+        // chunks of newer versions are skipped
+        put_chunk(&mut buf, b"XXXX", b"unknown");
+        assert_eq!(Metadata::from_slice(&buf).unwrap(), metadata);
+
+        // but a truncated chunk is not
+        let len = buf.len();
+        match Metadata::from_slice(&buf[..len - 1]) {
+            Err(Error::CorruptedFile) => {},
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Encode raw audio arriving in blocks
+//!
+//! The `ContainerWriter` compresses every complete frame as soon as it arrives, therefore only a
+//! single frame of raw audio is kept in memory. Each SH channel is scaled by a fixed factor, which
+//! maps the largest value of raw audio at full scale to the 16bit range. The decoded audio keeps
+//! the level of the raw audio. Its loudness is measured along and once all blocks are written, the
+//! header is filled in with the number of samples and the loudness.
+
+use std::f32;
+use std::io::{Seek, SeekFrom};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use opus::{self, Channels, Application};
+
+use error::{Error, Result};
+use configuration::{Configuration, Codec};
+use loudness::LoudnessMeter;
+use super::{Container, SeekIndex, RAW_BLOCK_SIZE, VERSION, INDEX_INTERVAL, crc32};
+
+/// Writes a new audio file block by block
+pub struct ContainerWriter<T> {
+    inner: T,
+    conf: Configuration,
+    codec: Codec,
+    /// Each SH channel needs its own encoder
+    encoders: Vec<opus::Encoder>,
+    /// Fixed scale of each SH channel during the encoding
+    scales: Vec<f32>,
+    /// Scales stored in the header, which are applied while decoding
+    header_scales: Vec<f32>,
+    /// Raw audio not filling a whole frame yet
+    pending: Vec<i16>,
+    /// Number of samples written so far
+    samples: u32,
+    /// Loudness of the raw audio
+    meter: LoudnessMeter,
+    /// Offsets of the frames in the seek index
+    offsets: Vec<u64>,
+    /// Size of the data section so far
+    offset: u64,
+    /// Number of frames written so far
+    frames: usize
+}
+
+impl<T> ContainerWriter<T>
+    where T: ReadBytesExt + WriteBytesExt + Seek
+{
+    /// Creates a new audio file and writes a preliminary header
+    pub fn new(conf: Configuration, mut inner: T) -> Result<ContainerWriter<T>> {
+        let codec = conf.codec();
+
+        let scales: Vec<f32> = codec.harmonics_range()?.into_iter().map(|range| {
+            if range < f32::EPSILON { 1.0 } else { 32767.0 / range }
+        }).collect();
+
+        // `to_channels` restores stereo audio at half its level, the decoded SH channels are
+        // amplified to make up for it
+        let gain = match conf {
+            Configuration::Stereo => 2.0,
+            _ => 1.0
+        };
+        let header_scales: Vec<f32> = scales.iter().map(|scale| scale / gain).collect();
+
+        // samples, the position of the seek index and the loudness are filled in by `finish`
+        inner.write_u8(VERSION).map_err(|err| Error::File(err))?;
+        inner.write_u8(conf.sh_order()).map_err(|err| Error::File(err))?;
+        inner.write_u32::<LittleEndian>(0).map_err(|err| Error::File(err))?;
+        for scale in &header_scales {
+            inner.write_f32::<LittleEndian>(*scale).map_err(|err| Error::File(err))?;
+        }
+        inner.write_u16::<LittleEndian>(INDEX_INTERVAL).map_err(|err| Error::File(err))?;
+        inner.write_u64::<LittleEndian>(0).map_err(|err| Error::File(err))?;
+        // the metadata section is added with `Container::set_metadata`
+        inner.write_u64::<LittleEndian>(0).map_err(|err| Error::File(err))?;
+        inner.write_f32::<LittleEndian>(f32::NAN).map_err(|err| Error::File(err))?;
+        inner.write_f32::<LittleEndian>(f32::NAN).map_err(|err| Error::File(err))?;
+
+        let encoders = (0..conf.num_harmonics()).map(|_| -> Result<opus::Encoder> {
+            let mut encoder = opus::Encoder::new(48000, Channels::Mono, Application::Audio)
+                .map_err(|err| Error::Opus(err))?;
+            encoder.set_bitrate(opus::Bitrate::Max)
+                .map_err(|err| Error::Opus(err))?;
+
+            Ok(encoder)
+        }).collect::<Result<Vec<_>>>()?;
+
+        Ok(ContainerWriter {
+            meter: LoudnessMeter::new(conf.num_channels() as usize),
+            inner, conf, codec, encoders, scales, header_scales,
+            pending: Vec::new(),
+            samples: 0,
+            offsets: Vec::new(),
+            offset: 0,
+            frames: 0
+        })
+    }
+
+    /// Number of samples written so far
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Write a block of raw audio with interleaved channels
+    ///
+    /// The block can have any number of samples, an incomplete frame is encoded as soon as
+    /// the next block completes it.
+    pub fn write(&mut self, pcm: &[i16]) -> Result<()> {
+        let num_channels = self.conf.num_channels() as usize;
+        let frame_size = RAW_BLOCK_SIZE * num_channels;
+
+        if pcm.len() % num_channels != 0 {
+            return Err(Error::InvalidSize);
+        }
+
+        self.samples += (pcm.len() / num_channels) as u32;
+        self.meter.feed(pcm);
+
+        // complete the pending frame first
+        let mut pcm = pcm;
+        if !self.pending.is_empty() {
+            let missing = usize::min(frame_size - self.pending.len(), pcm.len());
+            self.pending.extend_from_slice(&pcm[..missing]);
+            pcm = &pcm[missing..];
+
+            if self.pending.len() < frame_size {
+                return Ok(());
+            }
+
+            let frame = self.pending.split_off(0);
+            self.encode(&frame)?;
+        }
+
+        for frame in pcm.chunks(frame_size) {
+            if frame.len() == frame_size {
+                self.encode(frame)?;
+            } else {
+                self.pending.extend_from_slice(frame);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compress a frame of raw audio and append it to the data section
+    fn encode(&mut self, frame: &[i16]) -> Result<()> {
+        let num_harmonics = self.conf.num_harmonics() as usize;
+        let harmonics = self.codec.to_harmonics(frame)?;
+
+        if self.frames % INDEX_INTERVAL as usize == 0 {
+            self.offsets.push(self.offset);
+        }
+
+        let mut packets = Vec::with_capacity(num_harmonics);
+        for j in 0..num_harmonics {
+            // convert each channel seperately with the corresponding scaling factor
+            let source: Vec<i16> = (0..RAW_BLOCK_SIZE)
+                .map(|k| (harmonics[k * num_harmonics + j] * self.scales[j]) as i16)
+                .collect();
+
+            let mut packet = vec![0u8; 255];
+            let nwritten = self.encoders[j].encode(&source, &mut packet).map_err(|err| Error::Opus(err))?;
+            packet.truncate(nwritten);
+
+            packets.push(packet);
+        }
+
+        // write the size of each harmonic, followed by the packets and their checksum
+        let sizes: Vec<u8> = packets.iter().map(|x| x.len() as u8).collect();
+        self.inner.write_all(&sizes).map_err(|err| Error::File(err))?;
+
+        for packet in &packets {
+            self.inner.write_all(packet).map_err(|err| Error::File(err))?;
+            self.offset += 1 + packet.len() as u64;
+        }
+
+        let crc = packets.iter().fold(crc32(0, &sizes), |crc, packet| crc32(crc, packet));
+        self.inner.write_u32::<LittleEndian>(crc).map_err(|err| Error::File(err))?;
+        self.offset += 4;
+
+        self.frames += 1;
+
+        Ok(())
+    }
+
+    /// Encode the remaining raw audio, write the seek index and fill in the header
+    pub fn finish(mut self) -> Result<Container<T>> {
+        // the last frame is padded with silence
+        if !self.pending.is_empty() {
+            let mut frame = self.pending.split_off(0);
+            frame.resize(RAW_BLOCK_SIZE * self.conf.num_channels() as usize, 0);
+
+            self.encode(&frame)?;
+        }
+
+        let position = self.inner.seek(SeekFrom::Current(0)).map_err(|err| Error::File(err))?;
+
+        self.inner.write_u32::<LittleEndian>(self.offsets.len() as u32).map_err(|err| Error::File(err))?;
+        for offset in &self.offsets {
+            self.inner.write_u64::<LittleEndian>(*offset).map_err(|err| Error::File(err))?;
+        }
+
+        let loudness = self.meter.loudness();
+        let (integrated, true_peak) = match loudness {
+            Some(ref x) => (x.integrated, x.true_peak),
+            None => (f32::NAN, f32::NAN)
+        };
+
+        self.inner.seek(SeekFrom::Start(2)).map_err(|err| Error::File(err))?;
+        self.inner.write_u32::<LittleEndian>(self.samples).map_err(|err| Error::File(err))?;
+
+        // skip the scales and interval, which are already known, and the metadata field
+        self.inner.seek(SeekFrom::Start(6 + 4 * self.scales.len() as u64 + 2)).map_err(|err| Error::File(err))?;
+        self.inner.write_u64::<LittleEndian>(position).map_err(|err| Error::File(err))?;
+        self.inner.seek(SeekFrom::Current(8)).map_err(|err| Error::File(err))?;
+        self.inner.write_f32::<LittleEndian>(integrated).map_err(|err| Error::File(err))?;
+        self.inner.write_f32::<LittleEndian>(true_peak).map_err(|err| Error::File(err))?;
+
+        let index = SeekIndex { interval: INDEX_INTERVAL, offsets: self.offsets, position };
+
+        let mut container = Container::new(self.conf.sh_order(), self.samples, self.header_scales, self.inner).with_index(VERSION, index);
+        container.loudness = loudness;
+
+        Ok(container)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use configuration::Configuration;
+    use super::ContainerWriter;
+    use Container;
+    use RAW_BLOCK_SIZE;
+
+    #[test]
+    fn write_blocks() {
+        let pcm: Vec<i16> = (0..(20 * RAW_BLOCK_SIZE + 300) * 2)
+            .map(|i| ((i / 2) as f32 * 0.01).sin() * 5000.0).map(|x| x as i16).collect();
+
+        // blocks of any size result in the same file
+        let mut writer = ContainerWriter::new(Configuration::Stereo, Cursor::new(Vec::new())).unwrap();
+        for block in pcm.chunks(2 * 777) {
+            writer.write(block).unwrap();
+        }
+        assert_eq!(writer.samples(), 20 * RAW_BLOCK_SIZE as u32 + 300);
+
+        let blocks = writer.finish().unwrap().inner.into_inner();
+        let whole = Container::save_pcm(Configuration::Stereo, &pcm, Cursor::new(Vec::new()), None).unwrap().inner.into_inner();
+        assert_eq!(blocks, whole);
+
+        // the header is filled in and the audio keeps its level
+        let mut container = Container::load(Cursor::new(blocks)).unwrap();
+        assert_eq!(container.samples(), 20 * RAW_BLOCK_SIZE as u32 + 300);
+
+        let loudness = container.loudness().unwrap();
+        assert!((loudness.true_peak - 20.0 * (5000.0f32 / 32768.0).log10()).abs() < 0.5, "true peak {}", loudness.true_peak);
+
+        let mut peak = 0;
+        let mut samples = 0;
+        while let Ok(packet) = container.next_packet(Configuration::Stereo, 1.0) {
+            peak = packet.iter().fold(peak, |peak, x| i32::max(peak, (*x as i32).abs()));
+            samples += packet.len() / 2;
+        }
+
+        assert_eq!(samples, 21 * RAW_BLOCK_SIZE);
+        assert!(peak > 4500 && peak < 5500, "peak {} changed", peak);
+
+        // a gain is applied while decoding
+        container.seek_to_sample(10 * RAW_BLOCK_SIZE as u32);
+        let packet = container.next_packet(Configuration::Stereo, 2.0).unwrap();
+        let peak = packet.iter().fold(0, |peak, x| i32::max(peak, (*x as i32).abs()));
+        assert!(peak > 9000 && peak < 11000, "peak {} is not amplified", peak);
+    }
+}
@@ -2,10 +2,13 @@ extern crate chrono;
 extern crate hex_conf;
 extern crate hex_database;
 
-use std::env;
-use std::path::Path;
-use hex_database::{Collection, Event, events::Action};
-use chrono::{TimeZone, Utc, Date, Duration};
+use std::path::PathBuf;
+use hex_database::{Instance, GossipConf, Keypair};
+use chrono::{TimeZone, Utc, Duration};
+
+/// Compact the transition log with a checkpoint once it contains more than this number of
+/// transitions
+const CHECKPOINT_THRESHOLD: u64 = 10_000;
 
 fn main() {
     let (conf, path) = match hex_conf::Conf::new() {
@@ -20,11 +23,30 @@ fn main() {
     let mut gossip = GossipConf::new();
 
     if let Some(ref peer) = conf.peer {
-        gossip = gossip.id(peer.id());
+        gossip = gossip.keypair(Keypair::from_seed(&peer.secret()));
     }
 
-    let instance = Instance::from_file(&db_path, gossip);
-    let view = instance.view();
+    let instance = match Instance::from_file(&db_path, gossip) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error: Could not open database {:?}", err);
+            return;
+        }
+    };
+    let view = match instance.view() {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error: Could not open database view {:?}", err);
+            return;
+        }
+    };
+
+    // transitions before the checkpoint are pruned, new peers bootstrap from it
+    match view.checkpoint_if_needed(CHECKPOINT_THRESHOLD) {
+        Ok(true) => println!("Created checkpoint"),
+        Ok(false) => {},
+        Err(err) => eprintln!("Error: Could not create checkpoint {:?}", err)
+    }
 
     let newest_date = view.get_latest_summary_day()
         .map(|x| Utc.datetime_from_str(&format!("{} 10:10:00", x), "%Y-%m-%d %H:%M:%S").unwrap().date())
@@ -39,10 +61,10 @@ fn main() {
 
     let mut days = vec![(0u32, 0u32); num_days as usize];
 
-    let num_tracks = view.get_num_tracks();
+    let num_tracks = view.get_num_tracks().unwrap() as u32;
 
-    for day in 0..num_days {
-        let num_transitions = view.get_num_transitions(day);
+    for day in 0..days.len() {
+        let num_transitions = view.get_num_transitions(day as u32).unwrap() as u32;
 
         days[day] = (num_tracks, num_transitions);
     }
@@ -51,8 +73,7 @@ fn main() {
         let datestamp = Utc::today().checked_sub_signed(Duration::days(i as i64 + 1)).unwrap();
         let datestamp = datestamp.format("%Y-%m-%d");
 
-        
-        db.summarise_day(datestamp.to_string(), days[i].0, days[i].1).unwrap();
+        view.summarise_day(datestamp.to_string(), days[i].1, days[i].0).unwrap();
     }
 
     println!("{:#?}", days);
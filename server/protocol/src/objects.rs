@@ -8,7 +8,8 @@ use error::{Error, Result};
 
 use bincode::{serialize, deserialize};
 
-use hex_database::{Track, Playlist, Token, Event, TrackKey, PlaylistKey, TokenId, TransitionAction, Transition};
+use hex_database::{Track, Playlist, PlaylistQuery, Token, Event, TrackKey, PlaylistKey, TokenId, TransitionAction, Transition, Progress, PeerStatus};
+use hex_database::search::ParseError;
 
 /// Identification of a packet
 ///
@@ -82,6 +83,11 @@ pub enum RequestAction {
         title: Option<String>,
         desc: Option<String>
     },
+    /// Turn a playlist into a smart playlist, or back into a normal one
+    SetPlaylistQuery {
+        key: PlaylistKey,
+        query: Option<PlaylistQuery>
+    },
     /// Get all playlists
     GetPlaylists,
     /// Get a single playlist with key
@@ -131,13 +137,25 @@ pub enum RequestAction {
     GetSummary,
     /// Get all events
     GetTransitions,
+    /// Get aggregated listening statistics for plays between `from` and `to` (seconds since
+    /// UNIX epoch), optionally only from a single origin
+    GetStatistics {
+        query: Statistics,
+        from: i64,
+        to: i64,
+        origin: Option<String>
+    },
     /// Start download a bunch of tracks
     Download {
         format: String,
         tracks: Vec<TrackKey>
     },
     /// Ask for the download progress
-    AskDownloadProgress
+    AskDownloadProgress,
+    /// Ask for the progress of the replication from other peers
+    AskReplicationProgress,
+    /// Get the status of all peers known to the server
+    GetPeers
 }
 
 /// Wrapper for the Incoming message
@@ -183,6 +201,13 @@ pub enum AnswerAction {
         /// Are there more tracks available? (repeated call)
         more: bool
     },
+    /// The search query contains a syntax error
+    SearchError {
+        /// Searched query
+        query: String,
+        /// Position and kind of the error
+        error: ParseError
+    },
     /// Response to a `GetTrack` call
     Track(Track),
     ClearBuffer,
@@ -202,6 +227,7 @@ pub enum AnswerAction {
     SetPlaylistImage,
     AddToPlaylist,
     DeleteFromPlaylist,
+    SetPlaylistQuery,
     GetPlaylists(Vec<Playlist>),
     GetPlaylist((Playlist,Vec<Track>)),
     GetPlaylistsOfTrack(Vec<Playlist>),
@@ -216,8 +242,13 @@ pub enum AnswerAction {
     LastToken(Option<TokenId>),
     GetSummary(Vec<(String, u32, u32)>),
     GetTransitions(Vec<Transition>),
+    GetStatistics(StatisticsResult),
     Download,
     AskDownloadProgress(Vec<DownloadProgress>),
+    /// Progress of the replication, `None` if the server doesn't replicate
+    AskReplicationProgress(Option<Progress>),
+    /// Known peers, empty if the server is not connected to other peers
+    GetPeers(Vec<PeerStatus>),
     Transition(TransitionAction)
 }
 
@@ -248,6 +279,40 @@ impl Answer {
     }
 }
 
+/// Kind of listening statistic
+#[derive(Debug)]
+#[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Deserialize))]
+#[cfg_attr(feature="client", derive(Serialize))]
+pub enum Statistics {
+    /// Most played tracks
+    TopTracks { limit: u32 },
+    /// Most played interprets
+    TopInterprets { limit: u32 },
+    /// Most played albums
+    TopAlbums { limit: u32 },
+    /// Listening time per day
+    ListeningTime,
+    /// Plays per weekday and hour
+    Heatmap
+}
+
+/// Answer to a `GetStatistics` request
+#[derive(Debug)]
+#[cfg_attr(feature="client", derive(Deserialize))]
+#[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Serialize))]
+pub enum StatisticsResult {
+    /// Tracks with their number of plays
+    TopTracks(Vec<(Track, u32)>),
+    /// Interprets with their number of plays
+    TopInterprets(Vec<(String, u32)>),
+    /// Albums with their number of plays
+    TopAlbums(Vec<(String, u32)>),
+    /// Days (`%Y-%m-%d`) with the listening time in seconds
+    ListeningTime(Vec<(String, f64)>),
+    /// Number of plays indexed by weekday (beginning with Sunday) and hour, both in UTC
+    Heatmap([[u32; 24]; 7])
+}
+
 #[derive(Debug)]
 #[cfg_attr(feature="client", derive(Deserialize))]
 #[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Serialize))]
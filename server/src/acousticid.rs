@@ -10,20 +10,31 @@ use error::{Error, Result};
 
 /// Calculate a fingerprint to lookup music
 ///
-/// This function takes a raw audio and number of channels and calculates the corresponding
-/// fingerprint, strongly connected to the content.
-///
-///  * `num_channel`- Number of channel in `data`
-///  * `data` - Raw audio data with succeeding channels
-pub fn get_fingerprint(num_channel: u16, data: &[i16]) -> Result<Vec<u32>> {
-    let mut ctx = Chromaprint::new();
-    ctx.start(48000, num_channel as i32);
+/// This struct takes raw audio in blocks and calculates the corresponding fingerprint, strongly
+/// connected to the content. The blocks contain succeeding channels.
+pub struct Fingerprint(Chromaprint);
+
+impl Fingerprint {
+    /// Start a new fingerprint for raw audio with `num_channel` channels
+    pub fn new(num_channel: u16) -> Fingerprint {
+        let mut ctx = Chromaprint::new();
+        ctx.start(48000, num_channel as i32);
 
-    ctx.feed(data);
-    ctx.finish();
+        Fingerprint(ctx)
+    }
 
-    ctx.raw_fingerprint().ok_or(Error::AcousticID)
-        .map(|x| x.into_iter().map(|x| x as u32).collect())
+    /// Feed the next block of raw audio
+    pub fn feed(&mut self, data: &[i16]) {
+        self.0.feed(data);
+    }
+
+    /// Finish the calculation and return the fingerprint
+    pub fn finish(mut self) -> Result<Vec<u32>> {
+        self.0.finish();
+
+        self.0.raw_fingerprint().ok_or(Error::AcousticID)
+            .map(|x| x.into_iter().map(|x| x as u32).collect())
+    }
 }
 
 /*pub fn get_hash(fingerprint: &[i32]) -> i64 {
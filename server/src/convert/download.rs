@@ -53,7 +53,7 @@ fn worker(mut sender: Sender<DownloadProgress>, id: PacketId, format: String, tr
 
         println!("convert start");
         loop {
-            match container.next_packet(Configuration::Stereo) {
+            match container.next_packet(Configuration::Stereo, 1.0) {
                 Ok(buf) => { 
                     let buf: &[u8] = unsafe {
                         slice::from_raw_parts(
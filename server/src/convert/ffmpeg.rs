@@ -1,9 +1,9 @@
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 use std::path::PathBuf;
 use std::fs::{File, self};
 use std::process::Command;
 use std::process::Stdio;
-use std::{result, slice};
+use std::result;
 
 use tokio_io::AsyncRead;
 use tokio_codec;
@@ -81,24 +81,17 @@ impl State {
         }
     }
 
-    pub fn read(&self) -> (Vec<i16>, u32, f64) {
-        let mut file = File::open(&self.file_raw).unwrap();
-
-        let mut pcm = vec![];
-
-        file.read_to_end(&mut pcm).unwrap();
+    /// Open the converted raw audio, returns the file, the number of channels and the duration
+    ///
+    /// The temporary files are removed, but the opened file stays readable until it is closed.
+    pub fn open(&self) -> (File, u32, f64) {
+        let file = File::open(&self.file_raw).unwrap();
+        let len = file.metadata().unwrap().len();
 
         fs::remove_file(&self.file_raw).unwrap();
         fs::remove_file(&self.file_in).unwrap();
 
-        let pcm: &[i16] = unsafe {
-            slice::from_raw_parts(
-                pcm.as_ptr() as *const i16,
-                pcm.len() / 2
-            )
-        };
-
-        (pcm.into(), 2, pcm.len() as f64 / 2.0 / 48000.0)
+        (file, 2, len as f64 / 4.0 / 48000.0)
     }
 }   
 
@@ -5,6 +5,7 @@ pub mod download;
 
 use std::mem;
 use std::path::{PathBuf};
+use std::fs::File;
 use std::rc::Rc;
 use std::cell::RefCell;
 
@@ -80,8 +81,8 @@ impl UploadState {
         }
     }
 
-    pub fn converting_opus(handle: Handle, id: PacketId, desc: String, samples: &[i16], duration: f32, num_channel: u32, data_path: PathBuf) -> UploadState {
-        let mut dwnd = opus::Converter::new(handle.clone(), desc.clone(), Vec::from(samples), duration, num_channel, data_path);
+    pub fn converting_opus(handle: Handle, id: PacketId, desc: String, pcm: File, duration: f32, num_channel: u32, data_path: PathBuf) -> UploadState {
+        let mut dwnd = opus::Converter::new(handle.clone(), desc.clone(), pcm, duration, num_channel, data_path);
 
         let state = Rc::new(RefCell::new(opus::State::empty(desc)));
         let state2 = state.clone();
@@ -121,9 +122,9 @@ impl UploadState {
                 let state = state.borrow();
 
                 if state.progress >= 0.999 {
-                    let (data, num_channel, duration) = state.read();
+                    let (pcm, num_channel, duration) = state.open();
 
-                    (Some(UploadState::converting_opus(converter.handle.clone(), id.clone(), state.desc.clone(), &data, duration as f32, num_channel, data_path)), None)
+                    (Some(UploadState::converting_opus(converter.handle.clone(), id.clone(), state.desc.clone(), pcm, duration as f32, num_channel, data_path)), None)
                 } else {
                     (None, None)
                 }
@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 use std::thread;
 use std::fs::File;
+use std::io::Read;
 
 use futures::{IntoFuture, Future, Stream};
 use futures::sync::mpsc::{channel, Sender, Receiver};
@@ -8,10 +9,14 @@ use tokio_core::reactor::Handle;
 
 use error::{Result, Error};
 
-use hex_music_container::{Container, Configuration};
+use hex_music_container::{ContainerWriter, Configuration, Metadata};
+use tempfile::NamedTempFile;
 
-use acousticid;
-use hex_database::Track;
+use acousticid::Fingerprint;
+use hex_database::{Track, content_hash};
+
+/// Number of bytes converted at once, a second of stereo audio
+const BLOCK_SIZE: u64 = 48000 * 2 * 2;
 
 pub struct State {
     pub progress: f32,
@@ -29,20 +34,73 @@ impl State {
     }
 }
 
-fn worker(mut sender: Sender<State>, desc: String, samples: Vec<i16>, duration: f32, num_channel: u32, data_path: PathBuf) -> Result<Track> {
-    //loop {
-        // calculate the acousticid of the file
-    let fingerprint = acousticid::get_fingerprint(num_channel as u16, &samples)?;
-    let track = Track::empty(fingerprint, duration.into());
+fn worker(mut sender: Sender<State>, desc: String, mut pcm: File, duration: f32, num_channel: u32, data_path: PathBuf) -> Result<Track> {
+    sender.try_send(State { progress: 0.0, desc: desc.clone(), data: None })
+        .map_err(|_| Error::ChannelFailed)?;
+
+    // the key of the track is derived from its fingerprint, which is known at the end
+    let mut file = NamedTempFile::new_in(&data_path)
+        .map_err(|err| Error::Io(err))?;
+    let mut fingerprint = Fingerprint::new(num_channel as u16);
 
-    let file = File::create(data_path.join(track.key.to_path())).unwrap();
+    let (fingerprint, loudness) = {
+        let mut writer = ContainerWriter::new(Configuration::Stereo, file.as_file_mut())
+            .map_err(|err| Error::MusicContainer(err))?;
 
-    sender.try_send(State { progress: 0.0, desc: desc, data: None })
-        .map_err(|_| Error::ChannelFailed)?;
+        let total = duration * 48000.0;
+        let mut buf = Vec::new();
+
+        loop {
+            buf.clear();
+            let nread = (&mut pcm).take(BLOCK_SIZE).read_to_end(&mut buf)
+                .map_err(|err| Error::Io(err))?;
+
+            // ignore an incomplete sample at the end of the file
+            let nread = nread - nread % (2 * num_channel as usize);
+            if nread == 0 {
+                break;
+            }
+
+            let samples: Vec<i16> = buf[..nread].chunks(2)
+                .map(|x| (x[0] as u16 | (x[1] as u16) << 8) as i16)
+                .collect();
+
+            fingerprint.feed(&samples);
+            writer.write(&samples)
+                .map_err(|err| Error::MusicContainer(err))?;
+
+            // the state is only informative, skip it if the channel is full
+            let progress = f32::min(writer.samples() as f32 / total, 0.99);
+            let _ = sender.try_send(State { progress, desc: desc.clone(), data: None });
+        }
+
+        let fingerprint = fingerprint.finish()?;
+
+        let mut container = writer.finish()
+            .map_err(|err| Error::MusicContainer(err))?;
+        let loudness = container.loudness();
+
+        // embed the fingerprint, which identifies the file without the database
+        let metadata = Metadata {
+            fingerprint: Some(fingerprint.clone()),
+            loudness: loudness.map(|x| x.integrated),
+            ..Metadata::default()
+        };
+        container.set_metadata(&metadata)
+            .map_err(|err| Error::MusicContainer(err))?;
+
+        (fingerprint, loudness)
+    };
+
+    let mut track = Track::empty(fingerprint, duration.into());
+    track.loudness = loudness.map(|x| x.integrated as f64);
+    track.true_peak = loudness.map(|x| x.true_peak as f64);
+    // other peers check their download of the file against it
+    track.content_hash = Some(content_hash(file.as_file_mut())
+        .map_err(|err| Error::Database(err))?);
 
-    // TODO realtime
-    Container::save_pcm(Configuration::Stereo, &samples, file, None)
-        .map_err(|err| Error::MusicContainer(err))?;
+    file.persist(data_path.join(track.key.to_path()))
+        .map_err(|err| Error::Io(err.error))?;
 
     Ok(track)
 }
@@ -54,12 +112,12 @@ pub struct Converter {
 }
 
 impl Converter {
-    pub fn new(handle: Handle, desc: String, samples: Vec<i16>, duration: f32, num_channel: u32, data_path: PathBuf) -> Converter {
+    pub fn new(handle: Handle, desc: String, pcm: File, duration: f32, num_channel: u32, data_path: PathBuf) -> Converter {
         let (sender, recv) = channel(10);
 
         let thread = thread::spawn(move || {
             let mut sender2 = sender.clone();
-            let res = worker(sender, desc.clone(), samples, duration, num_channel, data_path)?;
+            let res = worker(sender, desc.clone(), pcm, duration, num_channel, data_path)?;
 
             sender2.try_send(State { progress: 1.0, desc: desc, data: Some(res) })
                 .map_err(|_| Error::ChannelFailed)?;
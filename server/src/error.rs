@@ -30,5 +30,9 @@ pub enum Error {
     /// Could not download with youtube-dl
     ConvertYoutube,
     /// Channel failed
-    ChannelFailed
+    ChannelFailed,
+    /// Request doesn't continue the previous request with the same id, e.g. seek before stream
+    InvalidRequest,
+    /// Tried to seek past the end of a track
+    SeekOutOfRange
 }
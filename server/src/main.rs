@@ -19,6 +19,10 @@
 //! port = 8004
 //! name = "Peer"
 //! sync_all = true
+//! # or only keep some playlists and tokens, or a cache of recently played tracks (MiB)
+//! # sync_playlists = [1, 4]
+//! # sync_tokens = [2]
+//! # cache_quota = 4096
 //! ```
 //!
 //! and can then be passed as an argument. (e.g. `./target/release/hex_server conf.toml`)
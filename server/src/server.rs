@@ -22,7 +22,7 @@ use state::State;
 use hex_conf::Conf;
 
 use hex_server_protocol::{Answer, AnswerAction};
-use hex_database::{Instance, GossipConf, TransitionAction};
+use hex_database::{Instance, GossipConf, Keypair, Permissions, Policy, TransitionAction};
 
 /// Start the websocket server, supplied with a configuration
 pub fn start(conf: Conf, path: PathBuf) {
@@ -35,14 +35,34 @@ pub fn start(conf: Conf, path: PathBuf) {
     let incoming = Server::bind(addr, &handle).unwrap().incoming();
 
     let mut gossip = GossipConf::new();
+    let mut permissions = Permissions::new();
     
     if let Some(ref peer) = conf.peer {
         gossip = gossip.addr((conf.host, peer.port));
-        gossip = gossip.id(peer.id());
+        gossip = gossip.keypair(Keypair::from_seed(&peer.secret()));
         gossip = gossip.network_key(peer.network_key());
+        for key in peer.accepted_network_keys() {
+            gossip = gossip.accept_network_key(key);
+        }
+        permissions = Permissions::from_names(peer.trusted());
     }
 
-    let mut instance = Instance::from_file(&path.join("music.db"), gossip);
+    let mut instance = match Instance::with_permissions(&path.join("music.db"), gossip, permissions) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error: Could not open database {:?}", err);
+            return;
+        }
+    };
+
+    // replicate audio data from other peers in the background
+    let replication = conf.peer.as_ref().and_then(|peer| {
+        let policy = Policy::new(peer.sync_all, peer.sync_playlists.clone(), peer.sync_tokens.clone(), peer.cache_quota());
+
+        instance.replicate(policy)
+            .map_err(|err| eprintln!("Error: Could not start replication {:?}", err))
+            .ok()
+    });
 
     let broadcasts: Rc<RefCell<Vec<Sender<TransitionAction>>>> = Rc::new(RefCell::new(Vec::new()));
 
@@ -79,9 +99,19 @@ pub fn start(conf: Conf, path: PathBuf) {
                 return Ok(());
             }
 
+            // every connection reads the database with its own view
+            let view = match instance.view() {
+                Ok(view) => view,
+                Err(err) => {
+                    eprintln!("Could not open database view = {:?}", err);
+                    spawn_future(upgrade.reject(), &handle);
+                    return Ok(());
+                }
+            };
+
             let handle2 = handle.clone();
             let path_cpy = path.clone();
-            let view = instance.view();
+            let replication = replication.clone();
             let (s, r) = channel(1024);
 
             broadcasts.borrow_mut().push(s);
@@ -91,7 +121,7 @@ pub fn start(conf: Conf, path: PathBuf) {
                 .use_protocol("rust-websocket")
                 .accept()
                 .and_then(move |(s,_)| {
-                    let mut state = State::new(handle2, &path_cpy, view);
+                    let mut state = State::new(handle2, &path_cpy, view, replication, addr.ip().to_string());
 
                     let (sink, stream) = s.split();
 
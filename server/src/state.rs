@@ -7,7 +7,9 @@
 use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::slice;
+use std::thread;
 
 use tokio_core::reactor::Handle;
 
@@ -15,9 +17,10 @@ use error::{Result, Error};
 
 use convert::{UploadState, download::{DownloadState}};
 
-use hex_database::{self, Track, Token, View, Playlist};
+use hex_database::{self, Track, Token, View, Playlist, Action, Replication};
 use hex_music_container::{self, Configuration, Container};
-use hex_server_protocol::{Request, Answer, RequestAction, AnswerAction, PacketId, objects::UploadProgress};
+use hex_server_protocol::{Request, Answer, RequestAction, AnswerAction, PacketId};
+use hex_server_protocol::objects::{UploadProgress, Statistics, StatisticsResult};
 
 use acousticid;
 
@@ -53,13 +56,17 @@ pub struct State {
     uploads: Vec<UploadState>,
     /// All downloads
     downloads: Vec<DownloadState>,
+    /// Replication of audio data from other peers
+    replication: Option<Replication>,
     /// Have we inserted a token last time?
-    token_avail: bool
+    token_avail: bool,
+    /// Origin of all requests, used to log events
+    origin: String
 }
 
 impl State {
     /// Create a new `State` from a configuration
-    pub fn new(handle: Handle, path: &Path, view: View) -> State {
+    pub fn new(handle: Handle, path: &Path, view: View, replication: Option<Replication>, origin: String) -> State {
         State {
             handle: handle,
             reqs: HashMap::new(),
@@ -67,7 +74,9 @@ impl State {
             data_path: path.join("data"),
             uploads: Vec::new(),
             downloads: Vec::new(),
-            token_avail: false
+            replication: replication,
+            token_avail: false,
+            origin: origin
         }
     }
 
@@ -89,7 +98,25 @@ impl State {
                     people.as_ref().map(String::as_str), 
                     composer.as_ref().map(String::as_str)
                 )
-                    .map(|x| AnswerAction::UpdateTrack(x))
+                    .map(|x| {
+                        // the audio file is only present if it was replicated to us, it is
+                        // copied while updating and therefore written in the background
+                        let path = self.data_path.join(x.to_path());
+                        if path.exists() {
+                            match self.collection.get_track(x) {
+                                Ok(track) => {
+                                    thread::spawn(move || {
+                                        if let Err(err) = hex_database::store_metadata(&path, &track) {
+                                            warn!("Could not store metadata of {}: {:?}", track.key, err);
+                                        }
+                                    });
+                                },
+                                Err(err) => warn!("Could not store metadata of {}: {:?}", x, err)
+                            }
+                        }
+
+                        AnswerAction::UpdateTrack(x)
+                    })
                     .map_err(|err| Error::Database(err))
             },
             RequestAction::Search { query } => {
@@ -101,55 +128,90 @@ impl State {
 
                 let (query, seek) = match prior_state {
                     &mut RequestState::Search{ ref mut query, ref mut seek } => (query, seek),
-                    _ => panic!("blub")
+                    _ => return Answer::new(id, Err(format!("{:?}", Error::InvalidRequest)))
                 };
 
-                self.collection.search_limited(&query, *seek)
-                    .map(|x| {
+                match self.collection.search_limited(&query, *seek) {
+                    Ok(x) => {
                         // update information about position in stream
                         let more = x.len() >= 50;
                         remove = !more;
-                        *seek += x.len() + 1;
+                        *seek += x.len();
 
                         // create a struct containing all results
-                        AnswerAction::SearchResult {
+                        Ok(AnswerAction::SearchResult {
                             query: query.clone(),
                             answ: x,
                             more: more
-                        }
-                    })
-                    .map_err(|err| Error::Database(err))
+                        })
+                    },
+                    Err(hex_database::Error::Search(err)) => {
+                        remove = true;
+
+                        // tell the client where the query is wrong
+                        Ok(AnswerAction::SearchError {
+                            query: query.clone(),
+                            error: err
+                        })
+                    },
+                    Err(err) => Err(Error::Database(err))
+                }
             },
             RequestAction::StreamNext { key } => {
                 let State {
                     ref data_path,
                     ref collection,
                     ref mut reqs,
+                    ref origin,
                     ..
                 } = *self;
 
                 let entry = reqs.entry(id.clone());
 
-                let prior_state = entry
-                    .or_insert_with(|| {
-                        //collection.add_event(Action::PlaySong(key.unwrap()).with_origin(origin)).unwrap();
-                        let file = File::open(data_path.join(key.unwrap().to_path())).unwrap();
-
-                        RequestState::Stream {
-                            container: Container::<File>::load(file).unwrap(),
-                            track: collection.get_track(key.unwrap()).unwrap()
+                let prior_state = match entry {
+                    Entry::Occupied(x) => x.into_mut(),
+                    Entry::Vacant(x) => {
+                        // the first request of a stream has to contain the track key
+                        let key = match key {
+                            Some(key) => key,
+                            None => return Answer::new(id, Err(format!("{:?}", Error::Database(hex_database::Error::NotFound))))
+                        };
+
+                        let stream = collection.get_track(key)
+                            .map_err(|err| Error::Database(err))
+                            .and_then(|track| {
+                                let file = File::open(data_path.join(key.to_path()))
+                                    .map_err(|err| Error::Io(err))?;
+                                let container = Container::<File>::load(file)
+                                    .map_err(|err| Error::MusicContainer(err))?;
+
+                                Ok(RequestState::Stream { container, track })
+                            });
+
+                        match stream {
+                            Ok(stream) => {
+                                // remember that the track was played
+                                if let Err(err) = collection.add_event(Action::PlaySong(key).with_origin(origin.clone())) {
+                                    error!("Could not log play of {}: {:?}", key, err);
+                                }
+
+                                x.insert(stream)
+                            },
+                            Err(err) => return Answer::new(id, Err(format!("{:?}", err)))
                         }
-                    });
+                    }
+                };
                 
-                let mut container = match prior_state {
-                    &mut RequestState::Stream { ref mut container, .. } => container,
-                    _ => panic!("blub")
+                let (mut container, gain) = match prior_state {
+                    &mut RequestState::Stream { ref mut container, ref track } =>
+                        (container, track.measured_loudness().map(|x| x.gain()).unwrap_or(1.0)),
+                    _ => return Answer::new(id, Err(format!("{:?}", Error::InvalidRequest)))
                 };
 
 
                 let mut pcm = Ok(Vec::new());
                 for _ in 0..10 {
-                    let data = container.next_packet(Configuration::Stereo)
+                    let data = container.next_packet(Configuration::Stereo, gain)
                         .map(|x| {
                             unsafe {
                                 slice::from_raw_parts(
@@ -190,13 +252,14 @@ impl State {
             },
 
             RequestAction::StreamSeek { sample } => {
-                let (mut container, track) = match self.reqs.get_mut(&id).unwrap() {
-                    &mut RequestState::Stream { ref mut container, ref mut track } => (container, track),
-                    _ => panic!("blub")
+                // a seek is only valid after the stream was started with `StreamNext`
+                let (mut container, track) = match self.reqs.get_mut(&id) {
+                    Some(&mut RequestState::Stream { ref mut container, ref mut track }) => (container, track),
+                    _ => return Answer::new(id, Err(format!("{:?}", Error::InvalidRequest)))
                 };
 
                 if sample as f64 > track.duration * 48000.0 {
-                    panic!("blub");
+                    return Answer::new(id, Err(format!("{:?}", Error::SeekOutOfRange)));
                 }
                 
                 //let pos = self.collection.stream_seek(pos, &track, &mut file);
@@ -223,17 +286,21 @@ impl State {
             },
 
             RequestAction::AddPlaylist { name } => {
-                let key = self.collection.last_playlist_key().unwrap() + 1;
-                let playlist = Playlist {
-                    key: key.clone(),
-                    title: name,
-                    desc: None,
-                    tracks: Vec::new(),
-                    origin: self.collection.id()
-                };
-
-                self.collection.add_playlist(playlist.clone())
-                    .map(|_| AnswerAction::AddPlaylist(playlist))
+                let collection = &self.collection;
+
+                collection.last_playlist_key()
+                    .and_then(|key| Ok(Playlist {
+                        key: key + 1,
+                        title: name,
+                        desc: None,
+                        tracks: Vec::new(),
+                        origin: collection.id()?,
+                        query: None
+                    }))
+                    .and_then(|playlist| {
+                        collection.add_playlist(playlist.clone())
+                            .map(|_| AnswerAction::AddPlaylist(playlist))
+                    })
                     .map_err(|err| Error::Database(err))
             },
 
@@ -265,8 +332,16 @@ impl State {
                     .map_err(|err| Error::Database(err))
             },
 
+            RequestAction::SetPlaylistQuery { key, query } => {
+                self.collection.set_playlist_query(key, query)
+                    .map(|_| AnswerAction::SetPlaylistQuery)
+                    .map_err(|err| Error::Database(err))
+            },
+
             RequestAction::GetPlaylists => {
-                Ok(AnswerAction::GetPlaylists(self.collection.get_playlists()))
+                self.collection.get_playlists()
+                    .map(|x| AnswerAction::GetPlaylists(x))
+                    .map_err(|err| Error::Database(err))
             },
 
             RequestAction::GetPlaylist { key }=> {
@@ -288,10 +363,16 @@ impl State {
             },
             RequestAction::DeleteTrack { key } => {
                 println!("Delete track with key: {}", key);
-                //self.collection.add_event(Action::DeleteSong(key.clone()).with_origin(origin.clone())).unwrap();
+                let res = self.collection.delete_track(key);
+
+                // only log deletions which actually happened
+                if res.is_ok() {
+                    if let Err(err) = self.collection.add_event(Action::DeleteSong(key.clone()).with_origin(self.origin.clone())) {
+                        error!("Could not log deletion of {}: {:?}", key, err);
+                    }
+                }
 
-                self.collection.delete_track(key)
-                    .map(|x| AnswerAction::DeleteTrack(x))
+                res.map(|x| AnswerAction::DeleteTrack(x))
                     .map_err(|err| Error::Database(err))
             },
 
@@ -316,7 +397,9 @@ impl State {
                 // tick each item
                 for item in &mut self.uploads {
                     if let Some(track) = item.tick(self.data_path.clone()) {
-                        self.collection.add_track(track).unwrap();
+                        if let Err(err) = self.collection.add_track(track) {
+                            error!("Could not add uploaded track: {:?}", err);
+                        }
                     }
                 }
 
@@ -365,16 +448,17 @@ impl State {
                     .map_err(|err| Error::Database(err))
             },
             RequestAction::CreateToken => {
-                let id = self.collection.last_token_id().unwrap() + 1;
-                let token = Token {
-                    token: id,
-                    key: None,
-                    played: Vec::new(),
-                    pos: None,
-                    last_use: 0
-                };
-
-                self.collection.add_token(token)
+                let collection = &self.collection;
+
+                collection.last_token_id()
+                    .map(|id| Token {
+                        token: id + 1,
+                        key: None,
+                        played: Vec::new(),
+                        pos: None,
+                        last_use: 0
+                    })
+                    .and_then(|token| collection.add_token(token))
                     .map(|id| AnswerAction::CreateToken(id))
                     .map_err(|err| Error::Database(err))
             },
@@ -393,10 +477,33 @@ impl State {
                     .map_err(|err| Error::Database(err))
             },
             RequestAction::GetSummary => {
-                Ok(AnswerAction::GetSummary(self.collection.get_complete_summary()))
+                self.collection.get_complete_summary()
+                    .map(|x| AnswerAction::GetSummary(x))
+                    .map_err(|err| Error::Database(err))
             },
             RequestAction::GetTransitions => {
-                Ok(AnswerAction::GetTransitions(self.collection.get_transitions()))
+                self.collection.get_transitions()
+                    .map(|x| AnswerAction::GetTransitions(x))
+                    .map_err(|err| Error::Database(err))
+            },
+            RequestAction::GetStatistics { query, from, to, origin } => {
+                let (range, origin) = (from..to, origin.as_ref().map(String::as_str));
+
+                let res = match query {
+                    Statistics::TopTracks { limit } => self.collection.get_top_tracks(range, origin, limit)
+                        .map(|x| StatisticsResult::TopTracks(x)),
+                    Statistics::TopInterprets { limit } => self.collection.get_top_interprets(range, origin, limit)
+                        .map(|x| StatisticsResult::TopInterprets(x)),
+                    Statistics::TopAlbums { limit } => self.collection.get_top_albums(range, origin, limit)
+                        .map(|x| StatisticsResult::TopAlbums(x)),
+                    Statistics::ListeningTime => self.collection.get_listening_time(range, origin)
+                        .map(|x| StatisticsResult::ListeningTime(x)),
+                    Statistics::Heatmap => self.collection.get_heatmap(range, origin)
+                        .map(|x| StatisticsResult::Heatmap(x))
+                };
+
+                res.map(|x| AnswerAction::GetStatistics(x))
+                    .map_err(|err| Error::Database(err))
             },
             RequestAction::Download { format, tracks } => {
                 let id = id.clone();
@@ -417,6 +524,17 @@ impl State {
                     .collect();
 
                 Ok(AnswerAction::AskDownloadProgress(res))
+            },
+            RequestAction::AskReplicationProgress => {
+                Ok(AnswerAction::AskReplicationProgress(self.replication.as_ref().map(|x| x.progress())))
+            },
+            RequestAction::GetPeers => {
+                // a server without gossip simply knows no peers
+                match self.collection.peers() {
+                    Ok(peers) => Ok(AnswerAction::GetPeers(peers)),
+                    Err(hex_database::Error::Offline) => Ok(AnswerAction::GetPeers(Vec::new())),
+                    Err(err) => Err(Error::Database(err))
+                }
             }
         };
 
@@ -16,8 +16,6 @@ mod events;
 mod token;
 
 use std::env;
-use std::fs::File;
-use std::io::Write;
 use std::path::PathBuf;
 use std::thread;
 use std::sync::mpsc::{Sender, Receiver, channel};
@@ -27,7 +25,7 @@ use events::Event;
 
 use error::Error;
 
-use hex_database::{Instance, Token, GossipConf, TrackKey};
+use hex_database::{Instance, Token, GossipConf, Keypair, Permissions, Policy, TrackKey, Action};
 
 fn main() {
     let (conf, path) = match hex_conf::Conf::new() {
@@ -41,15 +39,41 @@ fn main() {
     let db_path = path.join("music.db");
 
     let mut gossip = GossipConf::new();
+    let mut permissions = Permissions::new();
     
     if let Some(ref peer) = conf.peer {
         gossip = gossip.addr((conf.host, peer.port));
-        gossip = gossip.id(peer.id());
+        gossip = gossip.keypair(Keypair::from_seed(&peer.secret()));
         gossip = gossip.network_key(peer.network_key());
+        for key in peer.accepted_network_keys() {
+            gossip = gossip.accept_network_key(key);
+        }
+        permissions = Permissions::from_names(peer.trusted());
     }
 
-    let mut instance = Instance::from_file(&db_path, gossip);
-    let view = instance.view();
+    let mut instance = match Instance::with_permissions(&db_path, gossip, permissions) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error: Could not open database {:?}", err);
+            return;
+        }
+    };
+    let view = match instance.view() {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error: Could not open database view {:?}", err);
+            return;
+        }
+    };
+
+    // fetch the audio data of other peers in the background, as configured
+    let _replication = conf.peer.as_ref().and_then(|peer| {
+        let policy = Policy::new(peer.sync_all, peer.sync_playlists.clone(), peer.sync_tokens.clone(), peer.cache_quota());
+
+        instance.replicate(policy)
+            .map_err(|err| eprintln!("Error: Could not start replication {:?}", err))
+            .ok()
+    });
 
     let data_path_2 = data_path.clone();
     let (sender, receiver): (Sender<TrackKey>, Receiver<TrackKey>) = channel();
@@ -62,11 +86,10 @@ fn main() {
             }
 
             println!("Ask for file {}", key.to_string());
-            let buf = instance.ask_for_file(key.to_vec()).wait().unwrap();
-
-            println!("Got file write ..!");
-            let mut file = File::create(path).unwrap();
-            file.write(&buf).unwrap();
+            match instance.ask_for_file(key.to_vec()).map(|file| file.wait()) {
+                Ok(Ok(_)) => println!("Got file {}", key.to_string()),
+                _ => eprintln!("Could not download file {}", key.to_string())
+            }
         }
     });
     
@@ -115,7 +138,7 @@ fn main() {
                             },
                             Err(hex_database::Error::NotFound) => {
                                 println!("Not found!");
-                                let id = view.last_token_id().unwrap() + 1;
+                                let id = view.last_token_id().expect("Error: Could not get the last token id!") + 1;
                                 let token = Token {
                                     token: id,
                                     key: None,
@@ -164,7 +187,7 @@ fn main() {
 
         if create_counter == 3 {
             println!("Reset token to new id ..");
-            let id = view.last_token_id().unwrap() + 1;
+            let id = view.last_token_id().expect("Error: Could not get the last token id!") + 1;
             let token = Token {
                 token: id,
                 key: None,
@@ -185,6 +208,13 @@ fn main() {
                 if let Some(packet) = token.next_packet() {
                     audio.buffer(&packet);
                 }
+
+                // log a play whenever a track starts, also if it is the same as before
+                if let Some(key) = token.take_started() {
+                    if let Err(err) = view.add_event(Action::PlaySong(key).with_origin("zyklop".into())) {
+                        eprintln!("Error: Could not log play of {:?}: {:?}", key, err);
+                    }
+                }
             } else {
             }
         }
@@ -6,17 +6,18 @@ use std::path::{Path, PathBuf};
 use rand::{thread_rng, Rng};
 
 use hex_database::{Track, Token, TrackKey};
-use hex_music_container::{Container, Configuration};
+use hex_music_container::{Container, Configuration, Loudness};
 
 use error::{Error, Result};
 
 pub struct Stream {
     pub track: Track,
-    container: Container<File>
+    container: Container<File>,
+    gain: f32
 }
 
 impl Stream {
-    pub fn new(track: Track, data_path: &Path) -> Result<Stream> {
+    pub fn new(track: Track, data_path: &Path, gain: f32) -> Result<Stream> {
         let path = data_path.join(track.key.to_path());
         
         while !path.exists() {
@@ -31,12 +32,12 @@ impl Stream {
             .map_err(|err| Error::MusicContainer(err))?;
 
         Ok(Stream {
-            track, container
+            track, container, gain
         })
     }
 
     pub fn next(&mut self) -> Result<Vec<i16>> {
-        self.container.next_packet(Configuration::Stereo)
+        self.container.next_packet(Configuration::Stereo, self.gain)
             .map_err(|err| Error::MusicContainer(err))
     }
 
@@ -55,11 +56,38 @@ pub struct Current {
     not_played: Vec<Track>,
     played: Vec<Track>,
     data_path: PathBuf,
-    sender: Sender<TrackKey>
+    sender: Sender<TrackKey>,
+    /// Shared gain if all tracks belong to the same album
+    album_gain: Option<f32>,
+    /// Set whenever a new stream started and not yet reported
+    started: bool
+}
+
+/// Gain of an album, if all tracks are from the same album and their loudness is known
+fn album_gain(tracks: &[Track]) -> Option<f32> {
+    let album_key = tracks.first()?.album_key?;
+    if tracks.iter().any(|x| x.album_key != Some(album_key)) {
+        return None;
+    }
+
+    let parts = tracks.iter()
+        .map(|x| x.measured_loudness().map(|loudness| (loudness, x.duration)))
+        .collect::<Option<Vec<(Loudness, f64)>>>()?;
+
+    Loudness::combine(&parts).map(|x| x.gain())
+}
+
+/// Gain of a single track, the album gain is preferred to keep the dynamic between its tracks
+fn track_gain(track: &Track, album_gain: Option<f32>) -> f32 {
+    album_gain
+        .or_else(|| track.measured_loudness().map(|x| x.gain()))
+        .unwrap_or(1.0)
 }
 
 impl Current {
     pub fn new(mut token: Token, mut tracks: Vec<Track>, data_path: PathBuf, sender: Sender<TrackKey>) -> Current {
+        let album_gain = album_gain(&tracks);
+
         let current_track_key = token.played.pop();
         let current_track = current_track_key.and_then(|track_key| {
             tracks.iter().position(|x| x.key == track_key)
@@ -72,7 +100,8 @@ impl Current {
         
         let stream = match current_track {
             Some(track) => {
-                let mut stream = Stream::new(track, &data_path).unwrap();
+                let gain = track_gain(&track, album_gain);
+                let mut stream = Stream::new(track, &data_path, gain).unwrap();
 
                 println!("Load current track: {:?}", token.pos);
 
@@ -85,13 +114,18 @@ impl Current {
             None => None
         };
 
+        // resuming a track in the middle doesn't count as another play
+        let started = stream.is_some() && token.pos.map(|pos| pos == 0.0).unwrap_or(true);
+
         Current {
             stream,
             token,
             played,
             not_played,
             data_path,
-            sender
+            sender,
+            album_gain,
+            started
         }
     }
 
@@ -110,6 +144,18 @@ impl Current {
         self.stream.as_ref().map(|x| x.track.key)
     }
 
+    /// Returns the key of the current track once after each start of a stream, also if the
+    /// same track is started again
+    pub fn take_started(&mut self) -> Option<TrackKey> {
+        if self.started {
+            self.started = false;
+
+            self.track_key()
+        } else {
+            None
+        }
+    }
+
     pub fn has_tracks(&self) -> bool {
         !self.not_played.is_empty() || !self.played.is_empty() || self.stream.is_some()
     }
@@ -149,7 +195,9 @@ impl Current {
 
     pub fn create_stream(&self, elm: Track, path: &Path) -> Option<Stream> {
         // TODO acquire file if not existing
-        Some(Stream::new(elm, path).unwrap())
+        let gain = track_gain(&elm, self.album_gain);
+
+        Some(Stream::new(elm, path, gain).unwrap())
     }
 
     pub fn next_track(&mut self) {
@@ -174,6 +222,7 @@ impl Current {
             let elm = self.not_played.remove(0);
             self.stream = self.create_stream(elm, &self.data_path);
             self.token.pos = Some(0.0);
+            self.started = true;
         }
     }
 
@@ -188,10 +237,12 @@ impl Current {
             let elm = self.not_played.remove(0);
             self.stream = self.create_stream(elm, &self.data_path);
             self.token.pos = Some(0.0);
+            self.started = true;
         } else {
             let elm = self.played.pop().unwrap();
             self.stream = self.create_stream(elm, &self.data_path);
             self.token.pos = Some(0.0);
+            self.started = true;
         }
     }
 